@@ -1,27 +1,136 @@
-use ethers::types::H160;
+use crate::{amm::Protocol, config::DEFAULT_FEE};
+use ethers::{providers::Middleware, types::H160};
 use serde::Deserialize;
 use serde_yaml;
-use std::{collections::HashMap, fs};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+};
 
+pub const MAINNET: u64 = 1;
+
+/// The address book shipped with the crate, so that it does not depend on the working
+/// directory.
+const EMBEDDED: &str = include_str!("address_book.yaml");
+
+#[derive(Debug)]
+pub enum AddressBookError {
+    Io(std::io::Error),
+    Parse(String),
+    /// The book itself is inconsistent, e.g. a wrapped native token missing from `erc20`.
+    Invalid(String),
+    UnknownChain(u64),
+    UnknownToken {
+        chain_id: u64,
+        name: String,
+    },
+    UnknownDex {
+        chain_id: u64,
+        name: String,
+    },
+    UnknownPair {
+        dex: String,
+        token_a: String,
+        token_b: String,
+    },
+    NoQuoter {
+        dex: String,
+    },
+    /// The node serves another chain than the network's.
+    ChainMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    /// An address of the book has no code on the connected chain.
+    NoCode {
+        name: String,
+        address: H160,
+    },
+    Rpc(String),
+}
+
+impl fmt::Display for AddressBookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressBookError::Io(err) => write!(f, "could not read address book: {}", err),
+            AddressBookError::Parse(reason) => {
+                write!(f, "could not parse address book: {}", reason)
+            }
+            AddressBookError::Invalid(reason) => write!(f, "invalid address book: {}", reason),
+            AddressBookError::UnknownChain(chain_id) => {
+                write!(f, "no network with chain id {}", chain_id)
+            }
+            AddressBookError::UnknownToken { chain_id, name } => {
+                write!(f, "no token `{}` on chain {}", name, chain_id)
+            }
+            AddressBookError::UnknownDex { chain_id, name } => {
+                write!(f, "no dex `{}` on chain {}", name, chain_id)
+            }
+            AddressBookError::UnknownPair {
+                dex,
+                token_a,
+                token_b,
+            } => write!(f, "no {}/{} pair on {}", token_a, token_b, dex),
+            AddressBookError::NoQuoter { dex } => write!(f, "{} has no quoter", dex),
+            AddressBookError::ChainMismatch { expected, actual } => write!(
+                f,
+                "connected to chain {} but the network is chain {}",
+                actual, expected
+            ),
+            AddressBookError::NoCode { name, address } => {
+                write!(f, "{} at {:?} has no code", name, address)
+            }
+            AddressBookError::Rpc(reason) => write!(f, "rpc error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for AddressBookError {}
+
+/// Networks by chain id.
 #[derive(Debug, Deserialize)]
+#[serde(transparent)]
 pub struct AddressBook {
-    pub mainnet: Network,
+    networks: BTreeMap<u64, Network>,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Network {
+    #[serde(skip)]
+    pub chain_id: u64,
+    pub name: String,
+    /// Name in `erc20` of the wrapped native token, e.g. WETH.
+    wrapped_native: String,
     pub erc20: HashMap<String, H160>,
-    pub uniswap_v2: UniswapV2,
+    #[serde(default)]
+    pub dexes: HashMap<String, Dex>,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct UniswapV2 {
+#[serde(deny_unknown_fields)]
+pub struct Dex {
+    #[serde(skip)]
+    pub name: String,
+    pub protocol: Protocol,
     pub factory: H160,
     pub router: H160,
+    /// Swap fee of the dex's Uniswap V2 style pools, in hundredths of a basis point.
+    #[serde(default = "default_fee")]
+    pub fee: u32,
+    /// Off-chain quoting contract, for concentrated liquidity pools.
+    #[serde(default)]
+    pub quoter: Option<H160>,
+    /// Known pairs by token name, listed under both tokens once loaded.
+    #[serde(default)]
     pub pairs: HashMap<String, HashMap<String, H160>>,
 }
 
-impl UniswapV2 {
+fn default_fee() -> u32 {
+    DEFAULT_FEE
+}
+
+impl Dex {
     fn add_inverse_pairs(&mut self) {
         let mut inverse_pairs = HashMap::new();
 
@@ -41,15 +150,155 @@ impl UniswapV2 {
                 .or_insert(sub_pairs);
         }
     }
+
+    pub fn pair(&self, token_a: &str, token_b: &str) -> Result<H160, AddressBookError> {
+        self.pairs
+            .get(token_a)
+            .and_then(|pairs| pairs.get(token_b))
+            .copied()
+            .ok_or_else(|| AddressBookError::UnknownPair {
+                dex: self.name.clone(),
+                token_a: token_a.to_string(),
+                token_b: token_b.to_string(),
+            })
+    }
+
+    pub fn quoter(&self) -> Result<H160, AddressBookError> {
+        self.quoter.ok_or_else(|| AddressBookError::NoQuoter {
+            dex: self.name.clone(),
+        })
+    }
+}
+
+impl Network {
+    pub fn token(&self, name: &str) -> Result<H160, AddressBookError> {
+        self.erc20
+            .get(name)
+            .copied()
+            .ok_or_else(|| AddressBookError::UnknownToken {
+                chain_id: self.chain_id,
+                name: name.to_string(),
+            })
+    }
+
+    pub fn wrapped_native(&self) -> H160 {
+        // Checked when the book is loaded
+        self.erc20[&self.wrapped_native]
+    }
+
+    pub fn dex(&self, name: &str) -> Result<&Dex, AddressBookError> {
+        self.dexes
+            .get(name)
+            .ok_or_else(|| AddressBookError::UnknownDex {
+                chain_id: self.chain_id,
+                name: name.to_string(),
+            })
+    }
+
+    /// Dexes of a protocol, e.g. every Uniswap V2 fork, by name.
+    pub fn dexes_by_protocol(&self, protocol: Protocol) -> Vec<&Dex> {
+        let mut dexes: Vec<&Dex> = self
+            .dexes
+            .values()
+            .filter(|d| d.protocol == protocol)
+            .collect();
+        dexes.sort_by(|a, b| a.name.cmp(&b.name));
+        dexes
+    }
+
+    /// Checks that the node serves this network and that every factory, router and quoter has
+    /// code there, so that addresses of one chain are never used on another.
+    pub async fn verify<M: Middleware>(&self, middleware: &M) -> Result<(), AddressBookError> {
+        let actual = middleware
+            .get_chainid()
+            .await
+            .map_err(|e| AddressBookError::Rpc(e.to_string()))?
+            .as_u64();
+        if actual != self.chain_id {
+            return Err(AddressBookError::ChainMismatch {
+                expected: self.chain_id,
+                actual,
+            });
+        }
+        let mut contracts = vec![];
+        for dex in self.dexes.values() {
+            contracts.push((format!("{} factory", dex.name), dex.factory));
+            contracts.push((format!("{} router", dex.name), dex.router));
+            if let Some(quoter) = dex.quoter {
+                contracts.push((format!("{} quoter", dex.name), quoter));
+            }
+        }
+        for (name, address) in contracts {
+            let code = middleware
+                .get_code(address, None)
+                .await
+                .map_err(|e| AddressBookError::Rpc(e.to_string()))?;
+            if code.is_empty() {
+                return Err(AddressBookError::NoCode { name, address });
+            }
+        }
+        Ok(())
+    }
 }
 
 impl AddressBook {
+    /// The embedded address book.
     pub fn new() -> Self {
-        let data: String = fs::read_to_string("src/address_book/address_book.yaml")
-            .expect("Could not read address_book.yaml");
-        let mut address_book: AddressBook = serde_yaml::from_str(&data).unwrap();
-        address_book.mainnet.uniswap_v2.add_inverse_pairs();
-        address_book
+        Self::from_yaml(EMBEDDED).expect("Embedded address book is invalid")
+    }
+
+    pub fn from_yaml(yaml: &str) -> Result<Self, AddressBookError> {
+        let mut address_book: AddressBook =
+            serde_yaml::from_str(yaml).map_err(|e| AddressBookError::Parse(e.to_string()))?;
+        for (chain_id, network) in address_book.networks.iter_mut() {
+            network.chain_id = *chain_id;
+            if !network.erc20.contains_key(&network.wrapped_native) {
+                return Err(AddressBookError::Invalid(format!(
+                    "wrapped native token `{}` of {} is not in erc20",
+                    network.wrapped_native, network.name
+                )));
+            }
+            for (name, dex) in network.dexes.iter_mut() {
+                dex.name = name.clone();
+                dex.add_inverse_pairs();
+            }
+        }
+        Ok(address_book)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, AddressBookError> {
+        Self::from_yaml(&fs::read_to_string(path).map_err(AddressBookError::Io)?)
+    }
+
+    pub fn network(&self, chain_id: u64) -> Result<&Network, AddressBookError> {
+        self.networks
+            .get(&chain_id)
+            .ok_or(AddressBookError::UnknownChain(chain_id))
+    }
+
+    /// The network of the chain `middleware` is connected to, verified with `Network::verify`.
+    pub async fn connected_network<M: Middleware>(
+        &self,
+        middleware: &M,
+    ) -> Result<&Network, AddressBookError> {
+        let chain_id = middleware
+            .get_chainid()
+            .await
+            .map_err(|e| AddressBookError::Rpc(e.to_string()))?
+            .as_u64();
+        let network = self.network(chain_id)?;
+        network.verify(middleware).await?;
+        Ok(network)
+    }
+
+    /// Shortcut for code that only ever runs on mainnet, such as the tests. Panics if the book
+    /// has no mainnet.
+    pub fn mainnet(&self) -> &Network {
+        self.network(MAINNET).expect("Address book has no mainnet")
+    }
+
+    pub fn networks(&self) -> impl Iterator<Item = &Network> {
+        self.networks.values()
     }
 }
 
@@ -63,16 +312,62 @@ mod tests {
     async fn test_new_address_book() {
         let book = AddressBook::new();
         assert_eq!(
-            book.mainnet.erc20["weth"],
+            book.mainnet().erc20["weth"],
             H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap()
         );
         assert_eq!(
-            book.mainnet.uniswap_v2.factory,
+            book.mainnet().dexes["uniswap_v2"].factory,
             H160::from_str("0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f").unwrap()
         );
         assert_eq!(
-            book.mainnet.uniswap_v2.pairs["weth"]["usdc"],
-            book.mainnet.uniswap_v2.pairs["usdc"]["weth"]
+            book.mainnet().dexes["uniswap_v2"].pairs["weth"]["usdc"],
+            book.mainnet().dexes["uniswap_v2"].pairs["usdc"]["weth"]
+        );
+    }
+
+    #[test]
+    fn test_networks() {
+        let book = AddressBook::new();
+        let chains: Vec<u64> = book.networks().map(|n| n.chain_id).collect();
+        assert_eq!(chains, vec![1, 10, 56, 137, 8453, 42161]);
+        for network in book.networks() {
+            assert_eq!(
+                network.token(&network.wrapped_native).unwrap(),
+                network.wrapped_native()
+            );
+            assert!(!network.dexes.is_empty(), "{} has no dex", network.name);
+        }
+        let arbitrum = book.network(42161).unwrap();
+        assert_eq!(arbitrum.name, "arbitrum");
+        assert_eq!(arbitrum.dexes_by_protocol(Protocol::UniswapV3).len(), 1);
+
+        assert_eq!(
+            book.network(5).unwrap_err().to_string(),
+            "no network with chain id 5"
+        );
+        assert_eq!(
+            arbitrum.token("matic").unwrap_err().to_string(),
+            "no token `matic` on chain 42161"
+        );
+        assert_eq!(book.network(56).unwrap().dex("pancakeswap_v2").unwrap().fee, 250);
+        let sushiswap = arbitrum.dex("sushiswap").unwrap();
+        assert_eq!(
+            sushiswap.quoter().unwrap_err().to_string(),
+            "sushiswap has no quoter"
+        );
+        assert_eq!(
+            book.mainnet()
+                .dex("uniswap_v2")
+                .unwrap()
+                .pair("usdc", "weth")
+                .unwrap(),
+            H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc").unwrap()
+        );
+
+        let invalid = "1:\n  name: mainnet\n  wrapped_native: weth\n  erc20: {}\n";
+        assert_eq!(
+            AddressBook::from_yaml(invalid).unwrap_err().to_string(),
+            "invalid address book: wrapped native token `weth` of mainnet is not in erc20"
         );
     }
 }
@@ -1,2 +1,23 @@
+use serde::{Deserialize, Serialize};
+
 pub mod uniswap_v2;
+pub mod valuation;
 pub mod weth_value;
+
+/// Protocol identifiers, matching the `protocol` field of `SimulatorV1.SwapParams`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Protocol {
+    UniswapV2,
+    UniswapV3,
+    Curve,
+}
+
+impl Protocol {
+    pub fn id(&self) -> u8 {
+        match self {
+            Protocol::UniswapV2 => 0,
+            Protocol::UniswapV3 => 1,
+            Protocol::Curve => 2,
+        }
+    }
+}
@@ -1,16 +1,16 @@
 use crate::amm::uniswap_v2::factory::UniswapV2Factory;
-use crate::concurrent::{run_concurrent, BatchError};
+use crate::checkpoint::log_archive::{LogArchive, LogArchiveError};
 use crate::contract::PairCreatedFilter;
 use ethers::abi::RawLog;
 use ethers::prelude::EthEvent;
 use ethers::providers::{Provider, Ws};
 use ethers::{
     providers::Middleware,
-    types::{BlockNumber, Filter, ValueOrArray, H160, H256, U64},
+    types::{Filter, ValueOrArray, H160, H256},
 };
 use futures::StreamExt;
-use indicatif::ProgressBar;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use tracing::info;
 
 pub const PAIR_CREATED_EVENT_SIGNATURE: H256 = H256([
     13, 54, 72, 189, 15, 107, 168, 1, 52, 163, 59, 169, 39, 90, 197, 133, 217, 211, 21, 240, 173,
@@ -18,53 +18,34 @@ pub const PAIR_CREATED_EVENT_SIGNATURE: H256 = H256([
 ]);
 
 impl UniswapV2Factory {
-    async fn get_pair_addresses_from_logs<'a, M: Middleware + 'a>(
-        &self,
-        start: usize,
-        end: usize,
-        middleware: Arc<M>,
-        progress_bar: Option<Arc<Mutex<ProgressBar>>>,
-    ) -> Result<Vec<H160>, BatchError> {
-        let logs = middleware
-            .get_logs(
-                &Filter::new()
-                    .topic0(ValueOrArray::Value(PAIR_CREATED_EVENT_SIGNATURE))
-                    .address(self.address)
-                    .from_block(BlockNumber::Number(U64([start as u64])))
-                    .to_block(BlockNumber::Number(U64([end as u64]))),
-            )
-            .await
-            .map_err(|_| BatchError::new(start, end))?;
-
-        let mut addresses = vec![];
-        for log in logs {
-            let pair_created_event: PairCreatedFilter =
-                PairCreatedFilter::decode_log(&RawLog::from(log))
-                    .map_err(|_| BatchError::new(start, end))?;
-            addresses.push(pair_created_event.pair);
-        }
-        if let Some(pb) = progress_bar {
-            pb.lock().unwrap().inc(end as u64 - start as u64);
-        }
-        Ok(addresses)
-    }
-
     pub async fn get_pair_addresses_from_logs_concurrent<'a, M: Middleware + 'a>(
         &self,
         start: usize,
         end: usize,
         step: usize,
         middleware: Arc<M>,
-    ) -> Vec<H160> {
-        let batch_func =
-            |start: usize, end: usize, middleware: Arc<M>, pb: Option<Arc<Mutex<ProgressBar>>>| {
-                self.get_pair_addresses_from_logs(start, end, middleware.clone(), pb)
-            };
-        println!(
-            "Getting pair addresses from logs for Uniswap v2 factory {:?}, from block {} to {} with step {}",
-            self.address, start, end, step
+    ) -> Result<Vec<H160>, LogArchiveError> {
+        info!(
+            factory = ?self.address,
+            from_block = start,
+            to_block = end,
+            step,
+            "getting pair addresses from logs"
         );
-        run_concurrent(start, end, step, middleware, batch_func).await
+        let logs = LogArchive::get_logs(
+            PAIR_CREATED_EVENT_SIGNATURE,
+            &[self.address],
+            start as u64,
+            end as u64,
+            step,
+            middleware,
+        )
+        .await?;
+        Ok(logs
+            .into_iter()
+            .filter_map(|log| PairCreatedFilter::decode_log(&RawLog::from(log)).ok())
+            .map(|event| event.pair)
+            .collect())
     }
 
     pub async fn subscribe_pair_created_event<F>(wss: Arc<Provider<Ws>>, func: F)
@@ -1,10 +1,16 @@
 pub mod events;
 mod pair_addresses_batch_request;
-use ethers::{providers::Middleware, types::H160};
+use ethers::{
+    providers::Middleware,
+    types::{BlockNumber, H160},
+};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
 use crate::contract::IUniswapV2Factory;
+use tracing::warn;
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UniswapV2Factory {
     pub address: H160,
     pub fee: u64,
@@ -18,9 +24,14 @@ impl UniswapV2Factory {
         IUniswapV2Factory::new(self.address, middleware)
     }
 
-    pub async fn all_pairs_length<M: Middleware>(&self, middleware: Arc<M>) -> u64 {
+    pub async fn all_pairs_length<M: Middleware>(
+        &self,
+        middleware: Arc<M>,
+        block: BlockNumber,
+    ) -> u64 {
         self.contract(middleware)
             .all_pairs_length()
+            .block(block)
             .call()
             .await
             .expect(
@@ -52,7 +63,7 @@ impl UniswapV2Factory {
                 .as_str(),
             );
         if address == H160::zero() {
-            println!("Could not find pair for {:?} and {:?}", token_a, token_b);
+            warn!(?token_a, ?token_b, "no pair for tokens");
         }
         address
     }
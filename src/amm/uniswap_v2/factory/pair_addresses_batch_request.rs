@@ -3,7 +3,7 @@ use std::sync::{Arc, Mutex};
 use ethers::{
     abi::{ParamType, Token},
     providers::Middleware,
-    types::{Bytes, H160, U256},
+    types::{BlockNumber, Bytes, H160, U256},
 };
 use indicatif::ProgressBar;
 
@@ -11,6 +11,7 @@ use crate::{
     concurrent::{run_concurrent, BatchError},
     contract::GetUniswapV2PairsBatchRequest,
 };
+use tracing::info;
 
 use super::UniswapV2Factory;
 
@@ -20,6 +21,7 @@ impl UniswapV2Factory {
         start: usize,
         end: usize,
         middleware: Arc<M>,
+        block: BlockNumber,
         progress_bar: Option<Arc<Mutex<ProgressBar>>>,
     ) -> Result<Vec<H160>, BatchError> {
         let mut pairs = vec![];
@@ -32,6 +34,7 @@ impl UniswapV2Factory {
         let deployer = GetUniswapV2PairsBatchRequest::deploy(middleware, constructor_args)
             .map_err(|_| BatchError::new(start, end))?;
         let return_data: Bytes = deployer
+            .block(block)
             .call_raw()
             .await
             .map_err(|_| BatchError::new(start, end))?;
@@ -65,14 +68,20 @@ impl UniswapV2Factory {
         end: usize,
         step: usize,
         middleware: Arc<M>,
+        block: BlockNumber,
     ) -> Vec<H160> {
-        let batch_func =
-            |start: usize, end: usize, middleware: Arc<M>, pb: Option<Arc<Mutex<ProgressBar>>>| {
-                self.get_pair_addresses_from_factory_batch(start, end, middleware.clone(), pb)
-            };
-        println!(
-            "Getting pair addresses from Uniswap v2 factory {:?}, from {} to {} with step {}",
-            self.address, start, end, step
+        let batch_func = |start: usize,
+                          end: usize,
+                          middleware: Arc<M>,
+                          pb: Option<Arc<Mutex<ProgressBar>>>| {
+            self.get_pair_addresses_from_factory_batch(start, end, middleware.clone(), block, pb)
+        };
+        info!(
+            factory = ?self.address,
+            start,
+            end,
+            step,
+            "getting pair addresses from factory"
         );
         run_concurrent(start, end, step, middleware, batch_func).await
     }
@@ -1,11 +1,18 @@
 use crate::tests::fixtures;
+use ethers::types::BlockNumber;
 
 #[tokio::test]
 async fn test_get_pair_addresses_from_factory_concurrent_success() {
     let fixture = fixtures::Fixtures::new().await;
     let result = fixture
         .uniswap_v2_factory
-        .get_pair_addresses_from_factory(0, 10, 1, fixture.alchemy_provider.http.clone())
+        .get_pair_addresses_from_factory(
+            0,
+            10,
+            1,
+            fixture.alchemy_provider.http.clone(),
+            BlockNumber::Latest,
+        )
         .await;
     assert_eq!(result.len(), 10);
 }
@@ -20,6 +27,7 @@ async fn test_get_pair_addresses_from_factory_concurrent_failure() {
             10_000_010,
             1,
             fixture.alchemy_provider.http.clone(),
+            BlockNumber::Latest,
         )
         .await;
     assert_eq!(result.len(), 0);
@@ -36,7 +44,8 @@ async fn test_get_pair_addresses_from_logs_success() {
             100,
             fixture.alchemy_provider.http.clone(),
         )
-        .await;
+        .await
+        .unwrap();
     assert_eq!(result.len(), 2);
 }
 
@@ -45,7 +54,7 @@ async fn test_all_pairs_length() {
     let fixture = fixtures::Fixtures::new().await;
     let result = fixture
         .uniswap_v2_factory
-        .all_pairs_length(fixture.alchemy_provider.http.clone())
+        .all_pairs_length(fixture.alchemy_provider.http.clone(), BlockNumber::Latest)
         .await;
     assert!(result > 279_174, "Result: {}", result);
 }
@@ -57,18 +66,21 @@ async fn test_get_pair_address() {
         .uniswap_v2_factory
         .get_pair_address(
             fixture.alchemy_provider.http.clone(),
-            &fixture.book.mainnet.erc20["weth"],
-            &fixture.book.mainnet.erc20["usdc"],
+            &fixture.book.mainnet().erc20["weth"],
+            &fixture.book.mainnet().erc20["usdc"],
         )
         .await;
     let a2 = fixture
         .uniswap_v2_factory
         .get_pair_address(
             fixture.alchemy_provider.http.clone(),
-            &fixture.book.mainnet.erc20["usdc"],
-            &fixture.book.mainnet.erc20["weth"],
+            &fixture.book.mainnet().erc20["usdc"],
+            &fixture.book.mainnet().erc20["weth"],
         )
         .await;
     assert_eq!(a1, a2);
-    assert_eq!(a1, fixture.book.mainnet.uniswap_v2.pairs["weth"]["usdc"]);
+    assert_eq!(
+        a1,
+        fixture.book.mainnet().dexes["uniswap_v2"].pairs["weth"]["usdc"]
+    );
 }
@@ -0,0 +1,268 @@
+use super::{
+    events::{
+        burn::BURN_EVENT_SIGNATURE, get_pool_events, mint::MINT_EVENT_SIGNATURE,
+        swap::SWAP_EVENT_SIGNATURE,
+    },
+    UniswapV2Pool,
+};
+use crate::{
+    checkpoint::log_archive::LogArchiveError,
+    contract::{BurnFilter, MintFilter, SwapFilter},
+};
+use ethers::{
+    providers::Middleware,
+    types::{H160, U256},
+};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+pub const SECONDS_PER_YEAR: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+
+/// Trading and liquidity activity of a pool over a block window, in raw token amounts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolActivity {
+    pub address: H160,
+    pub swaps: usize,
+    /// Amounts of token 0 and token 1 sold into the pool.
+    pub volume_0: U256,
+    pub volume_1: U256,
+    /// LP fees charged on the volume.
+    pub fees_0: U256,
+    pub fees_1: U256,
+    pub mints: usize,
+    pub burns: usize,
+    pub liquidity_added_0: U256,
+    pub liquidity_added_1: U256,
+    pub liquidity_removed_0: U256,
+    pub liquidity_removed_1: U256,
+}
+
+impl PoolActivity {
+    pub fn new(address: H160) -> Self {
+        PoolActivity {
+            address,
+            ..Default::default()
+        }
+    }
+
+    pub fn add_swap(&mut self, event: &SwapFilter, fee: u32) {
+        self.swaps += 1;
+        self.volume_0 += event.amount_0_in;
+        self.volume_1 += event.amount_1_in;
+        // Fee of 300 => 0.3% of the input amount
+        self.fees_0 += event.amount_0_in * U256::from(fee) / U256::from(100_000);
+        self.fees_1 += event.amount_1_in * U256::from(fee) / U256::from(100_000);
+    }
+
+    pub fn add_mint(&mut self, event: &MintFilter) {
+        self.mints += 1;
+        self.liquidity_added_0 += event.amount_0;
+        self.liquidity_added_1 += event.amount_1;
+    }
+
+    pub fn add_burn(&mut self, event: &BurnFilter) {
+        self.burns += 1;
+        self.liquidity_removed_0 += event.amount_0;
+        self.liquidity_removed_1 += event.amount_1;
+    }
+
+    /// Net change of the deposited amounts of token 0 and token 1.
+    pub fn net_liquidity_change(&self) -> (f64, f64) {
+        let to_f64 = |x: U256| x.to_string().parse::<f64>().unwrap_or(f64::MAX);
+        (
+            to_f64(self.liquidity_added_0) - to_f64(self.liquidity_removed_0),
+            to_f64(self.liquidity_added_1) - to_f64(self.liquidity_removed_1),
+        )
+    }
+
+    /// Volume expressed in token 0 at the current reserves of `pool`.
+    pub fn volume_in_token_0(&self, pool: &UniswapV2Pool) -> U256 {
+        self.volume_0 + pool.quote(&pool.token_b, self.volume_1)
+    }
+
+    /// Annualised fee return of the liquidity in `pool`, for a window lasting `seconds`. Fees
+    /// and liquidity are both valued in token 0 at the current reserves.
+    pub fn apr(&self, pool: &UniswapV2Pool, seconds: u64) -> f64 {
+        if pool.reserve_0 == 0 || seconds == 0 {
+            return 0.0;
+        }
+        let fees = self.fees_0 + pool.quote(&pool.token_b, self.fees_1);
+        let liquidity = 2.0 * pool.reserve_0 as f64;
+        fees.to_string().parse::<f64>().unwrap_or(0.0) / liquidity * SECONDS_PER_YEAR
+            / seconds as f64
+    }
+}
+
+/// Aggregates decoded events into per-pool activity. Pools without events are included with
+/// zero activity.
+pub fn aggregate_activity(
+    pools: &[UniswapV2Pool],
+    swaps: &[(H160, SwapFilter)],
+    mints: &[(H160, MintFilter)],
+    burns: &[(H160, BurnFilter)],
+) -> HashMap<H160, PoolActivity> {
+    let fees: HashMap<H160, u32> = pools.iter().map(|p| (p.address, p.fee)).collect();
+    let mut activity: HashMap<H160, PoolActivity> = pools
+        .iter()
+        .map(|p| (p.address, PoolActivity::new(p.address)))
+        .collect();
+    for (address, event) in swaps {
+        if let Some(pool_activity) = activity.get_mut(address) {
+            pool_activity.add_swap(event, fees[address]);
+        }
+    }
+    for (address, event) in mints {
+        if let Some(pool_activity) = activity.get_mut(address) {
+            pool_activity.add_mint(event);
+        }
+    }
+    for (address, event) in burns {
+        if let Some(pool_activity) = activity.get_mut(address) {
+            pool_activity.add_burn(event);
+        }
+    }
+    activity
+}
+
+/// Fetches `Swap`, `Mint` and `Burn` events of `pools` between `start` and `end` and
+/// aggregates them per pool.
+pub async fn get_pool_activity<M: Middleware>(
+    pools: &[UniswapV2Pool],
+    start: usize,
+    end: usize,
+    step: usize,
+    middleware: Arc<M>,
+) -> Result<HashMap<H160, PoolActivity>, LogArchiveError> {
+    let addresses: HashSet<H160> = pools.iter().map(|p| p.address).collect();
+    let (start, end) = (start as u64, end as u64);
+    let swaps = get_pool_events::<SwapFilter, _>(
+        SWAP_EVENT_SIGNATURE,
+        start,
+        end,
+        step,
+        &addresses,
+        middleware.clone(),
+    )
+    .await?;
+    let mints = get_pool_events::<MintFilter, _>(
+        MINT_EVENT_SIGNATURE,
+        start,
+        end,
+        step,
+        &addresses,
+        middleware.clone(),
+    )
+    .await?;
+    let burns = get_pool_events::<BurnFilter, _>(
+        BURN_EVENT_SIGNATURE,
+        start,
+        end,
+        step,
+        &addresses,
+        middleware,
+    )
+    .await?;
+    Ok(aggregate_activity(pools, &swaps, &mints, &burns))
+}
+
+/// Pools ordered by `key`, highest first.
+pub fn rank_pools<F>(
+    pools: &[UniswapV2Pool],
+    activity: &HashMap<H160, PoolActivity>,
+    key: F,
+) -> Vec<(H160, f64)>
+where
+    F: Fn(&UniswapV2Pool, &PoolActivity) -> f64,
+{
+    let mut ranked: Vec<(H160, f64)> = pools
+        .iter()
+        .filter_map(|pool| {
+            activity
+                .get(&pool.address)
+                .map(|a| (pool.address, key(pool, a)))
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(n: u64, reserve_0: u128, reserve_1: u128) -> UniswapV2Pool {
+        UniswapV2Pool::new(
+            H160::from_low_u64_be(n),
+            H160::from_low_u64_be(1),
+            18,
+            H160::from_low_u64_be(2),
+            18,
+            reserve_0,
+            reserve_1,
+            300,
+            U256::zero(),
+        )
+    }
+
+    fn swap(amount_0_in: u64, amount_1_in: u64) -> SwapFilter {
+        SwapFilter {
+            sender: H160::zero(),
+            amount_0_in: amount_0_in.into(),
+            amount_1_in: amount_1_in.into(),
+            amount_0_out: U256::zero(),
+            amount_1_out: U256::zero(),
+            to: H160::zero(),
+        }
+    }
+
+    #[test]
+    fn test_aggregate_and_rank() {
+        let pools = vec![
+            pool(10, 1_000_000, 2_000_000),
+            pool(11, 1_000_000, 1_000_000),
+        ];
+        let (a, b) = (pools[0].address, pools[1].address);
+        let swaps = vec![
+            (a, swap(100_000, 0)),
+            (a, swap(0, 200_000)),
+            (b, swap(1_000, 0)),
+        ];
+        let mints = vec![(
+            b,
+            MintFilter {
+                sender: H160::zero(),
+                amount_0: 500.into(),
+                amount_1: 500.into(),
+            },
+        )];
+        let burns = vec![(
+            b,
+            BurnFilter {
+                sender: H160::zero(),
+                amount_0: 200.into(),
+                amount_1: 300.into(),
+                to: H160::zero(),
+            },
+        )];
+        let activity = aggregate_activity(&pools, &swaps, &mints, &burns);
+
+        assert_eq!(activity[&a].swaps, 2);
+        assert_eq!(activity[&a].fees_0, U256::from(300));
+        assert_eq!(activity[&a].fees_1, U256::from(600));
+        assert_eq!(
+            activity[&a].volume_in_token_0(&pools[0]),
+            U256::from(200_000)
+        );
+        assert_eq!(activity[&b].net_liquidity_change(), (300.0, 200.0));
+
+        // 600 token 0 of fees on 2_000_000 of liquidity over a year
+        let apr = activity[&a].apr(&pools[0], SECONDS_PER_YEAR as u64);
+        assert!((apr - 0.0003).abs() < 1e-12);
+
+        let ranked = rank_pools(&pools, &activity, |pool, a| a.apr(pool, 86_400));
+        assert_eq!(ranked.iter().map(|r| r.0).collect::<Vec<_>>(), vec![a, b]);
+    }
+}
@@ -0,0 +1,6 @@
+use ethers::types::H256;
+
+pub const BURN_EVENT_SIGNATURE: H256 = H256([
+    220, 205, 65, 47, 11, 18, 82, 129, 156, 177, 253, 51, 11, 147, 34, 76, 164, 38, 18, 137, 43,
+    179, 244, 247, 137, 151, 110, 109, 129, 147, 100, 150,
+]);
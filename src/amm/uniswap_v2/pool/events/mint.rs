@@ -0,0 +1,6 @@
+use ethers::types::H256;
+
+pub const MINT_EVENT_SIGNATURE: H256 = H256([
+    76, 32, 155, 95, 200, 173, 80, 117, 143, 19, 226, 225, 8, 139, 165, 106, 86, 13, 255, 105, 10,
+    28, 111, 239, 38, 57, 79, 76, 3, 130, 28, 79,
+]);
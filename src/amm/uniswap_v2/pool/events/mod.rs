@@ -1 +1,62 @@
+pub mod burn;
+pub mod mint;
+pub mod swap;
 pub mod sync;
+
+use crate::checkpoint::log_archive::{LogArchive, LogArchiveError};
+use ethers::{
+    abi::RawLog,
+    prelude::EthEvent,
+    providers::Middleware,
+    types::{H160, H256},
+};
+use std::{collections::HashSet, sync::Arc};
+use tracing::info;
+
+/// Events `E`, emitted with `signature`, of `addresses` between `start` and `end` (inclusive),
+/// in chain order. Logs that fail to decode are skipped.
+pub async fn get_pool_events<E: EthEvent, M: Middleware>(
+    signature: H256,
+    start: u64,
+    end: u64,
+    step: usize,
+    addresses: &HashSet<H160>,
+    middleware: Arc<M>,
+) -> Result<Vec<(H160, E)>, LogArchiveError> {
+    // An empty address set would match every emitter on chain.
+    if addresses.is_empty() {
+        return Ok(vec![]);
+    }
+    info!(
+        event = E::name().as_ref(),
+        from_block = start,
+        to_block = end,
+        step,
+        pools = addresses.len(),
+        "getting pool events from logs"
+    );
+    let addresses: Vec<H160> = addresses.iter().copied().collect();
+    let logs = LogArchive::get_logs(signature, &addresses, start, end, step, middleware).await?;
+    Ok(logs
+        .into_iter()
+        .filter_map(|log| {
+            E::decode_log(&RawLog::from(log.clone()))
+                .ok()
+                .map(|event| (log.address, event))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::{BurnFilter, MintFilter, SwapFilter, SyncFilter};
+
+    #[test]
+    fn test_event_signatures() {
+        assert_eq!(sync::SYNC_EVENT_SIGNATURE, SyncFilter::signature());
+        assert_eq!(swap::SWAP_EVENT_SIGNATURE, SwapFilter::signature());
+        assert_eq!(mint::MINT_EVENT_SIGNATURE, MintFilter::signature());
+        assert_eq!(burn::BURN_EVENT_SIGNATURE, BurnFilter::signature());
+    }
+}
@@ -0,0 +1,6 @@
+use ethers::types::H256;
+
+pub const SWAP_EVENT_SIGNATURE: H256 = H256([
+    215, 138, 217, 95, 164, 108, 153, 75, 101, 81, 208, 218, 133, 252, 39, 95, 230, 19, 206, 55,
+    101, 127, 184, 213, 227, 209, 48, 132, 1, 89, 216, 34,
+]);
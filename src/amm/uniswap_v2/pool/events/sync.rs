@@ -1,18 +1,19 @@
 use crate::amm::uniswap_v2::pool::UniswapV2Pool;
-use crate::concurrent::{run_concurrent_hash, BatchError};
+use crate::checkpoint::log_archive::{LogArchive, LogArchiveError};
 use crate::contract::SyncFilter;
+use crate::metrics::metrics;
 use ethers::prelude::EthEvent;
-use ethers::providers::{Provider, Ws};
-use ethers::types::{H160, U256};
+use ethers::providers::{Provider, ProviderError, Ws};
+use ethers::types::H160;
 use ethers::{
     abi::RawLog,
     providers::Middleware,
-    types::{BlockNumber, Filter, ValueOrArray, H256, U64},
+    types::{Filter, ValueOrArray, H256},
 };
 use futures::StreamExt;
-use indicatif::ProgressBar;
 use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use tracing::{debug, info, trace, warn};
 
 pub const SYNC_EVENT_SIGNATURE: H256 = H256([
     28, 65, 30, 154, 150, 224, 113, 36, 28, 47, 33, 247, 114, 107, 23, 174, 137, 227, 202, 180,
@@ -20,74 +21,51 @@ pub const SYNC_EVENT_SIGNATURE: H256 = H256([
 ]);
 
 impl UniswapV2Pool {
-    async fn get_sync_events_from_logs<'a, M: Middleware + 'a>(
+    pub async fn get_sync_events_from_logs_concurrent<'a, M: Middleware + 'a>(
         start: usize,
         end: usize,
+        step: usize,
         addresses: HashSet<H160>,
         middleware: Arc<M>,
-        progress_bar: Option<Arc<Mutex<ProgressBar>>>,
-    ) -> Result<HashMap<H160, SyncFilter>, BatchError> {
-        let logs = middleware
-            .get_logs(
-                &Filter::new()
-                    .topic0(ValueOrArray::Value(SYNC_EVENT_SIGNATURE))
-                    .from_block(BlockNumber::Number(U64([start as u64])))
-                    .to_block(BlockNumber::Number(U64([end as u64]))),
-            )
-            .await
-            .map_err(|_| BatchError::new(start, end))?;
-
+    ) -> Result<HashMap<H160, SyncFilter>, LogArchiveError> {
+        info!(
+            from_block = start,
+            to_block = end,
+            step,
+            "getting sync events from logs"
+        );
+        // An empty address set would match every emitter on chain.
+        if addresses.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let addresses: Vec<H160> = addresses.into_iter().collect();
+        let logs = LogArchive::get_logs(
+            SYNC_EVENT_SIGNATURE,
+            &addresses,
+            start as u64,
+            end as u64,
+            step,
+            middleware,
+        )
+        .await?;
+        // Archived logs are in chain order so the last event of each pool wins.
         let mut sync_events = HashMap::new();
-        let mut last_event: HashMap<H160, (U64, U256)> = HashMap::new();
+        metrics().observe_sync_logs(logs.iter());
         for log in logs {
-            let address = log.address;
-            if addresses.contains(&address) {
-                if let (Some(block_number), Some(index)) = (log.block_number, log.log_index) {
-                    let default_value = (U64::from(0), U256::from(0));
-                    let (current_block_number, current_index) =
-                        last_event.get(&address).unwrap_or(&default_value);
-                    if (&block_number, &index) >= (current_block_number, current_index) {
-                        let sync_event: SyncFilter = SyncFilter::decode_log(&RawLog::from(log))
-                            .map_err(|_| BatchError::new(start, end))?;
-                        last_event.insert(address, (*current_block_number, *current_index));
-                        sync_events.insert(address, sync_event);
-                    }
-                }
+            if let Ok(sync_event) = SyncFilter::decode_log(&RawLog::from(log.clone())) {
+                sync_events.insert(log.address, sync_event);
             }
         }
-        if let Some(pb) = progress_bar {
-            pb.lock().unwrap().inc(end as u64 - start as u64);
-        }
         Ok(sync_events)
     }
 
-    pub async fn get_sync_events_from_logs_concurrent<'a, M: Middleware + 'a>(
-        start: usize,
-        end: usize,
-        step: usize,
-        addresses: HashSet<H160>,
-        middleware: Arc<M>,
-    ) -> HashMap<H160, SyncFilter> {
-        let batch_func = |start: usize,
-                          end: usize,
-                          middleware: Arc<M>,
-                          pb: Option<Arc<Mutex<ProgressBar>>>| {
-            Self::get_sync_events_from_logs(start, end, addresses.clone(), middleware.clone(), pb)
-        };
-        println!(
-            "Getting sync events from logs for Uniswap v2 factory, from block {} to {} with step {}",
-            start, end, step
-        );
-        run_concurrent_hash(start, end, step, middleware, batch_func).await
-    }
-
     pub async fn sync_pools_from_logs<'a, M: Middleware + 'a>(
         start: usize,
         end: usize,
         step: usize,
         pools: &mut Vec<Self>,
         middleware: Arc<M>,
-    ) -> &mut Vec<Self> {
+    ) -> Result<&mut Vec<Self>, LogArchiveError> {
         let mut pools_map = HashMap::new();
         let addresses = pools
             .into_iter()
@@ -99,29 +77,51 @@ impl UniswapV2Pool {
             .collect();
         let sync_events =
             Self::get_sync_events_from_logs_concurrent(start, end, step, addresses, middleware)
-                .await;
+                .await?;
+        debug!(pools = sync_events.len(), "applying sync events");
         for (address, event) in sync_events {
             let pool = pools_map.get_mut(&address).unwrap();
             pool.reserve_0 = event.reserve_0;
             pool.reserve_1 = event.reserve_1;
         }
-        pools
+        Ok(pools)
     }
 
-    pub async fn subscribe_sync_event<F>(wss: Arc<Provider<Ws>>, func: F)
+    /// Calls `subscribed` once subscribed, then `func` with the pool, event and block number of
+    /// every Sync event from now on, until the subscription ends. Logs removed by a reorg and
+    /// logs that do not decode are skipped.
+    pub async fn subscribe_sync_event<S, F>(
+        wss: Arc<Provider<Ws>>,
+        subscribed: S,
+        func: F,
+    ) -> Result<(), ProviderError>
     where
-        F: Fn(H160, SyncFilter) -> (),
+        S: FnOnce(),
+        F: Fn(H160, SyncFilter, u64),
     {
         let filter = Filter::new().topic0(ValueOrArray::Value(SYNC_EVENT_SIGNATURE));
-        let mut stream = wss
-            .subscribe_logs(&filter)
-            .await
-            .expect("Could not subscribe to sync event");
-        println!("Subscribed to UniswapV2 sync events.");
+        let mut stream = wss.subscribe_logs(&filter).await?;
+        info!("subscribed to Uniswap V2 sync events");
+        subscribed();
         while let Some(log) = stream.next().await {
-            let sync_event: SyncFilter = SyncFilter::decode_log(&RawLog::from(log.clone()))
-                .expect("Failed to decode SyncFilter data");
-            func(log.address, sync_event);
+            if log.removed == Some(true) {
+                debug!(pool = ?log.address, block = ?log.block_number, "skipping removed sync event");
+                continue;
+            }
+            let sync_event = match SyncFilter::decode_log(&RawLog::from(log.clone())) {
+                Ok(sync_event) => sync_event,
+                Err(err) => {
+                    warn!(pool = ?log.address, %err, "skipping undecodable sync event");
+                    continue;
+                }
+            };
+            trace!(pool = ?log.address, block = ?log.block_number, "sync event");
+            func(
+                log.address,
+                sync_event,
+                log.block_number.unwrap_or_default().as_u64(),
+            );
         }
+        Ok(())
     }
 }
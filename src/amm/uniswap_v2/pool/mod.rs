@@ -1,14 +1,16 @@
+pub mod analytics;
 pub mod events;
+pub mod oracle;
 pub mod pool_data_batch_request;
-use self::pool_data_batch_request::get_uniswap_v2_pool_data_concurrent;
+use self::{oracle::Observation, pool_data_batch_request::get_uniswap_v2_pool_data_concurrent};
 use crate::{
-    arithmetic::{div_uu, q64_to_f64, U128_0X10000000000000000},
+    arithmetic::{fixed_point::Q64x64, price::Price},
     contract::{IErc20, IUniswapV2Pair},
 };
 use core::panic;
 use ethers::{
     providers::Middleware,
-    types::{H160, U256},
+    types::{BlockNumber, H160, U256},
 };
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
@@ -24,6 +26,10 @@ pub struct UniswapV2Pool {
     pub reserve_1: u128,
     pub fee: u32,
     pub eth_value: U256,
+    /// Oracle state as of the last pool data batch request or `sync_oracle`. `None` if the
+    /// pair's oracle calls reverted.
+    #[serde(default)]
+    pub oracle: Option<Observation>,
 }
 
 impl PartialEq for UniswapV2Pool {
@@ -55,6 +61,7 @@ impl UniswapV2Pool {
             reserve_1,
             fee,
             eth_value,
+            ..Default::default()
         }
     }
 
@@ -74,7 +81,14 @@ impl UniswapV2Pool {
     }
 
     pub async fn from_address<M: Middleware>(middleware: Arc<M>, address: H160, fee: u32) -> Self {
-        let pool = get_uniswap_v2_pool_data_concurrent(&vec![address], middleware, fee, 1).await;
+        let pool = get_uniswap_v2_pool_data_concurrent(
+            &vec![address],
+            middleware,
+            fee,
+            1,
+            BlockNumber::Latest,
+        )
+        .await;
         pool.into_iter().next().unwrap()
     }
 
@@ -92,37 +106,34 @@ impl UniswapV2Pool {
         (self.reserve_0, self.reserve_1) = self.get_reserves(middleware).await
     }
 
-    pub fn calculate_price_64_x_64(&self, base_token: H160) -> u128 {
-        let decimal_shift = self.token_a_decimals as i8 - self.token_b_decimals as i8;
-
-        let (r_0, r_1) = if decimal_shift < 0 {
-            (
-                U256::from(self.reserve_0)
-                    * U256::from(10u128.pow(decimal_shift.unsigned_abs() as u32)),
-                U256::from(self.reserve_1),
+    /// Exact price of `base_token` in whole units of the other token. `None` if the pool holds
+    /// none of `base_token`.
+    pub fn exact_price(&self, base_token: H160) -> Option<Price> {
+        if base_token == self.token_a {
+            Price::from_reserves(
+                self.reserve_0,
+                self.token_a_decimals,
+                self.reserve_1,
+                self.token_b_decimals,
             )
         } else {
-            (
-                U256::from(self.reserve_0),
-                U256::from(self.reserve_1) * U256::from(10u128.pow(decimal_shift as u32)),
+            Price::from_reserves(
+                self.reserve_1,
+                self.token_b_decimals,
+                self.reserve_0,
+                self.token_a_decimals,
             )
-        };
-
-        if base_token == self.token_a {
-            if r_0.is_zero() {
-                U128_0X10000000000000000
-            } else {
-                div_uu(r_1, r_0)
-            }
-        } else if r_1.is_zero() {
-            U128_0X10000000000000000
-        } else {
-            div_uu(r_0, r_1)
         }
     }
 
+    /// `None` if the pool holds none of `base_token` or the price is 2^64 or more.
+    pub fn calculate_price_64_x_64(&self, base_token: H160) -> Option<Q64x64> {
+        self.exact_price(base_token)?.to_q64x64()
+    }
+
+    /// Price of `base_token` as an `f64`, 1.0 if the pool holds none of it.
     pub fn price(&self, base_token: H160) -> f64 {
-        q64_to_f64(self.calculate_price_64_x_64(base_token))
+        self.exact_price(base_token).map_or(1.0, |p| p.to_f64())
     }
 
     pub fn simulate_swap(&self, token_in: &H160, amount_in: U256) -> U256 {
@@ -164,6 +175,50 @@ impl UniswapV2Pool {
         numerator / denominator
     }
 
+    /// Amount of `token_in` needed to receive exactly `amount_out`, or `None` if the pool does
+    /// not hold enough of the output token.
+    pub fn simulate_swap_exact_out(&self, token_in: &H160, amount_out: U256) -> Option<U256> {
+        if &self.token_a == token_in {
+            self.get_amount_in(
+                amount_out,
+                U256::from(self.reserve_0),
+                U256::from(self.reserve_1),
+            )
+        } else {
+            self.get_amount_in(
+                amount_out,
+                U256::from(self.reserve_1),
+                U256::from(self.reserve_0),
+            )
+        }
+    }
+
+    pub fn get_amount_in(
+        &self,
+        amount_out: U256,
+        reserve_in: U256,
+        reserve_out: U256,
+    ) -> Option<U256> {
+        if amount_out.is_zero() || reserve_in.is_zero() || amount_out >= reserve_out {
+            return None;
+        }
+        let fee = (10000 - (self.fee / 10)) / 10;
+        let numerator = reserve_in * amount_out * U256::from(1000);
+        let denominator = (reserve_out - amount_out) * U256::from(fee);
+        Some(numerator / denominator + 1)
+    }
+
+    /// Spot conversion of `amount` of `token_in` at the current reserve ratio, without fee or
+    /// slippage (`UniswapV2Library.quote`).
+    pub fn quote(&self, token_in: &H160, amount: U256) -> U256 {
+        let reserve_in = U256::from(self.get_reserve_for_token(token_in));
+        let reserve_out = U256::from(self.get_reserve_for_token(&self.get_token_out(token_in)));
+        if reserve_in.is_zero() {
+            return U256::zero();
+        }
+        amount * reserve_out / reserve_in
+    }
+
     pub fn get_token_out(&self, token_in: &H160) -> H160 {
         if &self.token_a == token_in {
             return self.token_b;
@@ -0,0 +1,315 @@
+use super::UniswapV2Pool;
+use crate::{
+    arithmetic::q112_to_f64,
+    concurrent::{run_concurrent_hash, BatchError},
+    contract::IUniswapV2Pair,
+};
+use ethers::{
+    abi::Token,
+    contract::{ContractError, Multicall, MulticallError, MULTICALL_ADDRESS},
+    providers::Middleware,
+    types::{BlockId, BlockNumber, H160, U256},
+};
+use futures::future;
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+
+#[derive(Debug)]
+pub enum OracleError {
+    /// The block whose timestamp the observations are taken at could not be read.
+    Block(String),
+    BlockNotFound(BlockNumber),
+}
+
+impl fmt::Display for OracleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OracleError::Block(err) => write!(f, "could not get the block: {}", err),
+            OracleError::BlockNotFound(block) => write!(f, "block {} not found", block),
+        }
+    }
+}
+
+impl std::error::Error for OracleError {}
+
+/// Cumulative prices of a pair at a point in time. The cumulatives are sums of UQ112x112 prices
+/// weighted by seconds and overflow by design, so only differences between two observations of
+/// the same pair are meaningful.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Observation {
+    pub timestamp: u32,
+    pub price_0_cumulative: U256,
+    pub price_1_cumulative: U256,
+}
+
+impl Observation {
+    /// Accumulates the prices given by the reserves up to `timestamp`, like the pair does on
+    /// its next `_update`.
+    pub fn accumulate(&self, reserve_0: u128, reserve_1: u128, timestamp: u32) -> Observation {
+        let elapsed = U256::from(timestamp.wrapping_sub(self.timestamp));
+        if elapsed.is_zero() || reserve_0 == 0 || reserve_1 == 0 {
+            return Observation { timestamp, ..*self };
+        }
+        let price_0 = (U256::from(reserve_1) << 112) / U256::from(reserve_0);
+        let price_1 = (U256::from(reserve_0) << 112) / U256::from(reserve_1);
+        Observation {
+            timestamp,
+            price_0_cumulative: self
+                .price_0_cumulative
+                .overflowing_add(price_0.overflowing_mul(elapsed).0)
+                .0,
+            price_1_cumulative: self
+                .price_1_cumulative
+                .overflowing_add(price_1.overflowing_mul(elapsed).0)
+                .0,
+        }
+    }
+
+    /// Time weighted average prices of token 0 and token 1 between `self` and `later`, as raw
+    /// UQ112x112 values. `None` if no time passed between the observations.
+    pub fn average_prices(&self, later: &Observation) -> Option<(U256, U256)> {
+        let elapsed = U256::from(later.timestamp.wrapping_sub(self.timestamp));
+        if elapsed.is_zero() {
+            return None;
+        }
+        Some((
+            later
+                .price_0_cumulative
+                .overflowing_sub(self.price_0_cumulative)
+                .0
+                / elapsed,
+            later
+                .price_1_cumulative
+                .overflowing_sub(self.price_1_cumulative)
+                .0
+                / elapsed,
+        ))
+    }
+}
+
+impl UniswapV2Pool {
+    /// The oracle state as stored in the pair at its last update, if synced.
+    pub fn last_observation(&self) -> Option<Observation> {
+        self.oracle
+    }
+
+    /// The oracle state at `timestamp`, assuming the reserves did not change since the last
+    /// observation (`UniswapV2OracleLibrary.currentCumulativePrices`).
+    pub fn observation_at(&self, timestamp: u32) -> Option<Observation> {
+        Some(
+            self.last_observation()?
+                .accumulate(self.reserve_0, self.reserve_1, timestamp),
+        )
+    }
+
+    /// Time weighted average price of `base_token` between two observations, in the same units
+    /// as `price`.
+    pub fn twap(&self, base_token: H160, start: &Observation, end: &Observation) -> Option<f64> {
+        let (price_0, price_1) = start.average_prices(end)?;
+        let decimal_shift = self.token_a_decimals as i32 - self.token_b_decimals as i32;
+        if base_token == self.token_a {
+            Some(q112_to_f64(price_0) * 10f64.powi(decimal_shift))
+        } else {
+            Some(q112_to_f64(price_1) * 10f64.powi(-decimal_shift))
+        }
+    }
+
+    /// Relative deviation of the spot price from the TWAP, e.g. `0.05` when spot is 5% above it.
+    /// Large deviations point at a pool whose reserves were moved within the window.
+    pub fn spot_twap_deviation(
+        &self,
+        base_token: H160,
+        start: &Observation,
+        end: &Observation,
+    ) -> Option<f64> {
+        let twap = self.twap(base_token, start, end)?;
+        if twap == 0.0 {
+            return None;
+        }
+        Some(self.price(base_token) / twap - 1.0)
+    }
+
+    /// Reads reserves and oracle state at `block`. On error the pool is left as is.
+    pub async fn sync_oracle<M: Middleware>(
+        &mut self,
+        middleware: Arc<M>,
+        block: BlockNumber,
+    ) -> Result<(), ContractError<M>> {
+        let (reserve_0, reserve_1, observation) =
+            get_oracle_state(self.address, middleware, block).await?;
+        self.reserve_0 = reserve_0;
+        self.reserve_1 = reserve_1;
+        self.oracle = Some(observation);
+        Ok(())
+    }
+}
+
+/// Reserves and the last observation stored in the pair at `block`.
+async fn get_oracle_state<M: Middleware>(
+    address: H160,
+    middleware: Arc<M>,
+    block: BlockNumber,
+) -> Result<(u128, u128, Observation), ContractError<M>> {
+    let pair = IUniswapV2Pair::new(address, middleware);
+    let (reserves, price_0_cumulative, price_1_cumulative) = future::try_join3(
+        pair.get_reserves().block(block).call(),
+        pair.price_0_cumulative_last().block(block).call(),
+        pair.price_1_cumulative_last().block(block).call(),
+    )
+    .await?;
+    let observation = Observation {
+        timestamp: reserves.2,
+        price_0_cumulative,
+        price_1_cumulative,
+    };
+    Ok((reserves.0, reserves.1, observation))
+}
+
+/// Reserves and the last observation stored in each pair at `block`, read in a single
+/// `eth_call` through Multicall3. `None` for pairs whose calls reverted.
+pub async fn get_oracle_states<M: Middleware>(
+    addresses: &[H160],
+    middleware: Arc<M>,
+    block: BlockNumber,
+) -> Result<Vec<Option<(u128, u128, Observation)>>, MulticallError<M>> {
+    let mut multicall =
+        Multicall::new_with_chain_id(middleware.clone(), Some(MULTICALL_ADDRESS), None::<u64>)?
+            .block(block);
+    for &address in addresses {
+        let pair = IUniswapV2Pair::new(address, middleware.clone());
+        multicall
+            .add_call(pair.get_reserves(), true)
+            .add_call(pair.price_0_cumulative_last(), true)
+            .add_call(pair.price_1_cumulative_last(), true);
+    }
+    let results = multicall.call_raw().await?;
+    Ok(results
+        .chunks(3)
+        .map(|calls| match calls {
+            [Ok(Token::Tuple(reserves)), Ok(Token::Uint(price_0_cumulative)), Ok(Token::Uint(price_1_cumulative))] =>
+            {
+                let reserves: Vec<U256> = reserves
+                    .iter()
+                    .filter_map(|token| token.clone().into_uint())
+                    .collect();
+                let [reserve_0, reserve_1, timestamp] = reserves[..] else {
+                    return None;
+                };
+                let observation = Observation {
+                    timestamp: timestamp.low_u32(),
+                    price_0_cumulative: *price_0_cumulative,
+                    price_1_cumulative: *price_1_cumulative,
+                };
+                Some((reserve_0.as_u128(), reserve_1.as_u128(), observation))
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+pub async fn get_observations_batch_request<M: Middleware>(
+    addresses: &[H160],
+    middleware: Arc<M>,
+    block: BlockNumber,
+    timestamp: u32,
+    progress_bar: Option<Arc<Mutex<ProgressBar>>>,
+    start: usize,
+    end: usize,
+) -> Result<HashMap<H160, Observation>, BatchError> {
+    let states = get_oracle_states(addresses, middleware, block)
+        .await
+        .map_err(|_| BatchError::new(start, end))?;
+    let mut observations = HashMap::new();
+    for (address, state) in addresses.iter().zip(states) {
+        let (reserve_0, reserve_1, observation) = state.ok_or(BatchError::new(start, end))?;
+        observations.insert(
+            *address,
+            observation.accumulate(reserve_0, reserve_1, timestamp),
+        );
+    }
+    if let Some(pb) = progress_bar {
+        pb.lock().unwrap().inc(addresses.len() as u64);
+    }
+    Ok(observations)
+}
+
+/// Observations of every pair at the timestamp of `block`, so that observations taken at two
+/// blocks give the TWAP over the time between them.
+pub async fn get_observations_concurrent<M: Middleware>(
+    addresses: &[H160],
+    middleware: Arc<M>,
+    step: usize,
+    block: BlockNumber,
+) -> Result<HashMap<H160, Observation>, OracleError> {
+    let timestamp = middleware
+        .get_block(BlockId::Number(block))
+        .await
+        .map_err(|err| OracleError::Block(err.to_string()))?
+        .ok_or(OracleError::BlockNotFound(block))?
+        .timestamp
+        .as_u32();
+    let batch_func =
+        |start: usize, end: usize, middleware: Arc<M>, pb: Option<Arc<Mutex<ProgressBar>>>| {
+            get_observations_batch_request(
+                &addresses[start..end],
+                middleware,
+                block,
+                timestamp,
+                pb,
+                start,
+                end,
+            )
+        };
+    info!(pairs = addresses.len(), "getting oracle observations");
+    Ok(run_concurrent_hash(0, addresses.len(), step, middleware, batch_func).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(reserve_0: u128, reserve_1: u128) -> UniswapV2Pool {
+        UniswapV2Pool::new(
+            H160::from_low_u64_be(10),
+            H160::from_low_u64_be(1),
+            18,
+            H160::from_low_u64_be(2),
+            6,
+            reserve_0,
+            reserve_1,
+            300,
+            U256::zero(),
+        )
+    }
+
+    #[test]
+    fn test_twap() {
+        let (token_a, token_b) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+        // 2000 token b per token a for 100 seconds, then 3000 for 300 seconds.
+        let mut pool = pool(10u128.pow(18), 2000 * 10u128.pow(6));
+        assert_eq!(pool.observation_at(0), None);
+        pool.oracle = Some(Observation {
+            timestamp: u32::MAX - 50,
+            price_0_cumulative: U256::MAX - 7,
+            price_1_cumulative: U256::zero(),
+        });
+        let start = pool.last_observation().unwrap();
+        let middle = pool
+            .observation_at(start.timestamp.wrapping_add(100))
+            .unwrap();
+        pool.reserve_1 = 3000 * 10u128.pow(6);
+        let end = middle.accumulate(pool.reserve_0, pool.reserve_1, middle.timestamp + 300);
+
+        assert!((pool.twap(token_a, &start, &middle).unwrap() - 2000.0).abs() < 1e-9);
+        assert!((pool.twap(token_a, &start, &end).unwrap() - 2750.0).abs() < 1e-9);
+        assert!((pool.twap(token_b, &middle, &end).unwrap() - 1.0 / 3000.0).abs() < 1e-12);
+        assert!((pool.spot_twap_deviation(token_a, &start, &middle).unwrap() - 0.5).abs() < 1e-9);
+        assert_eq!(pool.twap(token_a, &end, &end), None);
+    }
+}
@@ -1,4 +1,4 @@
-use super::UniswapV2Pool;
+use super::{oracle::get_oracle_states, UniswapV2Pool};
 use crate::{
     concurrent::{run_concurrent, BatchError},
     contract::GetUniswapV2PoolDataBatchRequest,
@@ -6,15 +6,17 @@ use crate::{
 use ethers::{
     abi::{ParamType, Token},
     providers::Middleware,
-    types::{Bytes, H160, U256},
+    types::{BlockNumber, Bytes, H160, U256},
 };
 use indicatif::ProgressBar;
 use std::sync::{Arc, Mutex};
+use tracing::info;
 
 pub async fn get_amm_data_batch_request<M: Middleware>(
     addresses: &[H160],
     middleware: Arc<M>,
     fee: u32,
+    block: BlockNumber,
     progress_bar: Option<Arc<Mutex<ProgressBar>>>,
     start: usize,
     end: usize,
@@ -28,6 +30,7 @@ pub async fn get_amm_data_batch_request<M: Middleware>(
         .map_err(|_| BatchError::new(start, end))?;
 
     let return_data: Bytes = deployer
+        .block(block)
         .call_raw()
         .await
         .map_err(|_| BatchError::new(start, end))?;
@@ -65,10 +68,20 @@ pub async fn get_amm_data_batch_request<M: Middleware>(
                     reserve_1: tup[5].to_owned().into_uint().unwrap().as_u128(),
                     fee,
                     eth_value: U256::zero(),
+                    oracle: None,
                 };
                 pools.push(pool);
             }
         });
+    // The compiled batch request only returns reserves, so the oracle state is read in a
+    // second, multicall batched `eth_call` at the same block.
+    let pool_addresses: Vec<H160> = pools.iter().map(|pool| pool.address).collect();
+    let states = get_oracle_states(&pool_addresses, middleware, block)
+        .await
+        .map_err(|_| BatchError::new(start, end))?;
+    for (pool, state) in pools.iter_mut().zip(states) {
+        pool.oracle = state.map(|(_, _, observation)| observation);
+    }
     if let Some(pb) = progress_bar {
         pb.lock().unwrap().inc(addresses.len() as u64);
     }
@@ -80,6 +93,7 @@ pub async fn get_uniswap_v2_pool_data_concurrent<M: Middleware>(
     middleware: Arc<M>,
     fee: u32,
     step: usize,
+    block: BlockNumber,
 ) -> Vec<UniswapV2Pool> {
     let batch_func =
         |start: usize, end: usize, middleware: Arc<M>, pb: Option<Arc<Mutex<ProgressBar>>>| {
@@ -87,11 +101,12 @@ pub async fn get_uniswap_v2_pool_data_concurrent<M: Middleware>(
                 &addresses[start..end],
                 middleware.clone(),
                 fee,
+                block,
                 pb,
                 start,
                 end,
             )
         };
-    println!("Getting amm data for {} pairs", addresses.len());
+    info!(pairs = addresses.len(), "getting pool data");
     run_concurrent(0, addresses.len(), step, middleware, batch_func).await
 }
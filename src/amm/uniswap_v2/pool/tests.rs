@@ -65,7 +65,8 @@ async fn test_get_uniswap_v2_pool_data_concurrent() {
     let pool = fixture.weth_usdc_uniswap_v2_pool.clone();
     let http = fixture.alchemy_provider.http.clone();
     let addresses = vec![pool.address];
-    let pools = get_uniswap_v2_pool_data_concurrent(&addresses, http, 300, 1).await;
+    let pools =
+        get_uniswap_v2_pool_data_concurrent(&addresses, http, 300, 1, BlockNumber::Latest).await;
     let new_pool = pools.into_iter().next().unwrap();
     assert_eq!(pool.address, new_pool.address);
     assert_eq!(pool.token_a, new_pool.token_a);
@@ -81,7 +82,7 @@ async fn test_factory() {
     let http = fixture.alchemy_provider.http.clone();
     assert_eq!(
         pool.factory(http).await,
-        fixture.book.mainnet.uniswap_v2.factory
+        fixture.book.mainnet().dexes["uniswap_v2"].factory
     );
 }
 
@@ -99,7 +100,8 @@ async fn test_sync_events() {
         hashset![pool.address],
         http,
     )
-    .await;
+    .await
+    .unwrap();
     assert!(events.contains_key(&pool.address));
     let event = &events[&pool.address];
     assert_eq!(event.reserve_0, pool.reserve_0);
@@ -124,7 +126,8 @@ async fn test_sync_pools_from_logs() {
         &mut pools,
         http.clone(),
     )
-    .await;
+    .await
+    .unwrap();
     let (r0, r1) = pools[0].get_reserves(http).await;
     assert_eq!(&r0, &pools[0].reserve_0);
     assert_eq!(&r1, &pools[0].reserve_1);
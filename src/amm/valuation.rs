@@ -0,0 +1,268 @@
+use super::uniswap_v2::pool::UniswapV2Pool;
+use crate::arithmetic::fixed_point::Q64x64;
+use ethers::types::{H160, U256};
+use std::collections::HashMap;
+
+/// Where the WETH price of a token comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PriceSource {
+    pool: H160,
+    /// WETH per token, both normalised to 18 decimals.
+    price: Q64x64,
+    /// Normalised WETH reserve of `pool`, used to pick the deepest pair.
+    weth_reserve: U256,
+}
+
+/// Values tokens and pools in WETH, and optionally USD, from the reserves of loaded pools.
+///
+/// Mirrors `getWethValueOfToken` of `GetWethValueInPoolBatchRequest`: a token is priced from a
+/// pair with WETH holding at least `weth_threshold` of (18 decimal) WETH, and a pool is only
+/// valued when both of its tokens are priced. Instead of the first factory with a pair, the
+/// pair with the most WETH among the loaded pools is used.
+#[derive(Debug, Clone)]
+pub struct Valuation {
+    pub weth: H160,
+    pub weth_threshold: U256,
+    /// Stablecoin assumed to be worth one dollar, priced through its deepest WETH pair.
+    pub usd_anchor: Option<H160>,
+    sources: HashMap<H160, PriceSource>,
+}
+
+/// `amount` scaled from `decimals` to 18 decimals. Zero for more than 95 decimals, where the
+/// divisor no longer fits in a `U256` and any `u128` amount rounds down to nothing anyway.
+fn normalize(amount: u128, decimals: u8) -> U256 {
+    match decimals as usize {
+        decimals @ 0..=18 => U256::from(amount) * U256::exp10(18 - decimals),
+        decimals @ 19..=95 => U256::from(amount) / U256::exp10(decimals - 18),
+        _ => U256::zero(),
+    }
+}
+
+impl Valuation {
+    pub fn new(weth: H160, weth_threshold: U256, usd_anchor: Option<H160>) -> Self {
+        Valuation {
+            weth,
+            weth_threshold,
+            usd_anchor,
+            sources: HashMap::new(),
+        }
+    }
+
+    pub fn from_pools(
+        pools: &[UniswapV2Pool],
+        weth: H160,
+        weth_threshold: U256,
+        usd_anchor: Option<H160>,
+    ) -> Self {
+        let mut valuation = Self::new(weth, weth_threshold, usd_anchor);
+        valuation.update(pools);
+        valuation
+    }
+
+    /// Recomputes every price from `pools`.
+    pub fn update(&mut self, pools: &[UniswapV2Pool]) {
+        self.sources.clear();
+        for pool in pools {
+            self.update_pool(pool);
+        }
+    }
+
+    /// Takes new reserves of `pool` into account, e.g. after a `Sync`. The pool becomes the
+    /// price source of its token if it already was or holds more WETH than the current source.
+    /// A source that dropped below the threshold is removed, but a shallower pair only takes
+    /// over on the next `update`.
+    pub fn update_pool(&mut self, pool: &UniswapV2Pool) {
+        let (token, token_reserve, weth_reserve) = if pool.token_a == self.weth {
+            (
+                pool.token_b,
+                normalize(pool.reserve_1, pool.token_b_decimals),
+                normalize(pool.reserve_0, pool.token_a_decimals),
+            )
+        } else if pool.token_b == self.weth {
+            (
+                pool.token_a,
+                normalize(pool.reserve_0, pool.token_a_decimals),
+                normalize(pool.reserve_1, pool.token_b_decimals),
+            )
+        } else {
+            return;
+        };
+        let current = self.sources.get(&token).copied();
+        let is_source = current.map(|s| s.pool) == Some(pool.address);
+        // Tokens worth 2^64 WETH or more cannot be priced, like in the contract.
+        let price = Q64x64::from_ratio(weth_reserve, token_reserve);
+        let Some(price) = price.filter(|_| weth_reserve >= self.weth_threshold) else {
+            if is_source {
+                self.sources.remove(&token);
+            }
+            return;
+        };
+        if is_source || current.is_none_or(|s| weth_reserve > s.weth_reserve) {
+            self.sources.insert(
+                token,
+                PriceSource {
+                    pool: pool.address,
+                    price,
+                    weth_reserve,
+                },
+            );
+        }
+    }
+
+    /// WETH per whole token, `None` if the token has no pair with enough WETH.
+    pub fn weth_price(&self, token: &H160) -> Option<f64> {
+        if token == &self.weth {
+            return Some(1.0);
+        }
+        self.sources.get(token).map(|s| s.price.to_f64())
+    }
+
+    /// Dollars per WETH, through the deepest pair of the anchor.
+    pub fn weth_usd_price(&self) -> Option<f64> {
+        let anchor_price = self.weth_price(&self.usd_anchor?)?;
+        if anchor_price == 0.0 {
+            return None;
+        }
+        Some(1.0 / anchor_price)
+    }
+
+    pub fn usd_price(&self, token: &H160) -> Option<f64> {
+        Some(self.weth_price(token)? * self.weth_usd_price()?)
+    }
+
+    /// WETH value of `amount` of `token` given in `decimals`, in wei. Zero if unpriced.
+    pub fn weth_value_of_token(&self, token: &H160, amount: u128, decimals: u8) -> U256 {
+        let amount = normalize(amount, decimals);
+        if token == &self.weth {
+            return amount;
+        }
+        match self.sources.get(token) {
+            Some(source) => source.price.mul_u256(amount).unwrap_or(U256::MAX),
+            None => U256::zero(),
+        }
+    }
+
+    /// WETH value of both reserves of `pool` in wei, zero unless both tokens are priced.
+    pub fn weth_value(&self, pool: &UniswapV2Pool) -> U256 {
+        let value_a =
+            self.weth_value_of_token(&pool.token_a, pool.reserve_0, pool.token_a_decimals);
+        let value_b =
+            self.weth_value_of_token(&pool.token_b, pool.reserve_1, pool.token_b_decimals);
+        if value_a.is_zero() || value_b.is_zero() {
+            return U256::zero();
+        }
+        value_a + value_b
+    }
+
+    pub fn usd_value(&self, pool: &UniswapV2Pool) -> Option<f64> {
+        let weth_value = self.weth_value(pool);
+        if weth_value.is_zero() {
+            return None;
+        }
+        let weth_value = weth_value.to_string().parse::<f64>().unwrap_or(0.0) / 1e18;
+        Some(weth_value * self.weth_usd_price()?)
+    }
+
+    /// Sets `eth_value` of every pool, as `sync_eth_value` does over RPC.
+    pub fn apply_eth_values(&self, pools: &mut [UniswapV2Pool]) {
+        for pool in pools {
+            pool.eth_value = self.weth_value(pool);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    const WETH: u64 = 1;
+    const USDC: u64 = 2;
+    const TOKEN: u64 = 3;
+
+    fn pool(n: u64, token_a: (u64, u8), token_b: (u64, u8), r0: u128, r1: u128) -> UniswapV2Pool {
+        UniswapV2Pool::new(
+            address(n),
+            address(token_a.0),
+            token_a.1,
+            address(token_b.0),
+            token_b.1,
+            r0,
+            r1,
+            300,
+            U256::zero(),
+        )
+    }
+
+    fn e(decimals: u32) -> u128 {
+        10u128.pow(decimals)
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(5, 6), U256::from(5) * U256::exp10(12));
+        assert_eq!(normalize(5 * 10u128.pow(20), 38), U256::from(5));
+        assert_eq!(normalize(u128::MAX, 95), U256::zero());
+        assert_eq!(normalize(u128::MAX, u8::MAX), U256::zero());
+    }
+
+    #[test]
+    fn test_valuation() {
+        let mut pools = vec![
+            // 2000 USDC per WETH
+            pool(10, (USDC, 6), (WETH, 18), 2_000_000 * e(6), 1_000 * e(18)),
+            // 0.01 WETH per token, deepest pair
+            pool(11, (TOKEN, 18), (WETH, 18), 10_000 * e(18), 100 * e(18)),
+            // 0.02 WETH per token, shallower pair
+            pool(12, (WETH, 18), (TOKEN, 18), 20 * e(18), 1_000 * e(18)),
+            // Below the threshold
+            pool(13, (4, 18), (WETH, 18), 1_000 * e(18), e(17)),
+            pool(14, (TOKEN, 18), (USDC, 6), 1_000 * e(18), 20_000 * e(6)),
+        ];
+        let valuation =
+            Valuation::from_pools(&pools, address(WETH), U256::exp10(18), Some(address(USDC)));
+
+        assert!((valuation.weth_price(&address(TOKEN)).unwrap() - 0.01).abs() < 1e-12);
+        assert!((valuation.weth_usd_price().unwrap() - 2000.0).abs() < 1e-6);
+        assert!((valuation.usd_price(&address(TOKEN)).unwrap() - 20.0).abs() < 1e-6);
+        assert_eq!(valuation.weth_price(&address(4)), None);
+
+        // 1000 tokens at 0.01 and 20000 USDC at 0.0005
+        let expected = 20.0 * 1e18;
+        let value = valuation
+            .weth_value(&pools[4])
+            .to_string()
+            .parse::<f64>()
+            .unwrap();
+        assert!((value - expected).abs() / expected < 1e-12);
+        assert!((valuation.usd_value(&pools[4]).unwrap() - 40_000.0).abs() < 1e-6);
+
+        valuation.apply_eth_values(&mut pools);
+        assert_eq!(pools[3].eth_value, U256::zero());
+        let value = pools[1].eth_value.to_string().parse::<f64>().unwrap();
+        assert!((value - 200.0 * 1e18).abs() / 1e18 < 1e-9);
+    }
+
+    #[test]
+    fn test_update_pool() {
+        let mut valuation = Valuation::new(address(WETH), U256::exp10(18), None);
+        let mut deep = pool(11, (TOKEN, 18), (WETH, 18), 10_000 * e(18), 100 * e(18));
+        let shallow = pool(12, (WETH, 18), (TOKEN, 18), 20 * e(18), 1_000 * e(18));
+        valuation.update_pool(&deep);
+        valuation.update_pool(&shallow);
+        assert!((valuation.weth_price(&address(TOKEN)).unwrap() - 0.01).abs() < 1e-12);
+
+        // A sync on the source moves the price
+        deep.reserve_1 = 200 * e(18);
+        valuation.update_pool(&deep);
+        assert!((valuation.weth_price(&address(TOKEN)).unwrap() - 0.02).abs() < 1e-12);
+
+        // and draining it below the threshold removes the price
+        deep.reserve_1 = e(17);
+        valuation.update_pool(&deep);
+        assert_eq!(valuation.weth_price(&address(TOKEN)), None);
+    }
+}
@@ -1,49 +1,97 @@
+use super::Protocol;
 use crate::concurrent::{run_concurrent_hash, BatchError};
 use crate::contract::GetWethValueInPoolBatchRequest;
 use ethers::abi::{ParamType, Token};
-use ethers::types::{Bytes, U256};
+use ethers::types::{BlockNumber, Bytes, U256};
 use ethers::{providers::Middleware, types::H160};
 use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use tracing::info;
 
-async fn get_weth_value_in_pool_batch_request<M: Middleware>(
+/// Fee tiers searched for a token/WETH pool on V3 factories, in order. They are fixed by
+/// `GetWethValueInPoolBatchRequest`.
+pub const UNISWAP_V3_FEE_TIERS: [u32; 3] = [500, 3000, 10000];
+
+/// A factory whose token/WETH pools are used to price tokens. Factories are tried in order and
+/// the first pool with enough WETH gives the price.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PricingFactory {
+    pub address: H160,
+    pub protocol: Protocol,
+}
+
+impl PricingFactory {
+    pub fn uniswap_v2(address: H160) -> Self {
+        PricingFactory {
+            address,
+            protocol: Protocol::UniswapV2,
+        }
+    }
+
+    /// Pools are looked up for each of `UNISWAP_V3_FEE_TIERS`.
+    pub fn uniswap_v3(address: H160) -> Self {
+        PricingFactory {
+            address,
+            protocol: Protocol::UniswapV3,
+        }
+    }
+
+    pub fn is_uniswap_v3(&self) -> bool {
+        self.protocol == Protocol::UniswapV3
+    }
+}
+
+fn constructor_args(
     pool_addresses: &[H160],
-    factory_addresses: &[H160],
+    factories: &[PricingFactory],
     weth: H160,
     weth_threshold: U256,
-    middleware: Arc<M>,
-    progress_bar: Option<Arc<Mutex<ProgressBar>>>,
-    start: usize,
-    end: usize,
-) -> Result<HashMap<H160, U256>, BatchError> {
+) -> Token {
     let pools = pool_addresses
         .iter()
         .map(|a| Token::Address(*a))
         .collect::<Vec<Token>>();
 
-    let factory_is_uni_v3 = factory_addresses
+    let factory_is_uni_v3 = factories
         .iter()
-        .map(|_| Token::Bool(false))
+        .map(|f| Token::Bool(f.is_uniswap_v3()))
         .collect::<Vec<Token>>();
 
-    let factories = factory_addresses
+    let factory_addresses = factories
         .iter()
-        .map(|a| Token::Address(*a))
+        .map(|f| Token::Address(f.address))
         .collect::<Vec<Token>>();
 
-    let constructor_args = Token::Tuple(vec![
+    Token::Tuple(vec![
         Token::Array(pools),
-        Token::Array(factories),
+        Token::Array(factory_addresses),
         Token::Array(factory_is_uni_v3),
         Token::Address(weth),
         Token::Uint(weth_threshold),
-    ]);
+    ])
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn get_weth_value_in_pool_batch_request<M: Middleware>(
+    pool_addresses: &[H160],
+    factories: &[PricingFactory],
+    weth: H160,
+    weth_threshold: U256,
+    middleware: Arc<M>,
+    block: BlockNumber,
+    progress_bar: Option<Arc<Mutex<ProgressBar>>>,
+    start: usize,
+    end: usize,
+) -> Result<HashMap<H160, U256>, BatchError> {
+    let constructor_args = constructor_args(pool_addresses, factories, weth, weth_threshold);
 
     let deployer = GetWethValueInPoolBatchRequest::deploy(middleware, constructor_args)
         .map_err(|_| BatchError::new(start, end))?;
 
     let return_data: Bytes = deployer
+        .block(block)
         .call_raw()
         .await
         .map_err(|_| BatchError::new(start, end))?;
@@ -74,29 +122,31 @@ async fn get_weth_value_in_pool_batch_request<M: Middleware>(
 }
 pub async fn get_weth_value_in_pool_concurrent<M: Middleware>(
     pool_addresses: &[H160],
-    factory_addresses: &[H160],
+    factories: &[PricingFactory],
     weth: H160,
     weth_threshold: U256,
     step: usize,
     middleware: Arc<M>,
+    block: BlockNumber,
 ) -> HashMap<H160, U256> {
     let batch_func =
         |start: usize, end: usize, middleware: Arc<M>, pb: Option<Arc<Mutex<ProgressBar>>>| {
             get_weth_value_in_pool_batch_request(
                 &pool_addresses[start..end],
-                factory_addresses,
+                factories,
                 weth,
                 weth_threshold,
                 middleware.clone(),
+                block,
                 pb,
                 start,
                 end,
             )
         };
-    println!(
-        "Getting ETH equivalent values for {} pools with value at least {:?} GWEI",
-        pool_addresses.len(),
-        weth_threshold
+    info!(
+        pools = pool_addresses.len(),
+        %weth_threshold,
+        "getting WETH values of pools"
     );
     run_concurrent_hash(0, pool_addresses.len(), step, middleware, batch_func).await
 }
@@ -113,16 +163,19 @@ mod tests {
         let fixture = fixtures::Fixtures::new().await;
         let http = fixture.alchemy_provider.http.clone();
         let weth_threshold = U256::from(10).pow(U256::from(18));
-        let pool_addresses = vec![fixture.book.mainnet.uniswap_v2.pairs["weth"]["usdc"]];
-        let factory_addresses = vec![fixture.book.mainnet.uniswap_v2.factory];
-        let weth_address = fixture.book.mainnet.erc20["weth"];
+        let pool_addresses = vec![fixture.book.mainnet().dexes["uniswap_v2"].pairs["weth"]["usdc"]];
+        let factories = vec![PricingFactory::uniswap_v2(
+            fixture.book.mainnet().dexes["uniswap_v2"].factory,
+        )];
+        let weth_address = fixture.book.mainnet().erc20["weth"];
         let weth_values = get_weth_value_in_pool_concurrent(
             &pool_addresses,
-            &factory_addresses,
+            &factories,
             weth_address.clone(),
             weth_threshold,
             5,
             http,
+            BlockNumber::Latest,
         )
         .await;
         let pool = &fixture.weth_usdc_uniswap_v2_pool;
@@ -130,4 +183,25 @@ mod tests {
         let weth_reserve = pool.get_reserve_for_token(&weth_address) as f64;
         Fixtures::assert_almost_equal(weth_usdt_value, weth_reserve * 2.0, 0.0005);
     }
+
+    #[test]
+    fn test_constructor_args_flag_v3_factories() {
+        let factories = [
+            PricingFactory::uniswap_v2(H160::from_low_u64_be(1)),
+            PricingFactory::uniswap_v3(H160::from_low_u64_be(2)),
+        ];
+        let args = constructor_args(&[], &factories, H160::zero(), U256::zero());
+        let args = args.into_tuple().unwrap();
+        assert_eq!(
+            args[1],
+            Token::Array(vec![
+                Token::Address(H160::from_low_u64_be(1)),
+                Token::Address(H160::from_low_u64_be(2)),
+            ])
+        );
+        assert_eq!(
+            args[2],
+            Token::Array(vec![Token::Bool(false), Token::Bool(true)])
+        );
+    }
 }
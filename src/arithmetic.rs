@@ -1,3 +1,6 @@
+pub mod fixed_point;
+pub mod price;
+
 use ethers::types::U256;
 use num_bigfloat::BigFloat;
 
@@ -31,6 +34,13 @@ pub fn q64_to_f64(x: u128) -> f64 {
         .to_f64()
 }
 
+/// Converts an unsigned 112.112 fixed point number, as used by the Uniswap V2 price oracle.
+pub fn q112_to_f64(x: U256) -> f64 {
+    let integer = (x >> 112).as_u128() as f64;
+    let fraction = (x & ((U256::one() << 112) - 1)).as_u128() as f64;
+    integer + fraction / 2f64.powi(112)
+}
+
 pub fn div_uu(x: U256, y: U256) -> u128 {
     if !y.is_zero() {
         let mut answer;
@@ -0,0 +1,127 @@
+use ethers::types::{U256, U512};
+use std::fmt;
+
+/// Unsigned 64.64 fixed point number, the format of `div_uu` and of the pool prices.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Q64x64(pub u128);
+
+impl Q64x64 {
+    pub const ZERO: Q64x64 = Q64x64(0);
+    pub const ONE: Q64x64 = Q64x64(1 << 64);
+    pub const MAX: Q64x64 = Q64x64(u128::MAX);
+
+    pub fn from_integer(x: u64) -> Self {
+        Q64x64((x as u128) << 64)
+    }
+
+    /// `numerator / denominator` rounded down, `None` if the denominator is zero or the result
+    /// does not fit in 64 integer bits.
+    pub fn from_ratio(numerator: U256, denominator: U256) -> Option<Self> {
+        if denominator.is_zero() {
+            return None;
+        }
+        let quotient = (U512::from(numerator) << 64) / U512::from(denominator);
+        Self::from_u512(quotient)
+    }
+
+    fn from_u512(x: U512) -> Option<Self> {
+        if x.bits() > 128 {
+            return None;
+        }
+        Some(Q64x64(x.low_u128()))
+    }
+
+    pub fn raw(&self) -> u128 {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Q64x64)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Q64x64)
+    }
+
+    pub fn checked_mul(self, other: Self) -> Option<Self> {
+        Self::from_u512(U256::from(self.0).full_mul(U256::from(other.0)) >> 64)
+    }
+
+    pub fn checked_div(self, other: Self) -> Option<Self> {
+        Self::from_ratio(U256::from(self.0), U256::from(other.0))
+    }
+
+    pub fn inverse(self) -> Option<Self> {
+        Self::ONE.checked_div(self)
+    }
+
+    /// `amount` multiplied by this number, rounded down. `None` on overflow.
+    pub fn mul_u256(self, amount: U256) -> Option<U256> {
+        U256::try_from(amount.full_mul(U256::from(self.0)) >> 64).ok()
+    }
+
+    /// Exact for integer parts below 2^53, otherwise the nearest `f64`.
+    pub fn to_f64(self) -> f64 {
+        (self.0 >> 64) as f64 + (self.0 as u64) as f64 / 2f64.powi(64)
+    }
+}
+
+impl fmt::Display for Q64x64 {
+    /// Decimal expansion rounded to the formatter's precision (20 digits by default), without
+    /// trailing zeros.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(20).min(38);
+        let scale = U256::exp10(precision);
+        let scaled = (U256::from(self.0) * scale + (U256::one() << 63)) >> 64;
+        let fraction = format!(
+            "{:0>width$}",
+            (scaled % scale).to_string(),
+            width = precision
+        );
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.is_empty() {
+            write!(f, "{}", scaled / scale)
+        } else {
+            write!(f, "{}.{}", scaled / scale, fraction)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arithmetic() {
+        let half = Q64x64::from_ratio(U256::from(1), U256::from(2)).unwrap();
+        let three = Q64x64::from_integer(3);
+        assert_eq!(half.checked_mul(three).unwrap().to_f64(), 1.5);
+        assert_eq!(three.checked_div(half).unwrap(), Q64x64::from_integer(6));
+        assert_eq!(half.inverse().unwrap(), Q64x64::from_integer(2));
+        assert_eq!(three.checked_sub(half).unwrap().to_f64(), 2.5);
+        assert_eq!(half.mul_u256(U256::from(7)), Some(U256::from(3)));
+        assert!(half < three);
+
+        assert_eq!(Q64x64::ZERO.inverse(), None);
+        assert_eq!(Q64x64::from_ratio(U256::one(), U256::zero()), None);
+        assert_eq!(Q64x64::from_ratio(U256::one() << 64, U256::one()), None);
+        assert_eq!(Q64x64::MAX.checked_mul(three), None);
+        assert_eq!(Q64x64::MAX.mul_u256(U256::MAX), None);
+    }
+
+    #[test]
+    fn test_display() {
+        let third = Q64x64::from_ratio(U256::from(1), U256::from(3)).unwrap();
+        assert_eq!(third.to_string(), "0.33333333333333333332");
+        assert_eq!(format!("{:.4}", third), "0.3333");
+        assert_eq!(Q64x64::from_integer(42).to_string(), "42");
+        let x = Q64x64::from_ratio(U256::from(25), U256::from(4)).unwrap();
+        assert_eq!(x.to_string(), "6.25");
+        let almost_one = Q64x64(Q64x64::ONE.raw() - 1);
+        assert_eq!(format!("{:.3}", almost_one), "1");
+    }
+}
@@ -0,0 +1,240 @@
+use super::fixed_point::Q64x64;
+use ethers::types::{U256, U512};
+use std::{cmp::Ordering, fmt};
+
+/// Exact price `numerator / denominator * 10^exponent`.
+///
+/// Keeping the decimal shift as an exponent instead of multiplying it into the numerator means
+/// building a price never overflows, whatever the decimals of the two tokens.
+#[derive(Debug, Clone, Copy)]
+pub struct Price {
+    pub numerator: U256,
+    pub denominator: U256,
+    pub exponent: i32,
+}
+
+fn gcd(mut a: U256, mut b: U256) -> U256 {
+    while !b.is_zero() {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// 10^n, `None` once it no longer fits.
+fn pow10(n: u32) -> Option<U512> {
+    U512::from(10).checked_pow(U512::from(n))
+}
+
+fn u512_to_f64(x: U512) -> f64 {
+    x.0.iter()
+        .rev()
+        .fold(0.0, |acc, word| acc * 2f64.powi(64) + *word as f64)
+}
+
+impl Price {
+    /// `None` if the denominator is zero.
+    pub fn new(numerator: U256, denominator: U256) -> Option<Self> {
+        Self::with_exponent(numerator, denominator, 0)
+    }
+
+    pub fn with_exponent(numerator: U256, denominator: U256, exponent: i32) -> Option<Self> {
+        if denominator.is_zero() {
+            return None;
+        }
+        Some(Price {
+            numerator,
+            denominator,
+            exponent,
+        })
+    }
+
+    /// Whole units of the quote token per whole unit of the base token, from reserves in the
+    /// tokens' smallest units.
+    pub fn from_reserves(
+        base_reserve: u128,
+        base_decimals: u8,
+        quote_reserve: u128,
+        quote_decimals: u8,
+    ) -> Option<Self> {
+        Self::with_exponent(
+            U256::from(quote_reserve),
+            U256::from(base_reserve),
+            base_decimals as i32 - quote_decimals as i32,
+        )
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.numerator.is_zero()
+    }
+
+    /// Same price with numerator and denominator divided by their greatest common divisor.
+    pub fn reduced(&self) -> Self {
+        let divisor = gcd(self.numerator, self.denominator);
+        if divisor.is_zero() {
+            return *self;
+        }
+        Price {
+            numerator: self.numerator / divisor,
+            denominator: self.denominator / divisor,
+            exponent: self.exponent,
+        }
+    }
+
+    pub fn inverse(&self) -> Option<Self> {
+        Self::with_exponent(self.denominator, self.numerator, -self.exponent)
+    }
+
+    /// Exact product, `None` if it cannot be represented even after reducing both factors.
+    pub fn checked_mul(&self, other: &Price) -> Option<Self> {
+        // Cross reduce so that e.g. a/b * b/c does not overflow.
+        let (a, b) = (self.reduced(), other.reduced());
+        let g1 = gcd(a.numerator, b.denominator).max(U256::one());
+        let g2 = gcd(b.numerator, a.denominator).max(U256::one());
+        Some(Price {
+            numerator: (a.numerator / g1).checked_mul(b.numerator / g2)?,
+            denominator: (a.denominator / g2).checked_mul(b.denominator / g1)?,
+            exponent: a.exponent.checked_add(b.exponent)?,
+        })
+    }
+
+    pub fn checked_div(&self, other: &Price) -> Option<Self> {
+        self.checked_mul(&other.inverse()?)
+    }
+
+    /// The price as a 64.64 number, rounded down. `None` if it is 2^64 or more.
+    pub fn to_q64x64(&self) -> Option<Q64x64> {
+        let shift = pow10(self.exponent.unsigned_abs());
+        let (numerator, denominator) = if self.exponent >= 0 {
+            (
+                U512::from(self.numerator).checked_mul(shift?)?,
+                U512::from(self.denominator),
+            )
+        } else {
+            match shift.and_then(|s| U512::from(self.denominator).checked_mul(s)) {
+                Some(denominator) => (U512::from(self.numerator), denominator),
+                // Too small to be anything but zero
+                None => return Some(Q64x64::ZERO),
+            }
+        };
+        let quotient = numerator.checked_mul(U512::one() << 64)? / denominator;
+        if quotient.bits() > 128 {
+            return None;
+        }
+        Some(Q64x64(quotient.low_u128()))
+    }
+
+    /// Nearest `f64`, or infinity and zero outside its range.
+    pub fn to_f64(&self) -> f64 {
+        if self.is_zero() {
+            return 0.0;
+        }
+        let ratio =
+            u512_to_f64(U512::from(self.numerator)) / u512_to_f64(U512::from(self.denominator));
+        // Apply the exponent in two steps so that a huge ratio and a tiny shift can cancel out.
+        let half = self.exponent / 2;
+        ratio * 10f64.powi(half) * 10f64.powi(self.exponent - half)
+    }
+
+    /// Both sides scaled to the same exponent: numerator * other.denominator and
+    /// other.numerator * denominator.
+    fn cross_products(&self, other: &Price) -> (U512, U512) {
+        (
+            self.numerator.full_mul(other.denominator),
+            other.numerator.full_mul(self.denominator),
+        )
+    }
+}
+
+impl PartialEq for Price {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Price {}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.is_zero(), other.is_zero()) {
+            (true, true) => return Ordering::Equal,
+            (true, false) => return Ordering::Less,
+            (false, true) => return Ordering::Greater,
+            _ => {}
+        }
+        let (lhs, rhs) = self.cross_products(other);
+        // Scale the side with the larger exponent; if that overflows 512 bits it is larger, as
+        // both cross products are nonzero and below 2^512.
+        let diff = self.exponent as i64 - other.exponent as i64;
+        let scale = |x: U512, n: i64| {
+            u32::try_from(n)
+                .ok()
+                .and_then(pow10)
+                .and_then(|p| x.checked_mul(p))
+        };
+        match diff.cmp(&0) {
+            Ordering::Equal => lhs.cmp(&rhs),
+            Ordering::Greater => scale(lhs, diff).map_or(Ordering::Greater, |l| l.cmp(&rhs)),
+            Ordering::Less => scale(rhs, -diff).map_or(Ordering::Less, |r| lhs.cmp(&r)),
+        }
+    }
+}
+
+impl fmt::Display for Price {
+    /// Exact decimal when the price fits in 64.64, otherwise scientific notation.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.to_q64x64() {
+            Some(q) if !q.is_zero() || self.is_zero() => fmt::Display::fmt(&q, f),
+            _ => write!(f, "{:e}", self.to_f64()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_reserves() {
+        // 2000 USDC (6 decimals) per WETH (18 decimals)
+        let price = Price::from_reserves(10u128.pow(21), 18, 2 * 10u128.pow(12), 6).unwrap();
+        assert!((price.to_f64() - 2000.0).abs() < 1e-9);
+        assert_eq!(price.to_q64x64(), Some(Q64x64::from_integer(2000)));
+        assert_eq!(price.to_string(), "2000");
+        let inverse = price.inverse().unwrap();
+        assert_eq!(format!("{:.6}", inverse), "0.0005");
+        assert_eq!(price.checked_mul(&inverse).unwrap().to_f64(), 1.0);
+        assert_eq!(Price::from_reserves(0, 18, 1, 18), None);
+    }
+
+    #[test]
+    fn test_extreme_decimals() {
+        // Decimal gaps that overflow 10u128.pow
+        let price = Price::from_reserves(1, 0, 1, 255).unwrap();
+        assert_eq!(price.to_q64x64(), Some(Q64x64::ZERO));
+        assert!((price.to_f64() / 1e-255 - 1.0).abs() < 1e-12);
+        assert_eq!(price.inverse().unwrap().to_q64x64(), None);
+        let formatted: f64 = price.to_string().parse().unwrap();
+        assert!((formatted / 1e-255 - 1.0).abs() < 1e-12);
+        let tiny = Price::from_reserves(1, 0, 10u128.pow(30), 200).unwrap();
+        assert!((tiny.to_f64() / 1e-170 - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_ordering() {
+        let a = Price::with_exponent(U256::from(1), U256::from(3), 2).unwrap();
+        let b = Price::with_exponent(U256::from(33), U256::from(1), 0).unwrap();
+        let c = Price::with_exponent(U256::from(100), U256::from(3), 0).unwrap();
+        assert!(a > b);
+        assert_eq!(a, c);
+        let huge = Price::with_exponent(U256::one(), U256::MAX, 300).unwrap();
+        let small = Price::with_exponent(U256::MAX, U256::one(), -300).unwrap();
+        assert!(huge > small);
+        assert!(Price::new(U256::zero(), U256::one()).unwrap() < small);
+    }
+}
@@ -0,0 +1,472 @@
+use crate::{
+    amm::uniswap_v2::{
+        factory::events::pair_created::PAIR_CREATED_EVENT_SIGNATURE,
+        pool::{
+            events::sync::SYNC_EVENT_SIGNATURE,
+            pool_data_batch_request::get_uniswap_v2_pool_data_concurrent, UniswapV2Pool,
+        },
+    },
+    checkpoint::{
+        log_archive::{LogArchive, LogArchiveError},
+        Checkpoint,
+    },
+    config::SearchConfig,
+    contract::{PairCreatedFilter, SyncFilter},
+    eth_provider::EthProvider,
+    path::{optimal_amount::find_optimal_amount_in_and_out, path_discovery::get_all_token_paths},
+};
+use csv::Writer;
+use ethers::{
+    abi::RawLog,
+    prelude::EthEvent,
+    providers::Middleware,
+    types::{BlockNumber, Log, H160, U256},
+};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+/// Pairs created in `logs` by `factory`, in chain order.
+fn created_pairs(factory: H160, logs: &[Log]) -> Vec<H160> {
+    logs.iter()
+        .filter(|log| {
+            log.address == factory && log.topics.first() == Some(&PAIR_CREATED_EVENT_SIGNATURE)
+        })
+        .filter_map(|log| PairCreatedFilter::decode_log(&RawLog::from(log.clone())).ok())
+        .map(|event| event.pair)
+        .collect()
+}
+
+/// Every `PairCreated` log of `factory` between `start` and `end` (inclusive) and the `Sync`
+/// logs of `pools` and of the pairs created meanwhile, in chain order, served from the log
+/// archive.
+pub async fn get_replay_logs<M: Middleware>(
+    factory: H160,
+    pools: &[H160],
+    start: u64,
+    end: u64,
+    step: usize,
+    middleware: Arc<M>,
+) -> Result<Vec<Log>, LogArchiveError> {
+    let mut logs = LogArchive::get_logs(
+        PAIR_CREATED_EVENT_SIGNATURE,
+        &[factory],
+        start,
+        end,
+        step,
+        middleware.clone(),
+    )
+    .await?;
+    let mut addresses = pools.to_vec();
+    addresses.extend(created_pairs(factory, &logs));
+    if !addresses.is_empty() {
+        logs.extend(
+            LogArchive::get_logs(
+                SYNC_EVENT_SIGNATURE,
+                &addresses,
+                start,
+                end,
+                step,
+                middleware,
+            )
+            .await?,
+        );
+    }
+    logs.sort_by_key(|log| (log.block_number, log.log_index));
+    Ok(logs)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Opportunity {
+    pub block_number: u64,
+    pub path: Vec<H160>,
+    pub amount_in: U256,
+    pub amount_out: U256,
+}
+
+impl Opportunity {
+    pub fn profit(&self) -> U256 {
+        self.amount_out.saturating_sub(self.amount_in)
+    }
+}
+
+pub fn write_opportunities_to_csv(opportunities: &[Opportunity], file_path: &str) {
+    let mut wtr = Writer::from_path(file_path).unwrap();
+    wtr.write_record(["block_number", "path", "amount_in", "amount_out", "profit"])
+        .unwrap();
+    for opportunity in opportunities {
+        let path = opportunity
+            .path
+            .iter()
+            .map(|t| format!("{:?}", t))
+            .collect::<Vec<_>>()
+            .join(", ");
+        wtr.write_record(&[
+            opportunity.block_number.to_string(),
+            path,
+            opportunity.amount_in.to_string(),
+            opportunity.amount_out.to_string(),
+            opportunity.profit().to_string(),
+        ])
+        .unwrap();
+    }
+    wtr.flush().unwrap();
+}
+
+/// Replays recorded logs through a pool snapshot and searches for arbitrage after every block.
+pub struct Backtest {
+    pub factory: H160,
+    /// Fee of the factory's pairs, given to the pairs it creates during the replay.
+    pub fee: u32,
+    pub base_token: H160,
+    pub pools: Vec<UniswapV2Pool>,
+    pub block_number: u64,
+    pub min_path_length: usize,
+    pub max_path_length: usize,
+    pub epsilon: f64,
+    /// Upper bound of the search for the best amount in.
+    pub max_amount_in: f64,
+    /// Decimals of the tokens of the pools, for the pairs created during the replay.
+    decimals: HashMap<H160, u8>,
+    index: HashMap<H160, usize>,
+    cycles: Vec<Vec<H160>>,
+}
+
+impl Backtest {
+    /// Starts from the pools in `checkpoint` as of its `last_block`, whose factory charges
+    /// `fee`. Only cycles starting and ending in `base_token` are searched, within `search`.
+    pub fn new(
+        checkpoint: Checkpoint<Vec<UniswapV2Pool>>,
+        fee: u32,
+        base_token: H160,
+        search: &SearchConfig,
+    ) -> Self {
+        let mut backtest = Backtest {
+            factory: checkpoint.factory_address(),
+            fee,
+            base_token,
+            block_number: checkpoint.last_block,
+            pools: checkpoint.data,
+            min_path_length: search.min_path_length,
+            max_path_length: search.max_path_length,
+            epsilon: search.epsilon.as_u128() as f64,
+            max_amount_in: search.max_amount_in.as_u128() as f64,
+            decimals: HashMap::new(),
+            index: HashMap::new(),
+            cycles: vec![],
+        };
+        for (idx, pool) in backtest.pools.iter().enumerate() {
+            backtest.index.insert(pool.address, idx);
+            backtest
+                .decimals
+                .insert(pool.token_a, pool.token_a_decimals);
+            backtest
+                .decimals
+                .insert(pool.token_b, pool.token_b_decimals);
+        }
+        backtest.update_cycles();
+        backtest
+    }
+
+    /// Replays the logs from the block after the snapshot up to `end_block`, fetching only the
+    /// ranges missing from the log archive. Decimals of the tokens of new pairs are read first.
+    pub async fn run(
+        &mut self,
+        provider: &EthProvider,
+        end_block: u64,
+        step: usize,
+    ) -> Result<Vec<Opportunity>, LogArchiveError> {
+        let pools: Vec<H160> = self.pools.iter().map(|p| p.address).collect();
+        let logs = get_replay_logs(
+            self.factory,
+            &pools,
+            self.block_number + 1,
+            end_block,
+            step,
+            provider.http.clone(),
+        )
+        .await?;
+        let new_pairs: Vec<H160> = created_pairs(self.factory, &logs)
+            .into_iter()
+            .filter(|pair| !self.index.contains_key(pair))
+            .collect();
+        if !new_pairs.is_empty() {
+            let new_pools = get_uniswap_v2_pool_data_concurrent(
+                &new_pairs,
+                provider.http.clone(),
+                self.fee,
+                step,
+                BlockNumber::Number(end_block.into()),
+            )
+            .await;
+            for pool in new_pools {
+                self.decimals.insert(pool.token_a, pool.token_a_decimals);
+                self.decimals.insert(pool.token_b, pool.token_b_decimals);
+            }
+        }
+        Ok(self.replay(&logs))
+    }
+
+    /// Applies `logs`, which must be in chain order, one block at a time and returns the
+    /// opportunities found after each block. Logs at or before the current block are skipped.
+    pub fn replay(&mut self, logs: &[Log]) -> Vec<Opportunity> {
+        let mut opportunities = vec![];
+        let mut touched = HashSet::new();
+        let mut pairs_created = false;
+        let mut current_block = None;
+        for log in logs {
+            let block_number = match log.block_number {
+                Some(block_number) if block_number.as_u64() > self.block_number => {
+                    block_number.as_u64()
+                }
+                _ => continue,
+            };
+            if current_block.is_some_and(|current| current != block_number) {
+                opportunities.extend(self.end_block(
+                    current_block.unwrap(),
+                    &touched,
+                    pairs_created,
+                ));
+                touched.clear();
+                pairs_created = false;
+            }
+            current_block = Some(block_number);
+            if let Some(address) = self.apply_log(log) {
+                pairs_created |= log.topics.first() == Some(&PAIR_CREATED_EVENT_SIGNATURE);
+                touched.insert(address);
+            }
+        }
+        if let Some(block_number) = current_block {
+            opportunities.extend(self.end_block(block_number, &touched, pairs_created));
+        }
+        opportunities
+    }
+
+    /// Updates the pool state with a single log and returns the address of the pool it touched.
+    /// Pairs created by other factories and syncs of unknown pools are ignored.
+    pub fn apply_log(&mut self, log: &Log) -> Option<H160> {
+        let topic = *log.topics.first()?;
+        if topic == SYNC_EVENT_SIGNATURE {
+            let idx = *self.index.get(&log.address)?;
+            let event = SyncFilter::decode_log(&RawLog::from(log.clone())).ok()?;
+            let pool = &mut self.pools[idx];
+            pool.reserve_0 = event.reserve_0;
+            pool.reserve_1 = event.reserve_1;
+            Some(pool.address)
+        } else if topic == PAIR_CREATED_EVENT_SIGNATURE && log.address == self.factory {
+            let event = PairCreatedFilter::decode_log(&RawLog::from(log.clone())).ok()?;
+            if self.index.contains_key(&event.pair) {
+                return None;
+            }
+            // Decimals are not part of the log, tokens not seen before keep 0 decimals. They
+            // are not needed to simulate swaps.
+            let decimals = |token| self.decimals.get(&token).copied().unwrap_or_default();
+            let pool = UniswapV2Pool::new(
+                event.pair,
+                event.token_0,
+                decimals(event.token_0),
+                event.token_1,
+                decimals(event.token_1),
+                0,
+                0,
+                self.fee,
+                U256::zero(),
+            );
+            self.index.insert(pool.address, self.pools.len());
+            self.pools.push(pool);
+            Some(event.pair)
+        } else {
+            None
+        }
+    }
+
+    fn end_block(
+        &mut self,
+        block_number: u64,
+        touched: &HashSet<H160>,
+        pairs_created: bool,
+    ) -> Vec<Opportunity> {
+        self.block_number = block_number;
+        if pairs_created {
+            self.update_cycles();
+        }
+        self.search(block_number, touched)
+    }
+
+    fn update_cycles(&mut self) {
+        let mut tokens_map: HashMap<&H160, Vec<&H160>> = HashMap::new();
+        for pool in &self.pools {
+            tokens_map
+                .entry(&pool.token_a)
+                .or_default()
+                .push(&pool.token_b);
+            tokens_map
+                .entry(&pool.token_b)
+                .or_default()
+                .push(&pool.token_a);
+        }
+        let paths = get_all_token_paths(
+            &self.base_token,
+            &tokens_map,
+            self.min_path_length,
+            self.max_path_length,
+        );
+        // Path discovery keeps one direction per cycle, both can be profitable.
+        self.cycles = paths
+            .into_iter()
+            .flat_map(|path| {
+                let path: Vec<H160> = path.into_iter().copied().collect();
+                let mut reversed = path.clone();
+                reversed.reverse();
+                [path, reversed]
+            })
+            .collect();
+    }
+
+    /// Finds the optimal trade on every cycle through a pool in `touched` and keeps the
+    /// profitable ones.
+    pub fn search(&self, block_number: u64, touched: &HashSet<H160>) -> Vec<Opportunity> {
+        let mut pool_map = HashMap::new();
+        for pool in &self.pools {
+            pool_map.insert((&pool.token_a, &pool.token_b), pool);
+            pool_map.insert((&pool.token_b, &pool.token_a), pool);
+        }
+        let mut opportunities = vec![];
+        for cycle in &self.cycles {
+            let through_touched = cycle
+                .windows(2)
+                .any(|hop| touched.contains(&pool_map[&(&hop[0], &hop[1])].address));
+            if !through_touched {
+                continue;
+            }
+            let (amount_in, amount_out) =
+                find_optimal_amount_in_and_out(cycle, &pool_map, self.epsilon, self.max_amount_in);
+            if amount_out > amount_in {
+                opportunities.push(Opportunity {
+                    block_number,
+                    path: cycle.clone(),
+                    amount_in,
+                    amount_out,
+                });
+            }
+        }
+        opportunities
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{
+        abi::{encode, Token},
+        types::{H256, U64},
+    };
+
+    fn token(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    fn sync_log(pool: H160, block: u64, index: u64, reserve_0: u128, reserve_1: u128) -> Log {
+        Log {
+            address: pool,
+            topics: vec![SYNC_EVENT_SIGNATURE],
+            data: encode(&[
+                Token::Uint(U256::from(reserve_0)),
+                Token::Uint(U256::from(reserve_1)),
+            ])
+            .into(),
+            block_number: Some(U64::from(block)),
+            log_index: Some(U256::from(index)),
+            ..Default::default()
+        }
+    }
+
+    fn pair_created_log(factory: H160, block: u64, a: H160, b: H160, pair: H160) -> Log {
+        Log {
+            address: factory,
+            topics: vec![PAIR_CREATED_EVENT_SIGNATURE, H256::from(a), H256::from(b)],
+            data: encode(&[Token::Address(pair), Token::Uint(U256::one())]).into(),
+            block_number: Some(U64::from(block)),
+            log_index: Some(U256::zero()),
+            ..Default::default()
+        }
+    }
+
+    fn backtest() -> Backtest {
+        let e18 = 10u128.pow(18);
+        let pools = vec![
+            UniswapV2Pool::new(
+                token(10),
+                token(1),
+                18,
+                token(2),
+                18,
+                100 * e18,
+                200_000 * e18,
+                300,
+                U256::zero(),
+            ),
+            UniswapV2Pool::new(
+                token(11),
+                token(2),
+                18,
+                token(3),
+                18,
+                200_000 * e18,
+                10_000 * e18,
+                300,
+                U256::zero(),
+            ),
+        ];
+        let checkpoint = Checkpoint::new(100, pools, &format!("uniswap_v2_pools.{:?}", token(99)));
+        let search = SearchConfig {
+            epsilon: U256::exp10(10),
+            min_path_length: 4,
+            max_path_length: 4,
+            ..Default::default()
+        };
+        Backtest::new(checkpoint, 250, token(1), &search)
+    }
+
+    #[test]
+    fn test_replay_pair_created_and_sync() {
+        let e18 = 10u128.pow(18);
+        let mut backtest = backtest();
+        assert!(backtest.cycles.is_empty());
+
+        let logs = vec![
+            // Already part of the snapshot.
+            sync_log(token(10), 100, 0, 1, 1),
+            pair_created_log(token(99), 101, token(3), token(1), token(12)),
+            sync_log(token(12), 101, 1, 10_000 * e18, 100 * e18),
+            // Another factory's pair is not tracked.
+            pair_created_log(token(98), 102, token(3), token(1), token(13)),
+            sync_log(token(13), 102, 1, 1, 1),
+            // Pushes the 3/1 pool out of line.
+            sync_log(token(12), 103, 0, 10_000 * e18, 60 * e18),
+        ];
+        let opportunities = backtest.replay(&logs);
+
+        assert_eq!(backtest.block_number, 103);
+        assert_eq!(backtest.pools.len(), 3);
+        assert_eq!(backtest.pools[0].reserve_0, 100 * e18);
+        assert_eq!(backtest.pools[2].reserve_1, 60 * e18);
+        assert_eq!(backtest.pools[2].fee, 250);
+        assert_eq!(backtest.pools[2].token_b_decimals, 18);
+        assert_eq!(backtest.cycles.len(), 2);
+        assert!(opportunities.iter().all(|o| o.profit() > U256::zero()));
+        // The new pool is priced in line with the others and block 102 only touched an
+        // untracked pool.
+        assert!(opportunities.iter().all(|o| o.block_number == 103));
+        assert_eq!(opportunities.len(), 1);
+        assert_eq!(
+            opportunities[0].path,
+            vec![token(1), token(3), token(2), token(1)]
+        );
+
+        // Replaying the same logs again is a no-op.
+        assert!(backtest.replay(&logs).is_empty());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::{
+    amm::uniswap_v2::pool::{events::swap::SWAP_EVENT_SIGNATURE, UniswapV2Pool},
+    checkpoint::log_archive::{LogArchive, LogArchiveError},
+    contract::SwapFilter,
+};
+use csv::Writer;
+use ethers::{
+    abi::RawLog,
+    prelude::EthEvent,
+    providers::Middleware,
+    types::{Log, H160, H256, I256, U256},
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+/// A single swap through a V2 pool, with amounts net of any tokens sent back in the same call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwapHop {
+    pub pool: H160,
+    pub token_in: H160,
+    pub token_out: H160,
+    pub amount_in: U256,
+    pub amount_out: U256,
+}
+
+impl SwapHop {
+    pub fn from_event(pool: &UniswapV2Pool, event: &SwapFilter) -> Option<Self> {
+        let (in_0, out_0, in_1, out_1) = (
+            event.amount_0_in,
+            event.amount_0_out,
+            event.amount_1_in,
+            event.amount_1_out,
+        );
+        let (token_in, token_out, amount_in, amount_out) = if in_0 > out_0 && out_1 > in_1 {
+            (pool.token_a, pool.token_b, in_0 - out_0, out_1 - in_1)
+        } else if in_1 > out_1 && out_0 > in_0 {
+            (pool.token_b, pool.token_a, in_1 - out_1, out_0 - in_0)
+        } else {
+            return None;
+        };
+        Some(SwapHop {
+            pool: pool.address,
+            token_in,
+            token_out,
+            amount_in,
+            amount_out,
+        })
+    }
+}
+
+/// Splits the hops of a transaction into legs where each hop sells what the previous one
+/// bought.
+pub fn split_legs(hops: Vec<SwapHop>) -> Vec<Vec<SwapHop>> {
+    let mut legs: Vec<Vec<SwapHop>> = vec![];
+    for hop in hops {
+        match legs.last_mut() {
+            Some(leg) if leg.last().map(|h| h.token_out) == Some(hop.token_in) => leg.push(hop),
+            _ => legs.push(vec![hop]),
+        }
+    }
+    legs
+}
+
+/// A cyclic path captured on chain, with the profit in the start token.
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectedArbitrage {
+    pub block_number: u64,
+    pub transaction_hash: H256,
+    pub pools: Vec<H160>,
+    pub path: Vec<H160>,
+    pub amount_in: U256,
+    pub amount_out: U256,
+}
+
+impl DetectedArbitrage {
+    fn from_leg(block_number: u64, transaction_hash: H256, leg: &[SwapHop]) -> Option<Self> {
+        let (first, last) = (leg.first()?, leg.last()?);
+        if leg.len() < 2 || first.token_in != last.token_out {
+            return None;
+        }
+        let mut path = vec![first.token_in];
+        path.extend(leg.iter().map(|hop| hop.token_out));
+        Some(DetectedArbitrage {
+            block_number,
+            transaction_hash,
+            pools: leg.iter().map(|hop| hop.pool).collect(),
+            path,
+            amount_in: first.amount_in,
+            amount_out: last.amount_out,
+        })
+    }
+
+    /// Realised profit, negative for cycles that lost money.
+    pub fn profit(&self) -> I256 {
+        I256::from_raw(self.amount_out) - I256::from_raw(self.amount_in)
+    }
+}
+
+/// Hash of a transaction and its swaps by log index, `None` for swaps that cannot be followed.
+type TransactionSwaps = (H256, Vec<(u64, Option<SwapHop>)>);
+
+#[derive(Debug, Clone, Default)]
+pub struct BlockReport {
+    pub block_number: u64,
+    /// Transactions that swapped through at least one known pool.
+    pub swap_transactions: usize,
+    pub arbitrages: Vec<DetectedArbitrage>,
+}
+
+/// Reconstructs the token paths of mined transactions over the pools of a registry, such as
+/// the pools of a checkpoint, to find the cycles others captured.
+pub struct BlockAnalyser {
+    pools: HashMap<H160, UniswapV2Pool>,
+}
+
+impl BlockAnalyser {
+    pub fn new(pools: &[UniswapV2Pool]) -> Self {
+        BlockAnalyser {
+            pools: pools.iter().map(|p| (p.address, p.clone())).collect(),
+        }
+    }
+
+    /// One report per block with swaps in `logs`. Swaps of unknown pools end a leg, since the
+    /// path cannot be followed through them.
+    pub fn analyse_logs(&self, logs: &[Log]) -> Vec<BlockReport> {
+        let mut transactions: BTreeMap<(u64, u64), TransactionSwaps> = BTreeMap::new();
+        for log in logs {
+            if log.topics.first() != Some(&SWAP_EVENT_SIGNATURE) {
+                continue;
+            }
+            let (Some(block), Some(tx_index), Some(tx_hash)) = (
+                log.block_number,
+                log.transaction_index,
+                log.transaction_hash,
+            ) else {
+                continue;
+            };
+            let hop = match (
+                self.pools.get(&log.address),
+                SwapFilter::decode_log(&RawLog::from(log.clone())),
+            ) {
+                (Some(pool), Ok(event)) => SwapHop::from_event(pool, &event),
+                _ => None,
+            };
+            let log_index = log.log_index.unwrap_or_default().as_u64();
+            transactions
+                .entry((block.as_u64(), tx_index.as_u64()))
+                .or_insert((tx_hash, vec![]))
+                .1
+                .push((log_index, hop));
+        }
+
+        let mut reports: BTreeMap<u64, BlockReport> = BTreeMap::new();
+        for ((block_number, _), (tx_hash, mut hops)) in transactions {
+            hops.sort_by_key(|(log_index, _)| *log_index);
+            let report = reports.entry(block_number).or_insert(BlockReport {
+                block_number,
+                ..Default::default()
+            });
+            if hops.iter().any(|(_, hop)| hop.is_some()) {
+                report.swap_transactions += 1;
+            }
+            for known in hops.split(|(_, hop)| hop.is_none()) {
+                let known = known.iter().filter_map(|(_, hop)| hop.clone()).collect();
+                for leg in split_legs(known) {
+                    if let Some(arbitrage) =
+                        DetectedArbitrage::from_leg(block_number, tx_hash, &leg)
+                    {
+                        report.arbitrages.push(arbitrage);
+                    }
+                }
+            }
+        }
+        reports.into_values().collect()
+    }
+
+    /// Analyses every block between `start` and `end` (inclusive), served from the log archive.
+    /// Only swaps of the analyser's pools are fetched.
+    pub async fn analyse_blocks<M: Middleware>(
+        &self,
+        start: u64,
+        end: u64,
+        step: usize,
+        middleware: Arc<M>,
+    ) -> Result<Vec<BlockReport>, LogArchiveError> {
+        if self.pools.is_empty() {
+            return Ok(vec![]);
+        }
+        let addresses: Vec<H160> = self.pools.keys().copied().collect();
+        let logs = LogArchive::get_logs(
+            SWAP_EVENT_SIGNATURE,
+            &addresses,
+            start,
+            end,
+            step,
+            middleware,
+        )
+        .await?;
+        Ok(self.analyse_logs(&logs))
+    }
+}
+
+pub fn write_arbitrages_to_csv(reports: &[BlockReport], file_path: &str) {
+    let mut wtr = Writer::from_path(file_path).unwrap();
+    wtr.write_record([
+        "block_number",
+        "transaction_hash",
+        "pools",
+        "path",
+        "amount_in",
+        "amount_out",
+        "profit",
+    ])
+    .unwrap();
+    let join = |addresses: &[H160]| {
+        addresses
+            .iter()
+            .map(|a| format!("{:?}", a))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    for arbitrage in reports.iter().flat_map(|r| &r.arbitrages) {
+        wtr.write_record(&[
+            arbitrage.block_number.to_string(),
+            format!("{:?}", arbitrage.transaction_hash),
+            join(&arbitrage.pools),
+            join(&arbitrage.path),
+            arbitrage.amount_in.to_string(),
+            arbitrage.amount_out.to_string(),
+            arbitrage.profit().to_string(),
+        ])
+        .unwrap();
+    }
+    wtr.flush().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{
+        abi::{encode, Token},
+        types::U64,
+    };
+
+    fn address(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    fn pool(n: u64, token_a: u64, token_b: u64) -> UniswapV2Pool {
+        UniswapV2Pool::new(
+            address(n),
+            address(token_a),
+            18,
+            address(token_b),
+            18,
+            1_000_000,
+            1_000_000,
+            300,
+            U256::zero(),
+        )
+    }
+
+    fn swap(pool: u64, tx: u64, log_index: u64, amounts: [u64; 4]) -> Log {
+        Log {
+            address: address(pool),
+            topics: vec![
+                SWAP_EVENT_SIGNATURE,
+                H256::from(address(99)),
+                H256::from(address(99)),
+            ],
+            data: encode(&amounts.map(|a| Token::Uint(a.into()))).into(),
+            block_number: Some(U64::from(100)),
+            transaction_index: Some(U64::from(tx)),
+            transaction_hash: Some(H256::from_low_u64_be(tx)),
+            log_index: Some(log_index.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_detects_cycles() {
+        // Tokens 1, 2, 3 with pools 10: (1, 2), 11: (2, 3), 12: (1, 3)
+        let analyser = BlockAnalyser::new(&[pool(10, 1, 2), pool(11, 2, 3), pool(12, 1, 3)]);
+        let logs = vec![
+            // Cycle 1 -> 2 -> 3 -> 1, logs out of order
+            swap(11, 0, 2, [90, 0, 0, 80]),
+            swap(10, 0, 1, [100, 0, 0, 90]),
+            swap(12, 0, 3, [0, 80, 110, 0]),
+            // Plain swap 1 -> 2 followed by an unknown pool
+            swap(10, 1, 4, [50, 0, 0, 45]),
+            swap(13, 1, 5, [45, 0, 0, 40]),
+            // Cycle through an unknown pool is not followed
+            swap(10, 2, 6, [100, 0, 0, 90]),
+            swap(13, 2, 7, [90, 0, 0, 80]),
+            swap(12, 2, 8, [0, 80, 110, 0]),
+        ];
+        let reports = analyser.analyse_logs(&logs);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].swap_transactions, 3);
+        assert_eq!(reports[0].arbitrages.len(), 1);
+        let arbitrage = &reports[0].arbitrages[0];
+        assert_eq!(arbitrage.transaction_hash, H256::from_low_u64_be(0));
+        assert_eq!(arbitrage.pools, vec![address(10), address(11), address(12)]);
+        assert_eq!(
+            arbitrage.path,
+            vec![address(1), address(2), address(3), address(1)]
+        );
+        assert_eq!(arbitrage.profit(), I256::from(10));
+    }
+}
@@ -0,0 +1,396 @@
+use ethers::{
+    signers::{LocalWallet, Signer},
+    types::{transaction::eip2718::TypedTransaction, BlockNumber, Bytes, H160, H256, U256, U64},
+    utils::{hash_message, keccak256},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+
+pub const FLASHBOTS_RELAY_URL: &str = "https://relay.flashbots.net";
+pub const FLASHBOTS_SIGNATURE_HEADER: &str = "X-Flashbots-Signature";
+
+#[derive(Debug)]
+pub enum BundleError {
+    Signing(String),
+    Http(String),
+    Relay { code: i64, message: String },
+    Decode(String),
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleError::Signing(err) => write!(f, "could not sign bundle: {}", err),
+            BundleError::Http(err) => write!(f, "relay request failed: {}", err),
+            BundleError::Relay { code, message } => {
+                write!(f, "relay returned error {}: {}", code, message)
+            }
+            BundleError::Decode(err) => write!(f, "could not decode relay response: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BundleError {}
+
+/// Signed transactions to be included, in order, in `block_number`.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bundle {
+    pub txs: Vec<Bytes>,
+    pub block_number: U64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_timestamp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_timestamp: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reverting_tx_hashes: Vec<H256>,
+}
+
+impl Bundle {
+    pub fn tx_hashes(&self) -> Vec<H256> {
+        self.txs
+            .iter()
+            .map(|tx| H256::from(keccak256(tx)))
+            .collect()
+    }
+}
+
+pub struct BundleBuilder {
+    bundle: Bundle,
+}
+
+impl BundleBuilder {
+    pub fn new(target_block: u64) -> Self {
+        BundleBuilder {
+            bundle: Bundle {
+                block_number: target_block.into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Appends an already signed, RLP encoded transaction.
+    pub fn push_raw(mut self, raw: Bytes) -> Self {
+        self.bundle.txs.push(raw);
+        self
+    }
+
+    /// Signs `tx` offline with `wallet` and appends it. Nonce, gas, fees and chain id must
+    /// already be set since nothing is read from a node.
+    pub fn push_signed(
+        self,
+        tx: &TypedTransaction,
+        wallet: &LocalWallet,
+    ) -> Result<Self, BundleError> {
+        let mut tx = tx.clone();
+        if tx.chain_id().is_none() {
+            tx.set_chain_id(wallet.chain_id());
+        }
+        let signature = wallet
+            .sign_transaction_sync(&tx)
+            .map_err(|e| BundleError::Signing(e.to_string()))?;
+        Ok(self.push_raw(tx.rlp_signed(&signature)))
+    }
+
+    pub fn min_timestamp(mut self, timestamp: u64) -> Self {
+        self.bundle.min_timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn max_timestamp(mut self, timestamp: u64) -> Self {
+        self.bundle.max_timestamp = Some(timestamp);
+        self
+    }
+
+    /// Lets the transaction with `hash` revert without invalidating the whole bundle.
+    pub fn allow_revert(mut self, hash: H256) -> Self {
+        self.bundle.reverting_tx_hashes.push(hash);
+        self
+    }
+
+    pub fn build(self) -> Bundle {
+        self.bundle
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallBundleTxResult {
+    pub tx_hash: H256,
+    pub gas_used: u64,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub revert: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallBundleResponse {
+    pub bundle_hash: H256,
+    pub coinbase_diff: U256,
+    pub total_gas_used: u64,
+    pub state_block_number: u64,
+    pub results: Vec<CallBundleTxResult>,
+}
+
+impl CallBundleResponse {
+    pub fn reverted(&self) -> Vec<&CallBundleTxResult> {
+        self.results
+            .iter()
+            .filter(|r| r.error.is_some() || r.revert.is_some())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendBundleResponse {
+    pub bundle_hash: H256,
+}
+
+#[derive(Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcError>,
+}
+
+/// Client for a Flashbots compatible relay. Requests are authenticated with `signer`, which
+/// only identifies the searcher and does not need to hold funds.
+pub struct BundleRelay {
+    pub url: String,
+    signer: LocalWallet,
+    client: reqwest::Client,
+}
+
+impl BundleRelay {
+    pub fn new(url: &str, signer: LocalWallet) -> Self {
+        BundleRelay {
+            url: url.to_string(),
+            signer,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn flashbots(signer: LocalWallet) -> Self {
+        Self::new(FLASHBOTS_RELAY_URL, signer)
+    }
+
+    pub fn signer_address(&self) -> H160 {
+        self.signer.address()
+    }
+
+    /// Value of the `X-Flashbots-Signature` header: the signer address and its EIP-191
+    /// signature of the hex encoded keccak256 hash of `body`.
+    pub fn sign_payload(&self, body: &str) -> Result<String, BundleError> {
+        let digest = format!("{:?}", H256::from(keccak256(body.as_bytes())));
+        let signature = self
+            .signer
+            .sign_hash(hash_message(digest))
+            .map_err(|e| BundleError::Signing(e.to_string()))?;
+        Ok(format!("{:?}:0x{}", self.signer.address(), signature))
+    }
+
+    async fn request<R: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<R, BundleError> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": [params],
+        })
+        .to_string();
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .header(FLASHBOTS_SIGNATURE_HEADER, self.sign_payload(&body)?)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| BundleError::Http(e.to_string()))?;
+        let text = response
+            .text()
+            .await
+            .map_err(|e| BundleError::Http(e.to_string()))?;
+        let response: RpcResponse<R> = serde_json::from_str(&text)
+            .map_err(|e| BundleError::Decode(format!("{}: {}", e, text)))?;
+        match (response.result, response.error) {
+            (_, Some(err)) => Err(BundleError::Relay {
+                code: err.code,
+                message: err.message,
+            }),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(BundleError::Decode(text)),
+        }
+    }
+
+    /// Simulates `bundle` on top of `state_block` with `eth_callBundle`.
+    pub async fn call_bundle(
+        &self,
+        bundle: &Bundle,
+        state_block: BlockNumber,
+    ) -> Result<CallBundleResponse, BundleError> {
+        let params = json!({
+            "txs": bundle.txs,
+            "blockNumber": bundle.block_number,
+            "stateBlockNumber": state_block,
+        });
+        self.request("eth_callBundle", params).await
+    }
+
+    /// Submits `bundle` for inclusion in its target block with `eth_sendBundle`.
+    pub async fn send_bundle(&self, bundle: &Bundle) -> Result<SendBundleResponse, BundleError> {
+        let params =
+            serde_json::to_value(bundle).map_err(|e| BundleError::Decode(e.to_string()))?;
+        self.request("eth_sendBundle", params).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Eip1559TransactionRequest, Signature};
+    use std::str::FromStr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::oneshot,
+    };
+
+    const PRIVATE_KEY: &str = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+
+    struct RecordedRequest {
+        signature: String,
+        body: String,
+    }
+
+    /// Serves a single HTTP request with `response` as the JSON body and records what it got.
+    async fn mock_relay(response: Value) -> (String, oneshot::Receiver<RecordedRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buffer = [0u8; 4096];
+            let (head, body) = loop {
+                let n = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|l| {
+                            l.to_lowercase()
+                                .strip_prefix("content-length: ")
+                                .map(str::to_string)
+                        })
+                        .and_then(|l| l.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break (head.to_string(), body.to_string());
+                    }
+                }
+            };
+            let signature = head
+                .lines()
+                .find_map(|l| l.strip_prefix("x-flashbots-signature: "))
+                .unwrap_or_default()
+                .to_string();
+            let payload = response.to_string();
+            let reply = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                payload.len(),
+                payload
+            );
+            socket.write_all(reply.as_bytes()).await.unwrap();
+            sender.send(RecordedRequest { signature, body }).ok();
+        });
+        (url, receiver)
+    }
+
+    fn signed_bundle(wallet: &LocalWallet) -> Bundle {
+        let tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .to(H160::from_low_u64_be(1))
+            .value(1)
+            .nonce(0)
+            .gas(21_000)
+            .max_fee_per_gas(100)
+            .max_priority_fee_per_gas(2)
+            .chain_id(1)
+            .into();
+        BundleBuilder::new(17_000_000)
+            .push_signed(&tx, wallet)
+            .unwrap()
+            .max_timestamp(1_700_000_000)
+            .build()
+    }
+
+    #[test]
+    fn test_bundle_serialization() {
+        let wallet = LocalWallet::from_str(PRIVATE_KEY).unwrap();
+        let bundle = signed_bundle(&wallet);
+        let value = serde_json::to_value(&bundle).unwrap();
+        assert_eq!(value["blockNumber"], "0x1036640");
+        assert_eq!(value["maxTimestamp"], 1_700_000_000);
+        assert!(value.get("minTimestamp").is_none());
+        assert!(value.get("revertingTxHashes").is_none());
+        assert_eq!(bundle.tx_hashes().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_bundle_to_mock_relay() {
+        let wallet = LocalWallet::from_str(PRIVATE_KEY).unwrap();
+        let bundle_hash = H256::from_low_u64_be(42);
+        let (url, request) = mock_relay(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": { "bundleHash": bundle_hash },
+        }))
+        .await;
+        let relay = BundleRelay::new(&url, wallet.clone());
+        let bundle = signed_bundle(&wallet);
+        let response = relay.send_bundle(&bundle).await.unwrap();
+        assert_eq!(response.bundle_hash, bundle_hash);
+
+        let request = request.await.unwrap();
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["method"], "eth_sendBundle");
+        assert_eq!(body["params"][0]["txs"][0], json!(bundle.txs[0]));
+        let (address, signature) = request.signature.split_once(':').unwrap();
+        assert_eq!(H160::from_str(address).unwrap(), wallet.address());
+        let digest = format!("{:?}", H256::from(keccak256(request.body.as_bytes())));
+        let signature = Signature::from_str(signature).unwrap();
+        assert_eq!(signature.recover(digest).unwrap(), wallet.address());
+    }
+
+    #[tokio::test]
+    async fn test_call_bundle_relay_error() {
+        let wallet = LocalWallet::from_str(PRIVATE_KEY).unwrap();
+        let (url, _) = mock_relay(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": { "code": -32000, "message": "bundle reverted" },
+        }))
+        .await;
+        let relay = BundleRelay::new(&url, wallet.clone());
+        let result = relay
+            .call_bundle(&signed_bundle(&wallet), BlockNumber::Latest)
+            .await;
+        assert!(matches!(
+            result,
+            Err(BundleError::Relay { code: -32000, .. })
+        ));
+    }
+}
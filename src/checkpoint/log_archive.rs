@@ -0,0 +1,553 @@
+use super::{storage::StorageError, Checkpoint};
+use crate::metrics::metrics;
+use ethers::{
+    providers::Middleware,
+    types::{BlockNumber, Filter, Log, ValueOrArray, H160, H256, U64},
+};
+use futures::future;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    sync::Arc,
+    time::Instant,
+};
+use tracing::{debug, debug_span, field, info_span, warn, Instrument};
+
+/// Rounds of retries of failed batches, each split in halves since the usual cause is a
+/// response too large for the node.
+const RETRY_ROUNDS: usize = 4;
+
+/// Largest address set fetched with an address filter. Larger sets are fetched for every
+/// address at once, one `eth_getLogs` per range costing less than a filter split in chunks.
+const MAX_FILTERED_ADDRESSES: usize = 500;
+
+/// Blocks behind the head whose logs are archived. Logs of more recent blocks can still be
+/// removed by a reorg, so they are fetched on every call and never archived.
+pub const CONFIRMATIONS: u64 = 64;
+
+#[derive(Debug)]
+pub enum LogArchiveError {
+    Storage(StorageError),
+    /// Block ranges still not fetched after every retry. What was fetched is archived, so the
+    /// next call only requests these ranges.
+    Missing(Vec<(u64, u64)>),
+    /// The head block, which bounds the archived blocks, could not be read.
+    Head(String),
+    ZeroStep,
+}
+
+impl fmt::Display for LogArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogArchiveError::Storage(err) => write!(f, "{}", err),
+            LogArchiveError::Missing(ranges) => {
+                write!(f, "could not fetch the logs of blocks {:?}", ranges)
+            }
+            LogArchiveError::Head(err) => write!(f, "could not get the head block: {}", err),
+            LogArchiveError::ZeroStep => write!(f, "logs are fetched in steps of at least a block"),
+        }
+    }
+}
+
+impl std::error::Error for LogArchiveError {}
+
+impl From<StorageError> for LogArchiveError {
+    fn from(err: StorageError) -> Self {
+        LogArchiveError::Storage(err)
+    }
+}
+
+/// `start..=end` split in two, or as is for a single block.
+fn split_range(start: u64, end: u64) -> Vec<(u64, u64)> {
+    if start == end {
+        return vec![(start, end)];
+    }
+    let middle = start + (end - start) / 2;
+    vec![(start, middle), (middle + 1, end)]
+}
+
+/// Adds `start..=end` to the sorted, non-overlapping `ranges`, merging adjacent ranges.
+fn merge_range(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    ranges.push((start, end));
+    ranges.sort();
+    let mut merged: Vec<(u64, u64)> = vec![];
+    for (range_start, range_end) in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if range_start <= last.1.saturating_add(1) => last.1 = last.1.max(range_end),
+            _ => merged.push((range_start, range_end)),
+        }
+    }
+    *ranges = merged;
+}
+
+/// Block ranges within `start..=end` that are in none of the sorted `ranges`.
+fn missing_in(ranges: &[(u64, u64)], start: u64, end: u64) -> Vec<(u64, u64)> {
+    let mut missing = vec![];
+    let mut from = start;
+    for &(range_start, range_end) in ranges {
+        if range_end < from {
+            continue;
+        }
+        if range_start > end {
+            break;
+        }
+        if range_start > from {
+            missing.push((from, range_start - 1));
+        }
+        from = from.max(range_end.saturating_add(1));
+        if from > end {
+            return missing;
+        }
+    }
+    if from <= end {
+        missing.push((from, end));
+    }
+    missing
+}
+
+/// Blocks `from..=to` to fetch for `addresses`, or for every address when `None`. Only
+/// batches below the confirmation depth are `archived`.
+#[derive(Debug, Clone)]
+struct Batch {
+    from: u64,
+    to: u64,
+    addresses: Option<Vec<H160>>,
+    archived: bool,
+}
+
+impl Batch {
+    /// Batches of at most `step` blocks covering `start..=end`.
+    fn split(
+        start: u64,
+        end: u64,
+        step: u64,
+        addresses: Option<Vec<H160>>,
+        archived: bool,
+    ) -> Vec<Batch> {
+        let mut batches = vec![];
+        let mut from = start;
+        while from <= end {
+            let to = from.saturating_add(step - 1).min(end);
+            batches.push(Batch {
+                from,
+                to,
+                addresses: addresses.clone(),
+                archived,
+            });
+            if to == u64::MAX {
+                break;
+            }
+            from = to + 1;
+        }
+        batches
+    }
+}
+
+/// Logs of a single event, together with the block ranges fetched so far. Ranges fetched for
+/// every address are kept apart from ranges fetched for single addresses, so the archive
+/// serves any address set and a set that grows only fetches for the new addresses. Ranges are
+/// inclusive, sorted and never overlap.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogArchive {
+    pub signature: H256,
+    /// Ranges fetched for every address.
+    pub ranges: Vec<(u64, u64)>,
+    /// Ranges fetched for single addresses, on top of `ranges`.
+    pub address_ranges: BTreeMap<H160, Vec<(u64, u64)>>,
+    pub logs: Vec<Log>,
+}
+
+impl LogArchive {
+    pub fn new(signature: H256) -> Self {
+        LogArchive {
+            signature,
+            ranges: vec![],
+            address_ranges: BTreeMap::new(),
+            logs: vec![],
+        }
+    }
+
+    /// One archive per signature.
+    pub fn id(signature: H256) -> String {
+        format!("logs.{:?}", signature)
+    }
+
+    /// Block ranges within `start..=end` not fetched for `address`.
+    fn missing_for(&self, address: &H160, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut ranges = self.ranges.clone();
+        for &(range_start, range_end) in self.address_ranges.get(address).into_iter().flatten() {
+            merge_range(&mut ranges, range_start, range_end);
+        }
+        missing_in(&ranges, start, end)
+    }
+
+    /// Block ranges within `start..=end` not fetched for some address of `addresses`, or for
+    /// every address when empty.
+    pub fn missing_ranges(&self, addresses: &[H160], start: u64, end: u64) -> Vec<(u64, u64)> {
+        if addresses.is_empty() {
+            return missing_in(&self.ranges, start, end);
+        }
+        let mut missing = vec![];
+        for address in addresses {
+            for (missing_start, missing_end) in self.missing_for(address, start, end) {
+                merge_range(&mut missing, missing_start, missing_end);
+            }
+        }
+        missing
+    }
+
+    /// Records `logs` as the complete set of matching logs of `addresses`, or of every address
+    /// when `None`, for `start..=end`. Logs removed by a reorg are dropped.
+    pub fn insert(&mut self, start: u64, end: u64, addresses: Option<&[H160]>, logs: Vec<Log>) {
+        self.logs.extend(logs.into_iter().filter(|log| {
+            log.block_number.is_some() && log.log_index.is_some() && log.removed != Some(true)
+        }));
+        self.logs
+            .sort_by_key(|log| (log.block_number, log.log_index));
+        self.logs
+            .dedup_by_key(|log| (log.block_number, log.log_index));
+        match addresses {
+            None => merge_range(&mut self.ranges, start, end),
+            Some(addresses) => {
+                for address in addresses {
+                    merge_range(self.address_ranges.entry(*address).or_default(), start, end);
+                }
+            }
+        }
+    }
+
+    /// Archived logs of `addresses`, or of every address when empty, emitted within
+    /// `start..=end`, in chain order.
+    pub fn logs_in_range(&self, addresses: &[H160], start: u64, end: u64) -> Vec<Log> {
+        let addresses: HashSet<&H160> = addresses.iter().collect();
+        self.logs
+            .iter()
+            .filter(|log| {
+                let block = log.block_number.unwrap().as_u64();
+                block >= start
+                    && block <= end
+                    && (addresses.is_empty() || addresses.contains(&log.address))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Last block fetched for some address.
+    fn last_fetched(&self) -> u64 {
+        self.address_ranges
+            .values()
+            .chain([&self.ranges])
+            .filter_map(|ranges| ranges.last().map(|r| r.1))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Batches fetching the blocks of `start..=end` missing for `addresses`, each filtered by
+    /// the addresses missing some of its blocks.
+    fn missing_batches(&self, addresses: &[H160], start: u64, end: u64, step: u64) -> Vec<Batch> {
+        if addresses.is_empty() || addresses.len() > MAX_FILTERED_ADDRESSES {
+            return missing_in(&self.ranges, start, end)
+                .into_iter()
+                .flat_map(|(from, to)| Batch::split(from, to, step, None, true))
+                .collect();
+        }
+        let missing: Vec<(H160, Vec<(u64, u64)>)> = addresses
+            .iter()
+            .map(|address| (*address, self.missing_for(address, start, end)))
+            .filter(|(_, missing)| !missing.is_empty())
+            .collect();
+        let mut ranges = vec![];
+        for (_, address_missing) in &missing {
+            for &(from, to) in address_missing {
+                merge_range(&mut ranges, from, to);
+            }
+        }
+        let mut batches = vec![];
+        for (from, to) in ranges {
+            for mut batch in Batch::split(from, to, step, None, true) {
+                batch.addresses = Some(
+                    missing
+                        .iter()
+                        .filter(|(_, ranges)| {
+                            ranges.iter().any(|r| r.0 <= batch.to && r.1 >= batch.from)
+                        })
+                        .map(|(address, _)| *address)
+                        .collect(),
+                );
+                batches.push(batch);
+            }
+        }
+        batches
+    }
+
+    async fn fetch<M: Middleware>(
+        &self,
+        batch: &Batch,
+        middleware: Arc<M>,
+    ) -> Result<Vec<Log>, M::Error> {
+        let mut filter = Filter::new()
+            .topic0(ValueOrArray::Value(self.signature))
+            .from_block(BlockNumber::Number(U64([batch.from])))
+            .to_block(BlockNumber::Number(U64([batch.to])));
+        if let Some(addresses) = &batch.addresses {
+            filter = filter.address(addresses.clone());
+        }
+        let started = Instant::now();
+        let result = middleware.get_logs(&filter).await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok(logs) => debug!(logs = logs.len(), latency_ms, "fetched logs"),
+            Err(err) => warn!(%err, latency_ms, "failed to get logs"),
+        }
+        result
+    }
+
+    /// Logs of `signature` emitted by `addresses`, or by any address when empty, within
+    /// `start..=end`, read from the archive on disk. Only the ranges that were never fetched
+    /// for these addresses are requested from `middleware`, in batches of `step` blocks, and
+    /// the last `CONFIRMATIONS` blocks are always requested and never archived. Failed batches
+    /// are retried in smaller ones, and ranges that still fail are returned as
+    /// `LogArchiveError::Missing` rather than left out of the logs.
+    pub async fn get_logs<M: Middleware>(
+        signature: H256,
+        addresses: &[H160],
+        start: u64,
+        end: u64,
+        step: usize,
+        middleware: Arc<M>,
+    ) -> Result<Vec<Log>, LogArchiveError> {
+        if step == 0 {
+            return Err(LogArchiveError::ZeroStep);
+        }
+        let mut addresses = addresses.to_vec();
+        addresses.sort();
+        addresses.dedup();
+        let id = LogArchive::id(signature);
+        let span = info_span!(
+            "get_logs",
+            %id,
+            addresses = addresses.len(),
+            from_block = start,
+            to_block = end,
+            step,
+            batches = field::Empty,
+            failed = field::Empty,
+        );
+        let head = middleware
+            .get_block_number()
+            .await
+            .map_err(|err| LogArchiveError::Head(err.to_string()))?
+            .as_u64();
+        // Archived blocks are `start..=archived_end`, the blocks after are recent.
+        let archived_end = head.saturating_sub(CONFIRMATIONS).min(end);
+        let mut checkpoint = Checkpoint::<LogArchive>::load_data(&id)?
+            .unwrap_or_else(|| Checkpoint::new(0, LogArchive::new(signature), &id));
+        let mut batches = vec![];
+        if start <= archived_end {
+            batches = checkpoint
+                .data
+                .missing_batches(&addresses, start, archived_end, step as u64);
+        }
+        let recent_start = start.max(archived_end.saturating_add(1));
+        if recent_start <= end {
+            let filter = (!addresses.is_empty() && addresses.len() <= MAX_FILTERED_ADDRESSES)
+                .then(|| addresses.clone());
+            batches.extend(Batch::split(recent_start, end, step as u64, filter, false));
+        }
+        let archive_changed = batches.iter().any(|batch| batch.archived);
+        span.record("batches", batches.len());
+
+        let mut recent = vec![];
+        for round in 0..=RETRY_ROUNDS {
+            if batches.is_empty() {
+                break;
+            }
+            if round > 0 {
+                span.in_scope(|| warn!(round, batches = batches.len(), "retrying failed batches"));
+                metrics().add_batch_retries(batches.len());
+            }
+            let futures = batches.iter().map(|batch| {
+                checkpoint
+                    .data
+                    .fetch(batch, middleware.clone())
+                    .instrument(debug_span!(
+                        "log_batch",
+                        from_block = batch.from,
+                        to_block = batch.to
+                    ))
+            });
+            let results = future::join_all(futures).instrument(span.clone()).await;
+            let mut failed = vec![];
+            for (batch, result) in batches.into_iter().zip(results) {
+                match result {
+                    Ok(logs) if batch.archived => checkpoint.data.insert(
+                        batch.from,
+                        batch.to,
+                        batch.addresses.as_deref(),
+                        logs,
+                    ),
+                    Ok(logs) => recent.extend(logs),
+                    Err(_) => failed.extend(split_range(batch.from, batch.to).into_iter().map(
+                        |(from, to)| Batch {
+                            from,
+                            to,
+                            ..batch.clone()
+                        },
+                    )),
+                }
+            }
+            batches = failed;
+        }
+        if archive_changed {
+            checkpoint.last_block = checkpoint.data.last_fetched();
+            checkpoint.save_data()?;
+        }
+        let mut missing = vec![];
+        if start <= archived_end {
+            missing = checkpoint
+                .data
+                .missing_ranges(&addresses, start, archived_end);
+        }
+        for batch in &batches {
+            merge_range(&mut missing, batch.from, batch.to);
+        }
+        span.record("failed", missing.len());
+        if !missing.is_empty() {
+            return Err(LogArchiveError::Missing(missing));
+        }
+        let mut logs = vec![];
+        if start <= archived_end {
+            logs = checkpoint
+                .data
+                .logs_in_range(&addresses, start, archived_end);
+        }
+        let wanted: HashSet<&H160> = addresses.iter().collect();
+        logs.extend(recent.into_iter().filter(|log| {
+            log.removed != Some(true) && (wanted.is_empty() || wanted.contains(&log.address))
+        }));
+        logs.sort_by_key(|log| (log.block_number, log.log_index));
+        Ok(logs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::U256;
+
+    fn log(address: u64, block: u64, index: u64) -> Log {
+        Log {
+            address: H160::from_low_u64_be(address),
+            block_number: Some(U64::from(block)),
+            log_index: Some(U256::from(index)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_missing_ranges() {
+        let mut archive = LogArchive::new(H256::zero());
+        assert_eq!(archive.missing_ranges(&[], 10, 20), vec![(10, 20)]);
+
+        archive.insert(12, 14, None, vec![]);
+        archive.insert(18, 30, None, vec![]);
+        assert_eq!(
+            archive.missing_ranges(&[], 10, 20),
+            vec![(10, 11), (15, 17)]
+        );
+        assert_eq!(archive.missing_ranges(&[], 12, 14), vec![]);
+        assert_eq!(
+            archive.missing_ranges(&[], 13, 40),
+            vec![(15, 17), (31, 40)]
+        );
+
+        archive.insert(15, 17, None, vec![]);
+        assert_eq!(archive.ranges, vec![(12, 30)]);
+        assert_eq!(
+            archive.missing_ranges(&[], 0, 100),
+            vec![(0, 11), (31, 100)]
+        );
+    }
+
+    #[test]
+    fn test_address_ranges() {
+        let (a, b) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+        let mut archive = LogArchive::new(H256::zero());
+        archive.insert(10, 20, None, vec![]);
+        archive.insert(21, 30, Some(&[a]), vec![]);
+        assert_eq!(archive.missing_ranges(&[a], 0, 40), vec![(0, 9), (31, 40)]);
+        // A new address only misses the blocks fetched for single addresses.
+        assert_eq!(archive.missing_ranges(&[a, b], 10, 40), vec![(21, 40)]);
+        assert_eq!(archive.missing_ranges(&[], 10, 30), vec![(21, 30)]);
+
+        let batches = archive.missing_batches(&[a, b], 10, 40, 5);
+        let spans: Vec<(u64, u64, Vec<H160>)> = batches
+            .into_iter()
+            .map(|batch| (batch.from, batch.to, batch.addresses.unwrap()))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (21, 25, vec![b]),
+                (26, 30, vec![b]),
+                (31, 35, vec![a, b]),
+                (36, 40, vec![a, b]),
+            ]
+        );
+        let many: Vec<H160> = (0..=MAX_FILTERED_ADDRESSES as u64)
+            .map(H160::from_low_u64_be)
+            .collect();
+        let batches = archive.missing_batches(&many, 0, 40, 100);
+        assert!(batches.iter().all(|batch| batch.addresses.is_none()));
+        assert_eq!(batches.len(), 2);
+    }
+
+    #[test]
+    fn test_split_range() {
+        assert_eq!(split_range(10, 10), vec![(10, 10)]);
+        assert_eq!(split_range(10, 11), vec![(10, 10), (11, 11)]);
+        assert_eq!(split_range(10, 20), vec![(10, 15), (16, 20)]);
+        let batches = Batch::split(10, 20, 4, None, true);
+        let spans: Vec<(u64, u64)> = batches.iter().map(|b| (b.from, b.to)).collect();
+        assert_eq!(spans, vec![(10, 13), (14, 17), (18, 20)]);
+    }
+
+    #[test]
+    fn test_insert_and_logs_in_range() {
+        let mut archive = LogArchive::new(H256::zero());
+        let removed = Log {
+            removed: Some(true),
+            ..log(1, 7, 0)
+        };
+        archive.insert(
+            5,
+            10,
+            None,
+            vec![log(1, 9, 0), log(2, 6, 1), log(1, 6, 0), removed],
+        );
+        // Overlapping fetches must not duplicate logs.
+        archive.insert(10, 12, None, vec![log(1, 9, 0), log(2, 11, 3)]);
+
+        let blocks = |logs: Vec<Log>| -> Vec<(u64, u64)> {
+            logs.iter()
+                .map(|l| {
+                    (
+                        l.block_number.unwrap().as_u64(),
+                        l.log_index.unwrap().as_u64(),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(
+            blocks(archive.logs_in_range(&[], 0, 100)),
+            vec![(6, 0), (6, 1), (9, 0), (11, 3)]
+        );
+        assert_eq!(
+            blocks(archive.logs_in_range(&[H160::from_low_u64_be(2)], 0, 100)),
+            vec![(6, 1), (11, 3)]
+        );
+        assert_eq!(archive.logs_in_range(&[], 7, 10).len(), 1);
+        assert_eq!(archive.ranges, vec![(5, 12)]);
+    }
+}
@@ -0,0 +1,555 @@
+use super::{
+    log_archive::{LogArchive, LogArchiveError},
+    migrations::Versioned,
+    storage::StorageError,
+    Checkpoint,
+};
+use crate::{
+    amm::{
+        uniswap_v2::{
+            factory::{events::pair_created::PAIR_CREATED_EVENT_SIGNATURE, UniswapV2Factory},
+            pool::{
+                events::sync::SYNC_EVENT_SIGNATURE,
+                pool_data_batch_request::get_uniswap_v2_pool_data_concurrent, UniswapV2Pool,
+            },
+        },
+        valuation::Valuation,
+        weth_value::{get_weth_value_in_pool_concurrent, PricingFactory},
+        Protocol,
+    },
+    contract::{PairCreatedFilter, SyncFilter},
+    eth_provider::EthProvider,
+    metrics::metrics,
+};
+use ethers::{
+    abi::RawLog,
+    prelude::EthEvent,
+    types::{BlockNumber, Log, H160, U256},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, time::Instant};
+use tracing::{info, instrument, warn};
+
+pub const MARKET_STATE_ID: &str = "market_state";
+
+/// Where a pool was discovered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolOrigin {
+    pub protocol: Protocol,
+    pub factory: H160,
+}
+
+/// Pools from every tracked factory, kept at a single block so paths can cross DEXes.
+///
+/// `sync`, `discover`, `export` and `serve` work on it with `--market`. Without it they, like the
+/// other commands, work on the per-factory `uniswap_v2_pools.*` checkpoints.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MarketState {
+    pub factories: Vec<UniswapV2Factory>,
+    /// Protocol of each tracked factory, recorded in the origin of the pools it creates.
+    pub protocols: HashMap<H160, Protocol>,
+    pub pools: Vec<UniswapV2Pool>,
+    pub origins: HashMap<H160, PoolOrigin>,
+}
+
+impl Versioned for MarketState {
+    const VERSION: u32 = 1;
+
+    fn migrate(from: u32, _data: &str) -> Result<String, String> {
+        Err(format!("no migration from version {}", from))
+    }
+}
+
+#[derive(Debug)]
+pub enum MarketStateError {
+    Storage(StorageError),
+    /// Pools are synced from `Sync` and `PairCreated` logs, which only Uniswap V2 factories
+    /// and their forks emit.
+    UnsupportedProtocol {
+        factory: H160,
+        protocol: Protocol,
+    },
+}
+
+impl fmt::Display for MarketStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MarketStateError::Storage(err) => write!(f, "{}", err),
+            MarketStateError::UnsupportedProtocol { factory, protocol } => write!(
+                f,
+                "factory {:?} is {:?}, only Uniswap V2 factories can be tracked",
+                factory, protocol
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MarketStateError {}
+
+impl From<StorageError> for MarketStateError {
+    fn from(err: StorageError) -> Self {
+        MarketStateError::Storage(err)
+    }
+}
+
+fn check_protocol(protocol: Protocol, factory: H160) -> Result<(), MarketStateError> {
+    if protocol != Protocol::UniswapV2 {
+        return Err(MarketStateError::UnsupportedProtocol { factory, protocol });
+    }
+    Ok(())
+}
+
+impl MarketState {
+    pub fn tracks_factory(&self, factory: &H160) -> bool {
+        self.factories.iter().any(|f| &f.address == factory)
+    }
+
+    /// Adds pools discovered through `origin`, skipping pools that are already tracked.
+    pub fn add_pools(&mut self, origin: PoolOrigin, pools: Vec<UniswapV2Pool>) {
+        for pool in pools {
+            if self.origins.contains_key(&pool.address) {
+                continue;
+            }
+            self.origins.insert(pool.address, origin);
+            self.pools.push(pool);
+        }
+    }
+
+    /// Starts tracking `factory`, whose pools are of `protocol`.
+    pub fn add_factory(
+        &mut self,
+        protocol: Protocol,
+        factory: UniswapV2Factory,
+    ) -> Result<(), MarketStateError> {
+        check_protocol(protocol, factory.address)?;
+        self.protocols.insert(factory.address, protocol);
+        self.factories.push(factory);
+        Ok(())
+    }
+
+    /// Origin of the pools created by the tracked `factory`.
+    pub fn factory_origin(&self, factory: H160) -> PoolOrigin {
+        PoolOrigin {
+            protocol: self.protocols[&factory],
+            factory,
+        }
+    }
+
+    pub fn origin(&self, pool: &H160) -> Option<&PoolOrigin> {
+        self.origins.get(pool)
+    }
+
+    pub fn pools_from<'a>(&'a self, factory: &'a H160) -> impl Iterator<Item = &'a UniswapV2Pool> {
+        self.pools
+            .iter()
+            .filter(move |p| self.origins.get(&p.address).map(|o| &o.factory) == Some(factory))
+    }
+
+    /// Applies `Sync` logs, in chain order, to the tracked pools. Other logs are ignored.
+    pub fn apply_sync_logs(&mut self, logs: &[Log]) {
+        let index: HashMap<H160, usize> = self
+            .pools
+            .iter()
+            .enumerate()
+            .map(|(idx, pool)| (pool.address, idx))
+            .collect();
+        for log in logs {
+            if log.topics.first() != Some(&SYNC_EVENT_SIGNATURE) {
+                continue;
+            }
+            if let Some(&idx) = index.get(&log.address) {
+                if let Ok(event) = SyncFilter::decode_log(&RawLog::from(log.clone())) {
+                    self.pools[idx].reserve_0 = event.reserve_0;
+                    self.pools[idx].reserve_1 = event.reserve_1;
+                }
+            }
+        }
+    }
+
+    /// Pairs announced in `logs` by tracked factories that are not tracked yet, per factory.
+    pub fn new_pairs(&self, logs: &[Log]) -> HashMap<H160, Vec<H160>> {
+        let mut pairs: HashMap<H160, Vec<H160>> = HashMap::new();
+        for log in logs {
+            if log.topics.first() != Some(&PAIR_CREATED_EVENT_SIGNATURE)
+                || !self.tracks_factory(&log.address)
+            {
+                continue;
+            }
+            if let Ok(event) = PairCreatedFilter::decode_log(&RawLog::from(log.clone())) {
+                if !self.origins.contains_key(&event.pair) {
+                    pairs.entry(log.address).or_default().push(event.pair);
+                }
+            }
+        }
+        pairs
+    }
+
+    pub fn token_to_pool_map(&self) -> HashMap<(&H160, &H160), &UniswapV2Pool> {
+        let mut map = HashMap::new();
+        for pool in &self.pools {
+            map.insert((&pool.token_a, &pool.token_b), pool);
+            map.insert((&pool.token_b, &pool.token_a), pool);
+        }
+        map
+    }
+}
+
+/// What a pool checkpoint is synced from: the logs of one factory, or the market state of
+/// every tracked factory.
+pub enum PoolSource {
+    Factory(UniswapV2Factory),
+    Market(Checkpoint<MarketState>),
+}
+
+impl PoolSource {
+    /// Syncs `checkpoint`, read from this source, to the current block. On error it stays at
+    /// its `last_block`.
+    pub async fn sync(
+        &mut self,
+        checkpoint: &mut Checkpoint<Vec<UniswapV2Pool>>,
+        provider: &EthProvider,
+        step: usize,
+    ) -> Result<(), LogArchiveError> {
+        match self {
+            PoolSource::Factory(factory) => checkpoint.sync(provider, factory, step).await,
+            PoolSource::Market(market) => {
+                let result = market.sync(provider, step).await;
+                *checkpoint = market.pools_checkpoint();
+                result
+            }
+        }
+    }
+}
+
+impl Checkpoint<MarketState> {
+    /// Loads the market state and brings it to the current block. Factories not tracked yet
+    /// are snapshotted at the state's block first, so every pool is read at the same block.
+    pub async fn get_market_state(
+        provider: &EthProvider,
+        factories: Vec<(Protocol, UniswapV2Factory)>,
+        step: usize,
+    ) -> Result<Self, MarketStateError> {
+        for (protocol, factory) in &factories {
+            check_protocol(*protocol, factory.address)?;
+        }
+        let current_block = provider.get_block_number().await;
+        metrics().set_head_block(current_block);
+        let mut checkpoint = Self::load_data(MARKET_STATE_ID)?
+            .unwrap_or_else(|| Self::new(current_block, MarketState::default(), MARKET_STATE_ID));
+        for (protocol, factory) in factories {
+            if !checkpoint.data.tracks_factory(&factory.address) {
+                checkpoint
+                    .add_factory(provider, protocol, factory, step)
+                    .await?;
+            }
+        }
+        if let Err(err) = checkpoint.update(provider, step, current_block).await {
+            warn!(%err, last_block = checkpoint.last_block, "could not sync market state");
+        }
+        metrics().set_checkpoint(checkpoint.last_block, checkpoint.data.pools.len());
+        checkpoint.save_data()?;
+        Ok(checkpoint)
+    }
+
+    /// The pools of every factory as a pool checkpoint at the same block, for the commands and
+    /// services that work on a list of pools. It is a view of this state and never saved.
+    pub fn pools_checkpoint(&self) -> Checkpoint<Vec<UniswapV2Pool>> {
+        Checkpoint::new(self.last_block, self.data.pools.clone(), MARKET_STATE_ID)
+    }
+
+    #[instrument(skip_all, fields(factory = ?factory.address, block = self.last_block))]
+    async fn add_factory(
+        &mut self,
+        provider: &EthProvider,
+        protocol: Protocol,
+        factory: UniswapV2Factory,
+        step: usize,
+    ) -> Result<(), MarketStateError> {
+        check_protocol(protocol, factory.address)?;
+        let block = BlockNumber::Number(self.last_block.into());
+        let pairs = factory
+            .get_pair_addresses_from_factory(
+                0,
+                factory.all_pairs_length(provider.http.clone(), block).await as usize,
+                step,
+                provider.http.clone(),
+                block,
+            )
+            .await;
+        let pools = get_uniswap_v2_pool_data_concurrent(
+            &pairs,
+            provider.http.clone(),
+            factory.fee as u32,
+            step,
+            block,
+        )
+        .await;
+        let address = factory.address;
+        self.data.add_factory(protocol, factory)?;
+        self.data
+            .add_pools(self.data.factory_origin(address), pools);
+        Ok(())
+    }
+
+    /// Syncs every tracked pool and discovers new pairs of every factory with one pass over the
+    /// `Sync` and `PairCreated` logs. On error the state stays at its `last_block`.
+    #[instrument(
+        name = "sync",
+        skip_all,
+        fields(id = %self.id, from_block = self.last_block + 1, to_block = current_block)
+    )]
+    async fn update(
+        &mut self,
+        provider: &EthProvider,
+        step: usize,
+        current_block: u64,
+    ) -> Result<(), LogArchiveError> {
+        if current_block <= self.last_block {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let (start, http) = (self.last_block + 1, provider.http.clone());
+        // An empty address set would match every emitter on chain.
+        let pools: Vec<H160> = self.data.pools.iter().map(|p| p.address).collect();
+        let sync_logs = if pools.is_empty() {
+            vec![]
+        } else {
+            LogArchive::get_logs(
+                SYNC_EVENT_SIGNATURE,
+                &pools,
+                start,
+                current_block,
+                step,
+                http.clone(),
+            )
+            .await?
+        };
+        let factories: Vec<H160> = self.data.factories.iter().map(|f| f.address).collect();
+        let pair_created_logs = if factories.is_empty() {
+            vec![]
+        } else {
+            LogArchive::get_logs(
+                PAIR_CREATED_EVENT_SIGNATURE,
+                &factories,
+                start,
+                current_block,
+                step,
+                http.clone(),
+            )
+            .await?
+        };
+        self.data.apply_sync_logs(&sync_logs);
+        metrics().observe_sync_logs(sync_logs.iter());
+
+        let block = BlockNumber::Number(current_block.into());
+        for (factory, pairs) in self.data.new_pairs(&pair_created_logs) {
+            let fee = self
+                .data
+                .factories
+                .iter()
+                .find(|f| f.address == factory)
+                .map(|f| f.fee as u32)
+                .expect("new pairs only come from tracked factories");
+            let pools =
+                get_uniswap_v2_pool_data_concurrent(&pairs, http.clone(), fee, step, block).await;
+            let origin = self.data.factory_origin(factory);
+            self.data.add_pools(origin, pools);
+        }
+        info!(
+            sync_events = sync_logs.len(),
+            pair_created_events = pair_created_logs.len(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "synced market state"
+        );
+        self.last_block = current_block;
+        Ok(())
+    }
+
+    /// Syncs to the current block. On error the state stays at its `last_block`.
+    pub async fn sync(
+        &mut self,
+        provider: &EthProvider,
+        step: usize,
+    ) -> Result<(), LogArchiveError> {
+        let current_block = provider.get_block_number().await;
+        metrics().set_head_block(current_block);
+        let result = self.update(provider, step, current_block).await;
+        metrics().set_checkpoint(self.last_block, self.data.pools.len());
+        self.save_data()?;
+        result
+    }
+
+    /// Values every pool in WETH as of `last_block`, looking for WETH pairs on every tracked
+    /// factory and then on `extra_factories`, which may be V2 or V3.
+    pub async fn sync_eth_value(
+        &mut self,
+        provider: &EthProvider,
+        weth: H160,
+        threshold: U256,
+        extra_factories: &[PricingFactory],
+        step: usize,
+    ) -> Result<(), StorageError> {
+        let mut factories: Vec<PricingFactory> = self
+            .data
+            .factories
+            .iter()
+            .map(|f| PricingFactory::uniswap_v2(f.address))
+            .collect();
+        factories.extend_from_slice(extra_factories);
+        let pool_addresses: Vec<H160> = self.data.pools.iter().map(|p| p.address).collect();
+        let weth_values = get_weth_value_in_pool_concurrent(
+            &pool_addresses,
+            &factories,
+            weth,
+            threshold,
+            step,
+            provider.http.clone(),
+            BlockNumber::Number(self.last_block.into()),
+        )
+        .await;
+        for pool in &mut self.data.pools {
+            pool.eth_value = *weth_values.get(&pool.address).unwrap_or(&U256::zero());
+        }
+        self.save_data()
+    }
+
+    /// Same as `sync_eth_value` but priced from the reserves of the tracked pools, without any
+    /// RPC calls.
+    pub fn sync_eth_value_from_pools(
+        &mut self,
+        weth: H160,
+        threshold: U256,
+    ) -> Result<(), StorageError> {
+        let pools = &mut self.data.pools;
+        Valuation::from_pools(pools, weth, threshold, None).apply_eth_values(pools);
+        self.save_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::storage::{Encoding, MemoryStorage};
+    use ethers::{
+        abi::{encode, Token},
+        types::{H256, U64},
+    };
+
+    fn address(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    fn pool(n: u64) -> UniswapV2Pool {
+        UniswapV2Pool::new(
+            address(n),
+            address(1),
+            18,
+            address(2),
+            18,
+            10,
+            20,
+            300,
+            U256::zero(),
+        )
+    }
+
+    fn origin(factory: u64) -> PoolOrigin {
+        PoolOrigin {
+            protocol: Protocol::UniswapV2,
+            factory: address(factory),
+        }
+    }
+
+    fn state() -> MarketState {
+        let mut state = MarketState::default();
+        for factory in [100, 200] {
+            state
+                .add_factory(
+                    Protocol::UniswapV2,
+                    UniswapV2Factory::new(address(factory), 300),
+                )
+                .unwrap();
+        }
+        state.add_pools(origin(100), vec![pool(10)]);
+        state.add_pools(origin(200), vec![pool(20), pool(10)]);
+        state
+    }
+
+    #[test]
+    fn test_origins() {
+        let state = state();
+        assert_eq!(state.pools.len(), 2);
+        assert_eq!(state.origin(&address(10)), Some(&origin(100)));
+        assert_eq!(state.origin(&address(20)), Some(&origin(200)));
+        let from_200: Vec<H160> = state.pools_from(&address(200)).map(|p| p.address).collect();
+        assert_eq!(from_200, vec![address(20)]);
+    }
+
+    #[test]
+    fn test_rejects_other_protocols() {
+        let mut state = state();
+        let err = state
+            .add_factory(
+                Protocol::UniswapV3,
+                UniswapV2Factory::new(address(300), 300),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            MarketStateError::UnsupportedProtocol {
+                protocol: Protocol::UniswapV3,
+                ..
+            }
+        ));
+        assert!(!state.tracks_factory(&address(300)));
+    }
+
+    #[test]
+    fn test_apply_logs() {
+        let mut state = state();
+        let sync = |pool: u64, block: u64, r0: u64, r1: u64| Log {
+            address: address(pool),
+            topics: vec![SYNC_EVENT_SIGNATURE],
+            data: encode(&[Token::Uint(r0.into()), Token::Uint(r1.into())]).into(),
+            block_number: Some(U64::from(block)),
+            ..Default::default()
+        };
+        state.apply_sync_logs(&[
+            sync(10, 1, 1, 2),
+            sync(20, 1, 3, 4),
+            sync(10, 2, 5, 6),
+            sync(99, 2, 7, 8),
+        ]);
+        assert_eq!((state.pools[0].reserve_0, state.pools[0].reserve_1), (5, 6));
+        assert_eq!((state.pools[1].reserve_0, state.pools[1].reserve_1), (3, 4));
+
+        let created = |factory: u64, pair: u64| Log {
+            address: address(factory),
+            topics: vec![
+                PAIR_CREATED_EVENT_SIGNATURE,
+                H256::from(address(1)),
+                H256::from(address(3)),
+            ],
+            data: encode(&[Token::Address(address(pair)), Token::Uint(U256::one())]).into(),
+            ..Default::default()
+        };
+        let new_pairs = state.new_pairs(&[created(100, 30), created(200, 20), created(300, 40)]);
+        assert_eq!(new_pairs.len(), 1);
+        assert_eq!(new_pairs[&address(100)], vec![address(30)]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let storage = MemoryStorage::new(Encoding::JsonZstd);
+        Checkpoint::new(5, state(), MARKET_STATE_ID)
+            .save_to(&storage)
+            .unwrap();
+        let loaded = Checkpoint::<MarketState>::load_from(&storage, MARKET_STATE_ID)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.last_block, 5);
+        assert_eq!(loaded.data.factories.len(), 2);
+        assert_eq!(loaded.data.origin(&address(20)), Some(&origin(200)));
+        assert_eq!(loaded.data.token_to_pool_map().len(), 2);
+    }
+}
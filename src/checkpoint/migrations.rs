@@ -0,0 +1,107 @@
+use super::log_archive::LogArchive;
+use crate::amm::uniswap_v2::pool::UniswapV2Pool;
+use ethers::types::{Log, H160, H256, U256};
+use serde::Deserialize;
+
+/// Schema version of checkpoint data. Files without a version header are version 0.
+///
+/// Bump `VERSION` whenever the serialised form of the type changes and teach `migrate` how to
+/// upgrade the previous version, then add a golden file for the new version to `testdata`.
+/// Migrations work on the JSON text of the data so that large integers such as reserves never
+/// pass through a lossy intermediate representation.
+pub trait Versioned {
+    const VERSION: u32;
+
+    /// Upgrades `data` written with version `from` to version `from + 1`.
+    fn migrate(from: u32, data: &str) -> Result<String, String>;
+}
+
+impl Versioned for Vec<H160> {
+    const VERSION: u32 = 1;
+
+    fn migrate(from: u32, data: &str) -> Result<String, String> {
+        match from {
+            0 => Ok(data.to_string()),
+            _ => Err(format!("no migration from version {}", from)),
+        }
+    }
+}
+
+/// Unversioned pool, optionally predating `eth_value`.
+#[derive(Deserialize)]
+struct UniswapV2PoolV0 {
+    address: H160,
+    token_a: H160,
+    token_a_decimals: u8,
+    token_b: H160,
+    token_b_decimals: u8,
+    reserve_0: u128,
+    reserve_1: u128,
+    fee: u32,
+    #[serde(default)]
+    eth_value: U256,
+}
+
+impl Versioned for Vec<UniswapV2Pool> {
+    const VERSION: u32 = 1;
+
+    fn migrate(from: u32, data: &str) -> Result<String, String> {
+        match from {
+            0 => {
+                let pools: Vec<UniswapV2PoolV0> =
+                    serde_json::from_str(data).map_err(|e| e.to_string())?;
+                let pools: Vec<UniswapV2Pool> = pools
+                    .into_iter()
+                    .map(|p| {
+                        UniswapV2Pool::new(
+                            p.address,
+                            p.token_a,
+                            p.token_a_decimals,
+                            p.token_b,
+                            p.token_b_decimals,
+                            p.reserve_0,
+                            p.reserve_1,
+                            p.fee,
+                            p.eth_value,
+                        )
+                    })
+                    .collect();
+                serde_json::to_string(&pools).map_err(|e| e.to_string())
+            }
+            _ => Err(format!("no migration from version {}", from)),
+        }
+    }
+}
+
+/// Log archive of a single address, or of every address when `address` is `None`.
+#[derive(Deserialize)]
+struct LogArchiveV1 {
+    signature: H256,
+    address: Option<H160>,
+    ranges: Vec<(u64, u64)>,
+    logs: Vec<Log>,
+}
+
+impl Versioned for LogArchive {
+    const VERSION: u32 = 2;
+
+    fn migrate(from: u32, data: &str) -> Result<String, String> {
+        match from {
+            0 => Ok(data.to_string()),
+            1 => {
+                let archive: LogArchiveV1 =
+                    serde_json::from_str(data).map_err(|e| e.to_string())?;
+                let mut migrated = LogArchive::new(archive.signature);
+                match archive.address {
+                    None => migrated.ranges = archive.ranges,
+                    Some(address) => {
+                        migrated.address_ranges.insert(address, archive.ranges);
+                    }
+                }
+                migrated.logs = archive.logs;
+                serde_json::to_string(&migrated).map_err(|e| e.to_string())
+            }
+            _ => Err(format!("no migration from version {}", from)),
+        }
+    }
+}
@@ -1,6 +1,19 @@
-use ethers::types::{H160, U256};
+pub mod log_archive;
+pub mod market_state;
+pub mod migrations;
+pub mod reconcile;
+pub mod storage;
+use ethers::types::{BlockNumber, H160, U256};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, str::FromStr};
+use serde_json::value::RawValue;
+use std::{collections::HashMap, str::FromStr, time::Instant};
+use tracing::{info, instrument, warn};
+
+use self::{
+    log_archive::LogArchiveError,
+    migrations::Versioned,
+    storage::{CheckpointStorage, Encoding, StorageError},
+};
 
 use crate::{
     amm::{
@@ -8,48 +21,104 @@ use crate::{
             factory::UniswapV2Factory,
             pool::{pool_data_batch_request::get_uniswap_v2_pool_data_concurrent, UniswapV2Pool},
         },
-        weth_value::get_weth_value_in_pool_concurrent,
+        valuation::Valuation,
+        weth_value::{get_weth_value_in_pool_concurrent, PricingFactory},
     },
     eth_provider::EthProvider,
+    metrics::metrics,
 };
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Checkpoint<T> {
+    #[serde(default)]
+    pub version: u32,
     pub last_block: u64,
     pub data: T,
     pub id: String,
 }
 
-impl<T: for<'a> Deserialize<'a> + Serialize> Checkpoint<T> {
+#[derive(Deserialize)]
+struct CheckpointHeader<'a> {
+    #[serde(default)]
+    version: u32,
+    last_block: u64,
+    #[serde(borrow)]
+    data: &'a RawValue,
+    id: String,
+}
+
+impl<T: for<'a> Deserialize<'a> + Serialize + Versioned> Checkpoint<T> {
     pub fn new(last_block: u64, data: T, id: &str) -> Self {
         Checkpoint {
+            version: T::VERSION,
             last_block,
             data,
             id: id.to_string(),
         }
     }
 
-    fn path(id: &str) -> String {
-        format!("src/checkpoint/data/{}", id)
+    /// Loads the checkpoint, upgrading it to the current schema version and writing the upgraded
+    /// checkpoint back to `storage` if it was stored with an older one.
+    pub fn load_from<S: CheckpointStorage + ?Sized>(
+        storage: &S,
+        id: &str,
+    ) -> Result<Option<Self>, StorageError> {
+        let data = match storage.read(id)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let corrupt = |reason: String| StorageError::Corrupt {
+            id: id.to_string(),
+            reason,
+        };
+        let header: Box<RawValue> = Encoding::decode(id, &data)?;
+        let header: CheckpointHeader =
+            serde_json::from_str(header.get()).map_err(|e| corrupt(e.to_string()))?;
+        if header.version > T::VERSION {
+            return Err(StorageError::UnsupportedVersion {
+                id: id.to_string(),
+                version: header.version,
+                supported: T::VERSION,
+            });
+        }
+        let mut data = header.data.get().to_string();
+        for from in header.version..T::VERSION {
+            data = T::migrate(from, &data)
+                .map_err(|e| corrupt(format!("migrating from version {}: {}", from, e)))?;
+        }
+        let checkpoint = Checkpoint {
+            version: T::VERSION,
+            last_block: header.last_block,
+            data: serde_json::from_str(&data).map_err(|e| corrupt(e.to_string()))?,
+            id: header.id,
+        };
+        if header.version < T::VERSION {
+            checkpoint.save_to(storage)?;
+        }
+        Ok(Some(checkpoint))
+    }
+
+    pub fn save_to<S: CheckpointStorage + ?Sized>(&self, storage: &S) -> Result<(), StorageError> {
+        storage.write(&self.id, &storage.encoding().encode(self)?)
     }
 
-    pub fn load_data(id: &str) -> Option<Self> {
-        match fs::read_to_string(Self::path(&id))
-            .map_err(|e| e.to_string())
-            .and_then(|data| serde_json::from_str(&data).map_err(|e| e.to_string()))
-        {
-            Ok(checkpoint) => checkpoint,
-            Err(_) => None,
-        }
+    /// Loads from the installed storage, see `storage::installed`.
+    /// `Ok(None)` means the checkpoint does not exist yet, a corrupt one is an error.
+    pub fn load_data(id: &str) -> Result<Option<Self>, StorageError> {
+        Self::load_from(&*storage::installed(), id)
     }
 
-    pub fn save_data(&self) {
-        let serialized = serde_json::to_string(self).unwrap();
-        fs::write(Self::path(&self.id), serialized).unwrap();
+    pub fn save_data(&self) -> Result<(), StorageError> {
+        self.save_to(&*storage::installed())
     }
 }
 
 impl Checkpoint<Vec<H160>> {
+    #[instrument(
+        name = "sync_pair_addresses",
+        skip_all,
+        fields(%id, from_block = 0, to_block = current_block)
+    )]
     async fn create(
         provider: &EthProvider,
         factory: &UniswapV2Factory,
@@ -57,16 +126,24 @@ impl Checkpoint<Vec<H160>> {
         id: &str,
         current_block: u64,
     ) -> Self {
+        let block = BlockNumber::Number(current_block.into());
         let pairs = factory
             .get_pair_addresses_from_factory(
                 0,
-                factory.all_pairs_length(provider.http.clone()).await as usize,
+                factory.all_pairs_length(provider.http.clone(), block).await as usize,
                 step,
                 provider.http.clone(),
+                block,
             )
             .await;
         Self::new(current_block, pairs, &id)
     }
+
+    #[instrument(
+        name = "sync_pair_addresses",
+        skip_all,
+        fields(id = %self.id, from_block = self.last_block + 1, to_block = current_block)
+    )]
     async fn update(
         mut self,
         provider: &EthProvider,
@@ -74,36 +151,55 @@ impl Checkpoint<Vec<H160>> {
         step: usize,
         current_block: u64,
     ) -> Self {
-        let new_pairs = factory
+        if current_block <= self.last_block {
+            return self;
+        }
+        match factory
             .get_pair_addresses_from_logs_concurrent(
-                self.last_block as usize,
+                (self.last_block + 1) as usize,
                 current_block as usize,
                 step,
                 provider.http.clone(),
             )
-            .await;
-        self.data.extend(new_pairs);
-        self.last_block = current_block;
+            .await
+        {
+            Ok(new_pairs) => {
+                self.data.extend(new_pairs);
+                self.last_block = current_block;
+            }
+            // Stays at `last_block`, so the next sync asks for the same blocks again.
+            Err(err) => warn!(%err, last_block = self.last_block, "could not sync pair addresses"),
+        }
         self
     }
     pub async fn sync_uniswap_v2_pair_addresses(
         provider: &EthProvider,
         factory: &UniswapV2Factory,
         step: usize,
-    ) -> Self {
-        let id = format!("uniswap_v2_pair_addresses.{:?}", factory.address);
+    ) -> Result<Self, StorageError> {
         let current_block = provider.get_block_number().await;
-        let checkpoint = match Self::load_data(&id) {
+        Self::sync_uniswap_v2_pair_addresses_at(provider, factory, step, current_block).await
+    }
+
+    /// Pair addresses as of `current_block`, with every call and log query pinned to that block.
+    pub async fn sync_uniswap_v2_pair_addresses_at(
+        provider: &EthProvider,
+        factory: &UniswapV2Factory,
+        step: usize,
+        current_block: u64,
+    ) -> Result<Self, StorageError> {
+        let id = format!("uniswap_v2_pair_addresses.{:?}", factory.address);
+        let checkpoint = match Self::load_data(&id)? {
             None => Self::create(provider, factory, step, &id, current_block).await,
             Some(c) => c.update(provider, factory, step, current_block).await,
         };
-        checkpoint.save_data();
-        checkpoint
+        checkpoint.save_data()?;
+        Ok(checkpoint)
     }
 }
 
 impl Checkpoint<Vec<UniswapV2Pool>> {
-    fn id(factory_address: &H160) -> String {
+    pub fn id(factory_address: &H160) -> String {
         format!("uniswap_v2_pools.{:?}", factory_address)
     }
 
@@ -116,31 +212,56 @@ impl Checkpoint<Vec<UniswapV2Pool>> {
         Self::get_factory_address_from_id(&self.id)
     }
 
+    #[instrument(name = "sync", skip_all, fields(%id, from_block = 0, to_block = current_block))]
     async fn create(
         provider: &EthProvider,
         factory: &UniswapV2Factory,
         id: &str,
         step: usize,
         current_block: u64,
-    ) -> Self {
-        let pairs =
-            Checkpoint::<Vec<H160>>::sync_uniswap_v2_pair_addresses(provider, factory, step).await;
-        let pools =
-            get_uniswap_v2_pool_data_concurrent(&pairs.data, provider.http.clone(), 300, step)
-                .await;
-        Self::new(current_block, pools, id)
+    ) -> Result<Self, StorageError> {
+        let started = Instant::now();
+        let pairs = Checkpoint::<Vec<H160>>::sync_uniswap_v2_pair_addresses_at(
+            provider,
+            factory,
+            step,
+            current_block,
+        )
+        .await?;
+        let pools = get_uniswap_v2_pool_data_concurrent(
+            &pairs.data,
+            provider.http.clone(),
+            factory.fee as u32,
+            step,
+            BlockNumber::Number(current_block.into()),
+        )
+        .await;
+        info!(
+            pools = pools.len(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "created pool checkpoint"
+        );
+        Ok(Self::new(current_block, pools, id))
     }
 
+    #[instrument(
+        name = "sync",
+        skip_all,
+        fields(id = %self.id, from_block = self.last_block + 1, to_block = current_block)
+    )]
+    /// Applies the Sync events and adds the pairs created since `last_block`. On error
+    /// `last_block` is left as is, replaying Sync events is harmless since the last one wins.
     async fn update(
         &mut self,
         provider: &EthProvider,
         factory: &UniswapV2Factory,
         step: usize,
         current_block: u64,
-    ) {
+    ) -> Result<(), LogArchiveError> {
         if current_block <= self.last_block {
-            return;
+            return Ok(());
         }
+        let started = Instant::now();
 
         UniswapV2Pool::sync_pools_from_logs(
             (self.last_block + 1) as usize,
@@ -149,63 +270,112 @@ impl Checkpoint<Vec<UniswapV2Pool>> {
             &mut self.data,
             provider.http.clone(),
         )
-        .await;
+        .await?;
         let new_pairs = factory
             .get_pair_addresses_from_logs_concurrent(
-                self.last_block as usize,
+                (self.last_block + 1) as usize,
                 current_block as usize,
                 step,
                 provider.http.clone(),
             )
-            .await;
+            .await?;
         let new_pools = get_uniswap_v2_pool_data_concurrent(
             &new_pairs,
             provider.http.clone(),
-            300,
+            factory.fee as u32,
             new_pairs.len().div_ceil(10).max(step),
+            BlockNumber::Number(current_block.into()),
         )
         .await;
+        info!(
+            pools = self.data.len(),
+            new_pools = new_pools.len(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "synced pool checkpoint"
+        );
         self.data.extend(new_pools);
         self.last_block = current_block;
+        Ok(())
     }
 
-    pub async fn get(provider: &EthProvider, factory: &UniswapV2Factory, step: usize) -> Self {
+    /// Loads the checkpoint of `factory` and brings it to the current block, or creates it.
+    /// A checkpoint that cannot be loaded or saved is an error, it is never rebuilt silently.
+    pub async fn get(
+        provider: &EthProvider,
+        factory: &UniswapV2Factory,
+        step: usize,
+    ) -> Result<Self, StorageError> {
         let id = Self::id(&factory.address);
         let current_block = provider.get_block_number().await;
-        let checkpoint = match Self::load_data(&id) {
-            None => Self::create(provider, factory, &id, step, current_block).await,
+        metrics().set_head_block(current_block);
+        let checkpoint = match Self::load_data(&id)? {
+            None => Self::create(provider, factory, &id, step, current_block).await?,
             Some(mut c) => {
-                c.update(provider, factory, step, current_block).await;
+                if let Err(err) = c.update(provider, factory, step, current_block).await {
+                    warn!(%err, last_block = c.last_block, "could not sync checkpoint");
+                }
                 c
             }
         };
-        checkpoint.save_data();
-        checkpoint
+        metrics().set_checkpoint(checkpoint.last_block, checkpoint.data.len());
+        checkpoint.save_data()?;
+        Ok(checkpoint)
     }
 
-    pub async fn sync(&mut self, provider: &EthProvider) {
-        let factory = UniswapV2Factory::new(self.factory_address(), 300);
+    /// Syncs to the current block, adding the pairs `factory` created since. On error the
+    /// checkpoint stays at its `last_block`.
+    pub async fn sync(
+        &mut self,
+        provider: &EthProvider,
+        factory: &UniswapV2Factory,
+        step: usize,
+    ) -> Result<(), LogArchiveError> {
         let current_block = provider.get_block_number().await;
-        self.update(provider, &factory, 100, current_block).await;
-        self.save_data()
+        metrics().set_head_block(current_block);
+        let result = self.update(provider, factory, step, current_block).await;
+        metrics().set_checkpoint(self.last_block, self.data.len());
+        self.save_data()?;
+        result
     }
 
-    pub async fn sync_eth_value(&mut self, provider: &EthProvider, weth: H160, threshold: U256) {
-        let factory_addresses = vec![self.factory_address()];
+    /// Values every pool in WETH, pricing tokens through the checkpoint's factory and then
+    /// through `extra_factories`, which may be V2 or V3.
+    pub async fn sync_eth_value(
+        &mut self,
+        provider: &EthProvider,
+        weth: H160,
+        threshold: U256,
+        extra_factories: &[PricingFactory],
+        step: usize,
+    ) -> Result<(), StorageError> {
+        let mut factories = vec![PricingFactory::uniswap_v2(self.factory_address())];
+        factories.extend_from_slice(extra_factories);
         let pool_addresses: Vec<H160> = self.data.iter().map(|p| p.address).collect();
         let weth_values = get_weth_value_in_pool_concurrent(
             &pool_addresses,
-            &factory_addresses,
+            &factories,
             weth,
             threshold,
-            100,
+            step,
             provider.http.clone(),
+            BlockNumber::Number(self.last_block.into()),
         )
         .await;
         for pool in &mut self.data {
             pool.eth_value = *weth_values.get(&pool.address).unwrap_or(&U256::zero());
         }
-        self.save_data();
+        self.save_data()
+    }
+
+    /// Same as `sync_eth_value` but priced from the reserves of the checkpoint's own pools,
+    /// without any RPC calls.
+    pub fn sync_eth_value_from_pools(
+        &mut self,
+        weth: H160,
+        threshold: U256,
+    ) -> Result<(), StorageError> {
+        Valuation::from_pools(&self.data, weth, threshold, None).apply_eth_values(&mut self.data);
+        self.save_data()
     }
 
     pub fn token_to_pool_map(&self) -> HashMap<(&H160, &H160), &UniswapV2Pool> {
@@ -220,8 +390,113 @@ impl Checkpoint<Vec<UniswapV2Pool>> {
 
 #[cfg(test)]
 mod tests {
-    use crate::tests::fixtures;
-    use std::sync::Arc;
+    use super::{
+        log_archive::LogArchive,
+        storage::{CheckpointStorage, Encoding, MemoryStorage, StorageError},
+        Checkpoint,
+    };
+    use crate::{amm::uniswap_v2::pool::UniswapV2Pool, tests::fixtures};
+    use ethers::types::{H160, U256};
+    use serde_json::Value;
+    use std::{str::FromStr, sync::Arc};
+
+    /// Loads a golden file and checks the upgraded checkpoint was written back.
+    fn load_golden<T>(golden: &[u8]) -> Checkpoint<T>
+    where
+        T: for<'a> serde::Deserialize<'a> + serde::Serialize + super::migrations::Versioned,
+    {
+        let id = serde_json::from_slice::<Value>(golden).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let storage = MemoryStorage::new(Encoding::Json);
+        storage.write(&id, golden).unwrap();
+        let checkpoint = Checkpoint::<T>::load_from(&storage, &id).unwrap().unwrap();
+        assert_eq!(checkpoint.version, T::VERSION);
+        let stored: Value = serde_json::from_slice(&storage.read(&id).unwrap().unwrap()).unwrap();
+        assert_eq!(stored["version"], T::VERSION);
+        checkpoint
+    }
+
+    fn assert_pair_addresses(checkpoint: Checkpoint<Vec<H160>>) {
+        assert_eq!(checkpoint.last_block, 17_600_000);
+        assert_eq!(checkpoint.data.len(), 2);
+        assert_eq!(
+            checkpoint.data[0],
+            H160::from_str("0xb4e16d0168e52d35cacd2c6185b44281ec28c9dc").unwrap()
+        );
+    }
+
+    fn assert_pool(checkpoint: &Checkpoint<Vec<UniswapV2Pool>>) -> &UniswapV2Pool {
+        assert_eq!(checkpoint.data.len(), 1);
+        let pool = &checkpoint.data[0];
+        assert_eq!(pool.token_a_decimals, 6);
+        assert_eq!(pool.reserve_0, 36_112_347_291_085);
+        assert_eq!(pool.reserve_1, 19_472_817_310_919_225_170_433);
+        assert_eq!(pool.fee, 300);
+        pool
+    }
+
+    #[test]
+    fn test_golden_pair_addresses() {
+        assert_pair_addresses(load_golden(include_bytes!(
+            "testdata/uniswap_v2_pair_addresses.v0.json"
+        )));
+        assert_pair_addresses(load_golden(include_bytes!(
+            "testdata/uniswap_v2_pair_addresses.v1.json"
+        )));
+    }
+
+    #[test]
+    fn test_golden_pools() {
+        let eth_value = U256::from_dec_str("152176824716484152286").unwrap();
+        let checkpoint = load_golden(include_bytes!(
+            "testdata/uniswap_v2_pools.v0_without_eth_value.json"
+        ));
+        assert_eq!(assert_pool(&checkpoint).eth_value, U256::zero());
+        let checkpoint = load_golden(include_bytes!("testdata/uniswap_v2_pools.v0.json"));
+        assert_eq!(assert_pool(&checkpoint).eth_value, eth_value);
+        let checkpoint = load_golden(include_bytes!("testdata/uniswap_v2_pools.v1.json"));
+        assert_eq!(assert_pool(&checkpoint).eth_value, eth_value);
+    }
+
+    #[test]
+    fn test_golden_log_archive() {
+        for golden in [
+            include_bytes!("testdata/logs.v0.json").as_slice(),
+            include_bytes!("testdata/logs.v1.json").as_slice(),
+            include_bytes!("testdata/logs.v2.json").as_slice(),
+        ] {
+            let checkpoint = load_golden::<LogArchive>(golden);
+            assert!(checkpoint.data.address_ranges.is_empty());
+            assert_eq!(
+                checkpoint.data.missing_ranges(&[], 17_600_000, 17_600_001),
+                vec![]
+            );
+            assert_eq!(
+                checkpoint
+                    .data
+                    .logs_in_range(&[], 17_600_001, 17_600_001)
+                    .len(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn test_newer_version_is_rejected() {
+        let golden = String::from_utf8(
+            include_bytes!("testdata/uniswap_v2_pair_addresses.v1.json").to_vec(),
+        )
+        .unwrap()
+        .replace("\"version\":1", "\"version\":99");
+        let storage = MemoryStorage::default();
+        storage.write("golden", golden.as_bytes()).unwrap();
+        match Checkpoint::<Vec<H160>>::load_from(&storage, "golden") {
+            Err(StorageError::UnsupportedVersion { version, .. }) => assert_eq!(version, 99),
+            _ => panic!("expected an unsupported version error"),
+        }
+    }
 
     #[tokio::test]
     async fn test_checkpoint_sync_pools_from_logs() {
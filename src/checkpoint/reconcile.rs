@@ -0,0 +1,281 @@
+use super::{
+    market_state::{MarketState, PoolSource},
+    storage::StorageError,
+    Checkpoint,
+};
+use crate::{
+    amm::uniswap_v2::pool::{
+        pool_data_batch_request::get_uniswap_v2_pool_data_concurrent, UniswapV2Pool,
+    },
+    eth_provider::EthProvider,
+};
+use ethers::{
+    providers::Middleware,
+    types::{BlockNumber, H160},
+};
+use rand::{seq::index::sample, thread_rng};
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc};
+use tracing::{debug, info};
+
+/// Difference between the reserves derived from logs and the reserves read from the pair.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReserveDrift {
+    pub address: H160,
+    pub stored: (u128, u128),
+    pub actual: (u128, u128),
+}
+
+impl ReserveDrift {
+    /// Largest relative difference of the two reserves, in basis points of the actual reserve.
+    pub fn drift_bps(&self) -> f64 {
+        let relative = |stored: u128, actual: u128| {
+            if actual == 0 {
+                return if stored == 0 { 0.0 } else { f64::INFINITY };
+            }
+            (stored as f64 - actual as f64).abs() / actual as f64 * 10_000.0
+        };
+        relative(self.stored.0, self.actual.0).max(relative(self.stored.1, self.actual.1))
+    }
+}
+
+/// Overwrites the reserves of `pools` with those in `actual` and returns the pools that
+/// differed. Pools missing from `actual` are left untouched.
+pub fn apply_reserves(pools: &mut [UniswapV2Pool], actual: &[UniswapV2Pool]) -> Vec<ReserveDrift> {
+    let actual: HashMap<H160, &UniswapV2Pool> = actual.iter().map(|p| (p.address, p)).collect();
+    let mut drifts = vec![];
+    for pool in pools {
+        if let Some(fresh) = actual.get(&pool.address) {
+            if (pool.reserve_0, pool.reserve_1) != (fresh.reserve_0, fresh.reserve_1) {
+                drifts.push(ReserveDrift {
+                    address: pool.address,
+                    stored: (pool.reserve_0, pool.reserve_1),
+                    actual: (fresh.reserve_0, fresh.reserve_1),
+                });
+                pool.reserve_0 = fresh.reserve_0;
+                pool.reserve_1 = fresh.reserve_1;
+            }
+        }
+    }
+    drifts
+}
+
+/// Re-reads the reserves of all pools, or of `sample_size` random ones, at `block` and repairs
+/// any that drifted from the log-derived state, e.g. rebasing tokens or donations without a
+/// `sync`.
+pub async fn reconcile_reserves<M: Middleware>(
+    pools: &mut [UniswapV2Pool],
+    sample_size: Option<usize>,
+    block: u64,
+    step: usize,
+    middleware: Arc<M>,
+) -> Vec<ReserveDrift> {
+    let indices: Vec<usize> = match sample_size {
+        Some(size) if size < pools.len() => sample(&mut thread_rng(), pools.len(), size).into_vec(),
+        _ => (0..pools.len()).collect(),
+    };
+    // Pools are read with their own fee, so the fresh pools only differ in their reserves.
+    let mut addresses_by_fee: HashMap<u32, Vec<H160>> = HashMap::new();
+    for idx in indices {
+        addresses_by_fee
+            .entry(pools[idx].fee)
+            .or_default()
+            .push(pools[idx].address);
+    }
+    let mut actual = vec![];
+    for (fee, addresses) in addresses_by_fee {
+        actual.extend(
+            get_uniswap_v2_pool_data_concurrent(
+                &addresses,
+                middleware.clone(),
+                fee,
+                step,
+                BlockNumber::Number(block.into()),
+            )
+            .await,
+        );
+    }
+    let drifts = apply_reserves(pools, &actual);
+    for drift in &drifts {
+        debug!(
+            pool = ?drift.address,
+            stored = ?drift.stored,
+            actual = ?drift.actual,
+            drift_bps = drift.drift_bps(),
+            "repaired reserves"
+        );
+    }
+    info!(
+        pools = actual.len(),
+        block,
+        drifted = drifts.len(),
+        "reconciled reserves"
+    );
+    drifts
+}
+
+/// Runs a reconciliation every `interval` blocks, for long running syncs.
+pub struct Reconciler {
+    pub interval: u64,
+    pub sample_size: Option<usize>,
+    pub step: usize,
+    last_reconciled: Option<u64>,
+}
+
+impl Reconciler {
+    pub fn new(interval: u64, sample_size: Option<usize>, step: usize) -> Self {
+        Reconciler {
+            interval,
+            sample_size,
+            step,
+            last_reconciled: None,
+        }
+    }
+
+    pub fn is_due(&self, block: u64) -> bool {
+        match self.last_reconciled {
+            Some(last) => block >= last + self.interval,
+            None => true,
+        }
+    }
+
+    /// Reconciles `pools`, which must reflect the state at `block`, if a reconciliation is due.
+    pub async fn maybe_reconcile<M: Middleware>(
+        &mut self,
+        pools: &mut [UniswapV2Pool],
+        block: u64,
+        middleware: Arc<M>,
+    ) -> Option<Vec<ReserveDrift>> {
+        if !self.is_due(block) {
+            return None;
+        }
+        let drifts =
+            reconcile_reserves(pools, self.sample_size, block, self.step, middleware).await;
+        self.last_reconciled = Some(block);
+        Some(drifts)
+    }
+}
+
+impl PoolSource {
+    /// Reconciles the pools of this source, which `checkpoint` was read from, if `reconciler`
+    /// is due at the checkpoint's block. Repairs are saved to the source's checkpoint.
+    pub async fn maybe_reconcile(
+        &mut self,
+        checkpoint: &mut Checkpoint<Vec<UniswapV2Pool>>,
+        reconciler: &mut Reconciler,
+        provider: &EthProvider,
+    ) -> Result<Option<Vec<ReserveDrift>>, StorageError> {
+        let (block, http) = (checkpoint.last_block, provider.http.clone());
+        match self {
+            PoolSource::Factory(_) => {
+                let drifts = reconciler
+                    .maybe_reconcile(&mut checkpoint.data, block, http)
+                    .await;
+                if drifts.is_some() {
+                    checkpoint.save_data()?;
+                }
+                Ok(drifts)
+            }
+            PoolSource::Market(market) => {
+                let drifts = reconciler
+                    .maybe_reconcile(&mut market.data.pools, block, http)
+                    .await;
+                if drifts.is_some() {
+                    market.save_data()?;
+                    *checkpoint = market.pools_checkpoint();
+                }
+                Ok(drifts)
+            }
+        }
+    }
+}
+
+impl Checkpoint<Vec<UniswapV2Pool>> {
+    /// Repairs reserves against the chain at `last_block` and saves the checkpoint.
+    pub async fn reconcile(
+        &mut self,
+        provider: &EthProvider,
+        sample_size: Option<usize>,
+        step: usize,
+    ) -> Result<Vec<ReserveDrift>, StorageError> {
+        let drifts = reconcile_reserves(
+            &mut self.data,
+            sample_size,
+            self.last_block,
+            step,
+            provider.http.clone(),
+        )
+        .await;
+        self.save_data()?;
+        Ok(drifts)
+    }
+}
+
+impl Checkpoint<MarketState> {
+    /// Repairs reserves against the chain at `last_block` and saves the checkpoint.
+    pub async fn reconcile(
+        &mut self,
+        provider: &EthProvider,
+        sample_size: Option<usize>,
+        step: usize,
+    ) -> Result<Vec<ReserveDrift>, StorageError> {
+        let drifts = reconcile_reserves(
+            &mut self.data.pools,
+            sample_size,
+            self.last_block,
+            step,
+            provider.http.clone(),
+        )
+        .await;
+        self.save_data()?;
+        Ok(drifts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::U256;
+
+    fn pool(n: u64, reserve_0: u128, reserve_1: u128) -> UniswapV2Pool {
+        let token = H160::from_low_u64_be;
+        UniswapV2Pool::new(
+            token(n),
+            token(1),
+            18,
+            token(2),
+            18,
+            reserve_0,
+            reserve_1,
+            300,
+            U256::zero(),
+        )
+    }
+
+    #[test]
+    fn test_apply_reserves() {
+        let mut pools = vec![pool(10, 100, 200), pool(11, 100, 200), pool(12, 5, 5)];
+        let actual = vec![pool(10, 100, 200), pool(11, 101, 190)];
+        let drifts = apply_reserves(&mut pools, &actual);
+        assert_eq!(
+            drifts,
+            vec![ReserveDrift {
+                address: H160::from_low_u64_be(11),
+                stored: (100, 200),
+                actual: (101, 190),
+            }]
+        );
+        assert!((drifts[0].drift_bps() - 526.315).abs() < 0.01);
+        assert_eq!((pools[1].reserve_0, pools[1].reserve_1), (101, 190));
+        assert_eq!((pools[2].reserve_0, pools[2].reserve_1), (5, 5));
+    }
+
+    #[test]
+    fn test_reconciler_is_due() {
+        let mut reconciler = Reconciler::new(100, Some(10), 10);
+        assert!(reconciler.is_due(1_000));
+        reconciler.last_reconciled = Some(1_000);
+        assert!(!reconciler.is_due(1_099));
+        assert!(reconciler.is_due(1_100));
+    }
+}
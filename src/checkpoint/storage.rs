@@ -0,0 +1,280 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, fmt,
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+pub const DEFAULT_CHECKPOINT_DIR: &str = "src/checkpoint/data";
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Numbers temporary files so that concurrent writes of the same id, from this process or
+/// another one, never share one.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Storage behind `Checkpoint::load_data` and `save_data`, see `install`.
+static INSTALLED: RwLock<Option<Arc<dyn CheckpointStorage>>> = RwLock::new(None);
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    /// The stored bytes exist but cannot be decoded. Checkpoints are never rebuilt silently
+    /// in this case, the file has to be inspected or removed by hand.
+    Corrupt {
+        id: String,
+        reason: String,
+    },
+    /// Written by a newer version of this crate.
+    UnsupportedVersion {
+        id: String,
+        version: u32,
+        supported: u32,
+    },
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(err) => write!(f, "checkpoint io error: {}", err),
+            StorageError::Corrupt { id, reason } => {
+                write!(f, "checkpoint {} is corrupt: {}", id, reason)
+            }
+            StorageError::UnsupportedVersion {
+                id,
+                version,
+                supported,
+            } => write!(
+                f,
+                "checkpoint {} has version {}, newest supported is {}",
+                id, version, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+/// How checkpoints are serialised when written. Reading detects the encoding from the data, so
+/// switching encodings does not invalidate existing checkpoints.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// JSON compressed with zstd, typically an order of magnitude smaller for pool and log data.
+    #[serde(rename = "zstd")]
+    JsonZstd,
+}
+
+impl Encoding {
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, StorageError> {
+        let json = serde_json::to_vec(value).map_err(io::Error::from)?;
+        match self {
+            Encoding::Json => Ok(json),
+            Encoding::JsonZstd => Ok(zstd::encode_all(json.as_slice(), 3)?),
+        }
+    }
+
+    pub fn decode<T: for<'a> Deserialize<'a>>(id: &str, data: &[u8]) -> Result<T, StorageError> {
+        let corrupt = |reason: String| StorageError::Corrupt {
+            id: id.to_string(),
+            reason,
+        };
+        let json = if data.starts_with(&ZSTD_MAGIC) {
+            zstd::decode_all(data).map_err(|e| corrupt(e.to_string()))?
+        } else {
+            data.to_vec()
+        };
+        serde_json::from_slice(&json).map_err(|e| corrupt(e.to_string()))
+    }
+}
+
+/// Where checkpoints are kept. `read` returns `Ok(None)` only when nothing was ever stored under
+/// `id`; `write` must never leave a partially written checkpoint behind.
+pub trait CheckpointStorage: Send + Sync {
+    fn read(&self, id: &str) -> Result<Option<Vec<u8>>, StorageError>;
+    fn write(&self, id: &str, data: &[u8]) -> Result<(), StorageError>;
+    fn encoding(&self) -> Encoding {
+        Encoding::Json
+    }
+}
+
+/// Makes `storage` the one behind every `load_data` and `save_data` of the process.
+pub fn install(storage: Arc<dyn CheckpointStorage>) {
+    *INSTALLED.write().unwrap() = Some(storage);
+}
+
+/// The installed storage, or else the file storage configured in the environment.
+pub fn installed() -> Arc<dyn CheckpointStorage> {
+    INSTALLED
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| Arc::new(FileStorage::from_env()))
+}
+
+/// One file per checkpoint in `dir`, replaced atomically by writing a temporary file next to it
+/// and renaming it over the old one.
+pub struct FileStorage {
+    pub dir: PathBuf,
+    pub encoding: Encoding,
+}
+
+impl FileStorage {
+    pub fn new<P: Into<PathBuf>>(dir: P, encoding: Encoding) -> Self {
+        FileStorage {
+            dir: dir.into(),
+            encoding,
+        }
+    }
+
+    /// Directory from `CHECKPOINT_DIR` and encoding from `CHECKPOINT_ENCODING` (`json` or
+    /// `zstd`), defaulting to plain JSON in `src/checkpoint/data`.
+    pub fn from_env() -> Self {
+        let dir = env::var("CHECKPOINT_DIR").unwrap_or(DEFAULT_CHECKPOINT_DIR.to_string());
+        let encoding = match env::var("CHECKPOINT_ENCODING").as_deref() {
+            Ok("zstd") => Encoding::JsonZstd,
+            _ => Encoding::Json,
+        };
+        Self::new(dir, encoding)
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+}
+
+impl CheckpointStorage for FileStorage {
+    fn read(&self, id: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        match fs::read(self.path(id)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&self, id: &str, data: &[u8]) -> Result<(), StorageError> {
+        fs::create_dir_all(&self.dir)?;
+        let tmp_path = self.path(&format!(
+            "{}.{}.{}.tmp",
+            id,
+            process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_path, self.path(id)));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        Ok(result?)
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+}
+
+/// Keeps checkpoints in memory, for tests and short lived tools.
+#[derive(Default)]
+pub struct MemoryStorage {
+    pub encoding: Encoding,
+    data: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new(encoding: Encoding) -> Self {
+        MemoryStorage {
+            encoding,
+            data: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl CheckpointStorage for MemoryStorage {
+    fn read(&self, id: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.data.lock().unwrap().get(id).cloned())
+    }
+
+    fn write(&self, id: &str, data: &[u8]) -> Result<(), StorageError> {
+        self.data
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::Checkpoint;
+    use ethers::types::H160;
+
+    #[test]
+    fn test_round_trip_and_detect_encoding() {
+        let checkpoint = Checkpoint::new(7, vec![H160::repeat_byte(1); 1000], "numbers");
+        let zstd = MemoryStorage::new(Encoding::JsonZstd);
+        checkpoint.save_to(&zstd).unwrap();
+        let compressed = zstd.read("numbers").unwrap().unwrap();
+        assert!(compressed.starts_with(&ZSTD_MAGIC));
+        assert!(compressed.len() < serde_json::to_vec(&checkpoint).unwrap().len());
+
+        let loaded = Checkpoint::<Vec<H160>>::load_from(&zstd, "numbers")
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.last_block, 7);
+        assert_eq!(loaded.data, checkpoint.data);
+        assert!(Checkpoint::<Vec<H160>>::load_from(&zstd, "missing")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_corrupt_checkpoint_is_an_error() {
+        let storage = MemoryStorage::default();
+        storage
+            .write("numbers", b"{\"last_block\": 7, \"da")
+            .unwrap();
+        match Checkpoint::<Vec<H160>>::load_from(&storage, "numbers") {
+            Err(StorageError::Corrupt { id, .. }) => assert_eq!(id, "numbers"),
+            _ => panic!("expected a corrupt checkpoint error"),
+        }
+    }
+
+    #[test]
+    fn test_file_storage_atomic_write() {
+        let dir = env::temp_dir().join(format!("eth-amm-checkpoint-{}", process::id()));
+        let storage = FileStorage::new(&dir, Encoding::Json);
+        storage.write("a", b"first").unwrap();
+        storage.write("a", b"second").unwrap();
+        assert_eq!(storage.read("a").unwrap().unwrap(), b"second");
+        let files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec!["a"]);
+        assert!(storage.read("b").unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,695 @@
+use crate::{
+    address_book::{AddressBook, Dex, Network},
+    amm::{uniswap_v2::pool::UniswapV2Pool, valuation::Valuation, Protocol},
+    checkpoint::Checkpoint,
+    config::{parse_amount, Config, SearchConfig, DEFAULT_FEE},
+    filters::{FilterContext, FilterPipeline, StageReport},
+    gas::{token_to_weth, GasPrice},
+    metrics::metrics,
+    path::path_discovery::get_all_token_paths,
+    simulator::{sort_by_net_profit, Simulation},
+};
+use ethers::types::{H160, U256};
+use eyre::{eyre, Result};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::SocketAddr,
+    str::FromStr,
+};
+
+pub const USAGE: &str = "\
+Usage: eth-amm [--config FILE] [--rpc local|alchemy|<url>] <command> [options]
+
+Commands:
+  sync      Sync the pool checkpoint of a factory, or with `--market` the market state of
+            every configured factory
+            [--factory F | --market] [--step N] [--weth-value] [--watch SECONDS]
+  pools     List the checkpointed pools that pass a filter pipeline
+            [--factory F] [--filters FILE] [--limit N]
+  discover  Find cycles through a base token in the checkpointed pools that are profitable
+            after gas at the node's current fees, or in the market state with `--market`
+            --token T [--factory F | --market] [--filters FILE] [--min-length N]
+            [--max-length N] [--epsilon N] [--limit N]
+  quote     Quote a token path, offline from the checkpoint and optionally on chain
+            --path T1,T2,...  [--factory F] [--amount N] [--on-chain]
+  execute   Swap a path through the router of a local fork
+            --path T1,T2,...  (--key-env VAR | --key-file FILE) [--factory F] [--router R]
+            [--amount N] [--epsilon N] [--reset-fork]
+  export    Write the cycles `discover` finds to a CSV or JSON file
+            --output FILE and the options of `discover`
+  serve     Serve quotes and opportunities over HTTP from a checkpoint kept in sync, and
+            stream opportunities over WebSocket when `server.stream_listen` is set
+            [--factory F | --market] [--listen ADDR] [--filters FILE]
+
+Tokens and routers are addresses or token names of the configured chain in the address book,
+factories are addresses, names from the config or dex names of that chain. Options not given
+default to the config, loaded from `--config` or `ETH_AMM_CONFIG` and overridden by the
+environment. `--rpc alchemy` uses the configured
+provider. Amounts are integers in the token's smallest unit, such as 1e18.
+Results are printed as JSON, logs go to stderr filtered by `ETH_AMM_LOG` (default info).";
+
+/// Options that take no value.
+const FLAGS: [&str; 4] = ["weth-value", "on-chain", "reset-fork", "market"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rpc {
+    Local,
+    Alchemy,
+    Url(String),
+}
+
+impl Rpc {
+    /// Whether the node runs on this machine, e.g. a Hardhat fork.
+    pub fn is_local(&self) -> bool {
+        match self {
+            Rpc::Local => true,
+            Rpc::Alchemy => false,
+            Rpc::Url(url) => url.contains("://localhost") || url.contains("://127.0.0.1"),
+        }
+    }
+}
+
+/// Where `execute` reads the private key from. Keys are never accepted on the command line,
+/// where they would end up in the shell history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    Env(String),
+    File(String),
+}
+
+impl KeySource {
+    pub fn read(&self) -> Result<String> {
+        let key = match self {
+            KeySource::Env(var) => std::env::var(var)
+                .map_err(|_| eyre!("Environment variable `{}` is not set", var))?,
+            KeySource::File(path) => fs::read_to_string(path)
+                .map_err(|e| eyre!("Could not read key file {}: {}", path, e))?,
+        };
+        Ok(key.trim().to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoverArgs {
+    pub factory: H160,
+    /// Search the market state of every configured factory instead of `factory`.
+    pub market: bool,
+    pub token: H160,
+    /// Pipeline file, the config's pipeline when not given.
+    pub filters: Option<String>,
+    pub search: SearchConfig,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Sync {
+        factory: H160,
+        fee: u32,
+        step: usize,
+        weth_value: bool,
+        /// Sync the market state of every configured factory instead of `factory`.
+        market: bool,
+        /// Keep syncing at this interval instead of exiting.
+        watch: Option<u64>,
+    },
+    Pools {
+        factory: H160,
+        filters: Option<String>,
+        limit: Option<usize>,
+    },
+    Discover(DiscoverArgs),
+    Quote {
+        factory: H160,
+        path: Vec<H160>,
+        amount: Option<U256>,
+        on_chain: bool,
+    },
+    Execute {
+        factory: H160,
+        router: H160,
+        path: Vec<H160>,
+        amount: Option<U256>,
+        epsilon: U256,
+        key: KeySource,
+        reset_fork: bool,
+    },
+    Export {
+        discover: DiscoverArgs,
+        output: String,
+    },
+    Serve {
+        factory: H160,
+        fee: u32,
+        market: bool,
+        listen: SocketAddr,
+        filters: Option<String>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Cli {
+    pub rpc: Rpc,
+    pub config: Config,
+    pub book: AddressBook,
+    pub command: Command,
+}
+
+/// `--name value`, `--name=value` and `--flag` options, consumed as the command reads them so
+/// that anything left over is reported as unknown.
+struct Options {
+    values: HashMap<String, String>,
+    flags: HashSet<String>,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(args: I) -> Result<Self> {
+        let mut options = Options {
+            values: HashMap::new(),
+            flags: HashSet::new(),
+        };
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            let name = arg
+                .strip_prefix("--")
+                .ok_or_else(|| eyre!("Unexpected argument `{}`", arg))?;
+            if let Some((name, value)) = name.split_once('=') {
+                options.values.insert(name.to_string(), value.to_string());
+            } else if FLAGS.contains(&name) {
+                options.flags.insert(name.to_string());
+            } else {
+                let value = args
+                    .next()
+                    .ok_or_else(|| eyre!("Option `--{}` needs a value", name))?;
+                options.values.insert(name.to_string(), value);
+            }
+        }
+        Ok(options)
+    }
+
+    fn take(&mut self, name: &str) -> Option<String> {
+        self.values.remove(name)
+    }
+
+    fn required(&mut self, name: &str) -> Result<String> {
+        self.take(name)
+            .ok_or_else(|| eyre!("Missing option `--{}`", name))
+    }
+
+    fn parsed<T: FromStr>(&mut self, name: &str) -> Result<Option<T>> {
+        self.take(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| eyre!("Invalid value `{}` for `--{}`", value, name))
+            })
+            .transpose()
+    }
+
+    fn amount(&mut self, name: &str) -> Result<Option<U256>> {
+        self.take(name)
+            .map(|value| parse_amount(&value).map_err(|e| eyre!("Invalid `--{}`: {}", name, e)))
+            .transpose()
+    }
+
+    fn flag(&mut self, name: &str) -> bool {
+        self.flags.remove(name)
+    }
+
+    fn finish(self) -> Result<()> {
+        let unknown = self.values.keys().chain(self.flags.iter()).next();
+        match unknown {
+            Some(name) => Err(eyre!("Unknown option `--{}`", name)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An address, or the name of a token of the network.
+pub fn resolve_address(network: &Network, value: &str) -> Result<H160> {
+    if let Ok(address) = network.token(&value.to_lowercase()) {
+        return Ok(address);
+    }
+    H160::from_str(value).map_err(|_| eyre!("`{}` is neither an address nor a known token", value))
+}
+
+/// Comma separated tokens.
+pub fn resolve_path(network: &Network, value: &str) -> Result<Vec<H160>> {
+    let path = value
+        .split(',')
+        .map(|token| resolve_address(network, token.trim()))
+        .collect::<Result<Vec<_>>>()?;
+    if path.len() < 2 {
+        return Err(eyre!("A path needs at least two tokens"));
+    }
+    Ok(path)
+}
+
+impl Cli {
+    /// Global options, then the config they point to, then the command, which takes its
+    /// defaults from the config.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self> {
+        let mut args = args.into_iter().peekable();
+        let mut rpc = Rpc::Local;
+        let mut config_path = None;
+        while let Some(arg) = args.next_if(|arg| arg.starts_with("--")) {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| eyre!("Option `{}` needs a value", arg))?;
+                    (arg, value)
+                }
+            };
+            match name.as_str() {
+                "--rpc" => rpc = Self::parse_rpc(&value),
+                "--config" => config_path = Some(value),
+                _ => return Err(eyre!("Unknown option `{}`", name)),
+            }
+        }
+        let config = Config::load(config_path.as_deref())?;
+        let book = match &config.chain.address_book {
+            Some(path) => {
+                AddressBook::from_file(path).map_err(|e| eyre!("Could not load {}: {}", path, e))?
+            }
+            None => AddressBook::new(),
+        };
+        let network = book.network(config.chain.chain_id)?;
+        let name = args.next().ok_or_else(|| eyre!("Missing command"))?;
+        let command = Command::parse(&name, Options::parse(args)?, &config, network)?;
+        Ok(Cli {
+            rpc,
+            config,
+            book,
+            command,
+        })
+    }
+
+    fn parse_rpc(value: &str) -> Rpc {
+        match value {
+            "local" => Rpc::Local,
+            "alchemy" => Rpc::Alchemy,
+            url => Rpc::Url(url.to_string()),
+        }
+    }
+}
+
+/// The network's `uniswap_v2` dex, or else its first Uniswap V2 fork.
+pub fn default_dex(network: &Network) -> Result<&Dex> {
+    network
+        .dex("uniswap_v2")
+        .ok()
+        .or_else(|| {
+            network
+                .dexes_by_protocol(Protocol::UniswapV2)
+                .first()
+                .copied()
+        })
+        .ok_or_else(|| eyre!("{} has no Uniswap V2 dex", network.name))
+}
+
+/// A factory of the config by name or address, the factory of a dex of the network by name, or
+/// any other address with the default fee.
+pub fn resolve_factory(config: &Config, network: &Network, value: &str) -> Result<(H160, u32)> {
+    if let Some(factory) = config.factory(value) {
+        return Ok((factory.address, factory.fee));
+    }
+    if let Ok(dex) = network.dex(value) {
+        return Ok((dex.factory, dex.fee));
+    }
+    H160::from_str(value)
+        .map(|address| (address, DEFAULT_FEE))
+        .map_err(|_| eyre!("`{}` is neither an address nor a known factory", value))
+}
+
+impl Command {
+    fn parse(name: &str, mut options: Options, config: &Config, network: &Network) -> Result<Self> {
+        let (factory, fee) = match (options.take("factory"), config.factories.first()) {
+            (Some(value), _) => resolve_factory(config, network, &value)?,
+            (None, Some(factory)) => (factory.address, factory.fee),
+            (None, None) => {
+                let dex = default_dex(network)?;
+                (dex.factory, dex.fee)
+            }
+        };
+
+        let command = match name {
+            "sync" => Command::Sync {
+                factory,
+                fee,
+                step: options.parsed("step")?.unwrap_or(config.concurrency.step),
+                weth_value: options.flag("weth-value"),
+                market: options.flag("market"),
+                watch: options.parsed("watch")?,
+            },
+            "pools" => Command::Pools {
+                factory,
+                filters: options.take("filters"),
+                limit: options.parsed("limit")?,
+            },
+            "discover" => Command::Discover(Self::parse_discover(
+                factory,
+                &mut options,
+                config,
+                network,
+            )?),
+            "quote" => Command::Quote {
+                factory,
+                path: resolve_path(network, &options.required("path")?)?,
+                amount: options.amount("amount")?,
+                on_chain: options.flag("on-chain"),
+            },
+            "execute" => {
+                let key = match (options.take("key-env"), options.take("key-file")) {
+                    (Some(var), None) => KeySource::Env(var),
+                    (None, Some(path)) => KeySource::File(path),
+                    _ => return Err(eyre!("Give exactly one of `--key-env` and `--key-file`")),
+                };
+                let router = match (options.take("router"), config.execution.router) {
+                    (Some(router), _) => resolve_address(network, &router)?,
+                    (None, Some(router)) => router,
+                    (None, None) => default_dex(network)?.router,
+                };
+                Command::Execute {
+                    factory,
+                    router,
+                    path: resolve_path(network, &options.required("path")?)?,
+                    amount: options.amount("amount")?,
+                    epsilon: options.amount("epsilon")?.unwrap_or(config.search.epsilon),
+                    key,
+                    reset_fork: options.flag("reset-fork"),
+                }
+            }
+            "export" => Command::Export {
+                output: options.required("output")?,
+                discover: Self::parse_discover(factory, &mut options, config, network)?,
+            },
+            "serve" => Command::Serve {
+                factory,
+                fee,
+                market: options.flag("market"),
+                listen: options.parsed("listen")?.unwrap_or(config.server.listen),
+                filters: options.take("filters"),
+            },
+            _ => return Err(eyre!("Unknown command `{}`", name)),
+        };
+        options.finish()?;
+        Ok(command)
+    }
+
+    fn parse_discover(
+        factory: H160,
+        options: &mut Options,
+        config: &Config,
+        network: &Network,
+    ) -> Result<DiscoverArgs> {
+        let defaults = &config.search;
+        Ok(DiscoverArgs {
+            factory,
+            market: options.flag("market"),
+            token: resolve_address(network, &options.required("token")?)?,
+            filters: options.take("filters"),
+            search: SearchConfig {
+                epsilon: options.amount("epsilon")?.unwrap_or(defaults.epsilon),
+                max_amount_in: defaults.max_amount_in,
+                min_path_length: options
+                    .parsed("min-length")?
+                    .unwrap_or(defaults.min_path_length),
+                max_path_length: options
+                    .parsed("max-length")?
+                    .unwrap_or(defaults.max_path_length),
+            },
+            limit: options.parsed("limit")?,
+        })
+    }
+}
+
+/// Pool of every ordered token pair, the one holding the most WETH when a pair has several.
+pub fn pool_map<'a>(pools: &[&'a UniswapV2Pool]) -> HashMap<(H160, H160), &'a UniswapV2Pool> {
+    let mut map: HashMap<(H160, H160), &UniswapV2Pool> = HashMap::new();
+    for pool in pools {
+        for pair in [(pool.token_a, pool.token_b), (pool.token_b, pool.token_a)] {
+            let current = map.entry(pair).or_insert(pool);
+            if pool.eth_value > current.eth_value {
+                *current = pool;
+            }
+        }
+    }
+    map
+}
+
+/// Pools along a token path, or the first hop without a pool.
+pub fn pools_along_path(
+    path: &[H160],
+    pools: &HashMap<(H160, H160), &UniswapV2Pool>,
+) -> Result<Vec<UniswapV2Pool>> {
+    path.windows(2)
+        .map(|hop| {
+            pools
+                .get(&(hop[0], hop[1]))
+                .map(|pool| (*pool).clone())
+                .ok_or_else(|| eyre!("No pool for {:?} -> {:?}", hop[0], hop[1]))
+        })
+        .collect()
+}
+
+/// Minimum WETH in a pair for it to price a token, one WETH.
+pub fn weth_threshold() -> U256 {
+    U256::exp10(18)
+}
+
+/// Pools of the checkpoint that pass `pipeline`, valued in `weth` and optionally in dollars
+/// through `usd`.
+pub fn filter_checkpoint<'a>(
+    checkpoint: &'a Checkpoint<Vec<UniswapV2Pool>>,
+    pipeline: &FilterPipeline,
+    weth: H160,
+    usd: Option<H160>,
+) -> Result<(Vec<&'a UniswapV2Pool>, Vec<StageReport>)> {
+    let valuation = Valuation::from_pools(&checkpoint.data, weth, weth_threshold(), usd);
+    let context = FilterContext {
+        valuation: Some(&valuation),
+        current_block: checkpoint.last_block,
+        ..Default::default()
+    };
+    pipeline.apply(checkpoint.data.iter().collect(), &context)
+}
+
+/// Simulates `path` at its best amount in, in the other direction if it is not profitable.
+pub fn simulate_cycle(token: H160, path: Vec<UniswapV2Pool>, search: &SearchConfig) -> Simulation {
+    let mut simulation = Simulation::with_search(token, path, search);
+    // Paths are unique up to direction, so try the other one too.
+    if simulation.profit().is_zero() {
+        simulation.reversed();
+    }
+    simulation
+}
+
+/// Every cycle through `token` over `pools`, simulated with `simulate_cycle`.
+pub fn simulate_cycles(
+    pools: &[&UniswapV2Pool],
+    token: H160,
+    search: &SearchConfig,
+) -> Vec<Simulation> {
+    let map = pool_map(pools);
+    let mut tokens_map: HashMap<&H160, Vec<&H160>> = HashMap::new();
+    for (token_in, token_out) in map.keys() {
+        tokens_map.entry(token_in).or_default().push(token_out);
+    }
+    get_all_token_paths(
+        &token,
+        &tokens_map,
+        search.min_path_length,
+        search.max_path_length,
+    )
+    .into_iter()
+    .filter_map(|path| {
+        let path: Vec<H160> = path.into_iter().copied().collect();
+        let pools = pools_along_path(&path, &map).ok()?;
+        Some(simulate_cycle(token, pools, search))
+    })
+    .collect()
+}
+
+/// `simulation` with its gas priced at `gas_price` in its token, if it is still profitable after
+/// gas. Tokens that cannot be priced from `weth` through `pools` give `None`.
+pub fn price_gas(
+    mut simulation: Simulation,
+    gas_price: &GasPrice,
+    weth: H160,
+    pools: &[&UniswapV2Pool],
+) -> Option<Simulation> {
+    if simulation.profit().is_zero() {
+        return None;
+    }
+    simulation.set_gas_cost(gas_price, weth, pools)?;
+    (!simulation.net_profit().is_zero()).then_some(simulation)
+}
+
+/// Cycles through `token` over `pools`, each simulated at its best amount in the profitable
+/// direction, from the most to the least profitable after gas at `gas_price`. Cycles that do
+/// not pay for their gas are dropped.
+pub fn discover_cycles(
+    pools: &[&UniswapV2Pool],
+    token: H160,
+    search: &SearchConfig,
+    gas_price: &GasPrice,
+    weth: H160,
+) -> Vec<Simulation> {
+    let mut simulations: Vec<Simulation> = simulate_cycles(pools, token, search)
+        .into_iter()
+        .filter_map(|simulation| price_gas(simulation, gas_price, weth, pools))
+        .collect();
+    metrics().record_opportunities(
+        simulations
+            .iter()
+            .filter_map(|s| token_to_weth(s.net_profit(), s.token, weth, pools)),
+    );
+    sort_by_net_profit(&mut simulations);
+    simulations
+}
+
+/// A simulation with amounts as decimal strings.
+pub fn simulation_to_json(simulation: &Simulation) -> Value {
+    json!({
+        "token": simulation.token,
+        "path": simulation.get_erc20_path(),
+        "pools": simulation.path.iter().map(|p| p.address).collect::<Vec<_>>(),
+        "amount_in": simulation.amount_in.to_string(),
+        "amount_out": simulation.amount_out.to_string(),
+        "amount_path": simulation.amount_path.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+        "profit": simulation.profit().to_string(),
+        "gas_cost": simulation.gas_cost.to_string(),
+        "net_profit": simulation.net_profit().to_string(),
+    })
+}
+
+pub fn write_simulations_to_json(simulations: &[Simulation], file_path: &str) -> Result<()> {
+    let simulations: Vec<Value> = simulations.iter().map(simulation_to_json).collect();
+    fs::write(file_path, serde_json::to_string_pretty(&simulations)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn address(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    #[test]
+    fn test_parse() {
+        let book = AddressBook::new();
+        let weth = book.mainnet().erc20["weth"];
+        let cli = Cli::parse(args("discover --token WETH --max-length=5 --epsilon 1e6")).unwrap();
+        assert_eq!(cli.rpc, Rpc::Local);
+        assert_eq!(
+            cli.command,
+            Command::Discover(DiscoverArgs {
+                factory: book.mainnet().dexes["uniswap_v2"].factory,
+                market: false,
+                token: weth,
+                filters: None,
+                search: SearchConfig {
+                    epsilon: U256::exp10(6),
+                    max_path_length: 5,
+                    ..Default::default()
+                },
+                limit: None,
+            })
+        );
+
+        let cli = Cli::parse(args("serve --market")).unwrap();
+        assert!(matches!(cli.command, Command::Serve { market: true, .. }));
+
+        let cli = Cli::parse(args(
+            "--rpc http://127.0.0.1:8545 execute --path weth,usdc,weth --key-env KEY --amount 1000",
+        ))
+        .unwrap();
+        assert!(cli.rpc.is_local());
+        match cli.command {
+            Command::Execute {
+                path, key, amount, ..
+            } => {
+                assert_eq!(path, vec![weth, book.mainnet().erc20["usdc"], weth]);
+                assert_eq!(key, KeySource::Env("KEY".to_string()));
+                assert_eq!(amount, Some(U256::from(1000)));
+            }
+            command => panic!("Unexpected command {:?}", command),
+        }
+
+        let error = |line: &str| Cli::parse(args(line)).unwrap_err().to_string();
+        assert_eq!(
+            error("quote --path weth"),
+            "A path needs at least two tokens"
+        );
+        assert_eq!(error("pools --limit"), "Option `--limit` needs a value");
+        assert_eq!(error("sync --on-chain"), "Unknown option `--on-chain`");
+        assert_eq!(
+            error("execute --path weth,usdc"),
+            "Give exactly one of `--key-env` and `--key-file`"
+        );
+        assert!(error("--config missing.yaml pools").starts_with("could not read config"));
+    }
+
+    #[test]
+    fn test_discover_cycles() {
+        let pool = |n: u64, token_a: u64, token_b: u64, reserve_0: u128, reserve_1: u128| {
+            UniswapV2Pool::new(
+                address(n),
+                address(token_a),
+                18,
+                address(token_b),
+                18,
+                reserve_0,
+                reserve_1,
+                300,
+                U256::from(n),
+            )
+        };
+        let e18 = 10u128.pow(18);
+        let pools = [
+            pool(10, 1, 2, 100 * e18, 200 * e18),
+            pool(11, 2, 3, 200 * e18, 200 * e18),
+            // Token 3 is cheap here, and a shallower pair is ignored
+            pool(12, 3, 1, 200 * e18, 110 * e18),
+            pool(5, 3, 1, e18, e18),
+        ];
+        let pools: Vec<&UniswapV2Pool> = pools.iter().collect();
+        let search = SearchConfig::default();
+        let simulations = discover_cycles(
+            &pools,
+            address(1),
+            &search,
+            &GasPrice::default(),
+            address(1),
+        );
+        assert_eq!(simulations.len(), 1);
+        let simulation = &simulations[0];
+        assert_eq!(
+            simulation
+                .path
+                .iter()
+                .map(|p| p.address)
+                .collect::<Vec<_>>(),
+            vec![address(10), address(11), address(12)]
+        );
+        assert!(!simulation.profit().is_zero());
+        assert_eq!(
+            simulation_to_json(simulation)["profit"],
+            simulation.profit().to_string()
+        );
+
+        // Gas priced in token 1 above the profit of the cycle drops it.
+        let expensive = GasPrice::new(simulation.profit(), U256::zero());
+        assert!(discover_cycles(&pools, address(1), &search, &expensive, address(1)).is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::metrics::metrics;
 use ethers::providers::Middleware;
 use futures::future;
 use indicatif::ProgressBar;
@@ -5,6 +6,8 @@ use std::collections::HashMap;
 use std::future::Future;
 use std::hash::Hash;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::{debug, debug_span, field, info, info_span, warn, Instrument, Span};
 
 #[derive(Debug)]
 pub struct BatchError {
@@ -18,6 +21,36 @@ impl BatchError {
     }
 }
 
+/// Runs the batch `start..end` in a `batch` span, logging its latency and whether it failed.
+fn batch<Fut, T>(start: usize, end: usize, fut: Fut) -> impl Future<Output = Result<T, BatchError>>
+where
+    Fut: Future<Output = Result<T, BatchError>>,
+{
+    async move {
+        let started = Instant::now();
+        let result = fut.await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok(_) => debug!(latency_ms, "batch done"),
+            Err(_) => warn!(latency_ms, "batch failed"),
+        }
+        result
+    }
+    .instrument(debug_span!("batch", start, end, size = end - start))
+}
+
+/// Span of a `run_concurrent` call, whose `retries` are the items retried one by one.
+fn run_span(start: usize, end: usize, step: usize) -> Span {
+    info_span!(
+        "run_concurrent",
+        start,
+        end,
+        step,
+        batches = (end - start).div_ceil(step.max(1)),
+        retries = field::Empty,
+    )
+}
+
 pub async fn run_concurrent<'a, F, Fut, V, M>(
     start: usize,
     end: usize,
@@ -31,34 +64,45 @@ where
     V: Send + 'a,
     M: Middleware + 'a,
 {
+    let span = run_span(start, end, step);
+    let started = Instant::now();
     let size = end - start;
     let pb = ProgressBar::new(size as u64);
     let shared_pb = Arc::new(Mutex::new(pb));
     let mut futures: Vec<_> = vec![];
 
     for i in (start..end).step_by(step) {
-        futures.push(func(
+        let batch_end = (i + step).min(end);
+        futures.push(batch(
             i,
-            (i + step).min(end),
-            middleware.clone(),
-            Some(shared_pb.clone()),
+            batch_end,
+            func(i, batch_end, middleware.clone(), Some(shared_pb.clone())),
         ));
     }
-    let results = future::join_all(futures).await;
+    let results = future::join_all(futures).instrument(span.clone()).await;
+    let mut retries = 0;
     let mut combined_results = vec![];
 
     for result in results {
         match result {
             Ok(data) => combined_results.extend(data),
             Err(err) => {
-                println!(
-                    "Failed to get results from {} to end {} trying with step 1.",
-                    err.start, err.end
-                );
-                let futures = (err.start..err.end)
-                    .into_iter()
-                    .map(|idx| func(idx, idx, middleware.clone(), Some(shared_pb.clone())));
-                let results = future::join_all(futures).await;
+                span.in_scope(|| {
+                    warn!(
+                        from = err.start,
+                        to = err.end,
+                        "retrying failed batch with step 1"
+                    )
+                });
+                retries += err.end - err.start;
+                let futures = (err.start..err.end).map(|idx| {
+                    batch(
+                        idx,
+                        idx,
+                        func(idx, idx, middleware.clone(), Some(shared_pb.clone())),
+                    )
+                });
+                let results = future::join_all(futures).instrument(span.clone()).await;
                 for result in results {
                     if let Ok(result) = result {
                         combined_results.extend(result);
@@ -67,6 +111,15 @@ where
             }
         }
     }
+    span.record("retries", retries);
+    metrics().add_batch_retries(retries);
+    span.in_scope(|| {
+        info!(
+            results = combined_results.len(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "run_concurrent done"
+        )
+    });
     combined_results
 }
 
@@ -84,21 +137,24 @@ where
     V: Send + 'a,
     M: Middleware + 'a,
 {
+    let span = run_span(start, end, step);
+    let started = Instant::now();
     let size = end - start;
     let pb = ProgressBar::new(size as u64);
     let shared_pb = Arc::new(Mutex::new(pb));
     let mut futures: Vec<_> = vec![];
 
     for i in (start..end).step_by(step) {
-        futures.push(func(
+        let batch_end = (i + step).min(end);
+        futures.push(batch(
             i,
-            (i + step).min(end),
-            middleware.clone(),
-            Some(shared_pb.clone()),
+            batch_end,
+            func(i, batch_end, middleware.clone(), Some(shared_pb.clone())),
         ));
     }
 
-    let results = future::join_all(futures).await;
+    let results = future::join_all(futures).instrument(span.clone()).await;
+    let mut retries = 0;
     let mut combined_results = HashMap::new();
 
     for result in results {
@@ -107,14 +163,22 @@ where
                 combined_results.insert(k, v);
             }),
             Err(err) => {
-                println!(
-                    "Failed to get results from {} to end {} trying with step 1.",
-                    err.start, err.end
-                );
-                let futures = (err.start..err.end)
-                    .into_iter()
-                    .map(|idx| func(idx, idx, middleware.clone(), Some(shared_pb.clone())));
-                let results = future::join_all(futures).await;
+                span.in_scope(|| {
+                    warn!(
+                        from = err.start,
+                        to = err.end,
+                        "retrying failed batch with step 1"
+                    )
+                });
+                retries += err.end - err.start;
+                let futures = (err.start..err.end).map(|idx| {
+                    batch(
+                        idx,
+                        idx,
+                        func(idx, idx, middleware.clone(), Some(shared_pb.clone())),
+                    )
+                });
+                let results = future::join_all(futures).instrument(span.clone()).await;
                 for res in results {
                     if let Ok(res) = res {
                         if let Some((k, v)) = res.into_iter().next() {
@@ -125,5 +189,14 @@ where
             }
         }
     }
+    span.record("retries", retries);
+    metrics().add_batch_retries(retries);
+    span.in_scope(|| {
+        info!(
+            results = combined_results.len(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "run_concurrent done"
+        )
+    });
     combined_results
 }
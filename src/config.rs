@@ -0,0 +1,677 @@
+use crate::{
+    checkpoint::{
+        reconcile::Reconciler,
+        storage::{self, Encoding, FileStorage, DEFAULT_CHECKPOINT_DIR},
+    },
+    filters::FilterPipeline,
+};
+use ethers::types::{H160, U256};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet, env, fmt, fs, net::SocketAddr, path::Path, str::FromStr, sync::Arc,
+};
+
+/// Items per batch request and log query, the `step` of `run_concurrent`.
+pub const DEFAULT_STEP: usize = 100;
+/// Uniswap V2 fee in hundredths of a basis point, 0.3%.
+pub const DEFAULT_FEE: u32 = 300;
+/// File loaded by `Config::from_env` when set.
+pub const CONFIG_ENV: &str = "ETH_AMM_CONFIG";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: String,
+        err: std::io::Error,
+    },
+    Parse {
+        path: String,
+        reason: String,
+    },
+    Env {
+        var: String,
+        reason: String,
+    },
+    /// Every problem found by `validate`.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, err } => write!(f, "could not read config {}: {}", path, err),
+            ConfigError::Parse { path, reason } => {
+                write!(f, "could not parse config {}: {}", path, reason)
+            }
+            ConfigError::Env { var, reason } => write!(f, "invalid env `{}`: {}", var, reason),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid config: {}", problems.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Whole token amount in its smallest unit, written as an integer, a decimal string or in
+/// scientific notation such as `1e18`.
+pub fn parse_amount(value: &str) -> Result<U256, String> {
+    let invalid = || format!("`{}` is not a whole amount", value);
+    let (mantissa, exponent) = match value.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<u32>().map_err(|_| invalid())?),
+        None => (value, 0),
+    };
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let fraction = fraction.trim_end_matches('0');
+    let exponent = exponent
+        .checked_sub(fraction.len() as u32)
+        .ok_or_else(invalid)?;
+    let digits = format!("{}{}", integer, fraction);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    U256::from_dec_str(&digits)
+        .ok()
+        .and_then(|n| n.checked_mul(U256::from(10).checked_pow(exponent.into())?))
+        .ok_or_else(|| format!("`{}` does not fit in 256 bits", value))
+}
+
+/// Amounts as decimal strings, since YAML and TOML integers stop at 64 bits.
+mod amount {
+    use super::parse_amount;
+    use ethers::types::U256;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Amount {
+        Integer(u64),
+        Float(f64),
+        String(String),
+    }
+
+    pub fn serialize<S: Serializer>(amount: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&amount.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<U256, D::Error> {
+        match Amount::deserialize(deserializer)? {
+            Amount::Integer(n) => Ok(U256::from(n)),
+            Amount::Float(f) => parse_amount(&f.to_string()).map_err(D::Error::custom),
+            Amount::String(s) => parse_amount(&s).map_err(D::Error::custom),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProviderConfig {
+    /// HTTP endpoint, `ALCHEMY_RPC` when not configured.
+    pub http: String,
+    /// WebSocket endpoint, `ALCHEMY_WSS` when not configured.
+    pub wss: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChainConfig {
+    pub chain_id: u64,
+    /// Address book file, the embedded one when not set.
+    pub address_book: Option<String>,
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        ChainConfig {
+            chain_id: 1,
+            address_book: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FactoryConfig {
+    pub name: String,
+    pub address: H160,
+    #[serde(default = "default_fee")]
+    pub fee: u32,
+}
+
+fn default_fee() -> u32 {
+    DEFAULT_FEE
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckpointConfig {
+    pub dir: String,
+    pub encoding: Encoding,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        CheckpointConfig {
+            dir: DEFAULT_CHECKPOINT_DIR.to_string(),
+            encoding: Encoding::Json,
+        }
+    }
+}
+
+impl CheckpointConfig {
+    pub fn storage(&self) -> FileStorage {
+        FileStorage::new(&self.dir, self.encoding)
+    }
+
+    /// Makes every `load_data` and `save_data` use this directory and encoding.
+    pub fn install(&self) {
+        storage::install(Arc::new(self.storage()));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConcurrencyConfig {
+    pub step: usize,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        ConcurrencyConfig { step: DEFAULT_STEP }
+    }
+}
+
+/// Bounds of the cycle search and of the ternary search for the best amount in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchConfig {
+    /// Precision of the best amount in.
+    #[serde(with = "amount")]
+    pub epsilon: U256,
+    /// Largest amount in tried.
+    #[serde(with = "amount")]
+    pub max_amount_in: U256,
+    /// Path lengths count the start token twice, a triangle has length 4.
+    pub min_path_length: usize,
+    pub max_path_length: usize,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        SearchConfig {
+            epsilon: U256::exp10(4),
+            max_amount_in: U256::exp10(20),
+            min_path_length: 3,
+            max_path_length: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExecutionConfig {
+    pub router: Option<H160>,
+    /// Largest amount in sent in a single swap.
+    #[serde(with = "amount")]
+    pub max_amount_in: U256,
+    /// Smallest expected profit worth sending a transaction for.
+    #[serde(with = "amount")]
+    pub min_profit: U256,
+}
+
+impl Default for ExecutionConfig {
+    fn default() -> Self {
+        ExecutionConfig {
+            router: None,
+            max_amount_in: U256::exp10(18),
+            min_profit: U256::zero(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address to serve Prometheus metrics on at `/metrics`, e.g. `127.0.0.1:9100`. Nothing is
+    /// served when not set.
+    pub listen: Option<SocketAddr>,
+}
+
+/// Periodic re-reading of the reserves of the synced pools, repairing those that drifted from
+/// their `Sync` logs, in `sync --watch`, `serve` and the opportunity stream.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconcileConfig {
+    /// Blocks between two reconciliations. Reserves are never reconciled when not set.
+    pub interval: Option<u64>,
+    /// Pools re-read per reconciliation, picked at random. Every pool when not set.
+    pub sample: Option<usize>,
+}
+
+impl ReconcileConfig {
+    /// A reconciler reading reserves in batches of `step`, if reconciliation is enabled.
+    pub fn reconciler(&self, step: usize) -> Option<Reconciler> {
+        self.interval
+            .map(|interval| Reconciler::new(interval, self.sample, step))
+    }
+}
+
+/// Quote service of the `serve` command.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: SocketAddr,
+    /// Seconds between two syncs of the served checkpoint.
+    pub sync_interval: u64,
+    /// Most swaps in a quoted route.
+    pub max_hops: usize,
+    /// Tokens arbitrage cycles start from, the network's wrapped native token when empty.
+    pub base_tokens: Vec<H160>,
+    /// Address to stream opportunities on over WebSocket as Sync events arrive. Nothing is
+    /// streamed when not set.
+    pub stream_listen: Option<SocketAddr>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            sync_interval: 12,
+            max_hops: 3,
+            base_tokens: vec![],
+            stream_listen: None,
+        }
+    }
+}
+
+/// Settings of the library and the command line tool. Layered as defaults, then a YAML or
+/// TOML file where every field is optional, then environment variables.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub provider: ProviderConfig,
+    pub chain: ChainConfig,
+    /// Factories to sync, the address book's Uniswap V2 factory when empty.
+    pub factories: Vec<FactoryConfig>,
+    pub checkpoint: CheckpointConfig,
+    pub concurrency: ConcurrencyConfig,
+    /// Pipeline applied when a command is not given its own.
+    pub filters: FilterPipeline,
+    pub search: SearchConfig,
+    pub execution: ExecutionConfig,
+    pub metrics: MetricsConfig,
+    pub server: ServerConfig,
+    pub reconcile: ReconcileConfig,
+}
+
+fn integer<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("`{}` is not an integer", value))
+}
+
+fn socket_address(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse()
+        .map_err(|_| format!("`{}` is not a socket address", value))
+}
+
+impl Config {
+    /// Parses YAML, or TOML if `path` ends in `.toml`.
+    pub fn parse(data: &str, path: &str) -> Result<Self, ConfigError> {
+        let parse_error = |reason: String| ConfigError::Parse {
+            path: path.to_string(),
+            reason,
+        };
+        if path.ends_with(".toml") {
+            toml::from_str(data).map_err(|e| parse_error(e.to_string()))
+        } else {
+            serde_yaml::from_str(data).map_err(|e| parse_error(e.to_string()))
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let data = fs::read_to_string(path).map_err(|err| ConfigError::Io {
+            path: path.to_string(),
+            err,
+        })?;
+        Self::parse(&data, path)
+    }
+
+    /// Defaults, or the file given (or in `ETH_AMM_CONFIG`), with the environment applied on
+    /// top and validated.
+    pub fn load(path: Option<&str>) -> Result<Self, ConfigError> {
+        let path = path
+            .map(str::to_string)
+            .or_else(|| env::var(CONFIG_ENV).ok());
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.apply_env(|var| env::var(var).ok().filter(|v| !v.is_empty()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::load(None)
+    }
+
+    /// Overrides settings from variables looked up with `var`:
+    ///
+    /// - `ALCHEMY_RPC`, `ALCHEMY_WSS`: provider endpoints
+    /// - `ETH_AMM_CHAIN_ID`, `ETH_AMM_ADDRESS_BOOK`
+    /// - `CHECKPOINT_DIR`, `CHECKPOINT_ENCODING` (`json` or `zstd`)
+    /// - `ETH_AMM_STEP`
+    /// - `ETH_AMM_EPSILON`, `ETH_AMM_MAX_AMOUNT_IN`
+    /// - `ETH_AMM_EXECUTION_MAX_AMOUNT_IN`, `ETH_AMM_MIN_PROFIT`
+    /// - `ETH_AMM_METRICS_LISTEN`, `ETH_AMM_SERVER_LISTEN`, `ETH_AMM_STREAM_LISTEN`
+    /// - `ETH_AMM_RECONCILE_INTERVAL`, `ETH_AMM_RECONCILE_SAMPLE`
+    pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<(), ConfigError> {
+        fn set<T, F: Fn(&str) -> Option<String>>(
+            var: &F,
+            name: &str,
+            target: &mut T,
+            parse: impl Fn(&str) -> Result<T, String>,
+        ) -> Result<(), ConfigError> {
+            if let Some(value) = var(name) {
+                *target = parse(&value).map_err(|reason| ConfigError::Env {
+                    var: name.to_string(),
+                    reason,
+                })?;
+            }
+            Ok(())
+        }
+        let string = |v: &str| Ok(v.to_string());
+        set(&var, "ALCHEMY_RPC", &mut self.provider.http, string)?;
+        set(&var, "ALCHEMY_WSS", &mut self.provider.wss, string)?;
+        set(&var, "ETH_AMM_CHAIN_ID", &mut self.chain.chain_id, integer)?;
+        set(
+            &var,
+            "ETH_AMM_ADDRESS_BOOK",
+            &mut self.chain.address_book,
+            |v| Ok(Some(v.to_string())),
+        )?;
+        set(&var, "CHECKPOINT_DIR", &mut self.checkpoint.dir, string)?;
+        set(
+            &var,
+            "CHECKPOINT_ENCODING",
+            &mut self.checkpoint.encoding,
+            |v| match v {
+                "json" => Ok(Encoding::Json),
+                "zstd" => Ok(Encoding::JsonZstd),
+                _ => Err(format!("`{}` is neither `json` nor `zstd`", v)),
+            },
+        )?;
+        set(&var, "ETH_AMM_STEP", &mut self.concurrency.step, integer)?;
+        set(
+            &var,
+            "ETH_AMM_EPSILON",
+            &mut self.search.epsilon,
+            parse_amount,
+        )?;
+        set(
+            &var,
+            "ETH_AMM_MAX_AMOUNT_IN",
+            &mut self.search.max_amount_in,
+            parse_amount,
+        )?;
+        set(
+            &var,
+            "ETH_AMM_EXECUTION_MAX_AMOUNT_IN",
+            &mut self.execution.max_amount_in,
+            parse_amount,
+        )?;
+        set(
+            &var,
+            "ETH_AMM_MIN_PROFIT",
+            &mut self.execution.min_profit,
+            parse_amount,
+        )?;
+        set(
+            &var,
+            "ETH_AMM_METRICS_LISTEN",
+            &mut self.metrics.listen,
+            |v| socket_address(v).map(Some),
+        )?;
+        set(
+            &var,
+            "ETH_AMM_SERVER_LISTEN",
+            &mut self.server.listen,
+            socket_address,
+        )?;
+        set(
+            &var,
+            "ETH_AMM_STREAM_LISTEN",
+            &mut self.server.stream_listen,
+            |v| socket_address(v).map(Some),
+        )?;
+        set(
+            &var,
+            "ETH_AMM_RECONCILE_INTERVAL",
+            &mut self.reconcile.interval,
+            |v| integer(v).map(Some),
+        )?;
+        set(
+            &var,
+            "ETH_AMM_RECONCILE_SAMPLE",
+            &mut self.reconcile.sample,
+            |v| integer(v).map(Some),
+        )?;
+        Ok(())
+    }
+
+    /// Checks every setting, reporting all problems at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = vec![];
+        let mut check = |ok: bool, problem: String| {
+            if !ok {
+                problems.push(problem);
+            }
+        };
+        let http = &self.provider.http;
+        check(
+            http.is_empty() || http.starts_with("http://") || http.starts_with("https://"),
+            format!("provider.http `{}` is not an http(s) url", http),
+        );
+        let wss = &self.provider.wss;
+        check(
+            wss.is_empty() || wss.starts_with("ws://") || wss.starts_with("wss://"),
+            format!("provider.wss `{}` is not a ws(s) url", wss),
+        );
+        check(
+            self.chain.chain_id > 0,
+            "chain.chain_id must be positive".to_string(),
+        );
+        if let Some(address_book) = &self.chain.address_book {
+            check(
+                Path::new(address_book).is_file(),
+                format!("chain.address_book `{}` does not exist", address_book),
+            );
+        }
+        let mut addresses = HashSet::new();
+        for factory in &self.factories {
+            check(
+                addresses.insert(factory.address),
+                format!("factory {:?} is listed twice", factory.address),
+            );
+            check(
+                factory.fee < 10_000,
+                format!(
+                    "factory {} has fee {}, above 100%",
+                    factory.name, factory.fee
+                ),
+            );
+        }
+        check(
+            !self.checkpoint.dir.is_empty(),
+            "checkpoint.dir is empty".to_string(),
+        );
+        check(
+            self.concurrency.step > 0,
+            "concurrency.step must be positive".to_string(),
+        );
+        let search = &self.search;
+        check(
+            !search.epsilon.is_zero(),
+            "search.epsilon must be positive".to_string(),
+        );
+        check(
+            search.max_amount_in > search.epsilon,
+            "search.max_amount_in must be above search.epsilon".to_string(),
+        );
+        // The ternary search runs on f64, which also bounds `simulate_swap_offline`'s u128.
+        check(
+            search.max_amount_in <= U256::from(u128::MAX),
+            "search.max_amount_in must fit in 128 bits".to_string(),
+        );
+        check(
+            search.min_path_length >= 3,
+            "search.min_path_length must be at least 3".to_string(),
+        );
+        check(
+            search.min_path_length <= search.max_path_length,
+            "search.min_path_length is above search.max_path_length".to_string(),
+        );
+        check(
+            !self.execution.max_amount_in.is_zero(),
+            "execution.max_amount_in must be positive".to_string(),
+        );
+        check(
+            self.server.sync_interval > 0,
+            "server.sync_interval must be positive".to_string(),
+        );
+        check(
+            self.server.max_hops > 0,
+            "server.max_hops must be positive".to_string(),
+        );
+        check(
+            self.reconcile.interval != Some(0),
+            "reconcile.interval must be positive".to_string(),
+        );
+        check(
+            self.reconcile.sample != Some(0),
+            "reconcile.sample must be positive".to_string(),
+        );
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// Address and fee of the named factory, or of the factory at that address.
+    pub fn factory(&self, name_or_address: &str) -> Option<&FactoryConfig> {
+        self.factories.iter().find(|f| {
+            f.name == name_or_address
+                || format!("{:?}", f.address) == name_or_address.to_lowercase()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!(parse_amount("1000"), Ok(U256::from(1000)));
+        assert_eq!(parse_amount("1e18"), Ok(U256::exp10(18)));
+        assert_eq!(parse_amount("2.5e3"), Ok(U256::from(2500)));
+        assert_eq!(parse_amount("100000000000000000000"), Ok(U256::exp10(20)));
+        assert!(parse_amount("2.5").is_err());
+        assert!(parse_amount("0x10").is_err());
+        assert!(parse_amount("1e100").is_err());
+    }
+
+    #[test]
+    fn test_layers() {
+        let yaml = r#"
+chain:
+  chain_id: 1
+factories:
+  - name: sushiswap
+    address: "0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac"
+search:
+  max_amount_in: 1e19
+  max_path_length: 5
+filters:
+  stages:
+    - non_zero_reserves
+"#;
+        let mut config = Config::parse(yaml, "config.yaml").unwrap();
+        let toml = r#"
+[[factories]]
+name = "sushiswap"
+address = "0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac"
+
+[search]
+max_amount_in = "1e19"
+max_path_length = 5
+
+[filters]
+stages = ["non_zero_reserves"]
+"#;
+        assert_eq!(Config::parse(toml, "config.toml").unwrap(), config);
+        assert_eq!(config.search.max_amount_in, U256::exp10(19));
+        assert_eq!(config.search.epsilon, U256::exp10(4));
+        assert_eq!(config.factory("sushiswap").unwrap().fee, DEFAULT_FEE);
+        assert_eq!(config.concurrency.step, DEFAULT_STEP);
+
+        let vars = HashMap::from([
+            ("ALCHEMY_RPC", "https://eth.example"),
+            ("CHECKPOINT_ENCODING", "zstd"),
+            ("ETH_AMM_STEP", "50"),
+            ("ETH_AMM_METRICS_LISTEN", "127.0.0.1:9100"),
+            ("ETH_AMM_STREAM_LISTEN", "127.0.0.1:8081"),
+            ("ETH_AMM_RECONCILE_INTERVAL", "300"),
+        ]);
+        config
+            .apply_env(|var| vars.get(var).map(|v| v.to_string()))
+            .unwrap();
+        assert_eq!(config.provider.http, "https://eth.example");
+        assert_eq!(config.checkpoint.encoding, Encoding::JsonZstd);
+        assert_eq!(config.concurrency.step, 50);
+        assert_eq!(
+            config.metrics.listen,
+            Some(SocketAddr::from(([127, 0, 0, 1], 9100)))
+        );
+        assert_eq!(
+            config.server.stream_listen,
+            Some(SocketAddr::from(([127, 0, 0, 1], 8081)))
+        );
+        assert_eq!(config.reconcile.interval, Some(300));
+        assert_eq!(config.reconcile.reconciler(10).unwrap().sample_size, None);
+        config.validate().unwrap();
+
+        let error = Config::parse("search:\n  epsilon_: 1", "config.yaml").unwrap_err();
+        assert!(error.to_string().contains("unknown field `epsilon_`"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::default();
+        config.provider.http = "localhost:8545".to_string();
+        config.search.min_path_length = 6;
+        config.concurrency.step = 0;
+        match config.validate() {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(
+                problems,
+                vec![
+                    "provider.http `localhost:8545` is not an http(s) url",
+                    "concurrency.step must be positive",
+                    "search.min_path_length is above search.max_path_length",
+                ]
+            ),
+            result => panic!("Unexpected {:?}", result),
+        }
+        let error = Config::default()
+            .apply_env(|_| Some("x".to_string()))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid env `ETH_AMM_CHAIN_ID`: `x` is not an integer"
+        );
+    }
+}
@@ -10,8 +10,13 @@ abigen!(
         function token0() external view returns (address)
         function token1() external view returns (address)
         function factory() external view returns (address)
+        function price0CumulativeLast() external view returns (uint256)
+        function price1CumulativeLast() external view returns (uint256)
         function swap(uint256 amount0Out, uint256 amount1Out, address to, bytes calldata data);
         event Sync(uint112 reserve0, uint112 reserve1)
+        event Swap(address indexed sender, uint256 amount0In, uint256 amount1In, uint256 amount0Out, uint256 amount1Out, address indexed to)
+        event Mint(address indexed sender, uint256 amount0, uint256 amount1)
+        event Burn(address indexed sender, uint256 amount0, uint256 amount1, address indexed to)
     ]"#;
 
     IErc20,
@@ -40,7 +45,15 @@ abigen!(
 
     IUniswapRouter,
     r#"[
+        function swapExactTokensForTokens(uint amountIn, uint amountOutMin, address[] calldata path, address to, uint deadline) external returns (uint[] memory amounts)
+        function swapTokensForExactTokens(uint amountOut, uint amountInMax, address[] calldata path, address to, uint deadline) external returns (uint[] memory amounts)
         function swapExactETHForTokens(uint amountOutMin, address[] calldata path, address to, uint deadline) external payable returns (uint[] memory amounts)
+        function swapTokensForExactETH(uint amountOut, uint amountInMax, address[] calldata path, address to, uint deadline) external returns (uint[] memory amounts)
+        function swapExactTokensForETH(uint amountIn, uint amountOutMin, address[] calldata path, address to, uint deadline) external returns (uint[] memory amounts)
+        function swapETHForExactTokens(uint amountOut, address[] calldata path, address to, uint deadline) external payable returns (uint[] memory amounts)
+        function swapExactTokensForTokensSupportingFeeOnTransferTokens(uint amountIn, uint amountOutMin, address[] calldata path, address to, uint deadline) external
+        function swapExactETHForTokensSupportingFeeOnTransferTokens(uint amountOutMin, address[] calldata path, address to, uint deadline) external payable
+        function swapExactTokensForETHSupportingFeeOnTransferTokens(uint amountIn, uint amountOutMin, address[] calldata path, address to, uint deadline) external
         ]"#;
 
 );
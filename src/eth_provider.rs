@@ -1,22 +1,32 @@
-use std::{error::Error, sync::Arc};
+use std::{error::Error, str::FromStr, sync::Arc};
 
 use ethers::{
     middleware::SignerMiddleware,
-    providers::{Http, Middleware, Provider, Ws},
+    providers::{Http, Middleware, Provider, ProviderError, Ws},
     signers::LocalWallet,
     types::{H160, U256},
 };
 use serde_json::json;
 
+use crate::{
+    config::{ConfigError, ProviderConfig},
+    metrics::MeteredHttp,
+};
+
+/// HTTP provider whose requests are counted in `metrics()`.
+pub type HttpProvider = Provider<MeteredHttp>;
+
 pub struct EthProvider {
-    pub http: Arc<Provider<Http>>,
+    pub http: Arc<HttpProvider>,
     pub http_endpoint: String,
     pub wss_endpoint: String,
 }
 
 impl EthProvider {
-    async fn new(http_endpoint: String, wss_endpoint: String) -> EthProvider {
-        let http = Arc::new(Provider::<Http>::try_from(&http_endpoint).unwrap());
+    pub async fn new(http_endpoint: String, wss_endpoint: String) -> EthProvider {
+        let http = Arc::new(Provider::new(MeteredHttp(
+            Http::from_str(&http_endpoint).unwrap(),
+        )));
         EthProvider {
             http,
             http_endpoint,
@@ -36,6 +46,16 @@ impl EthProvider {
         Self::new(Self::alchemy_rpc(), Self::alchemy_wss()).await
     }
 
+    /// Endpoints of `config`, an error if no HTTP endpoint is configured.
+    pub async fn from_config(config: &ProviderConfig) -> Result<EthProvider, ConfigError> {
+        if config.http.is_empty() {
+            return Err(ConfigError::Invalid(vec![
+                "provider.http is not set, configure it or set ALCHEMY_RPC".to_string(),
+            ]));
+        }
+        Ok(Self::new(config.http.clone(), config.wss.clone()).await)
+    }
+
     pub async fn new_local() -> EthProvider {
         let rpc_endpoint = "http://localhost:8545".to_string();
         let wss_endpoint = "wss://localhost:8545".to_string();
@@ -55,7 +75,7 @@ impl EthProvider {
             "id": 1
         });
         client
-            .post(self.http.url().to_string())
+            .post(&self.http_endpoint)
             .json(&reset_payload)
             .send()
             .await?;
@@ -88,17 +108,20 @@ impl EthProvider {
     }
 
     pub async fn get_wss(&self) -> Arc<Provider<Ws>> {
-        Arc::new(
-            Provider::<Ws>::connect(&self.wss_endpoint)
-                .await
-                .expect("Could not connect to web socket."),
-        )
+        self.connect_wss()
+            .await
+            .expect("Could not connect to web socket.")
+    }
+
+    /// A new connection to the web socket endpoint.
+    pub async fn connect_wss(&self) -> Result<Arc<Provider<Ws>>, ProviderError> {
+        Ok(Arc::new(Provider::<Ws>::connect(&self.wss_endpoint).await?))
     }
 
     pub async fn get_signer_middleware(
         &self,
         private_key: &str,
-    ) -> Arc<SignerMiddleware<Arc<Provider<Http>>, LocalWallet>> {
+    ) -> Arc<SignerMiddleware<Arc<HttpProvider>, LocalWallet>> {
         let wallet = private_key
             .parse::<LocalWallet>()
             .expect("Could not parse private key.");
@@ -1,6 +1,17 @@
-use ethers::types::{H160, U256};
+use crate::amm::{
+    uniswap_v2::pool::{
+        analytics::PoolActivity, events::sync::SYNC_EVENT_SIGNATURE, UniswapV2Pool,
+    },
+    valuation::Valuation,
+};
+use ethers::types::{Log, H160, U256};
+use eyre::{eyre, Result};
 use itertools::Itertools;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+};
 
 pub fn filter_pools_for_eth_value(
     pools: Vec<H160>,
@@ -12,3 +23,277 @@ pub fn filter_pools_for_eth_value(
         .filter(|p| eth_value_in_pools.get(&p).unwrap_or(&U256::zero()) > &value)
         .collect_vec()
 }
+
+/// Block of the last `Sync` of every pool in `logs`.
+pub fn last_sync_blocks(logs: &[Log]) -> HashMap<H160, u64> {
+    let mut blocks = HashMap::new();
+    for log in logs {
+        if log.topics.first() != Some(&SYNC_EVENT_SIGNATURE) {
+            continue;
+        }
+        if let Some(block) = log.block_number {
+            let last = blocks.entry(log.address).or_insert(0);
+            *last = block.as_u64().max(*last);
+        }
+    }
+    blocks
+}
+
+/// Data some filters need besides the pool. A filter whose data is missing is an error, so a
+/// misconfigured pipeline fails loudly rather than removing or keeping every pool.
+#[derive(Default)]
+pub struct FilterContext<'a> {
+    pub valuation: Option<&'a Valuation>,
+    pub activity: Option<&'a HashMap<H160, PoolActivity>>,
+    pub last_sync_blocks: Option<&'a HashMap<H160, u64>>,
+    pub current_block: u64,
+    pub fee_on_transfer_tokens: Option<&'a HashSet<H160>>,
+}
+
+/// A predicate over pools. In a config file every filter is either a bare name, such as
+/// `non_zero_reserves`, or a single key map, such as `min_weth_value: 10.0`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolFilter {
+    /// `eth_value` of at least this many WETH.
+    MinWethValue(f64),
+    /// Value of both reserves in dollars, from the context's valuation.
+    MinUsdValue(f64),
+    /// Both tokens are in the list.
+    AllowTokens(HashSet<H160>),
+    /// Neither token is in the list.
+    DenyTokens(HashSet<H160>),
+    NonZeroDecimals,
+    NonZeroReserves,
+    /// At least this many swaps in the context's activity window.
+    MinSwaps(usize),
+    /// Synced within this many blocks of the context's current block.
+    MaxSyncAge(u64),
+    /// Neither token is a known fee-on-transfer token.
+    ExcludeFeeOnTransfer,
+    And(Vec<PoolFilter>),
+    Or(Vec<PoolFilter>),
+    Not(Box<PoolFilter>),
+}
+
+impl PoolFilter {
+    /// Whether `pool` passes, or an error if the filter needs data missing from `context`.
+    pub fn matches(&self, pool: &UniswapV2Pool, context: &FilterContext) -> Result<bool> {
+        let tokens = [pool.token_a, pool.token_b];
+        let missing = |data: &str| eyre!("{} needs {} in the filter context", self.name(), data);
+        Ok(match self {
+            PoolFilter::MinWethValue(value) => {
+                pool.eth_value.to_string().parse::<f64>().unwrap_or(0.0) >= value * 1e18
+            }
+            PoolFilter::MinUsdValue(value) => context
+                .valuation
+                .ok_or_else(|| missing("a valuation"))?
+                .usd_value(pool)
+                .is_some_and(|usd| usd >= *value),
+            PoolFilter::AllowTokens(allowed) => tokens.iter().all(|t| allowed.contains(t)),
+            PoolFilter::DenyTokens(denied) => !tokens.iter().any(|t| denied.contains(t)),
+            PoolFilter::NonZeroDecimals => pool.token_a_decimals > 0 && pool.token_b_decimals > 0,
+            PoolFilter::NonZeroReserves => pool.reserve_0 > 0 && pool.reserve_1 > 0,
+            PoolFilter::MinSwaps(swaps) => context
+                .activity
+                .ok_or_else(|| missing("pool activity"))?
+                .get(&pool.address)
+                .is_some_and(|a| a.swaps >= *swaps),
+            PoolFilter::MaxSyncAge(age) => context
+                .last_sync_blocks
+                .ok_or_else(|| missing("last sync blocks"))?
+                .get(&pool.address)
+                .is_some_and(|block| context.current_block.saturating_sub(*block) <= *age),
+            PoolFilter::ExcludeFeeOnTransfer => {
+                let fot = context
+                    .fee_on_transfer_tokens
+                    .ok_or_else(|| missing("fee-on-transfer tokens"))?;
+                !tokens.iter().any(|t| fot.contains(t))
+            }
+            PoolFilter::And(filters) => {
+                for filter in filters {
+                    if !filter.matches(pool, context)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            PoolFilter::Or(filters) => {
+                for filter in filters {
+                    if filter.matches(pool, context)? {
+                        return Ok(true);
+                    }
+                }
+                false
+            }
+            PoolFilter::Not(filter) => !filter.matches(pool, context)?,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        let join = |filters: &[PoolFilter]| filters.iter().map(|f| f.name()).join(", ");
+        match self {
+            PoolFilter::MinWethValue(value) => format!("min_weth_value({})", value),
+            PoolFilter::MinUsdValue(value) => format!("min_usd_value({})", value),
+            PoolFilter::AllowTokens(tokens) => format!("allow_tokens({})", tokens.len()),
+            PoolFilter::DenyTokens(tokens) => format!("deny_tokens({})", tokens.len()),
+            PoolFilter::NonZeroDecimals => "non_zero_decimals".to_string(),
+            PoolFilter::NonZeroReserves => "non_zero_reserves".to_string(),
+            PoolFilter::MinSwaps(swaps) => format!("min_swaps({})", swaps),
+            PoolFilter::MaxSyncAge(age) => format!("max_sync_age({})", age),
+            PoolFilter::ExcludeFeeOnTransfer => "exclude_fee_on_transfer".to_string(),
+            PoolFilter::And(filters) => format!("and({})", join(filters)),
+            PoolFilter::Or(filters) => format!("or({})", join(filters)),
+            PoolFilter::Not(filter) => format!("not({})", filter.name()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageReport {
+    pub stage: String,
+    pub input: usize,
+    pub removed: usize,
+}
+
+/// Filters applied one after another, reporting how many pools each stage removed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FilterPipeline {
+    pub stages: Vec<PoolFilter>,
+}
+
+impl FilterPipeline {
+    pub fn new(stages: Vec<PoolFilter>) -> Self {
+        FilterPipeline { stages }
+    }
+
+    pub fn from_yaml(yaml: &str) -> Result<Self> {
+        Ok(serde_yaml::from_str(yaml)?)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        Self::from_yaml(&fs::read_to_string(path)?)
+    }
+
+    /// Pools that pass every stage. Fails if a stage needs data missing from `context`.
+    pub fn apply<'a>(
+        &self,
+        pools: Vec<&'a UniswapV2Pool>,
+        context: &FilterContext,
+    ) -> Result<(Vec<&'a UniswapV2Pool>, Vec<StageReport>)> {
+        let mut pools = pools;
+        let mut reports = vec![];
+        for stage in &self.stages {
+            let input = pools.len();
+            let mut kept = Vec::with_capacity(input);
+            for pool in pools {
+                if stage.matches(pool, context)? {
+                    kept.push(pool);
+                }
+            }
+            pools = kept;
+            reports.push(StageReport {
+                stage: stage.name(),
+                input,
+                removed: input - pools.len(),
+            });
+        }
+        Ok((pools, reports))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::U64;
+
+    fn address(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    fn pool(n: u64, token_a: u64, token_b: u64, reserve_0: u128, eth_value: u64) -> UniswapV2Pool {
+        UniswapV2Pool::new(
+            address(n),
+            address(token_a),
+            18,
+            address(token_b),
+            18,
+            reserve_0,
+            1_000,
+            300,
+            U256::from(eth_value) * U256::exp10(18),
+        )
+    }
+
+    #[test]
+    fn test_pipeline_from_yaml() {
+        let yaml = format!(
+            r#"
+stages:
+  - non_zero_reserves
+  - deny_tokens: ["{:?}"]
+  - or:
+      - min_weth_value: 10.0
+      - and:
+          - max_sync_age: 5
+          - not: exclude_fee_on_transfer
+"#,
+            address(9)
+        );
+        let pipeline = FilterPipeline::from_yaml(&yaml).unwrap();
+        assert_eq!(pipeline.stages.len(), 3);
+
+        let pools = [
+            pool(10, 1, 2, 0, 100),
+            pool(11, 1, 9, 1_000, 100),
+            pool(12, 1, 2, 1_000, 100),
+            pool(13, 1, 3, 1_000, 1),
+            pool(14, 1, 2, 1_000, 1),
+            pool(15, 1, 3, 1_000, 1),
+        ];
+        let sync = |pool: u64, block: u64| Log {
+            address: address(pool),
+            topics: vec![SYNC_EVENT_SIGNATURE],
+            block_number: Some(U64::from(block)),
+            ..Default::default()
+        };
+        let last_sync_blocks = last_sync_blocks(&[sync(13, 98), sync(14, 99), sync(15, 90)]);
+        let fee_on_transfer_tokens = HashSet::from([address(3)]);
+        let context = FilterContext {
+            last_sync_blocks: Some(&last_sync_blocks),
+            current_block: 100,
+            fee_on_transfer_tokens: Some(&fee_on_transfer_tokens),
+            ..Default::default()
+        };
+
+        let (kept, reports) = pipeline.apply(pools.iter().collect(), &context).unwrap();
+        assert_eq!(
+            kept.iter().map(|p| p.address).collect_vec(),
+            vec![address(12), address(13)]
+        );
+        assert_eq!(
+            reports.iter().map(|r| r.removed).collect_vec(),
+            vec![1, 1, 2]
+        );
+        assert_eq!(reports[1].stage, "deny_tokens(1)");
+        assert_eq!(
+            reports[2].stage,
+            "or(min_weth_value(10), and(max_sync_age(5), not(exclude_fee_on_transfer)))"
+        );
+    }
+
+    #[test]
+    fn test_missing_context_is_an_error() {
+        let pools = [pool(10, 1, 2, 1_000, 1)];
+        let pipeline = FilterPipeline::new(vec![PoolFilter::Not(Box::new(
+            PoolFilter::ExcludeFeeOnTransfer,
+        ))]);
+        let err = pipeline
+            .apply(pools.iter().collect(), &FilterContext::default())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "exclude_fee_on_transfer needs fee-on-transfer tokens in the filter context"
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use crate::amm::{uniswap_v2::pool::UniswapV2Pool, Protocol};
+use ethers::{
+    providers::Middleware,
+    types::{transaction::eip2718::TypedTransaction, BlockNumber, H160, U256},
+};
+use eyre::Result;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+
+pub const BASE_TRANSACTION_GAS: u64 = 21_000;
+pub const ROUTER_OVERHEAD_GAS: u64 = 30_000;
+
+impl Protocol {
+    /// Typical gas used by a single swap hop on this protocol.
+    pub fn swap_gas(&self) -> u64 {
+        match self {
+            Protocol::UniswapV2 => 60_000,
+            Protocol::UniswapV3 => 110_000,
+            Protocol::Curve => 130_000,
+        }
+    }
+}
+
+/// Static gas estimate for a transaction swapping through one hop per protocol in `hops`.
+pub fn estimate_swap_gas(hops: &[Protocol]) -> U256 {
+    let swaps: u64 = hops.iter().map(Protocol::swap_gas).sum();
+    U256::from(BASE_TRANSACTION_GAS + ROUTER_OVERHEAD_GAS + swaps)
+}
+
+/// Gas estimate from the node via `eth_estimateGas`, meant to be run against a fork.
+pub async fn estimate_gas_on_fork<M: Middleware>(
+    middleware: Arc<M>,
+    tx: &TypedTransaction,
+) -> Result<U256>
+where
+    M::Error: 'static,
+{
+    Ok(middleware.estimate_gas(tx, None).await?)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct GasPrice {
+    pub base_fee: U256,
+    pub priority_fee: U256,
+}
+
+impl GasPrice {
+    pub fn new(base_fee: U256, priority_fee: U256) -> Self {
+        GasPrice {
+            base_fee,
+            priority_fee,
+        }
+    }
+
+    /// Price paid per unit of gas if the transaction lands in the next block.
+    pub fn effective_gas_price(&self) -> U256 {
+        self.base_fee + self.priority_fee
+    }
+
+    /// `maxFeePerGas` leaving room for the base fee to double before the transaction is priced out.
+    pub fn max_fee_per_gas(&self) -> U256 {
+        self.base_fee * 2 + self.priority_fee
+    }
+
+    /// Cost in wei of spending `gas` units.
+    pub fn cost(&self, gas: U256) -> U256 {
+        gas * self.effective_gas_price()
+    }
+}
+
+/// Tracks base and priority fees over the most recent blocks using `eth_feeHistory`.
+pub struct FeeTracker {
+    pub block_count: u64,
+    pub reward_percentile: f64,
+}
+
+impl Default for FeeTracker {
+    fn default() -> Self {
+        FeeTracker {
+            block_count: 10,
+            reward_percentile: 50.0,
+        }
+    }
+}
+
+impl FeeTracker {
+    pub fn new(block_count: u64, reward_percentile: f64) -> Self {
+        FeeTracker {
+            block_count,
+            reward_percentile,
+        }
+    }
+
+    pub async fn gas_price<M: Middleware>(&self, middleware: Arc<M>) -> Result<GasPrice>
+    where
+        M::Error: 'static,
+    {
+        let history = middleware
+            .fee_history(
+                self.block_count,
+                BlockNumber::Latest,
+                &[self.reward_percentile],
+            )
+            .await?;
+        // The last entry is the base fee of the block after the newest one in the window.
+        let base_fee = history.base_fee_per_gas.last().copied().unwrap_or_default();
+        let mut rewards: Vec<U256> = history
+            .reward
+            .iter()
+            .filter_map(|block| block.first().copied())
+            .collect();
+        rewards.sort();
+        let priority_fee = rewards.get(rewards.len() / 2).copied().unwrap_or_default();
+        Ok(GasPrice::new(base_fee, priority_fee))
+    }
+}
+
+/// Converts an amount of WETH into `token` at spot prices, walking the fewest hops through
+/// `pools` and taking the deepest pool at every hop. Returns `None` if `token` is unreachable.
+pub fn weth_to_token(
+    amount: U256,
+    weth: H160,
+    token: H160,
+    pools: &[&UniswapV2Pool],
+) -> Option<U256> {
+    if weth == token {
+        return Some(amount);
+    }
+    let mut adjacent: HashMap<H160, Vec<&UniswapV2Pool>> = HashMap::new();
+    for &pool in pools {
+        if pool.reserve_0 == 0 || pool.reserve_1 == 0 {
+            continue;
+        }
+        adjacent.entry(pool.token_a).or_default().push(pool);
+        adjacent.entry(pool.token_b).or_default().push(pool);
+    }
+
+    let mut visited = HashSet::from([weth]);
+    let mut queue = VecDeque::from([(weth, amount)]);
+    while let Some((current, value)) = queue.pop_front() {
+        let mut best: HashMap<H160, &UniswapV2Pool> = HashMap::new();
+        for &pool in adjacent.get(&current).into_iter().flatten() {
+            let next = pool.get_token_out(&current);
+            let deepest = best.entry(next).or_insert(pool);
+            if pool.get_reserve_for_token(&current) > deepest.get_reserve_for_token(&current) {
+                *deepest = pool;
+            }
+        }
+        for (next, pool) in best {
+            if !visited.insert(next) {
+                continue;
+            }
+            let next_value = pool.quote(&current, value);
+            if next == token {
+                return Some(next_value);
+            }
+            queue.push_back((next, next_value));
+        }
+    }
+    None
+}
+
+/// Converts an amount of `token` into WETH at spot prices, like `weth_to_token` the other way.
+pub fn token_to_weth(
+    amount: U256,
+    token: H160,
+    weth: H160,
+    pools: &[&UniswapV2Pool],
+) -> Option<U256> {
+    weth_to_token(amount, token, weth, pools)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(address: u64, token_a: H160, token_b: H160, r0: u128, r1: u128) -> UniswapV2Pool {
+        UniswapV2Pool::new(
+            H160::from_low_u64_be(address),
+            token_a,
+            18,
+            token_b,
+            18,
+            r0,
+            r1,
+            300,
+            U256::zero(),
+        )
+    }
+
+    #[test]
+    fn test_estimate_swap_gas() {
+        let gas = estimate_swap_gas(&[Protocol::UniswapV2; 3]);
+        assert_eq!(gas, U256::from(21_000 + 30_000 + 3 * 60_000));
+        assert!(
+            estimate_swap_gas(&[Protocol::UniswapV3]) > estimate_swap_gas(&[Protocol::UniswapV2])
+        );
+    }
+
+    #[test]
+    fn test_gas_price_cost() {
+        let price = GasPrice::new(U256::from(30), U256::from(2));
+        assert_eq!(price.cost(U256::from(100_000)), U256::from(3_200_000));
+        assert_eq!(price.max_fee_per_gas(), U256::from(62));
+    }
+
+    #[test]
+    fn test_weth_to_token() {
+        let weth = H160::from_low_u64_be(1);
+        let usdc = H160::from_low_u64_be(2);
+        let link = H160::from_low_u64_be(3);
+        let pools = [
+            pool(10, weth, usdc, 1_000, 2_000_000),
+            pool(11, weth, usdc, 10, 30_000),
+            pool(12, usdc, link, 5_000, 1_000),
+        ];
+        let pools: Vec<&UniswapV2Pool> = pools.iter().collect();
+        assert_eq!(
+            weth_to_token(U256::from(7), weth, weth, &pools),
+            Some(U256::from(7))
+        );
+        // Deepest WETH/USDC pool wins over the shallow one with a better price.
+        assert_eq!(
+            weth_to_token(U256::from(1), weth, usdc, &pools),
+            Some(U256::from(2_000))
+        );
+        assert_eq!(
+            weth_to_token(U256::from(1), weth, link, &pools),
+            Some(U256::from(400))
+        );
+        assert_eq!(
+            weth_to_token(U256::from(1), weth, H160::from_low_u64_be(4), &pools),
+            None
+        );
+        assert_eq!(
+            token_to_weth(U256::from(400), link, weth, &pools),
+            Some(U256::from(1))
+        );
+    }
+}
@@ -1,11 +1,23 @@
 pub mod address_book;
 pub mod amm;
 pub mod arithmetic;
+pub mod backtest;
+pub mod block_analysis;
+pub mod bundle;
 pub mod checkpoint;
+pub mod cli;
 pub mod concurrent;
+pub mod config;
 pub mod contract;
 pub mod eth_provider;
 pub mod filters;
+pub mod gas;
+pub mod logging;
+pub mod mempool;
+pub mod metrics;
 pub mod path;
+pub mod server;
 pub mod simulator;
+pub mod stream;
 pub mod tests;
+pub mod transaction;
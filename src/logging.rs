@@ -0,0 +1,16 @@
+use tracing_subscriber::EnvFilter;
+
+/// Environment variable with the filter directives to log with, e.g. `debug` or
+/// `info,eth_amm::stream=debug`. `info` when not set.
+pub const LOG_ENV: &str = "ETH_AMM_LOG";
+
+/// Installs a subscriber writing one line per event to stderr, filtered by `ETH_AMM_LOG`,
+/// unless one already is. The library only emits `tracing` events, applications that embed it
+/// install their own subscriber instead.
+pub fn init() {
+    let filter = EnvFilter::try_from_env(LOG_ENV).unwrap_or_else(|_| EnvFilter::new("info"));
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .try_init();
+}
@@ -1,46 +1,477 @@
-use eth_amm::{address_book::AddressBook, eth_provider::EthProvider, simulator::Simulation};
-use ethers::types::{Address, U256};
-use eyre::Result;
-use std::str::FromStr;
+use eth_amm::{
+    address_book::Network,
+    amm::{
+        uniswap_v2::{factory::UniswapV2Factory, pool::UniswapV2Pool},
+        Protocol,
+    },
+    checkpoint::{
+        market_state::{MarketState, PoolSource, MARKET_STATE_ID},
+        Checkpoint,
+    },
+    cli::{
+        discover_cycles, filter_checkpoint, pool_map, pools_along_path, simulation_to_json,
+        weth_threshold, write_simulations_to_json, Cli, Command, DiscoverArgs, Rpc, USAGE,
+    },
+    config::Config,
+    eth_provider::EthProvider,
+    filters::{FilterPipeline, StageReport},
+    gas::FeeTracker,
+    logging,
+    metrics::{self, metrics},
+    server::{self, QuoteService, ServiceSettings},
+    simulator::{write_simulations_to_csv, Simulation},
+    stream::{self, OpportunityStream},
+};
+use ethers::{
+    signers::{LocalWallet, Signer},
+    types::H160,
+};
+use eyre::{eyre, Result};
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tracing::error;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    dotenv::dotenv().ok();
-    let provider = EthProvider::new_local().await.clone();
-    let book = AddressBook::new();
-    let public_address = Address::from_str("0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266")?;
-    let private_key = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
-
-    let path = vec![
-        book.mainnet.erc20["weth"],
-        book.mainnet.erc20["usdt"],
-        book.mainnet.erc20["usd_old"],
-        book.mainnet.erc20["weth"],
-    ];
-
-    let simu = Simulation::new_from_erc20_path(
-        provider.clone(),
-        book.mainnet.uniswap_v2.factory,
-        path,
-        U256::exp10(4),
+/// Connects to the node, checking that a remote node serves the configured network. Local
+/// forks keep the addresses of the forked chain under their own chain id, so are not checked.
+async fn provider(rpc: &Rpc, config: &Config, network: &Network) -> Result<Arc<EthProvider>> {
+    let provider = match rpc {
+        Rpc::Local => EthProvider::new_local().await.clone(),
+        Rpc::Alchemy => EthProvider::from_config(&config.provider).await?.clone(),
+        Rpc::Url(url) => EthProvider::new(url.clone(), url.replacen("http", "ws", 1))
+            .await
+            .clone(),
+    };
+    if !rpc.is_local() {
+        network.verify(provider.http.as_ref()).await?;
+    }
+    Ok(provider)
+}
+
+fn load_pools(factory: H160) -> Result<Checkpoint<Vec<UniswapV2Pool>>> {
+    let id = Checkpoint::<Vec<UniswapV2Pool>>::id(&factory);
+    Checkpoint::load_data(&id)?
+        .ok_or_else(|| eyre!("No checkpoint for factory {:?}, run `sync` first", factory))
+}
+
+/// The pools of every factory of the market state, as of its last sync.
+fn load_market_pools() -> Result<Checkpoint<Vec<UniswapV2Pool>>> {
+    let checkpoint = Checkpoint::<MarketState>::load_data(MARKET_STATE_ID)?
+        .ok_or_else(|| eyre!("No market state, run `sync --market` first"))?;
+    Ok(checkpoint.pools_checkpoint())
+}
+
+/// Factories of the market state: the configured ones, or else the network's Uniswap V2 dexes.
+fn market_factories(config: &Config, network: &Network) -> Vec<(Protocol, UniswapV2Factory)> {
+    if config.factories.is_empty() {
+        network
+            .dexes_by_protocol(Protocol::UniswapV2)
+            .into_iter()
+            .map(|dex| {
+                (
+                    dex.protocol,
+                    UniswapV2Factory::new(dex.factory, dex.fee.into()),
+                )
+            })
+            .collect()
+    } else {
+        config
+            .factories
+            .iter()
+            .map(|f| {
+                (
+                    Protocol::UniswapV2,
+                    UniswapV2Factory::new(f.address, f.fee.into()),
+                )
+            })
+            .collect()
+    }
+}
+
+/// The synced pools of the market state with `market`, else of `factory`, and the source that
+/// keeps them in sync. With `weth_value` the pools are valued in WETH first.
+async fn sync_pools(
+    provider: &EthProvider,
+    factory: UniswapV2Factory,
+    market: bool,
+    weth_value: bool,
+    step: usize,
+    config: &Config,
+    network: &Network,
+) -> Result<(Checkpoint<Vec<UniswapV2Pool>>, PoolSource)> {
+    let weth = network.wrapped_native();
+    if market {
+        let mut checkpoint = Checkpoint::<MarketState>::get_market_state(
+            provider,
+            market_factories(config, network),
+            step,
+        )
+        .await?;
+        if weth_value {
+            checkpoint
+                .sync_eth_value(provider, weth, weth_threshold(), &[], step)
+                .await?;
+        }
+        Ok((
+            checkpoint.pools_checkpoint(),
+            PoolSource::Market(checkpoint),
+        ))
+    } else {
+        let mut checkpoint =
+            Checkpoint::<Vec<UniswapV2Pool>>::get(provider, &factory, step).await?;
+        if weth_value {
+            checkpoint
+                .sync_eth_value(provider, weth, weth_threshold(), &[], step)
+                .await?;
+        }
+        Ok((checkpoint, PoolSource::Factory(factory)))
+    }
+}
+
+fn stages_to_json(reports: &[StageReport]) -> Value {
+    reports
+        .iter()
+        .map(|r| json!({"stage": r.stage, "input": r.input, "removed": r.removed}))
+        .collect()
+}
+
+/// The pipeline in `filters`, or the config's pipeline.
+fn pipeline(filters: &Option<String>, config: &Config) -> Result<FilterPipeline> {
+    Ok(match filters {
+        Some(path) => FilterPipeline::from_file(path)
+            .map_err(|e| eyre!("Could not load filters {}: {}", path, e))?,
+        None => config.filters.clone(),
+    })
+}
+
+/// Pools of the checkpoint that pass the pipeline in `filters`, or the config's pipeline.
+fn filter_pools<'a>(
+    checkpoint: &'a Checkpoint<Vec<UniswapV2Pool>>,
+    filters: &Option<String>,
+    config: &Config,
+    network: &Network,
+) -> Result<(Vec<&'a UniswapV2Pool>, Vec<StageReport>)> {
+    let pipeline = pipeline(filters, config)?;
+    filter_checkpoint(
+        checkpoint,
+        &pipeline,
+        network.wrapped_native(),
+        network.token("usdc").ok(),
     )
-    .await;
+}
 
-    println!(
-        "Best amount is {:?} with amount out {:?} and profit of {:?}",
-        simu.amount_in,
-        simu.amount_out,
-        simu.profit()
+/// Cycles of the checkpoint, with gas priced at the node's current fees.
+async fn discover(
+    rpc: &Rpc,
+    args: &DiscoverArgs,
+    config: &Config,
+    network: &Network,
+) -> Result<(u64, Vec<Simulation>)> {
+    let checkpoint = if args.market {
+        load_market_pools()?
+    } else {
+        load_pools(args.factory)?
+    };
+    let (pools, _) = filter_pools(&checkpoint, &args.filters, config, network)?;
+    let provider = provider(rpc, config, network).await?;
+    let gas_price = FeeTracker::default()
+        .gas_price(provider.http.clone())
+        .await?;
+    let mut simulations = discover_cycles(
+        &pools,
+        args.token,
+        &args.search,
+        &gas_price,
+        network.wrapped_native(),
     );
+    if let Some(limit) = args.limit {
+        simulations.truncate(limit);
+    }
+    Ok((checkpoint.last_block, simulations))
+}
 
-    let amount_out = simu
-        .swap_using_router(
-            book.mainnet.uniswap_v2.router,
-            provider.clone(),
-            public_address,
-            private_key,
-        )
-        .await;
-    println!("Swap yielded: {:?}", amount_out);
+async fn run(cli: Cli) -> Result<Value> {
+    let Cli {
+        rpc,
+        config,
+        book,
+        command,
+    } = cli;
+    let (rpc, config) = (&rpc, &config);
+    let network = book.network(config.chain.chain_id)?;
+    match command {
+        Command::Sync {
+            factory,
+            fee,
+            step,
+            weth_value,
+            market,
+            watch,
+        } => {
+            let provider = provider(rpc, config, network).await?;
+            let factory = UniswapV2Factory::new(factory, fee.into());
+            let (mut checkpoint, mut source) = sync_pools(
+                &provider, factory, market, weth_value, step, config, network,
+            )
+            .await?;
+            if let Some(seconds) = watch {
+                let mut reconciler = config.reconcile.reconciler(step);
+                loop {
+                    tokio::time::sleep(Duration::from_secs(seconds)).await;
+                    if let Err(err) = source.sync(&mut checkpoint, &provider, step).await {
+                        error!(%err, last_block = checkpoint.last_block, "sync failed");
+                    }
+                    if let Some(reconciler) = &mut reconciler {
+                        if let Err(err) = source
+                            .maybe_reconcile(&mut checkpoint, reconciler, &provider)
+                            .await
+                        {
+                            error!(%err, last_block = checkpoint.last_block, "reconcile failed");
+                        }
+                    }
+                }
+            }
+            Ok(json!({
+                "id": checkpoint.id,
+                "last_block": checkpoint.last_block,
+                "pools": checkpoint.data.len(),
+            }))
+        }
+        Command::Pools {
+            factory,
+            filters,
+            limit,
+        } => {
+            let checkpoint = load_pools(factory)?;
+            let (pools, reports) = filter_pools(&checkpoint, &filters, config, network)?;
+            let count = pools.len();
+            let pools: Vec<&UniswapV2Pool> = pools
+                .into_iter()
+                .take(limit.unwrap_or(usize::MAX))
+                .collect();
+            Ok(json!({
+                "last_block": checkpoint.last_block,
+                "stages": stages_to_json(&reports),
+                "count": count,
+                "pools": pools,
+            }))
+        }
+        Command::Discover(args) => {
+            let (last_block, simulations) = discover(rpc, &args, config, network).await?;
+            Ok(json!({
+                "last_block": last_block,
+                "cycles": simulations.iter().map(simulation_to_json).collect::<Vec<_>>(),
+            }))
+        }
+        Command::Quote {
+            factory,
+            path,
+            amount,
+            on_chain,
+        } => {
+            let checkpoint = load_pools(factory)?;
+            let pools: Vec<&UniswapV2Pool> = checkpoint.data.iter().collect();
+            let pools = pools_along_path(&path, &pool_map(&pools))?;
+            let is_cycle = path.first() == path.last();
+            let simulation = Simulation::with_search(path[0], pools, &config.search);
+            let amount = match amount {
+                Some(amount) => amount,
+                None if is_cycle => simulation.amount_in,
+                None => return Err(eyre!("Give `--amount` to quote a path that is not a cycle")),
+            };
+            let (amount_out, amount_path) = simulation.simulate_swap_offline(amount);
+            let mut quote = json!({
+                "last_block": checkpoint.last_block,
+                "path": path,
+                "pools": simulation.path.iter().map(|p| p.address).collect::<Vec<_>>(),
+                "amount_in": amount.to_string(),
+                "amount_out": amount_out.to_string(),
+                "amount_path": amount_path.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+            });
+            if is_cycle {
+                quote["best"] = simulation_to_json(&simulation);
+            }
+            if on_chain {
+                let provider = provider(rpc, config, network).await?;
+                let amount_out = simulation
+                    .simulate_swap(provider.http.clone(), amount)
+                    .await;
+                quote["on_chain_amount_out"] = json!(amount_out.to_string());
+            }
+            Ok(quote)
+        }
+        Command::Execute {
+            factory,
+            router,
+            path,
+            amount,
+            epsilon,
+            key,
+            reset_fork,
+        } => {
+            if !rpc.is_local() {
+                return Err(eyre!("`execute` only runs against a local fork"));
+            }
+            if path[0] != network.wrapped_native() {
+                return Err(eyre!(
+                    "The router swaps the native token, the path must start with its wrapped token"
+                ));
+            }
+            let private_key = key.read()?;
+            let wallet: LocalWallet = private_key
+                .parse()
+                .map_err(|e| eyre!("Invalid private key: {}", e))?;
+            let provider = provider(rpc, config, network).await?;
+            if reset_fork {
+                provider
+                    .reset_local_to_alchemy_fork()
+                    .await
+                    .map_err(|e| eyre!("Could not reset the fork: {}", e))?;
+            }
+            let mut simulation =
+                Simulation::new_from_erc20_path(provider.clone(), factory, path, epsilon).await;
+            let limits = &config.execution;
+            let amount = match amount {
+                Some(amount) if amount > limits.max_amount_in => {
+                    return Err(eyre!(
+                        "Amount {} is above execution.max_amount_in {}",
+                        amount,
+                        limits.max_amount_in
+                    ))
+                }
+                Some(amount) => amount,
+                None => simulation.amount_in.min(limits.max_amount_in),
+            };
+            simulation.amount_in = amount;
+            (simulation.amount_out, simulation.amount_path) =
+                simulation.simulate_swap_offline(amount);
+            let gas = simulation
+                .estimate_gas_on_fork(router, provider.clone(), wallet.address())
+                .await?;
+            let gas_price = FeeTracker::default()
+                .gas_price(provider.http.clone())
+                .await?;
+            // The path starts from WETH, so its gas cost needs no pool to be priced.
+            simulation.set_gas_cost_of(gas, &gas_price, network.wrapped_native(), &[]);
+            if simulation.net_profit() < limits.min_profit {
+                return Err(eyre!(
+                    "Expected profit {} after gas is below execution.min_profit {}",
+                    simulation.net_profit(),
+                    limits.min_profit
+                ));
+            }
+            let amount_out = simulation
+                .swap_using_router(router, provider, wallet.address(), &private_key)
+                .await?;
+            Ok(json!({
+                "from": wallet.address(),
+                "simulation": simulation_to_json(&simulation),
+                "amount_out": amount_out.to_string(),
+            }))
+        }
+        Command::Export {
+            discover: args,
+            output,
+        } => {
+            let (last_block, simulations) = discover(rpc, &args, config, network).await?;
+            let cycles = simulations.len();
+            if output.ends_with(".csv") {
+                write_simulations_to_csv(simulations, &output);
+            } else {
+                write_simulations_to_json(&simulations, &output)?;
+            }
+            Ok(json!({
+                "last_block": last_block,
+                "output": output,
+                "cycles": cycles,
+            }))
+        }
+        Command::Serve {
+            factory,
+            fee,
+            market,
+            listen,
+            filters,
+        } => {
+            let provider = provider(rpc, config, network).await?;
+            let factory = UniswapV2Factory::new(factory, fee.into());
+            let step = config.concurrency.step;
+            let (checkpoint, source) =
+                sync_pools(&provider, factory, market, false, step, config, network).await?;
+            let base_tokens = if config.server.base_tokens.is_empty() {
+                vec![network.wrapped_native()]
+            } else {
+                config.server.base_tokens.clone()
+            };
+            let settings = ServiceSettings {
+                search: config.search.clone(),
+                filters: pipeline(&filters, config)?,
+                max_hops: config.server.max_hops,
+                base_tokens,
+                weth: network.wrapped_native(),
+                usd: network.token("usdc").ok(),
+                tokens: network.erc20.clone(),
+            };
+            let gas_price = FeeTracker::default()
+                .gas_price(provider.http.clone())
+                .await?;
+            let stream = match config.server.stream_listen {
+                Some(_) => Some(Arc::new(OpportunityStream::new(
+                    &checkpoint,
+                    settings.clone(),
+                    gas_price,
+                )?)),
+                None => None,
+            };
+            let service = Arc::new(QuoteService::new(checkpoint, settings, gas_price).await?);
+            if let (Some(stream), Some(addr)) = (stream, config.server.stream_listen) {
+                let following = stream.clone();
+                let provider = provider.clone();
+                let reconciler = config.reconcile.reconciler(step);
+                tokio::spawn(async move { following.follow(provider, step, reconciler).await });
+                let tracking = stream.clone();
+                let synced = service.clone();
+                tokio::spawn(async move { tracking.follow_checkpoints(&synced).await });
+                tokio::spawn(async move {
+                    if let Err(err) = stream::serve(stream, addr).await {
+                        error!(%err, "opportunity stream stopped");
+                    }
+                });
+            }
+            let interval = Duration::from_secs(config.server.sync_interval);
+            let syncing = service.clone();
+            let reconciler = config.reconcile.reconciler(step);
+            tokio::spawn(async move {
+                syncing
+                    .keep_synced(provider, source, step, interval, reconciler)
+                    .await
+            });
+            server::serve(service, listen).await?;
+            Ok(json!({}))
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    logging::init();
+    let cli = match Cli::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    cli.config.checkpoint.install();
+    metrics().set_opportunity_threshold(cli.config.execution.min_profit);
+    if let Some(addr) = cli.config.metrics.listen {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(addr).await {
+                error!(%err, "metrics server stopped");
+            }
+        });
+    }
+    let output = run(cli).await?;
+    println!("{}", serde_json::to_string_pretty(&output)?);
     Ok(())
 }
@@ -0,0 +1,504 @@
+use crate::{
+    amm::uniswap_v2::pool::UniswapV2Pool, contract::IUniswapRouterCalls, gas::GasPrice,
+    simulator::Simulation,
+};
+use ethers::{
+    abi::AbiDecode,
+    providers::{Middleware, Provider, ProviderError, Ws},
+    types::{Transaction, H160, H256, U256},
+    utils::rlp::{Decodable, Rlp},
+};
+use futures::StreamExt;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tracing::info;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum SwapAmount {
+    ExactIn {
+        amount_in: U256,
+        amount_out_min: U256,
+    },
+    ExactOut {
+        amount_out: U256,
+        amount_in_max: U256,
+    },
+}
+
+/// A Uniswap V2 router swap seen in the mempool.
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingSwap {
+    pub hash: H256,
+    pub router: H160,
+    pub sender: H160,
+    pub path: Vec<H160>,
+    pub amount: SwapAmount,
+    pub to: H160,
+    pub deadline: U256,
+}
+
+fn exact_in(amount_in: U256, amount_out_min: U256) -> SwapAmount {
+    SwapAmount::ExactIn {
+        amount_in,
+        amount_out_min,
+    }
+}
+
+fn exact_out(amount_out: U256, amount_in_max: U256) -> SwapAmount {
+    SwapAmount::ExactOut {
+        amount_out,
+        amount_in_max,
+    }
+}
+
+/// Decodes a router swap from the calldata of `tx`. ETH variants take the amount in from the
+/// transaction value. Returns `None` for anything that is not a router swap.
+pub fn decode_router_swap(tx: &Transaction) -> Option<PendingSwap> {
+    let router = tx.to?;
+    let (path, amount, to, deadline) = match IUniswapRouterCalls::decode(&tx.input).ok()? {
+        IUniswapRouterCalls::SwapExactTokensForTokens(c) => (
+            c.path,
+            exact_in(c.amount_in, c.amount_out_min),
+            c.to,
+            c.deadline,
+        ),
+        IUniswapRouterCalls::SwapTokensForExactTokens(c) => (
+            c.path,
+            exact_out(c.amount_out, c.amount_in_max),
+            c.to,
+            c.deadline,
+        ),
+        IUniswapRouterCalls::SwapExactETHForTokens(c) => (
+            c.path,
+            exact_in(tx.value, c.amount_out_min),
+            c.to,
+            c.deadline,
+        ),
+        IUniswapRouterCalls::SwapTokensForExactETH(c) => (
+            c.path,
+            exact_out(c.amount_out, c.amount_in_max),
+            c.to,
+            c.deadline,
+        ),
+        IUniswapRouterCalls::SwapExactTokensForETH(c) => (
+            c.path,
+            exact_in(c.amount_in, c.amount_out_min),
+            c.to,
+            c.deadline,
+        ),
+        IUniswapRouterCalls::SwapETHForExactTokens(c) => {
+            (c.path, exact_out(c.amount_out, tx.value), c.to, c.deadline)
+        }
+        IUniswapRouterCalls::SwapExactTokensForTokensSupportingFeeOnTransferTokens(c) => (
+            c.path,
+            exact_in(c.amount_in, c.amount_out_min),
+            c.to,
+            c.deadline,
+        ),
+        IUniswapRouterCalls::SwapExactETHForTokensSupportingFeeOnTransferTokens(c) => (
+            c.path,
+            exact_in(tx.value, c.amount_out_min),
+            c.to,
+            c.deadline,
+        ),
+        IUniswapRouterCalls::SwapExactTokensForETHSupportingFeeOnTransferTokens(c) => (
+            c.path,
+            exact_in(c.amount_in, c.amount_out_min),
+            c.to,
+            c.deadline,
+        ),
+    };
+    if path.len() < 2 {
+        return None;
+    }
+    Some(PendingSwap {
+        hash: tx.hash,
+        router,
+        sender: tx.from,
+        path,
+        amount,
+        to,
+        deadline,
+    })
+}
+
+/// Decodes a signed, RLP encoded transaction, as broadcast or recorded, into a router swap.
+pub fn decode_raw_router_swap(raw: &[u8]) -> Option<PendingSwap> {
+    let mut tx = Transaction::decode(&Rlp::new(raw)).ok()?;
+    tx.recover_from_mut().ok()?;
+    decode_router_swap(&tx)
+}
+
+impl PendingSwap {
+    /// Applies the swap to clones of the pools along its path and returns them, each once and
+    /// in path order, with their post-trade reserves. A pool crossed by several hops sees all of
+    /// them. Returns `None` if a pool is unknown or if the router would revert on the slippage
+    /// bound. Fee-on-transfer taxes are not modelled.
+    pub fn predict_pool_impact(
+        &self,
+        pool_map: &HashMap<(&H160, &H160), &UniswapV2Pool>,
+    ) -> Option<Vec<UniswapV2Pool>> {
+        let mut hops = vec![];
+        for hop in self.path.windows(2) {
+            hops.push((hop[0], *pool_map.get(&(&hop[0], &hop[1]))?));
+        }
+        let mut amount = match self.amount {
+            SwapAmount::ExactIn { amount_in, .. } => amount_in,
+            SwapAmount::ExactOut {
+                amount_out,
+                amount_in_max,
+            } => {
+                // The router quotes every hop on the pre-trade reserves.
+                let mut amount = amount_out;
+                for (token_in, pool) in hops.iter().rev() {
+                    amount = pool.simulate_swap_exact_out(token_in, amount)?;
+                }
+                if amount > amount_in_max {
+                    return None;
+                }
+                amount
+            }
+        };
+        let mut order = vec![];
+        let mut pools: HashMap<H160, UniswapV2Pool> = HashMap::new();
+        for (token_in, hop_pool) in hops {
+            if amount > U256::from(u128::MAX) {
+                return None;
+            }
+            let pool = pools.entry(hop_pool.address).or_insert_with(|| {
+                order.push(hop_pool.address);
+                hop_pool.clone()
+            });
+            amount = pool.simulate_swap_mut(&token_in, amount);
+        }
+        if let SwapAmount::ExactIn { amount_out_min, .. } = self.amount {
+            if amount < amount_out_min {
+                return None;
+            }
+        }
+        Some(order.iter().filter_map(|a| pools.remove(a)).collect())
+    }
+}
+
+/// Re-optimises every cycle in `cycles` that trades through a pool in `predicted`, using the
+/// predicted post-trade reserves, and returns the profitable ones ordered by net profit. Gas is
+/// priced at `gas_price` and converted from `weth` through the pools, cycles whose start token
+/// cannot be priced are dropped.
+pub fn score_backruns(
+    predicted: &[UniswapV2Pool],
+    cycles: &[Vec<H160>],
+    pool_map: &HashMap<(&H160, &H160), &UniswapV2Pool>,
+    epsilon: U256,
+    gas_price: &GasPrice,
+    weth: H160,
+) -> Vec<Simulation> {
+    let predicted: HashMap<H160, &UniswapV2Pool> =
+        predicted.iter().map(|p| (p.address, p)).collect();
+    // Pools once each, at their predicted reserves, to price gas in the cycle's token.
+    let pools: Vec<&UniswapV2Pool> = pool_map
+        .values()
+        .map(|pool| (pool.address, *predicted.get(&pool.address).unwrap_or(pool)))
+        .collect::<HashMap<H160, &UniswapV2Pool>>()
+        .into_values()
+        .collect();
+    let mut simulations = vec![];
+    for cycle in cycles {
+        let mut path = vec![];
+        for hop in cycle.windows(2) {
+            match pool_map.get(&(&hop[0], &hop[1])) {
+                Some(pool) => path.push(*predicted.get(&pool.address).unwrap_or(pool)),
+                None => break,
+            }
+        }
+        if path.len() + 1 != cycle.len() || !path.iter().any(|p| predicted.contains_key(&p.address))
+        {
+            continue;
+        }
+        let mut simulation =
+            Simulation::new(cycle[0], path.into_iter().cloned().collect(), epsilon);
+        if !simulation.profit().is_zero()
+            && simulation.set_gas_cost(gas_price, weth, &pools).is_some()
+        {
+            simulations.push(simulation);
+        }
+    }
+    crate::simulator::sort_by_net_profit(&mut simulations);
+    simulations
+}
+
+pub struct MempoolWatcher {
+    pub routers: HashSet<H160>,
+    pub concurrency: usize,
+}
+
+impl MempoolWatcher {
+    pub fn new<I: IntoIterator<Item = H160>>(routers: I) -> Self {
+        MempoolWatcher {
+            routers: routers.into_iter().collect(),
+            concurrency: 32,
+        }
+    }
+
+    /// Streams pending transaction hashes from `wss`, fetches their bodies and calls `func`
+    /// with every decoded swap sent to one of the watched routers. Returns once the
+    /// subscription ends, or the error if it cannot be opened.
+    pub async fn subscribe_pending_swaps<F>(
+        &self,
+        wss: Arc<Provider<Ws>>,
+        func: F,
+    ) -> Result<(), ProviderError>
+    where
+        F: Fn(PendingSwap),
+    {
+        let stream = wss.subscribe_pending_txs().await?;
+        info!("subscribed to pending transactions");
+        let mut transactions = stream
+            .map(|hash| {
+                let wss = wss.clone();
+                async move { wss.get_transaction(hash).await }
+            })
+            .buffer_unordered(self.concurrency);
+        while let Some(tx) = transactions.next().await {
+            if let Ok(Some(tx)) = tx {
+                if tx.to.is_some_and(|to| self.routers.contains(&to)) {
+                    if let Some(swap) = decode_router_swap(&tx) {
+                        func(swap);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::{SwapETHForExactTokensCall, SwapExactTokensForTokensCall};
+    use ethers::{
+        abi::AbiEncode,
+        signers::{LocalWallet, Signer},
+        types::{transaction::eip2718::TypedTransaction, Eip1559TransactionRequest},
+    };
+    use std::str::FromStr;
+
+    const PRIVATE_KEY: &str = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+
+    fn token(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    fn pools() -> Vec<UniswapV2Pool> {
+        let e18 = 10u128.pow(18);
+        vec![
+            UniswapV2Pool::new(
+                token(10),
+                token(1),
+                18,
+                token(2),
+                18,
+                100 * e18,
+                200_000 * e18,
+                300,
+                U256::zero(),
+            ),
+            UniswapV2Pool::new(
+                token(11),
+                token(2),
+                18,
+                token(3),
+                18,
+                100_000 * e18,
+                10_000 * e18,
+                300,
+                U256::zero(),
+            ),
+            UniswapV2Pool::new(
+                token(12),
+                token(3),
+                18,
+                token(1),
+                18,
+                10_000 * e18,
+                50 * e18,
+                300,
+                U256::zero(),
+            ),
+        ]
+    }
+
+    fn pool_map(pools: &[UniswapV2Pool]) -> HashMap<(&H160, &H160), &UniswapV2Pool> {
+        let mut map = HashMap::new();
+        for pool in pools {
+            map.insert((&pool.token_a, &pool.token_b), pool);
+            map.insert((&pool.token_b, &pool.token_a), pool);
+        }
+        map
+    }
+
+    /// Signs a router call the way a wallet would broadcast it.
+    fn raw_transaction(router: H160, input: Vec<u8>, value: U256) -> Vec<u8> {
+        let wallet = LocalWallet::from_str(PRIVATE_KEY).unwrap();
+        let tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .to(router)
+            .data(input)
+            .value(value)
+            .nonce(7)
+            .gas(200_000)
+            .max_fee_per_gas(100)
+            .max_priority_fee_per_gas(2)
+            .chain_id(1)
+            .into();
+        let signature = wallet.sign_transaction_sync(&tx).unwrap();
+        tx.rlp_signed(&signature).to_vec()
+    }
+
+    #[test]
+    fn test_decode_raw_exact_tokens_for_tokens() {
+        let router = token(99);
+        let call = SwapExactTokensForTokensCall {
+            amount_in: U256::exp10(18),
+            amount_out_min: U256::from(1_900) * U256::exp10(18),
+            path: vec![token(1), token(2)],
+            to: token(5),
+            deadline: U256::from(1_700_000_000),
+        };
+        let swap =
+            decode_raw_router_swap(&raw_transaction(router, call.encode(), U256::zero())).unwrap();
+        assert_eq!(swap.router, router);
+        assert_eq!(
+            swap.sender,
+            LocalWallet::from_str(PRIVATE_KEY).unwrap().address()
+        );
+        assert_eq!(swap.path, vec![token(1), token(2)]);
+        assert_eq!(
+            swap.amount,
+            exact_in(U256::exp10(18), U256::from(1_900) * U256::exp10(18))
+        );
+    }
+
+    #[test]
+    fn test_decode_eth_for_exact_tokens_uses_value() {
+        let call = SwapETHForExactTokensCall {
+            amount_out: U256::from(1_000),
+            path: vec![token(1), token(2)],
+            to: token(5),
+            deadline: U256::from(1_700_000_000),
+        };
+        let swap =
+            decode_raw_router_swap(&raw_transaction(token(99), call.encode(), U256::from(3)))
+                .unwrap();
+        assert_eq!(swap.amount, exact_out(U256::from(1_000), U256::from(3)));
+        assert!(
+            decode_raw_router_swap(&raw_transaction(token(99), vec![1, 2, 3], U256::zero()))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_predict_pool_impact() {
+        let pools = pools();
+        let map = pool_map(&pools);
+        let swap = PendingSwap {
+            hash: H256::zero(),
+            router: token(99),
+            sender: token(5),
+            path: vec![token(1), token(2), token(3)],
+            amount: exact_in(U256::exp10(18), U256::zero()),
+            to: token(5),
+            deadline: U256::zero(),
+        };
+        let predicted = swap.predict_pool_impact(&map).unwrap();
+        let first_out = pools[0].simulate_swap(&token(1), U256::exp10(18));
+        assert_eq!(predicted[0].reserve_0, pools[0].reserve_0 + 10u128.pow(18));
+        assert_eq!(
+            predicted[0].reserve_1,
+            pools[0].reserve_1 - first_out.as_u128()
+        );
+        assert_eq!(
+            predicted[1].reserve_0,
+            pools[1].reserve_0 + first_out.as_u128()
+        );
+
+        let too_greedy = PendingSwap {
+            amount: exact_in(U256::exp10(18), U256::exp10(30)),
+            ..swap.clone()
+        };
+        assert!(too_greedy.predict_pool_impact(&map).is_none());
+
+        let exact = PendingSwap {
+            amount: exact_out(first_out, U256::exp10(19)),
+            path: vec![token(1), token(2)],
+            ..swap
+        };
+        let predicted = exact.predict_pool_impact(&map).unwrap();
+        assert!(predicted[0].reserve_1 <= pools[0].reserve_1 - first_out.as_u128());
+
+        // Both hops of a round trip land on the same pool.
+        let round_trip = PendingSwap {
+            amount: exact_in(U256::exp10(18), U256::zero()),
+            path: vec![token(1), token(2), token(1)],
+            ..exact
+        };
+        let predicted = round_trip.predict_pool_impact(&map).unwrap();
+        assert_eq!(predicted.len(), 1);
+        let mut expected = pools[0].clone();
+        let back = expected.simulate_swap_mut(&token(1), U256::exp10(18));
+        expected.simulate_swap_mut(&token(2), back);
+        assert_eq!(predicted[0].reserve_0, expected.reserve_0);
+        assert_eq!(predicted[0].reserve_1, expected.reserve_1);
+    }
+
+    #[test]
+    fn test_score_backruns() {
+        let pools = pools();
+        let map = pool_map(&pools);
+        let cycles = vec![vec![token(1), token(2), token(3), token(1)]];
+        let gas_price = GasPrice::new(U256::exp10(9), U256::zero());
+        let baseline = score_backruns(&[], &cycles, &map, U256::exp10(10), &gas_price, token(1));
+        assert!(baseline.is_empty());
+
+        // A large buy of token 3 with token 1 pushes the 3/1 pool further out of line.
+        let swap = PendingSwap {
+            hash: H256::zero(),
+            router: token(99),
+            sender: token(5),
+            path: vec![token(1), token(3)],
+            amount: exact_in(U256::exp10(19), U256::zero()),
+            to: token(5),
+            deadline: U256::zero(),
+        };
+        let predicted = swap.predict_pool_impact(&map).unwrap();
+        let backruns = score_backruns(
+            &predicted,
+            &cycles,
+            &map,
+            U256::exp10(10),
+            &gas_price,
+            token(1),
+        );
+        assert_eq!(backruns.len(), 1);
+        assert!(!backruns[0].profit().is_zero());
+        assert_eq!(
+            backruns[0].gas_cost,
+            gas_price.cost(backruns[0].estimate_gas())
+        );
+        assert_eq!(
+            backruns[0].net_profit(),
+            backruns[0].profit() - backruns[0].gas_cost
+        );
+        // No pool trades token 4, so gas paid in it cannot be priced in token 1.
+        assert!(score_backruns(
+            &predicted,
+            &cycles,
+            &map,
+            U256::exp10(10),
+            &gas_price,
+            token(4)
+        )
+        .is_empty());
+    }
+}
@@ -0,0 +1,285 @@
+use async_trait::async_trait;
+use ethers::{
+    providers::{Http, HttpClientError, JsonRpcClient},
+    types::{Log, U256},
+};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Debug,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+};
+use tracing::info;
+
+/// Upper bounds of the buckets of `eth_amm_sync_events_per_block`.
+const SYNC_EVENT_BUCKETS: [f64; 8] = [1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0];
+
+/// Counters and gauges of the sync loop, RPC usage and opportunity flow, kept in their own
+/// Prometheus registry. Recording is always on and cheap, serving them is optional.
+pub struct Metrics {
+    registry: Registry,
+    rpc_calls: IntCounterVec,
+    rpc_errors: IntCounterVec,
+    batch_retries: IntCounter,
+    head_block: IntGauge,
+    checkpoint_block: IntGauge,
+    checkpoint_lag: IntGauge,
+    pools_tracked: IntGauge,
+    sync_events_per_block: Histogram,
+    cycles_evaluated: IntCounter,
+    opportunities: IntCounter,
+    /// Smallest net profit in WETH counted as an opportunity, see `set_opportunity_threshold`.
+    opportunity_threshold: Mutex<U256>,
+}
+
+/// The process wide metrics.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::default)
+}
+
+/// Registers `metric` in `registry` and returns it.
+fn registered<M: prometheus::core::Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: prometheus::Result<M>,
+) -> M {
+    let metric = metric.expect("Valid metric");
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("Metric registered once");
+    metric
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str| registered(&registry, IntCounter::new(name, help));
+        let gauge = |name: &str, help: &str| registered(&registry, IntGauge::new(name, help));
+        let per_method = |name: &str, help: &str| {
+            registered(
+                &registry,
+                IntCounterVec::new(Opts::new(name, help), &["method"]),
+            )
+        };
+        Metrics {
+            rpc_calls: per_method(
+                "eth_amm_rpc_calls_total",
+                "JSON-RPC requests sent, per method.",
+            ),
+            rpc_errors: per_method(
+                "eth_amm_rpc_errors_total",
+                "JSON-RPC requests that failed, per method.",
+            ),
+            batch_retries: counter(
+                "eth_amm_batch_retries_total",
+                "Items of failed batches fetched again one by one.",
+            ),
+            head_block: gauge("eth_amm_head_block", "Latest block seen on the node."),
+            checkpoint_block: gauge(
+                "eth_amm_checkpoint_block",
+                "Last block synced into the checkpoint.",
+            ),
+            checkpoint_lag: gauge(
+                "eth_amm_checkpoint_lag_blocks",
+                "Blocks the checkpoint is behind the head.",
+            ),
+            pools_tracked: gauge("eth_amm_pools_tracked", "Pools in the checkpoint."),
+            sync_events_per_block: registered(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        "eth_amm_sync_events_per_block",
+                        "Sync events applied per block that had any.",
+                    )
+                    .buckets(SYNC_EVENT_BUCKETS.to_vec()),
+                ),
+            ),
+            cycles_evaluated: counter(
+                "eth_amm_cycles_evaluated_total",
+                "Cycles whose best amount in was searched.",
+            ),
+            opportunities: counter(
+                "eth_amm_opportunities_total",
+                "Cycles found with a net profit worth at least the threshold in WETH.",
+            ),
+            opportunity_threshold: Mutex::new(U256::zero()),
+            registry,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_rpc(&self, method: &str, ok: bool) {
+        self.rpc_calls.with_label_values(&[method]).inc();
+        if !ok {
+            self.rpc_errors.with_label_values(&[method]).inc();
+        }
+    }
+
+    /// Items of failed `run_concurrent` batches fetched again one by one.
+    pub fn add_batch_retries(&self, retries: usize) {
+        self.batch_retries.inc_by(retries as u64);
+    }
+
+    pub fn set_head_block(&self, block: u64) {
+        self.head_block.set(block as i64);
+        self.update_lag();
+    }
+
+    /// Last block and size of the checkpoint the sync loop keeps up to date.
+    pub fn set_checkpoint(&self, block: u64, pools: usize) {
+        self.checkpoint_block.set(block as i64);
+        self.pools_tracked.set(pools as i64);
+        self.update_lag();
+    }
+
+    fn update_lag(&self) {
+        let lag = self.head_block.get() - self.checkpoint_block.get();
+        self.checkpoint_lag.set(lag.max(0));
+    }
+
+    /// Observes the number of `Sync` events of each block in `logs`. Blocks without any are
+    /// not observed.
+    pub fn observe_sync_logs<'a, I: IntoIterator<Item = &'a Log>>(&self, logs: I) {
+        let mut per_block: HashMap<u64, u64> = HashMap::new();
+        for log in logs {
+            if let Some(block) = log.block_number {
+                *per_block.entry(block.as_u64()).or_default() += 1;
+            }
+        }
+        for count in per_block.into_values() {
+            self.observe_sync_events(count);
+        }
+    }
+
+    pub fn observe_sync_events(&self, count: u64) {
+        self.sync_events_per_block.observe(count as f64);
+    }
+
+    pub fn add_cycles_evaluated(&self, cycles: usize) {
+        self.cycles_evaluated.inc_by(cycles as u64);
+    }
+
+    /// Counts the net profits, valued in WETH, at or above the opportunity threshold.
+    pub fn record_opportunities<I: IntoIterator<Item = U256>>(&self, weth_profits: I) {
+        let threshold = *self.opportunity_threshold.lock().unwrap();
+        let count = weth_profits
+            .into_iter()
+            .filter(|profit| !profit.is_zero() && *profit >= threshold)
+            .count();
+        self.opportunities.inc_by(count as u64);
+    }
+
+    /// Smallest net profit in WETH counted as an opportunity, usually `execution.min_profit`.
+    pub fn set_opportunity_threshold(&self, threshold: U256) {
+        *self.opportunity_threshold.lock().unwrap() = threshold;
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .expect("Metrics encode as text")
+    }
+}
+
+/// HTTP transport counting every request in `metrics()`, per method and outcome.
+#[derive(Debug)]
+pub struct MeteredHttp(pub Http);
+
+#[async_trait]
+impl JsonRpcClient for MeteredHttp {
+    type Error = HttpClientError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let result = self.0.request(method, params).await;
+        metrics().record_rpc(method, result.is_ok());
+        result
+    }
+}
+
+/// Serves `metrics()` on `GET /metrics` until the process exits.
+pub async fn serve(addr: SocketAddr) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+            let response = match (request.method(), request.uri().path()) {
+                (&Method::GET, "/metrics") => Response::builder()
+                    .header("Content-Type", TextEncoder::new().format_type())
+                    .body(Body::from(metrics().render())),
+                _ => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty()),
+            };
+            Ok::<_, Infallible>(response.expect("Valid response"))
+        }))
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    info!(%addr, "serving metrics");
+    server.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::U64;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record_rpc("eth_call", true);
+        metrics.record_rpc("eth_call", false);
+        metrics.record_rpc("eth_getLogs", true);
+        metrics.add_batch_retries(3);
+        metrics.set_head_block(110);
+        metrics.set_checkpoint(100, 42);
+        let log = |block: u64| Log {
+            block_number: Some(U64::from(block)),
+            ..Default::default()
+        };
+        metrics.observe_sync_logs(&[log(1), log(1), log(2)]);
+        metrics.observe_sync_events(1000);
+        metrics.add_cycles_evaluated(7);
+        metrics.set_opportunity_threshold(U256::from(10));
+        metrics.record_opportunities(vec![U256::zero(), U256::from(9), U256::from(10)]);
+
+        let text = metrics.render();
+        for line in [
+            "eth_amm_rpc_calls_total{method=\"eth_call\"} 2",
+            "eth_amm_rpc_calls_total{method=\"eth_getLogs\"} 1",
+            "eth_amm_rpc_errors_total{method=\"eth_call\"} 1",
+            "eth_amm_batch_retries_total 3",
+            "eth_amm_checkpoint_lag_blocks 10",
+            "eth_amm_pools_tracked 42",
+            "eth_amm_sync_events_per_block_bucket{le=\"1\"} 1",
+            "eth_amm_sync_events_per_block_bucket{le=\"5\"} 2",
+            "eth_amm_sync_events_per_block_bucket{le=\"500\"} 2",
+            "eth_amm_sync_events_per_block_bucket{le=\"+Inf\"} 3",
+            "eth_amm_sync_events_per_block_sum 1003",
+            "eth_amm_sync_events_per_block_count 3",
+            "eth_amm_cycles_evaluated_total 7",
+            "eth_amm_opportunities_total 1",
+            "# TYPE eth_amm_sync_events_per_block histogram",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing `{}` in\n{}",
+                line,
+                text
+            );
+        }
+    }
+}
@@ -1,3 +1,4 @@
 pub mod optimal_amount;
 pub mod path_discovery;
+pub mod route;
 pub mod simulator;
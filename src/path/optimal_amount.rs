@@ -24,16 +24,18 @@ where
     ((low + high) / 2.0, step)
 }
 
+/// Best amount in between zero and `max_amount_in`, to a precision of `epsilon`.
 pub fn find_optimal_amount_in(
     path: &Vec<H160>,
     pool_map: &HashMap<(&H160, &H160), &UniswapV2Pool>,
     epsilon: f64,
+    max_amount_in: f64,
 ) -> U256 {
     let f = |amount_in: f64| {
         let amount_out = simulate_swap_using_pools(U256::from(amount_in as u128), path, pool_map);
         amount_out.as_u128() as f64 - amount_in
     };
-    let (amount, _) = find_local_maximum(0.0, 10f64.powf(20.0), epsilon, f);
+    let (amount, _) = find_local_maximum(0.0, max_amount_in, epsilon, f);
     U256::from(amount as u128)
 }
 
@@ -41,8 +43,9 @@ pub fn find_optimal_amount_in_and_out(
     path: &Vec<H160>,
     pool_map: &HashMap<(&H160, &H160), &UniswapV2Pool>,
     epsilon: f64,
+    max_amount_in: f64,
 ) -> (U256, U256) {
-    let amount_in = find_optimal_amount_in(path, pool_map, epsilon);
+    let amount_in = find_optimal_amount_in(path, pool_map, epsilon, max_amount_in);
     let amount_out = simulate_swap_using_pools(amount_in, path, pool_map);
     (amount_in, amount_out)
 }
@@ -60,6 +63,7 @@ mod tests {
             &fixture.weth_link_matic_weth_path,
             &fixture.pools.token_to_pool_map(),
             10f64.powf(4.0),
+            10f64.powf(20.0),
         );
         assert_ne!(amount_in, U256::zero());
         assert!(amount_in < U256::exp10(13));
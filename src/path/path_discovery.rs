@@ -90,10 +90,10 @@ mod tests {
 
     fn setup(book: &AddressBook) -> HashMap<&H160, Vec<&H160>> {
         let tokens = vec![
-            &book.mainnet.erc20["weth"],
-            &book.mainnet.erc20["link"],
-            &book.mainnet.erc20["matic"],
-            &book.mainnet.erc20["usdt"],
+            &book.mainnet().erc20["weth"],
+            &book.mainnet().erc20["link"],
+            &book.mainnet().erc20["matic"],
+            &book.mainnet().erc20["usdt"],
         ];
         let mut tokens_map: HashMap<&H160, Vec<&H160>> = HashMap::new();
         for t1 in &tokens {
@@ -116,7 +116,7 @@ mod tests {
     async fn test_get_all_token_paths() {
         let book = AddressBook::new();
         let tokens_map = setup(&book);
-        let paths = get_all_token_paths(&book.mainnet.erc20["weth"], &tokens_map, 3, 5);
+        let paths = get_all_token_paths(&book.mainnet().erc20["weth"], &tokens_map, 3, 5);
         assert_eq!(paths.len(), 7);
     }
 
@@ -124,7 +124,7 @@ mod tests {
     async fn test_get_all_token_paths_min_length() {
         let book = AddressBook::new();
         let tokens_map = setup(&book);
-        let paths = get_all_token_paths(&book.mainnet.erc20["weth"], &tokens_map, 5, 5);
+        let paths = get_all_token_paths(&book.mainnet().erc20["weth"], &tokens_map, 5, 5);
         assert_eq!(paths.len(), 1);
         assert_eq!(paths.last().unwrap().len(), 5);
     }
@@ -133,7 +133,7 @@ mod tests {
     async fn test_get_all_token_paths_min_length_2() {
         let book = AddressBook::new();
         let tokens_map = setup(&book);
-        let paths = get_all_token_paths(&book.mainnet.erc20["weth"], &tokens_map, 4, 5);
+        let paths = get_all_token_paths(&book.mainnet().erc20["weth"], &tokens_map, 4, 5);
         assert_eq!(paths.len(), 4, "{:?}", paths);
         assert_path_size(paths, 4, 5);
     }
@@ -142,7 +142,7 @@ mod tests {
     async fn test_get_all_token_paths_max_length() {
         let book = AddressBook::new();
         let tokens_map = setup(&book);
-        let paths = get_all_token_paths(&book.mainnet.erc20["weth"], &tokens_map, 0, 2);
+        let paths = get_all_token_paths(&book.mainnet().erc20["weth"], &tokens_map, 0, 2);
         assert_eq!(paths.len(), 0, "{:?}", paths);
     }
 
@@ -150,7 +150,7 @@ mod tests {
     async fn test_get_all_token_paths_max_length_1() {
         let book = AddressBook::new();
         let tokens_map = setup(&book);
-        let paths = get_all_token_paths(&book.mainnet.erc20["weth"], &tokens_map, 0, 3);
+        let paths = get_all_token_paths(&book.mainnet().erc20["weth"], &tokens_map, 0, 3);
         assert_eq!(paths.len(), 3, "{:?}", paths);
         assert_path_size(paths, 3, 3);
     }
@@ -159,7 +159,7 @@ mod tests {
     async fn test_get_all_token_paths_max_length_2() {
         let book = AddressBook::new();
         let tokens_map = setup(&book);
-        let paths = get_all_token_paths(&book.mainnet.erc20["weth"], &tokens_map, 0, 4);
+        let paths = get_all_token_paths(&book.mainnet().erc20["weth"], &tokens_map, 0, 4);
         assert_eq!(paths.len(), 6, "{:?}", paths);
         assert_path_size(paths, 3, 4);
     }
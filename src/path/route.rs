@@ -0,0 +1,248 @@
+use crate::amm::uniswap_v2::pool::UniswapV2Pool;
+use ethers::types::{H160, U256};
+use std::collections::HashMap;
+
+/// A swap route through pools, with the amount entering and leaving each hop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub tokens: Vec<H160>,
+    pub pools: Vec<H160>,
+    /// `amounts[0]` goes in, `amounts[i + 1]` comes out of `pools[i]`.
+    pub amounts: Vec<U256>,
+}
+
+impl Route {
+    pub fn amount_in(&self) -> U256 {
+        self.amounts[0]
+    }
+
+    pub fn amount_out(&self) -> U256 {
+        *self.amounts.last().unwrap()
+    }
+
+    fn contains(&self, token: &H160) -> bool {
+        self.tokens.contains(token)
+    }
+}
+
+/// Best routes between two tokens over a set of pools, simulated offline from their reserves.
+///
+/// Routes are searched hop by hop, keeping for every token the best amount reached so far, so
+/// a route never visits a token twice and the search stays linear in the number of pools.
+///
+/// The router keeps its own copy of the pools, so it is built once per block and shared.
+pub struct Router {
+    pools: Vec<UniswapV2Pool>,
+    /// Indices in `pools` of the pools trading each token.
+    pools_by_token: HashMap<H160, Vec<usize>>,
+}
+
+impl Router {
+    pub fn new(pools: &[UniswapV2Pool]) -> Self {
+        let pools: Vec<UniswapV2Pool> = pools
+            .iter()
+            .filter(|pool| pool.reserve_0 != 0 && pool.reserve_1 != 0)
+            .cloned()
+            .collect();
+        let mut pools_by_token: HashMap<H160, Vec<usize>> = HashMap::new();
+        for (i, pool) in pools.iter().enumerate() {
+            pools_by_token.entry(pool.token_a).or_default().push(i);
+            pools_by_token.entry(pool.token_b).or_default().push(i);
+        }
+        Router {
+            pools,
+            pools_by_token,
+        }
+    }
+
+    fn pools_of<'a>(&'a self, token: &H160) -> impl Iterator<Item = &'a UniswapV2Pool> + 'a {
+        self.pools_by_token
+            .get(token)
+            .into_iter()
+            .flatten()
+            .map(|&i| &self.pools[i])
+    }
+
+    /// Route with the most `to` out for `amount_in` of `from`, in at most `max_hops` swaps.
+    pub fn best_exact_in(
+        &self,
+        from: H160,
+        to: H160,
+        amount_in: U256,
+        max_hops: usize,
+    ) -> Option<Route> {
+        let start = Route {
+            tokens: vec![from],
+            pools: vec![],
+            amounts: vec![amount_in],
+        };
+        let mut layer = HashMap::from([(from, start)]);
+        let mut best: Option<Route> = None;
+        for _ in 0..max_hops {
+            let mut next: HashMap<H160, Route> = HashMap::new();
+            for (token, route) in &layer {
+                for pool in self.pools_of(token) {
+                    let token_out = pool.get_token_out(token);
+                    if route.contains(&token_out) {
+                        continue;
+                    }
+                    let amount_out = pool.simulate_swap(token, route.amount_out());
+                    if amount_out.is_zero()
+                        || next
+                            .get(&token_out)
+                            .is_some_and(|r| r.amount_out() >= amount_out)
+                    {
+                        continue;
+                    }
+                    let mut route = route.clone();
+                    route.tokens.push(token_out);
+                    route.pools.push(pool.address);
+                    route.amounts.push(amount_out);
+                    next.insert(token_out, route);
+                }
+            }
+            if let Some(route) = next.remove(&to) {
+                if best
+                    .as_ref()
+                    .is_none_or(|b| route.amount_out() > b.amount_out())
+                {
+                    best = Some(route);
+                }
+            }
+            layer = next;
+        }
+        best
+    }
+
+    /// Route needing the least `from` to receive exactly `amount_out` of `to`, in at most
+    /// `max_hops` swaps.
+    pub fn best_exact_out(
+        &self,
+        from: H160,
+        to: H160,
+        amount_out: U256,
+        max_hops: usize,
+    ) -> Option<Route> {
+        // Searched backwards from `to`, so routes are built reversed.
+        let end = Route {
+            tokens: vec![to],
+            pools: vec![],
+            amounts: vec![amount_out],
+        };
+        let mut layer = HashMap::from([(to, end)]);
+        let mut best: Option<Route> = None;
+        for _ in 0..max_hops {
+            let mut next: HashMap<H160, Route> = HashMap::new();
+            for (token, route) in &layer {
+                for pool in self.pools_of(token) {
+                    let token_in = pool.get_token_out(token);
+                    if route.contains(&token_in) {
+                        continue;
+                    }
+                    let Some(amount_in) =
+                        pool.simulate_swap_exact_out(&token_in, route.amount_out())
+                    else {
+                        continue;
+                    };
+                    if next
+                        .get(&token_in)
+                        .is_some_and(|r| r.amount_out() <= amount_in)
+                    {
+                        continue;
+                    }
+                    let mut route = route.clone();
+                    route.tokens.push(token_in);
+                    route.pools.push(pool.address);
+                    route.amounts.push(amount_in);
+                    next.insert(token_in, route);
+                }
+            }
+            if let Some(route) = next.remove(&from) {
+                if best
+                    .as_ref()
+                    .is_none_or(|b| route.amount_out() < b.amount_out())
+                {
+                    best = Some(route);
+                }
+            }
+            layer = next;
+        }
+        best.map(|mut route| {
+            route.tokens.reverse();
+            route.pools.reverse();
+            route.amounts.reverse();
+            route
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(
+        address: u64,
+        token_a: u64,
+        token_b: u64,
+        reserve_0: u128,
+        reserve_1: u128,
+    ) -> UniswapV2Pool {
+        UniswapV2Pool {
+            address: H160::from_low_u64_be(address),
+            token_a: H160::from_low_u64_be(token_a),
+            token_b: H160::from_low_u64_be(token_b),
+            reserve_0,
+            reserve_1,
+            fee: 300,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_best_routes() {
+        let token = H160::from_low_u64_be;
+        // A shallow direct pool and a deep route through token 3.
+        let pools = [
+            pool(100, 1, 2, 1_000_000, 1_000_000),
+            pool(101, 1, 3, 100_000_000, 100_000_000),
+            pool(102, 3, 2, 100_000_000, 100_000_000),
+            pool(103, 1, 4, 0, 100_000_000),
+        ];
+        let router = Router::new(&pools);
+
+        let small = router
+            .best_exact_in(token(1), token(2), U256::from(1000), 3)
+            .unwrap();
+        assert_eq!(small.pools, vec![H160::from_low_u64_be(100)]);
+        assert_eq!(
+            small.amount_out(),
+            pools[0].simulate_swap(&token(1), U256::from(1000))
+        );
+
+        let large = router
+            .best_exact_in(token(1), token(2), U256::from(500_000), 3)
+            .unwrap();
+        assert_eq!(large.tokens, vec![token(1), token(3), token(2)]);
+        assert_eq!(large.amounts.len(), 3);
+        assert!(
+            router
+                .best_exact_in(token(1), token(2), U256::from(500_000), 1)
+                .unwrap()
+                .amount_out()
+                < large.amount_out()
+        );
+        assert!(router
+            .best_exact_in(token(1), token(4), U256::from(1000), 3)
+            .is_none());
+
+        let exact_out = router
+            .best_exact_out(token(1), token(2), large.amount_out(), 3)
+            .unwrap();
+        assert_eq!(exact_out.tokens, large.tokens);
+        assert_eq!(exact_out.amount_out(), large.amount_out());
+        assert!(exact_out.amount_in() <= large.amount_in());
+        assert!(router
+            .best_exact_out(token(1), token(2), U256::from(200_000_000), 3)
+            .is_none());
+    }
+}
@@ -98,7 +98,7 @@ mod tests {
         let amount_in = U256::exp10(17);
         let result = simulate_using_router(
             &fixture.local_provider,
-            fixture.book.mainnet.uniswap_v2.router,
+            fixture.book.mainnet().dexes["uniswap_v2"].router,
             amount_in,
             fixture.weth_link_matic_weth_path.clone(),
             fixture.local_node_account.address,
@@ -138,7 +138,7 @@ mod tests {
             .unwrap();
         let router_result = simulate_using_router(
             &fixture.local_provider,
-            fixture.book.mainnet.uniswap_v2.router,
+            fixture.book.mainnet().dexes["uniswap_v2"].router,
             amount_in,
             fixture.weth_link_matic_weth_path.clone(),
             fixture.local_node_account.address,
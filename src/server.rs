@@ -0,0 +1,520 @@
+use crate::{
+    amm::uniswap_v2::pool::UniswapV2Pool,
+    checkpoint::{market_state::PoolSource, reconcile::Reconciler, Checkpoint},
+    cli::{discover_cycles, filter_checkpoint, simulation_to_json},
+    config::{parse_amount, SearchConfig},
+    eth_provider::EthProvider,
+    filters::FilterPipeline,
+    gas::{FeeTracker, GasPrice},
+    path::route::{Route, Router},
+    simulator::{sort_by_net_profit, Simulation},
+};
+use ethers::types::{H160, U256};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap, convert::Infallible, fmt, net::SocketAddr, str::FromStr, sync::Arc,
+    time::Duration,
+};
+use tokio::sync::watch;
+use tracing::{info, info_span, warn, Instrument};
+
+#[derive(Debug, PartialEq)]
+pub enum ServiceError {
+    BadRequest(String),
+    NotFound(String),
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            ServiceError::NotFound(reason) => write!(f, "not found: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+impl ServiceError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ServiceError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ServiceError::NotFound(_) => StatusCode::NOT_FOUND,
+        }
+    }
+}
+
+/// How the service values pools to filter them and which cycles it looks for.
+#[derive(Debug, Clone)]
+pub struct ServiceSettings {
+    pub search: SearchConfig,
+    pub filters: FilterPipeline,
+    pub max_hops: usize,
+    /// Tokens opportunities start from.
+    pub base_tokens: Vec<H160>,
+    pub weth: H160,
+    pub usd: Option<H160>,
+    /// Token names accepted in place of addresses.
+    pub tokens: HashMap<String, H160>,
+}
+
+/// The served checkpoint, and the router and opportunities built from it when it was last
+/// synced, with gas priced at `gas_price`.
+pub struct State {
+    pub checkpoint: Checkpoint<Vec<UniswapV2Pool>>,
+    pub router: Router,
+    pub opportunities: Vec<Simulation>,
+    pub gas_price: GasPrice,
+}
+
+/// Quotes, pool lookups and arbitrage opportunities computed offline from the reserves of a
+/// checkpoint kept in memory. Every answer carries the `last_block` it was computed at.
+pub struct QuoteService {
+    /// The state served, replaced once a sync completes.
+    state: watch::Sender<Arc<State>>,
+    settings: Arc<ServiceSettings>,
+}
+
+fn route_to_json(route: &Route) -> Value {
+    json!({
+        "tokens": route.tokens,
+        "pools": route.pools,
+        "amount_in": route.amount_in().to_string(),
+        "amount_out": route.amount_out().to_string(),
+        "amounts": route.amounts.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+    })
+}
+
+/// A pool with its reserves as decimal strings, they do not fit JSON numbers.
+fn pool_to_json(pool: &UniswapV2Pool) -> Value {
+    json!({
+        "address": pool.address,
+        "token_a": pool.token_a,
+        "token_b": pool.token_b,
+        "reserve_0": pool.reserve_0.to_string(),
+        "reserve_1": pool.reserve_1.to_string(),
+        "fee": pool.fee,
+        "eth_value": pool.eth_value.to_string(),
+    })
+}
+
+impl ServiceSettings {
+    /// Token of the `name` parameter, given by address or by name.
+    pub fn token(
+        &self,
+        params: &HashMap<String, String>,
+        name: &str,
+    ) -> Result<H160, ServiceError> {
+        let value = params
+            .get(name)
+            .ok_or_else(|| ServiceError::BadRequest(format!("missing `{}`", name)))?;
+        if let Some(address) = self.tokens.get(&value.to_lowercase()) {
+            return Ok(*address);
+        }
+        H160::from_str(value).map_err(|_| {
+            ServiceError::BadRequest(format!("`{}` is neither an address nor a token", value))
+        })
+    }
+}
+
+/// Amount of the `name` parameter, if given.
+pub fn amount_param(
+    params: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<U256>, ServiceError> {
+    let Some(value) = params.get(name) else {
+        return Ok(None);
+    };
+    let amount = parse_amount(value).map_err(ServiceError::BadRequest)?;
+    // Reserves fit in 112 bits, so amounts up to 128 bits cannot overflow a swap.
+    if amount.is_zero() || amount > U256::from(u128::MAX) {
+        return Err(ServiceError::BadRequest(format!(
+            "`{}` must be positive and fit in 128 bits",
+            name
+        )));
+    }
+    Ok(Some(amount))
+}
+
+/// Address of the `name` parameter, which is required. Token names are not accepted.
+fn address_param(params: &HashMap<String, String>, name: &str) -> Result<H160, ServiceError> {
+    let value = params
+        .get(name)
+        .ok_or_else(|| ServiceError::BadRequest(format!("missing `{}`", name)))?;
+    H160::from_str(value)
+        .map_err(|_| ServiceError::BadRequest(format!("`{}` is not an address", value)))
+}
+
+/// Query string parameters, decoded.
+pub fn query_params(query: &str) -> HashMap<String, String> {
+    form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect()
+}
+
+impl QuoteService {
+    pub async fn new(
+        checkpoint: Checkpoint<Vec<UniswapV2Pool>>,
+        settings: ServiceSettings,
+        gas_price: GasPrice,
+    ) -> eyre::Result<Self> {
+        let settings = Arc::new(settings);
+        let state = Self::state(checkpoint, settings.clone(), gas_price).await?;
+        Ok(QuoteService {
+            state: watch::Sender::new(Arc::new(state)),
+            settings,
+        })
+    }
+
+    /// Builds the router and searches the opportunities of `checkpoint` on the blocking pool,
+    /// they take long enough to stall the requests served meanwhile.
+    async fn state(
+        checkpoint: Checkpoint<Vec<UniswapV2Pool>>,
+        settings: Arc<ServiceSettings>,
+        gas_price: GasPrice,
+    ) -> eyre::Result<State> {
+        tokio::task::spawn_blocking(move || {
+            let (pools, _) =
+                filter_checkpoint(&checkpoint, &settings.filters, settings.weth, settings.usd)?;
+            let mut opportunities = vec![];
+            for token in &settings.base_tokens {
+                opportunities.extend(discover_cycles(
+                    &pools,
+                    *token,
+                    &settings.search,
+                    &gas_price,
+                    settings.weth,
+                ));
+            }
+            sort_by_net_profit(&mut opportunities);
+            Ok(State {
+                router: Router::new(&checkpoint.data),
+                checkpoint,
+                opportunities,
+                gas_price,
+            })
+        })
+        .await?
+    }
+
+    /// The current state, which stays valid while a sync replaces it.
+    pub fn snapshot(&self) -> Arc<State> {
+        self.state.borrow().clone()
+    }
+
+    /// Receives every state served from now on, e.g. to track the pools created meanwhile.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<State>> {
+        self.state.subscribe()
+    }
+
+    /// Serves `checkpoint` from now on, with its opportunities searched again at `gas_price`.
+    pub async fn replace(
+        &self,
+        checkpoint: Checkpoint<Vec<UniswapV2Pool>>,
+        gas_price: GasPrice,
+    ) -> eyre::Result<Arc<State>> {
+        let state = Arc::new(Self::state(checkpoint, self.settings.clone(), gas_price).await?);
+        self.state.send_replace(state.clone());
+        Ok(state)
+    }
+
+    /// Syncs a copy of the checkpoint from `source` every `interval`, reconciles its reserves
+    /// when `reconciler` is due, and serves it once synced, so that requests are never blocked
+    /// by RPC calls.
+    pub async fn keep_synced(
+        &self,
+        provider: Arc<EthProvider>,
+        mut source: PoolSource,
+        step: usize,
+        interval: Duration,
+        mut reconciler: Option<Reconciler>,
+    ) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let snapshot = self.snapshot();
+            let mut checkpoint = snapshot.checkpoint.clone();
+            let from_block = checkpoint.last_block;
+            if let Err(err) = source
+                .sync(&mut checkpoint, &provider, step)
+                .instrument(info_span!("serve_sync", from_block))
+                .await
+            {
+                warn!(%err, from_block, "sync failed, serving the previous block");
+            }
+            if checkpoint.last_block > from_block {
+                if let Some(reconciler) = &mut reconciler {
+                    if let Err(err) = source
+                        .maybe_reconcile(&mut checkpoint, reconciler, &provider)
+                        .await
+                    {
+                        warn!(%err, last_block = checkpoint.last_block, "reconcile failed");
+                    }
+                }
+                let gas_price = match FeeTracker::default().gas_price(provider.http.clone()).await {
+                    Ok(gas_price) => gas_price,
+                    Err(err) => {
+                        warn!(%err, "could not get gas price, keeping the previous one");
+                        snapshot.gas_price
+                    }
+                };
+                match self.replace(checkpoint, gas_price).await {
+                    Ok(state) => info!(
+                        last_block = state.checkpoint.last_block,
+                        opportunities = state.opportunities.len(),
+                        "serving new block"
+                    ),
+                    Err(err) => warn!(%err, from_block, "could not search the new block"),
+                }
+            }
+        }
+    }
+
+    /// Answers `GET path?query` with a JSON body.
+    pub fn handle(&self, path: &str, query: &str) -> Result<Value, ServiceError> {
+        let params = query_params(query);
+        let state = self.snapshot();
+        let checkpoint = &state.checkpoint;
+        let result = match path {
+            "/status" => json!({
+                "pools": checkpoint.data.len(),
+                "opportunities": state.opportunities.len(),
+            }),
+            "/quote" | "/quote/exact-out" => {
+                let (from, to) = (
+                    self.settings.token(&params, "from")?,
+                    self.settings.token(&params, "to")?,
+                );
+                let amount = amount_param(&params, "amount")?
+                    .ok_or_else(|| ServiceError::BadRequest("missing `amount`".to_string()))?;
+                let max_hops = self.settings.max_hops;
+                let route = if path == "/quote" {
+                    state.router.best_exact_in(from, to, amount, max_hops)
+                } else {
+                    state.router.best_exact_out(from, to, amount, max_hops)
+                };
+                let route = route.ok_or_else(|| {
+                    ServiceError::NotFound(format!(
+                        "no route from {:?} to {:?} within {} hops",
+                        from, to, max_hops
+                    ))
+                })?;
+                route_to_json(&route)
+            }
+            "/pool" => {
+                if params.contains_key("address") {
+                    let address = address_param(&params, "address")?;
+                    let pool = checkpoint
+                        .data
+                        .iter()
+                        .find(|p| p.address == address)
+                        .ok_or_else(|| {
+                            ServiceError::NotFound(format!("no pool at {:?}", address))
+                        })?;
+                    json!({ "pools": [pool_to_json(pool)] })
+                } else {
+                    let (a, b) = (
+                        self.settings.token(&params, "token_a")?,
+                        self.settings.token(&params, "token_b")?,
+                    );
+                    let mut pools: Vec<&UniswapV2Pool> = checkpoint
+                        .data
+                        .iter()
+                        .filter(|p| {
+                            (p.token_a, p.token_b) == (a, b) || (p.token_a, p.token_b) == (b, a)
+                        })
+                        .collect();
+                    pools.sort_by_key(|p| std::cmp::Reverse(p.eth_value));
+                    let pools: Vec<Value> = pools.into_iter().map(pool_to_json).collect();
+                    json!({ "pools": pools })
+                }
+            }
+            "/opportunities" => {
+                let token = match params.contains_key("token") {
+                    true => Some(self.settings.token(&params, "token")?),
+                    false => None,
+                };
+                let min_profit = amount_param(&params, "min_profit")?.unwrap_or_default();
+                let limit = match params.get("limit") {
+                    Some(limit) => limit.parse().map_err(|_| {
+                        ServiceError::BadRequest(format!("`{}` is not a limit", limit))
+                    })?,
+                    None => usize::MAX,
+                };
+                let opportunities: Vec<Value> = state
+                    .opportunities
+                    .iter()
+                    .filter(|s| token.is_none_or(|t| s.token == t))
+                    .filter(|s| s.net_profit() >= min_profit)
+                    .take(limit)
+                    .map(simulation_to_json)
+                    .collect();
+                json!({ "opportunities": opportunities })
+            }
+            _ => return Err(ServiceError::NotFound(format!("no endpoint {}", path))),
+        };
+        let mut result = result;
+        result["last_block"] = json!(checkpoint.last_block);
+        Ok(result)
+    }
+}
+
+/// Serves `service` over HTTP until the process exits. Every endpoint takes `GET` requests:
+///
+/// - `/status`
+/// - `/quote?from=A&to=B&amount=N`: best route and output for `N` of `A`
+/// - `/quote/exact-out?from=A&to=B&amount=N`: best route and input to receive `N` of `B`
+/// - `/pool?address=P` or `/pool?token_a=A&token_b=B`
+/// - `/opportunities?token=T&min_profit=N&limit=N`, every parameter optional
+pub async fn serve(service: Arc<QuoteService>, addr: SocketAddr) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let service = service.clone();
+                async move {
+                    let (status, body) = match request.method() {
+                        &Method::GET => {
+                            let uri = request.uri();
+                            match service.handle(uri.path(), uri.query().unwrap_or("")) {
+                                Ok(body) => (StatusCode::OK, body),
+                                Err(err) => (err.status(), json!({ "error": err.to_string() })),
+                            }
+                        }
+                        _ => (
+                            StatusCode::METHOD_NOT_ALLOWED,
+                            json!({ "error": "only GET is supported" }),
+                        ),
+                    };
+                    let response = Response::builder()
+                        .status(status)
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(body.to_string()))
+                        .expect("Valid response");
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    info!(%addr, "serving quotes");
+    server.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::PoolFilter;
+
+    fn address(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    fn pool(
+        address_: u64,
+        token_a: u64,
+        token_b: u64,
+        reserve_0: u128,
+        reserve_1: u128,
+    ) -> UniswapV2Pool {
+        UniswapV2Pool {
+            address: address(address_),
+            token_a: address(token_a),
+            token_b: address(token_b),
+            reserve_0,
+            reserve_1,
+            fee: 300,
+            ..Default::default()
+        }
+    }
+
+    async fn service() -> QuoteService {
+        // Token 1 is cheaper against token 3 in pool 102 than through token 2.
+        let pools = vec![
+            pool(100, 1, 2, 10u128.pow(22), 10u128.pow(22)),
+            pool(101, 2, 3, 10u128.pow(22), 10u128.pow(22)),
+            pool(102, 3, 1, 10u128.pow(22), 2 * 10u128.pow(22)),
+        ];
+        let settings = ServiceSettings {
+            search: SearchConfig::default(),
+            filters: FilterPipeline::new(vec![PoolFilter::NonZeroReserves]),
+            max_hops: 3,
+            base_tokens: vec![address(1)],
+            weth: address(1),
+            usd: None,
+            tokens: HashMap::from([("weth".to_string(), address(1))]),
+        };
+        QuoteService::new(
+            Checkpoint::new(42, pools, "test"),
+            settings,
+            GasPrice::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_handle() {
+        let service = service().await;
+
+        let quote = service
+            .handle(
+                "/quote",
+                "from=WETH&to=0x0000000000000000000000000000000000000003&amount=1e18",
+            )
+            .unwrap();
+        assert_eq!(quote["last_block"], 42);
+        assert_eq!(quote["tokens"], json!([address(1), address(2), address(3)]));
+        assert_eq!(quote["amount_in"], "1000000000000000000");
+
+        let exact_out = service
+            .handle(
+                "/quote/exact-out",
+                &format!(
+                    "from=weth&to={:?}&amount={}",
+                    address(3),
+                    quote["amount_out"].as_str().unwrap()
+                ),
+            )
+            .unwrap();
+        assert_eq!(exact_out["tokens"], quote["tokens"]);
+        assert_eq!(exact_out["amount_out"], quote["amount_out"]);
+
+        let pools = service
+            .handle("/pool", &format!("token_a={:?}&token_b=weth", address(3)))
+            .unwrap();
+        assert_eq!(pools["pools"][0]["address"], json!(address(102)));
+        let missing = service.handle("/pool", &format!("address={:?}", address(7)));
+        assert!(matches!(missing, Err(ServiceError::NotFound(_))));
+        assert_eq!(
+            service.handle("/pool", "address=weth").unwrap_err(),
+            ServiceError::BadRequest("`weth` is not an address".to_string())
+        );
+
+        let opportunities = service
+            .handle("/opportunities", "token=weth&limit=1")
+            .unwrap();
+        let best = &opportunities["opportunities"][0];
+        assert_eq!(
+            best["path"],
+            json!([address(1), address(2), address(3), address(1)])
+        );
+        let none = service.handle("/opportunities", "min_profit=1e30").unwrap();
+        assert_eq!(none["opportunities"], json!([]));
+
+        assert_eq!(
+            service.handle("/quote", "from=weth&to=weth").unwrap_err(),
+            ServiceError::BadRequest("missing `amount`".to_string())
+        );
+        assert!(service
+            .handle("/quote", "from=weth&to=usdc&amount=1")
+            .is_err());
+        assert!(matches!(
+            service.handle("/nope", ""),
+            Err(ServiceError::NotFound(_))
+        ));
+    }
+}
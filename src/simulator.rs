@@ -1,20 +1,29 @@
 use crate::{
-    amm::uniswap_v2::{factory::UniswapV2Factory, pool::UniswapV2Pool},
+    amm::{
+        uniswap_v2::{factory::UniswapV2Factory, pool::UniswapV2Pool},
+        Protocol,
+    },
+    config::{SearchConfig, DEFAULT_FEE},
     contract::{IErc20, IUniswapRouter, SimulatorV1, SwapParams},
     eth_provider::EthProvider,
+    gas::{estimate_gas_on_fork, estimate_swap_gas, weth_to_token, GasPrice},
+    metrics::metrics,
+    transaction::TransactionManager,
 };
 use csv::Writer;
 use ethers::{
     abi::{ParamType, Token},
+    contract::ContractCall,
     providers::Middleware,
-    types::{Bytes, H160, U256},
+    types::{BlockNumber, Bytes, H160, U256},
 };
 use eyre::Result;
 use futures::future;
 use serde::Serialize;
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
+use tracing::{debug, debug_span};
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Simulation {
     pub token: H160,
     pub path: Vec<UniswapV2Pool>,
@@ -22,9 +31,18 @@ pub struct Simulation {
     pub amount_out: U256,
     pub amount_path: Vec<U256>,
     pub epsilon: U256,
+    /// Upper bound of the search for the best amount in.
+    pub max_amount_in: U256,
+    pub gas_cost: U256,
 }
 
-pub fn write_simulations_to_csv(simulations: Vec<Simulation>, file_path: &str) {
+/// Sorts simulations from the most to the least profitable after gas.
+pub fn sort_by_net_profit(simulations: &mut [Simulation]) {
+    simulations.sort_by_key(|sim| std::cmp::Reverse(sim.net_profit()));
+}
+
+pub fn write_simulations_to_csv(mut simulations: Vec<Simulation>, file_path: &str) {
+    sort_by_net_profit(&mut simulations);
     let mut wtr = Writer::from_path(file_path).unwrap();
     wtr.write_record(&[
         "token",
@@ -33,6 +51,8 @@ pub fn write_simulations_to_csv(simulations: Vec<Simulation>, file_path: &str) {
         "amount_out",
         "amount_path",
         "profit",
+        "gas_cost",
+        "net_profit",
     ])
     .unwrap();
     for sim in simulations {
@@ -47,8 +67,19 @@ pub fn write_simulations_to_csv(simulations: Vec<Simulation>, file_path: &str) {
         let amount_out = sim.amount_out.to_string();
         let amount_path = format!("{:?}", sim.amount_path);
         let profit = sim.profit().to_string();
-        wtr.write_record(&[token, path, amount_in, amount_out, amount_path, profit])
-            .unwrap();
+        let gas_cost = sim.gas_cost.to_string();
+        let net_profit = sim.net_profit().to_string();
+        wtr.write_record(&[
+            token,
+            path,
+            amount_in,
+            amount_out,
+            amount_path,
+            profit,
+            gas_cost,
+            net_profit,
+        ])
+        .unwrap();
     }
     wtr.flush().unwrap();
 }
@@ -77,13 +108,27 @@ impl SwapParams {
 
 impl Simulation {
     pub fn new(token: H160, path: Vec<UniswapV2Pool>, epsilon: U256) -> Self {
+        Self::with_search(
+            token,
+            path,
+            &SearchConfig {
+                epsilon,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Simulation at the best amount in, searched with the precision and bound of `search`.
+    pub fn with_search(token: H160, path: Vec<UniswapV2Pool>, search: &SearchConfig) -> Self {
         let mut simulation = Simulation {
             token,
             path,
             amount_in: U256::zero(),
             amount_out: U256::zero(),
             amount_path: vec![U256::zero()],
-            epsilon,
+            epsilon: search.epsilon,
+            max_amount_in: search.max_amount_in,
+            gas_cost: U256::zero(),
         };
         simulation.get_best_amount();
         simulation
@@ -95,7 +140,7 @@ impl Simulation {
         path: Vec<H160>,
         epsilon: U256,
     ) -> Self {
-        let factory = UniswapV2Factory::new(factory_address, 300);
+        let factory = UniswapV2Factory::new(factory_address, DEFAULT_FEE.into());
         let mut futures = vec![];
         for i in 0..(path.len() - 1) {
             futures.push(factory.get_pair_address(
@@ -110,7 +155,7 @@ impl Simulation {
             futures.push(UniswapV2Pool::from_address(
                 provider.http.clone(),
                 pair,
-                300,
+                DEFAULT_FEE,
             ))
         }
         Self::new(path.as_slice()[0], future::join_all(futures).await, epsilon)
@@ -123,6 +168,43 @@ impl Simulation {
         U256::zero()
     }
 
+    /// Profit left after paying for gas, both in units of `token`.
+    pub fn net_profit(&self) -> U256 {
+        self.profit().saturating_sub(self.gas_cost)
+    }
+
+    pub fn protocols(&self) -> Vec<Protocol> {
+        vec![Protocol::UniswapV2; self.path.len()]
+    }
+
+    pub fn estimate_gas(&self) -> U256 {
+        estimate_swap_gas(&self.protocols())
+    }
+
+    /// Prices the static gas estimate at `gas_price` and converts it into `token` through `pools`.
+    /// The gas cost does not depend on the amount swapped, so the optimal amount in is unchanged.
+    pub fn set_gas_cost(
+        &mut self,
+        gas_price: &GasPrice,
+        weth: H160,
+        pools: &[&UniswapV2Pool],
+    ) -> Option<U256> {
+        self.set_gas_cost_of(self.estimate_gas(), gas_price, weth, pools)
+    }
+
+    /// Like `set_gas_cost`, for `gas` units measured elsewhere, e.g. estimated on a fork.
+    pub fn set_gas_cost_of(
+        &mut self,
+        gas: U256,
+        gas_price: &GasPrice,
+        weth: H160,
+        pools: &[&UniswapV2Pool],
+    ) -> Option<U256> {
+        let cost = weth_to_token(gas_price.cost(gas), weth, self.token, pools)?;
+        self.gas_cost = cost;
+        Some(cost)
+    }
+
     fn find_local_maximum<F>(mut low: f64, mut high: f64, epsilon: f64, mut f: F) -> (f64, usize)
     where
         F: FnMut(f64) -> f64,
@@ -132,6 +214,10 @@ impl Simulation {
         while high - low > epsilon {
             let mid1 = low + (high - low) / 3.0;
             let mid2 = high - (high - low) / 3.0;
+            // Large amounts are spaced wider than `epsilon` as floats, stop once they stall.
+            if mid1 <= low || mid2 >= high {
+                break;
+            }
 
             if f(mid1) < f(mid2) {
                 low = mid1;
@@ -150,27 +236,63 @@ impl Simulation {
         provider: Arc<EthProvider>,
         public_key: H160,
         private_key: &str,
+    ) -> Result<U256> {
+        let manager = TransactionManager::from_provider(&provider, private_key).await;
+        self.swap_using_router_with_manager(router_address, provider, &manager, public_key)
+            .await
+    }
+
+    /// Swaps through the router with `manager` and measures the yield once the transaction
+    /// receipt is confirmed.
+    pub async fn swap_using_router_with_manager(
+        &self,
+        router_address: H160,
+        provider: Arc<EthProvider>,
+        manager: &TransactionManager,
+        public_key: H160,
     ) -> Result<U256> {
         let erc20_path = self.get_erc20_path();
         let last_token = IErc20::new(erc20_path.last().unwrap().clone(), provider.http.clone());
         let current_balance = last_token.balance_of(public_key).await?;
-        let router = IUniswapRouter::new(
-            router_address,
-            provider.get_signer_middleware(private_key).await,
-        );
-        let amount_out_min = U256::zero();
+        let router = IUniswapRouter::new(router_address, manager.client());
+        let call = self.router_swap(&router, public_key)?;
+        let receipt = manager.send_and_confirm(call.tx, 0).await?;
+        let mut balance = last_token.balance_of(public_key);
+        if let Some(block_number) = receipt.block_number {
+            balance = balance.block(BlockNumber::Number(block_number));
+        }
+        Ok(balance.call().await? - current_balance)
+    }
+
+    /// Gas the router swap of `swap_using_router` takes from `public_key`, estimated with
+    /// `eth_estimateGas`, so only meaningful against a fork.
+    pub async fn estimate_gas_on_fork(
+        &self,
+        router_address: H160,
+        provider: Arc<EthProvider>,
+        public_key: H160,
+    ) -> Result<U256> {
+        let router = IUniswapRouter::new(router_address, provider.http.clone());
+        let mut tx = self.router_swap(&router, public_key)?.tx;
+        tx.set_from(public_key);
+        estimate_gas_on_fork(provider.http.clone(), &tx).await
+    }
+
+    /// Router call swapping `amount_in` of the native token along the path to `public_key`.
+    fn router_swap<M: Middleware>(
+        &self,
+        router: &IUniswapRouter<M>,
+        public_key: H160,
+    ) -> Result<ContractCall<M, Vec<U256>>> {
         let deadline = U256::from(
             std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)?
                 .as_secs()
                 + 10,
         );
-        router
-            .swap_exact_eth_for_tokens(amount_out_min, erc20_path, public_key, deadline)
-            .value(self.amount_in)
-            .send()
-            .await?;
-        Ok(last_token.balance_of(public_key).await? - current_balance)
+        Ok(router
+            .swap_exact_eth_for_tokens(U256::zero(), self.get_erc20_path(), public_key, deadline)
+            .value(self.amount_in))
     }
 
     pub fn get_erc20_path(&self) -> Vec<H160> {
@@ -197,15 +319,34 @@ impl Simulation {
     }
 
     pub fn get_best_amount(&mut self) {
+        let span = debug_span!(
+            "simulation",
+            token = ?self.token,
+            hops = self.path.len(),
+            max_amount_in = %self.max_amount_in,
+        );
+        let _entered = span.enter();
+        let started = Instant::now();
+        metrics().add_cycles_evaluated(1);
         let f = |amount: f64| {
             let (amount_out, _) = self.simulate_swap_offline(U256::from(amount as u128));
             amount_out.as_u128() as f64 - amount
         };
-        let (amount, _) =
-            Self::find_local_maximum(0.0, 10f64.powf(20.0), self.epsilon.as_u128() as f64, f);
+        let (amount, _) = Self::find_local_maximum(
+            0.0,
+            self.max_amount_in.as_u128() as f64,
+            self.epsilon.as_u128() as f64,
+            f,
+        );
         let amount = U256::from(amount as u128);
         self.amount_in = amount;
         (self.amount_out, self.amount_path) = self.simulate_swap_offline(amount);
+        debug!(
+            amount_in = %self.amount_in,
+            profit = %self.profit(),
+            latency_us = started.elapsed().as_micros() as u64,
+            "found best amount"
+        );
     }
 
     pub async fn simulate_swap<M: Middleware>(&self, middleware: Arc<M>, amount: U256) -> U256 {
@@ -260,26 +401,27 @@ mod tests {
         dotenv::dotenv().ok();
         let provider = EthProvider::new_alchemy().await;
         let book = AddressBook::new();
-        let factory: UniswapV2Factory = UniswapV2Factory::new(book.mainnet.uniswap_v2.factory, 300);
+        let factory: UniswapV2Factory =
+            UniswapV2Factory::new(book.mainnet().dexes["uniswap_v2"].factory, 300);
         let weth_usdc = factory
             .get_pair_address(
                 provider.http.clone(),
-                &book.mainnet.erc20["weth"],
-                &book.mainnet.erc20["usdc"],
+                &book.mainnet().erc20["weth"],
+                &book.mainnet().erc20["usdc"],
             )
             .await;
         let usdc_matic = factory
             .get_pair_address(
                 provider.http.clone(),
-                &book.mainnet.erc20["matic"],
-                &book.mainnet.erc20["usdc"],
+                &book.mainnet().erc20["matic"],
+                &book.mainnet().erc20["usdc"],
             )
             .await;
         let matic_weth = factory
             .get_pair_address(
                 provider.http.clone(),
-                &book.mainnet.erc20["matic"],
-                &book.mainnet.erc20["weth"],
+                &book.mainnet().erc20["matic"],
+                &book.mainnet().erc20["weth"],
             )
             .await;
         let pools: Vec<UniswapV2Pool> = vec![
@@ -288,7 +430,7 @@ mod tests {
             UniswapV2Pool::from_address(provider.http.clone(), matic_weth, 300).await,
         ];
         println!("{:?} {:?} {:?}", weth_usdc, usdc_matic, matic_weth);
-        let simulation = Simulation::new(book.mainnet.erc20["weth"], pools, U256::exp10(6));
+        let simulation = Simulation::new(book.mainnet().erc20["weth"], pools, U256::exp10(6));
         SetupResult(provider, simulation, book)
     }
 
@@ -296,7 +438,7 @@ mod tests {
     async fn test_simulate_swap() {
         let SetupResult(provider, simulation, book) = setup().await;
         let sim = Simulation::new(
-            book.mainnet.erc20["weth"],
+            book.mainnet().erc20["weth"],
             vec![simulation.path.into_iter().next().unwrap()], // weth-usdc
             simulation.epsilon,
         );
@@ -332,24 +474,24 @@ mod tests {
         let path = vec![
             UniswapV2Pool::from_address(
                 provider.http.clone(),
-                book.mainnet.uniswap_v2.pairs["weth"]["usdt"],
+                book.mainnet().dexes["uniswap_v2"].pairs["weth"]["usdt"],
                 300,
             )
             .await,
             UniswapV2Pool::from_address(
                 provider.http.clone(),
-                book.mainnet.uniswap_v2.pairs["usdt"]["usd_old"],
+                book.mainnet().dexes["uniswap_v2"].pairs["usdt"]["usd_old"],
                 300,
             )
             .await,
             UniswapV2Pool::from_address(
                 provider.http.clone(),
-                book.mainnet.uniswap_v2.pairs["usd_old"]["weth"],
+                book.mainnet().dexes["uniswap_v2"].pairs["usd_old"]["weth"],
                 300,
             )
             .await,
         ];
-        let sim = Simulation::new(book.mainnet.erc20["weth"], path, U256::exp10(14));
+        let sim = Simulation::new(book.mainnet().erc20["weth"], path, U256::exp10(14));
         let amount_out = sim
             .simulate_swap(provider.http.clone(), sim.amount_in)
             .await;
@@ -363,25 +505,86 @@ mod tests {
         assert_eq!(
             path,
             vec![
-                book.mainnet.erc20["weth"],
-                book.mainnet.erc20["usdc"],
-                book.mainnet.erc20["matic"],
-                book.mainnet.erc20["weth"],
+                book.mainnet().erc20["weth"],
+                book.mainnet().erc20["usdc"],
+                book.mainnet().erc20["matic"],
+                book.mainnet().erc20["weth"],
             ]
         )
     }
 
+    #[test]
+    fn test_net_profit() {
+        let (weth, usdc, link) = (
+            H160::from_low_u64_be(1),
+            H160::from_low_u64_be(2),
+            H160::from_low_u64_be(3),
+        );
+        let e18 = 10u128.pow(18);
+        let pools = vec![
+            UniswapV2Pool::new(
+                H160::from_low_u64_be(10),
+                weth,
+                18,
+                usdc,
+                18,
+                100 * e18,
+                200_000 * e18,
+                300,
+                U256::zero(),
+            ),
+            UniswapV2Pool::new(
+                H160::from_low_u64_be(11),
+                usdc,
+                18,
+                link,
+                18,
+                100_000 * e18,
+                20_000 * e18,
+                300,
+                U256::zero(),
+            ),
+            UniswapV2Pool::new(
+                H160::from_low_u64_be(12),
+                link,
+                18,
+                weth,
+                18,
+                10_000 * e18,
+                60 * e18,
+                300,
+                U256::zero(),
+            ),
+        ];
+        let mut simulation = Simulation::new(weth, pools.clone(), U256::exp10(10));
+        assert!(!simulation.profit().is_zero());
+        assert_eq!(simulation.net_profit(), simulation.profit());
+
+        let gas_price = GasPrice::new(U256::exp10(10), U256::exp10(9));
+        let refs: Vec<&UniswapV2Pool> = pools.iter().collect();
+        let gas_cost = simulation.set_gas_cost(&gas_price, weth, &refs).unwrap();
+        assert_eq!(gas_cost, gas_price.cost(simulation.estimate_gas()));
+        assert_eq!(simulation.net_profit(), simulation.profit() - gas_cost);
+
+        let mut unprofitable = Simulation::new(weth, pools.clone(), U256::exp10(10));
+        unprofitable.gas_cost = unprofitable.profit();
+        let mut simulations = vec![unprofitable, simulation];
+        sort_by_net_profit(&mut simulations);
+        assert!(simulations[0].net_profit() > simulations[1].net_profit());
+        assert!(simulations[1].net_profit().is_zero());
+    }
+
     #[tokio::test]
     async fn test_new_from_path() {
         let SetupResult(provider, simulation, book) = setup().await;
         let simu = Simulation::new_from_erc20_path(
             provider.clone(),
-            book.mainnet.uniswap_v2.factory,
+            book.mainnet().dexes["uniswap_v2"].factory,
             vec![
-                book.mainnet.erc20["weth"],
-                book.mainnet.erc20["usdc"],
-                book.mainnet.erc20["matic"],
-                book.mainnet.erc20["weth"],
+                book.mainnet().erc20["weth"],
+                book.mainnet().erc20["usdc"],
+                book.mainnet().erc20["matic"],
+                book.mainnet().erc20["weth"],
             ],
             U256::exp10(4),
         )
@@ -0,0 +1,812 @@
+use crate::{
+    amm::uniswap_v2::pool::UniswapV2Pool,
+    checkpoint::{
+        reconcile::{Reconciler, ReserveDrift},
+        Checkpoint,
+    },
+    cli::{filter_checkpoint, price_gas, simulate_cycle, simulate_cycles, simulation_to_json},
+    config::SearchConfig,
+    contract::SyncFilter,
+    eth_provider::EthProvider,
+    gas::{token_to_weth, FeeTracker, GasPrice},
+    metrics::metrics,
+    server::{amount_param, query_params, QuoteService, ServiceError, ServiceSettings},
+    simulator::Simulation,
+};
+use ethers::{
+    providers::Middleware,
+    types::{H160, U256},
+};
+use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc, oneshot},
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{Request, Response},
+        http::StatusCode,
+        Error as WsError, Message,
+    },
+};
+use tracing::{debug, info, warn};
+
+/// Events buffered for a client before it is disconnected for being too slow.
+const CLIENT_BUFFER: usize = 1024;
+
+/// The Sync events of a block arrive together, a pause this long ends the block.
+const BLOCK_SETTLE: Duration = Duration::from_millis(200);
+
+/// Bounds of the wait before subscribing again to Sync events, doubled on every failure.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How the opportunity of a cycle changed at a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    New,
+    Updated,
+    Expired,
+}
+
+/// A change of the opportunity of a cycle. `id` names the cycle while the stream runs.
+#[derive(Clone)]
+pub struct OpportunityEvent {
+    pub change: Change,
+    pub id: usize,
+    pub block: u64,
+    /// The opportunity, as last seen when it expired.
+    pub simulation: Simulation,
+}
+
+struct Cycle {
+    token: H160,
+    pools: Vec<H160>,
+}
+
+/// The cycles through the base tokens and their opportunities, simulated again whenever a Sync
+/// event changes the reserves of one of their pools.
+pub struct OpportunityTracker {
+    search: SearchConfig,
+    /// Gas of the cycles is priced at `gas_price` and converted from `weth`.
+    weth: H160,
+    gas_price: GasPrice,
+    /// Pools of the cycles, at the reserves of their last Sync event.
+    pools: HashMap<H160, UniswapV2Pool>,
+    cycles: Vec<Cycle>,
+    cycles_by_pool: HashMap<H160, Vec<usize>>,
+    /// Simulation of every profitable cycle.
+    opportunities: BTreeMap<usize, Simulation>,
+    /// Pools with Sync events since the last `end_block`.
+    changed: HashSet<H160>,
+    block: u64,
+}
+
+impl OpportunityTracker {
+    pub fn new(
+        pools: &[&UniswapV2Pool],
+        block: u64,
+        base_tokens: &[H160],
+        search: &SearchConfig,
+        weth: H160,
+        gas_price: GasPrice,
+    ) -> Self {
+        let mut tracker = OpportunityTracker {
+            search: search.clone(),
+            weth,
+            gas_price,
+            pools: HashMap::new(),
+            cycles: vec![],
+            cycles_by_pool: HashMap::new(),
+            opportunities: BTreeMap::new(),
+            changed: HashSet::new(),
+            block,
+        };
+        for token in base_tokens {
+            for simulation in simulate_cycles(pools, *token, search) {
+                let id = tracker.track(*token, &simulation);
+                if let Some(simulation) = price_gas(simulation, &gas_price, weth, pools) {
+                    tracker.opportunities.insert(id, simulation);
+                }
+            }
+        }
+        tracker
+    }
+
+    /// Adds `simulation`'s cycle and its pools, returns the id of the cycle.
+    fn track(&mut self, token: H160, simulation: &Simulation) -> usize {
+        let id = self.cycles.len();
+        for pool in &simulation.path {
+            self.cycles_by_pool
+                .entry(pool.address)
+                .or_default()
+                .push(id);
+            self.pools
+                .entry(pool.address)
+                .or_insert_with(|| pool.clone());
+        }
+        self.cycles.push(Cycle {
+            token,
+            pools: simulation.path.iter().map(|p| p.address).collect(),
+        });
+        id
+    }
+
+    /// Tracks the cycles of `simulations` through `token` that are not tracked yet, e.g.
+    /// through pairs created since the tracker was built, keeping the ids of the known cycles.
+    /// Their new pools start at the simulated reserves, the known ones keep those of their last
+    /// Sync event. The new cycles are simulated at the end of the block, returns how many.
+    pub fn add_cycles(&mut self, token: H160, simulations: &[Simulation]) -> usize {
+        let known: HashSet<Vec<H160>> = self
+            .cycles
+            .iter()
+            .filter(|cycle| cycle.token == token)
+            .map(|cycle| cycle.pools.clone())
+            .collect();
+        let mut added = 0;
+        for simulation in simulations {
+            let addresses: Vec<H160> = simulation.path.iter().map(|p| p.address).collect();
+            if known.contains(&addresses) {
+                continue;
+            }
+            self.track(token, simulation);
+            self.changed.extend(addresses);
+            added += 1;
+        }
+        added
+    }
+
+    /// Applies a Sync event to its pool, if the pool is part of a cycle.
+    pub fn apply(&mut self, address: H160, sync: &SyncFilter) {
+        if let Some(pool) = self.pools.get_mut(&address) {
+            pool.reserve_0 = sync.reserve_0;
+            pool.reserve_1 = sync.reserve_1;
+            self.changed.insert(address);
+        }
+    }
+
+    /// Overwrites the reserves of the tracked pools that drifted from their Sync events. The
+    /// cycles through them are simulated again at the end of the block.
+    pub fn repair(&mut self, drifts: &[ReserveDrift]) {
+        for drift in drifts {
+            if let Some(pool) = self.pools.get_mut(&drift.address) {
+                (pool.reserve_0, pool.reserve_1) = drift.actual;
+                self.changed.insert(drift.address);
+            }
+        }
+    }
+
+    /// Prices gas at `gas_price` from now on. The current opportunities are simulated again at
+    /// the end of the block.
+    pub fn set_gas_price(&mut self, gas_price: GasPrice) {
+        if gas_price == self.gas_price {
+            return;
+        }
+        self.gas_price = gas_price;
+        for id in self.opportunities.keys() {
+            self.changed.extend(&self.cycles[*id].pools);
+        }
+    }
+
+    /// Simulates again the cycles through the pools changed since the last call, and returns
+    /// how their opportunities changed at `block`.
+    pub fn end_block(&mut self, block: u64) -> Vec<OpportunityEvent> {
+        self.block = block;
+        let mut ids: Vec<usize> = self
+            .changed
+            .drain()
+            .filter_map(|address| self.cycles_by_pool.get(&address))
+            .flatten()
+            .copied()
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        let pools: Vec<&UniswapV2Pool> = self.pools.values().collect();
+        let mut events = vec![];
+        for id in ids {
+            let cycle = &self.cycles[id];
+            let path = cycle.pools.iter().map(|a| self.pools[a].clone()).collect();
+            let simulation = simulate_cycle(cycle.token, path, &self.search);
+            let event = |change, simulation| OpportunityEvent {
+                change,
+                id,
+                block,
+                simulation,
+            };
+            let Some(simulation) = price_gas(simulation, &self.gas_price, self.weth, &pools) else {
+                if let Some(previous) = self.opportunities.remove(&id) {
+                    events.push(event(Change::Expired, previous));
+                }
+                continue;
+            };
+            let change = match self.opportunities.get(&id) {
+                None => Change::New,
+                Some(previous)
+                    if previous.amount_path == simulation.amount_path
+                        && previous.gas_cost == simulation.gas_cost =>
+                {
+                    continue
+                }
+                Some(_) => Change::Updated,
+            };
+            self.opportunities.insert(id, simulation.clone());
+            events.push(event(change, simulation));
+        }
+        metrics().record_opportunities(
+            events
+                .iter()
+                .filter(|event| event.change == Change::New)
+                .filter_map(|event| {
+                    let simulation = &event.simulation;
+                    token_to_weth(simulation.net_profit(), simulation.token, self.weth, &pools)
+                }),
+        );
+        events
+    }
+
+    /// The current opportunities, as new at the last block.
+    pub fn opportunities(&self) -> Vec<OpportunityEvent> {
+        self.opportunities
+            .iter()
+            .map(|(id, simulation)| OpportunityEvent {
+                change: Change::New,
+                id: *id,
+                block: self.block,
+                simulation: simulation.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Opportunities a client asked for with `?token=T&min_profit=N`, both optional.
+///
+/// An opportunity is sent as new the first time it passes the filter and as expired once it
+/// stops passing, so a client sees a consistent set whatever its filter.
+pub struct ClientFilter {
+    token: Option<H160>,
+    min_profit: U256,
+    sent: HashSet<usize>,
+}
+
+impl ClientFilter {
+    pub fn parse(query: &str, settings: &ServiceSettings) -> Result<Self, ServiceError> {
+        let params = query_params(query);
+        let token = if params.contains_key("token") {
+            Some(settings.token(&params, "token")?)
+        } else {
+            None
+        };
+        Ok(ClientFilter {
+            token,
+            min_profit: amount_param(&params, "min_profit")?.unwrap_or_default(),
+            sent: HashSet::new(),
+        })
+    }
+
+    fn passes(&self, simulation: &Simulation) -> bool {
+        self.token.is_none_or(|token| simulation.token == token)
+            && simulation.net_profit() >= self.min_profit
+    }
+
+    /// The message telling the client about `event`, if it should know.
+    pub fn message(&mut self, event: &OpportunityEvent) -> Option<Value> {
+        let passes = event.change != Change::Expired && self.passes(&event.simulation);
+        let change = match (passes, self.sent.contains(&event.id)) {
+            (true, false) => {
+                self.sent.insert(event.id);
+                Change::New
+            }
+            (true, true) => Change::Updated,
+            (false, true) => {
+                self.sent.remove(&event.id);
+                Change::Expired
+            }
+            (false, false) => return None,
+        };
+        let mut message = simulation_to_json(&event.simulation);
+        message["type"] = json!(change);
+        message["id"] = json!(event.id);
+        message["block"] = json!(event.block);
+        Some(message)
+    }
+}
+
+/// Opportunities kept up to date from Sync events and pushed to WebSocket clients.
+pub struct OpportunityStream {
+    tracker: Mutex<OpportunityTracker>,
+    events: broadcast::Sender<Arc<OpportunityEvent>>,
+    settings: ServiceSettings,
+}
+
+impl OpportunityStream {
+    /// Tracks the cycles through the base tokens over the pools of `checkpoint` that pass the
+    /// settings' filters.
+    pub fn new(
+        checkpoint: &Checkpoint<Vec<UniswapV2Pool>>,
+        settings: ServiceSettings,
+        gas_price: GasPrice,
+    ) -> eyre::Result<Self> {
+        let (pools, _) =
+            filter_checkpoint(checkpoint, &settings.filters, settings.weth, settings.usd)?;
+        let tracker = OpportunityTracker::new(
+            &pools,
+            checkpoint.last_block,
+            &settings.base_tokens,
+            &settings.search,
+            settings.weth,
+            gas_price,
+        );
+        let (events, _) = broadcast::channel(CLIENT_BUFFER);
+        Ok(OpportunityStream {
+            tracker: Mutex::new(tracker),
+            events,
+            settings,
+        })
+    }
+
+    /// The current opportunities, and the events after them.
+    fn subscribe(
+        &self,
+    ) -> (
+        Vec<OpportunityEvent>,
+        broadcast::Receiver<Arc<OpportunityEvent>>,
+    ) {
+        let tracker = self.tracker.lock().unwrap();
+        (tracker.opportunities(), self.events.subscribe())
+    }
+
+    /// Tracks the cycles through the pools of every state `service` serves from now on, so
+    /// that pairs created while the stream runs are searched too. Returns with the service.
+    pub async fn follow_checkpoints(&self, service: &QuoteService) {
+        let mut states = service.subscribe();
+        while states.changed().await.is_ok() {
+            let state = states.borrow_and_update().clone();
+            let last_block = state.checkpoint.last_block;
+            let settings = self.settings.clone();
+            // The search takes long enough to stall the runtime, and must not hold the tracker.
+            let searched = tokio::task::spawn_blocking(move || {
+                let (pools, _) = filter_checkpoint(
+                    &state.checkpoint,
+                    &settings.filters,
+                    settings.weth,
+                    settings.usd,
+                )?;
+                Ok::<_, eyre::Report>(
+                    settings
+                        .base_tokens
+                        .iter()
+                        .map(|token| (*token, simulate_cycles(&pools, *token, &settings.search)))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .await;
+            let cycles = match searched
+                .map_err(eyre::Report::from)
+                .and_then(|cycles| cycles)
+            {
+                Ok(cycles) => cycles,
+                Err(err) => {
+                    warn!(%err, last_block, "could not search the new pools");
+                    continue;
+                }
+            };
+            let mut tracker = self.tracker.lock().unwrap();
+            let added: usize = cycles
+                .iter()
+                .map(|(token, simulations)| tracker.add_cycles(*token, simulations))
+                .sum();
+            if added > 0 {
+                info!(added, last_block, "tracking new cycles");
+            }
+        }
+    }
+
+    /// Publishes the changes of `block`, with gas priced at the current fees of `provider` and
+    /// the reserves of the tracked pools reconciled first when `reconciler` is due.
+    async fn publish(
+        &self,
+        provider: &EthProvider,
+        block: u64,
+        reconciler: &mut Option<Reconciler>,
+    ) {
+        if let Some(reconciler) = reconciler.as_mut().filter(|r| r.is_due(block)) {
+            let mut pools: Vec<UniswapV2Pool> = self
+                .tracker
+                .lock()
+                .unwrap()
+                .pools
+                .values()
+                .cloned()
+                .collect();
+            if let Some(drifts) = reconciler
+                .maybe_reconcile(&mut pools, block, provider.http.clone())
+                .await
+            {
+                self.tracker.lock().unwrap().repair(&drifts);
+            }
+        }
+        let gas_price = FeeTracker::default().gas_price(provider.http.clone()).await;
+        // Held while sending so that a client subscribing misses no event.
+        let mut tracker = self.tracker.lock().unwrap();
+        match gas_price {
+            Ok(gas_price) => tracker.set_gas_price(gas_price),
+            Err(err) => warn!(%err, block, "could not get gas price, keeping the previous one"),
+        }
+        let events = tracker.end_block(block);
+        debug!(block, events = events.len(), "publishing opportunities");
+        for event in events {
+            let _ = self.events.send(Arc::new(event));
+        }
+    }
+
+    /// Follows the Sync events of `provider` and publishes the changes of each block once its
+    /// events stop arriving. Whenever the subscription fails or ends, subscribes again after a
+    /// backoff, so this only returns with the process.
+    pub async fn follow(
+        &self,
+        provider: Arc<EthProvider>,
+        step: usize,
+        mut reconciler: Option<Reconciler>,
+    ) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let started = Instant::now();
+            match self
+                .follow_subscription(&provider, step, &mut reconciler)
+                .await
+            {
+                Ok(()) => warn!("sync event subscription ended"),
+                Err(err) => warn!(%err, "sync event subscription failed"),
+            }
+            // A subscription that lasted was not failing, start the backoff over.
+            if started.elapsed() > MAX_BACKOFF {
+                backoff = MIN_BACKOFF;
+            }
+            info!(?backoff, "subscribing to sync events again");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Follows one subscription, catching up on the events it missed once subscribed.
+    async fn follow_subscription(
+        &self,
+        provider: &EthProvider,
+        step: usize,
+        reconciler: &mut Option<Reconciler>,
+    ) -> eyre::Result<()> {
+        let wss = provider.connect_wss().await?;
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let (subscribed, is_subscribed) = oneshot::channel();
+        let subscription = tokio::spawn(UniswapV2Pool::subscribe_sync_event(
+            wss,
+            move || {
+                let _ = subscribed.send(());
+            },
+            move |address, sync, block| {
+                let _ = sender.send((address, sync, block));
+            },
+        ));
+        if is_subscribed.await.is_err() {
+            subscription.await??;
+            return Ok(());
+        }
+        // Events up to `synced` are already applied from the logs.
+        let synced = self.resync(provider, step, reconciler).await?;
+        let mut pending: Option<u64> = None;
+        loop {
+            let received = match pending {
+                Some(_) => tokio::time::timeout(BLOCK_SETTLE, receiver.recv()).await,
+                None => Ok(receiver.recv().await),
+            };
+            match received {
+                Ok(Some((_, _, block))) if block <= synced => {}
+                Ok(Some((address, sync, block))) => {
+                    if let Some(previous) = pending.filter(|previous| *previous != block) {
+                        self.publish(provider, previous, reconciler).await;
+                    }
+                    self.tracker.lock().unwrap().apply(address, &sync);
+                    pending = Some(block);
+                }
+                Ok(None) => {
+                    if let Some(block) = pending {
+                        self.publish(provider, block, reconciler).await;
+                    }
+                    return Ok(subscription.await??);
+                }
+                Err(_) => {
+                    if let Some(block) = pending.take() {
+                        self.publish(provider, block, reconciler).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Applies the Sync events of the tracked pools from the tracker's block to the head and
+    /// publishes them, so that no event is missed between two subscriptions. Returns the head.
+    async fn resync(
+        &self,
+        provider: &EthProvider,
+        step: usize,
+        reconciler: &mut Option<Reconciler>,
+    ) -> eyre::Result<u64> {
+        let (from_block, addresses) = {
+            let tracker = self.tracker.lock().unwrap();
+            (tracker.block, tracker.pools.keys().copied().collect())
+        };
+        let head = provider.http.get_block_number().await?.as_u64();
+        if head <= from_block {
+            return Ok(from_block);
+        }
+        let sync_events = UniswapV2Pool::get_sync_events_from_logs_concurrent(
+            (from_block + 1) as usize,
+            head as usize,
+            step,
+            addresses,
+            provider.http.clone(),
+        )
+        .await?;
+        info!(
+            from_block,
+            head,
+            pools = sync_events.len(),
+            "caught up on sync events"
+        );
+        {
+            let mut tracker = self.tracker.lock().unwrap();
+            for (address, sync) in &sync_events {
+                tracker.apply(*address, sync);
+            }
+        }
+        self.publish(provider, head, reconciler).await;
+        Ok(head)
+    }
+
+    async fn client(&self, socket: TcpStream) -> Result<(), WsError> {
+        let mut filter = None;
+        // The error response type is tungstenite's.
+        #[allow(clippy::result_large_err)]
+        let callback = |request: &Request, response: Response| match ClientFilter::parse(
+            request.uri().query().unwrap_or(""),
+            &self.settings,
+        ) {
+            Ok(parsed) => {
+                filter = Some(parsed);
+                Ok(response)
+            }
+            Err(err) => Err(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Some(err.to_string()))
+                .expect("Valid response")),
+        };
+        let socket = accept_hdr_async(socket, callback).await?;
+        let mut filter = filter.expect("Filter parsed during the handshake");
+        let (mut sink, mut incoming) = socket.split();
+        let (opportunities, mut events) = self.subscribe();
+        for event in &opportunities {
+            if let Some(message) = filter.message(event) {
+                sink.send(Message::Text(message.to_string())).await?;
+            }
+        }
+        loop {
+            tokio::select! {
+                event = events.recv() => {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!(skipped, "disconnecting a client too slow for the stream");
+                            return sink.send(Message::Close(None)).await;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => {
+                            return sink.send(Message::Close(None)).await;
+                        }
+                    };
+                    if let Some(message) = filter.message(&event) {
+                        sink.send(Message::Text(message.to_string())).await?;
+                    }
+                }
+                message = incoming.next() => match message {
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => return Err(err),
+                },
+            }
+        }
+    }
+}
+
+/// Streams opportunities to WebSocket clients of `addr` until the process exits.
+///
+/// Clients connect to `/?token=T&min_profit=N`, every parameter optional, and receive the
+/// current opportunities then every change as JSON messages, each with its `type` (`new`,
+/// `updated` or `expired`), cycle `id`, `block` and the fields of a simulation.
+pub async fn serve(stream: Arc<OpportunityStream>, addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(%addr, "streaming opportunities");
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(%err, "could not accept a client");
+                continue;
+            }
+        };
+        let stream = stream.clone();
+        tokio::spawn(async move {
+            if let Err(err) = stream.client(socket).await {
+                debug!(%peer, %err, "client disconnected");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::{FilterPipeline, PoolFilter};
+
+    fn address(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    #[test]
+    fn test_tracker_and_filters() {
+        let e22 = 10u128.pow(22);
+        let pool = |n: u64, token_a: u64, token_b: u64, reserve_1: u128| UniswapV2Pool {
+            address: address(n),
+            token_a: address(token_a),
+            token_b: address(token_b),
+            reserve_0: e22,
+            reserve_1,
+            fee: 300,
+            ..Default::default()
+        };
+        // Token 1 is cheap against token 3 in pool 102, pool 103 is in no cycle.
+        let pools = [
+            pool(100, 1, 2, e22),
+            pool(101, 2, 3, e22),
+            pool(102, 3, 1, 2 * e22),
+            pool(103, 1, 4, e22),
+        ];
+        let pools: Vec<&UniswapV2Pool> = pools.iter().collect();
+        let mut tracker = OpportunityTracker::new(
+            &pools,
+            10,
+            &[address(1)],
+            &SearchConfig::default(),
+            address(1),
+            GasPrice::default(),
+        );
+        let opportunities = tracker.opportunities();
+        assert_eq!(opportunities.len(), 1);
+        assert_eq!(opportunities[0].block, 10);
+
+        let settings = ServiceSettings {
+            search: SearchConfig::default(),
+            filters: FilterPipeline::new(vec![PoolFilter::NonZeroReserves]),
+            max_hops: 3,
+            base_tokens: vec![address(1)],
+            weth: address(1),
+            usd: None,
+            tokens: HashMap::from([("weth".to_string(), address(1))]),
+        };
+        let mut all = ClientFilter::parse("token=weth", &settings).unwrap();
+        let mut rich = ClientFilter::parse("min_profit=1e30", &settings).unwrap();
+        assert!(ClientFilter::parse("token=nope", &settings).is_err());
+        let message = all.message(&opportunities[0]).unwrap();
+        assert_eq!(message["type"], "new");
+        assert_eq!(message["block"], 10);
+        assert_eq!(message["pools"].as_array().unwrap().len(), 3);
+        assert!(rich.message(&opportunities[0]).is_none());
+
+        let sync = |reserve_1: u128| SyncFilter {
+            reserve_0: e22,
+            reserve_1,
+        };
+        tracker.apply(address(103), &sync(2 * e22));
+        assert!(tracker.end_block(11).is_empty());
+
+        tracker.apply(address(102), &sync(3 * e22));
+        let updated = tracker.end_block(12);
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].change, Change::Updated);
+        assert!(updated[0].simulation.profit() > opportunities[0].simulation.profit());
+        assert_eq!(all.message(&updated[0]).unwrap()["type"], "updated");
+
+        tracker.apply(address(102), &sync(e22));
+        let expired = tracker.end_block(13);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].change, Change::Expired);
+        assert_eq!(all.message(&expired[0]).unwrap()["type"], "expired");
+        assert!(all.message(&expired[0]).is_none());
+        assert!(tracker.opportunities().is_empty());
+
+        // Gas costing more than the profit expires an opportunity without any Sync event.
+        tracker.apply(address(102), &sync(2 * e22));
+        let new = tracker.end_block(14);
+        assert_eq!(new[0].change, Change::New);
+        tracker.set_gas_price(GasPrice::new(new[0].simulation.profit(), U256::zero()));
+        let expired = tracker.end_block(15);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].change, Change::Expired);
+
+        // Reserves repaired by a reconciliation are simulated again like a Sync event.
+        tracker.set_gas_price(GasPrice::default());
+        tracker.repair(&[ReserveDrift {
+            address: address(102),
+            stored: (e22, 2 * e22),
+            actual: (e22, 3 * e22),
+        }]);
+        let repaired = tracker.end_block(16);
+        assert_eq!(repaired.len(), 1);
+        assert_eq!(repaired[0].change, Change::New);
+        assert_eq!(tracker.pools[&address(102)].reserve_1, 3 * e22);
+    }
+
+    #[test]
+    fn test_add_cycles() {
+        let e22 = 10u128.pow(22);
+        let pool = |n: u64, token_a: u64, token_b: u64, reserve_1: u128| UniswapV2Pool {
+            address: address(n),
+            token_a: address(token_a),
+            token_b: address(token_b),
+            reserve_0: e22,
+            reserve_1,
+            fee: 300,
+            ..Default::default()
+        };
+        let pools = [
+            pool(100, 1, 2, e22),
+            pool(101, 2, 3, e22),
+            pool(102, 3, 1, 2 * e22),
+        ];
+        let refs: Vec<&UniswapV2Pool> = pools.iter().collect();
+        let search = SearchConfig::default();
+        let mut tracker = OpportunityTracker::new(
+            &refs,
+            10,
+            &[address(1)],
+            &search,
+            address(1),
+            GasPrice::default(),
+        );
+        let known = tracker.cycles.len();
+        let opportunity = tracker.opportunities()[0].id;
+        tracker.apply(
+            address(102),
+            &SyncFilter {
+                reserve_0: e22,
+                reserve_1: 3 * e22,
+            },
+        );
+
+        // Pairs 103 and 104 are created, token 1 is cheap against token 4.
+        let created = [pool(103, 2, 4, e22), pool(104, 4, 1, 2 * e22)];
+        let refs: Vec<&UniswapV2Pool> = pools.iter().chain(&created).collect();
+        let simulations = simulate_cycles(&refs, address(1), &search);
+        assert!(tracker.add_cycles(address(1), &simulations) > 0);
+        assert_eq!(tracker.add_cycles(address(1), &simulations), 0);
+        assert!(tracker.cycles.len() > known);
+        // Known pools keep the reserves of their Sync events.
+        assert_eq!(tracker.pools[&address(102)].reserve_1, 3 * e22);
+
+        let events = tracker.end_block(11);
+        assert!(events.iter().any(|e| e.change == Change::New
+            && e.simulation.path[0].address == address(100)
+            && e.simulation.path.iter().any(|p| p.address == address(104))));
+        assert!(events
+            .iter()
+            .any(|e| e.id == opportunity && e.change == Change::Updated));
+    }
+}
@@ -5,9 +5,9 @@ use crate::{
     amm::uniswap_v2::{factory::UniswapV2Factory, pool::UniswapV2Pool},
     checkpoint::Checkpoint,
     eth_provider::EthProvider,
+    filters::{FilterContext, FilterPipeline, PoolFilter},
 };
 use ethers::types::H160;
-use itertools::Itertools;
 use rand::{seq::SliceRandom, thread_rng};
 
 pub struct Fixtures {
@@ -32,13 +32,15 @@ impl Fixtures {
         let alchemy_provider = EthProvider::new_alchemy().await;
         let local_provider = EthProvider::new_local().await;
         let book = AddressBook::new();
-        let uniswap_v2_factory = UniswapV2Factory::new(book.mainnet.uniswap_v2.factory, 300);
+        let uniswap_v2_factory =
+            UniswapV2Factory::new(book.mainnet().dexes["uniswap_v2"].factory, 300);
         let pools =
             Checkpoint::<Vec<UniswapV2Pool>>::get(&alchemy_provider, &uniswap_v2_factory, 100)
-                .await;
+                .await
+                .unwrap();
         let weth_usdc_uniswap_v2_pool = UniswapV2Pool::from_address(
             alchemy_provider.http.clone(),
-            book.mainnet.uniswap_v2.pairs["weth"]["usdc"],
+            book.mainnet().dexes["uniswap_v2"].pairs["weth"]["usdc"],
             300,
         )
         .await;
@@ -48,10 +50,10 @@ impl Fixtures {
                 .to_string(),
         };
         let weth_link_matic_weth_path = vec![
-            book.mainnet.erc20["weth"],
-            book.mainnet.erc20["link"],
-            book.mainnet.erc20["matic"],
-            book.mainnet.erc20["weth"],
+            book.mainnet().erc20["weth"],
+            book.mainnet().erc20["link"],
+            book.mainnet().erc20["matic"],
+            book.mainnet().erc20["weth"],
         ];
         Fixtures {
             alchemy_provider,
@@ -67,14 +69,14 @@ impl Fixtures {
 
     pub fn random_pools(&self, size: usize) -> Vec<&UniswapV2Pool> {
         let mut rng = thread_rng();
-        self.pools
-            .data
-            .iter()
-            .filter(|p| p.token_a_decimals > 0 && p.token_b_decimals > 0 && p.reserve_0 > 0)
-            .collect_vec()
-            .choose_multiple(&mut rng, size)
-            .cloned()
-            .collect()
+        let pipeline = FilterPipeline::new(vec![
+            PoolFilter::NonZeroDecimals,
+            PoolFilter::NonZeroReserves,
+        ]);
+        let (pools, _) = pipeline
+            .apply(self.pools.data.iter().collect(), &FilterContext::default())
+            .unwrap();
+        pools.choose_multiple(&mut rng, size).cloned().collect()
     }
 
     pub fn assert_almost_equal(v1: f64, v2: f64, epsilon: f64) {
@@ -0,0 +1,500 @@
+use crate::{
+    eth_provider::{EthProvider, HttpProvider},
+    gas::FeeTracker,
+};
+use ethers::{
+    abi::ParamType,
+    middleware::SignerMiddleware,
+    providers::{Middleware, MiddlewareError},
+    signers::{LocalWallet, Signer},
+    types::{
+        transaction::eip2718::TypedTransaction, BlockNumber, Bytes, TransactionReceipt,
+        TransactionRequest, H160, H256, U256,
+    },
+};
+use std::{fmt, sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::Instant};
+use tracing::warn;
+
+pub type SignerClient = SignerMiddleware<Arc<HttpProvider>, LocalWallet>;
+
+const ERROR_STRING_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+const MIN_REPLACEMENT_BUMP_PERCENT: u64 = 10;
+const CANCEL_GAS: u64 = 21_000;
+const NONCE_ERRORS: [&str; 4] = [
+    "nonce too low",
+    "nonce too high",
+    "already known",
+    "replacement transaction underpriced",
+];
+
+#[derive(Debug)]
+pub enum TxError {
+    Provider(String),
+    Reverted {
+        hash: Option<H256>,
+        reason: Option<String>,
+    },
+    Dropped(H256),
+    Timeout(H256),
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxError::Provider(err) => write!(f, "provider error: {}", err),
+            TxError::Reverted { hash, reason } => write!(
+                f,
+                "transaction {} reverted: {}",
+                hash.map(|h| format!("{:?}", h)).unwrap_or_default(),
+                reason.as_deref().unwrap_or("no reason")
+            ),
+            TxError::Dropped(hash) => write!(f, "transaction {:?} was dropped", hash),
+            TxError::Timeout(hash) => write!(f, "timed out waiting for transaction {:?}", hash),
+        }
+    }
+}
+
+impl std::error::Error for TxError {}
+
+impl TxError {
+    fn from_middleware<E: MiddlewareError>(err: E) -> Self {
+        let reason = err
+            .as_error_response()
+            .and_then(|response| response.as_revert_data())
+            .and_then(|data| decode_revert_reason(&data));
+        match reason {
+            Some(reason) => TxError::Reverted {
+                hash: None,
+                reason: Some(reason),
+            },
+            None => TxError::Provider(err.to_string()),
+        }
+    }
+}
+
+/// Decodes `Error(string)` and `Panic(uint256)` revert payloads. Custom errors are returned as
+/// their hex encoded selector and arguments.
+pub fn decode_revert_reason(data: &[u8]) -> Option<String> {
+    if data.len() < 4 {
+        return None;
+    }
+    let (selector, payload) = data.split_at(4);
+    if selector == ERROR_STRING_SELECTOR {
+        ethers::abi::decode(&[ParamType::String], payload)
+            .ok()?
+            .into_iter()
+            .next()?
+            .into_string()
+    } else if selector == PANIC_SELECTOR {
+        let code = ethers::abi::decode(&[ParamType::Uint(256)], payload)
+            .ok()?
+            .into_iter()
+            .next()?
+            .into_uint()?;
+        Some(format!("panic code {:#x}", code))
+    } else {
+        Some(format!("custom error {}", Bytes::from(data.to_vec())))
+    }
+}
+
+/// True for node rejections caused by a nonce that is already used or ahead of the account.
+fn is_nonce_error(err: &TxError) -> bool {
+    match err {
+        TxError::Provider(message) => {
+            let message = message.to_lowercase();
+            NONCE_ERRORS.iter().any(|e| message.contains(e))
+        }
+        _ => false,
+    }
+}
+
+/// Increases a fee by `percent`, at least by the 10% nodes require to accept a replacement.
+pub fn bump_fee(fee: U256, percent: u64) -> U256 {
+    let percent = percent.max(MIN_REPLACEMENT_BUMP_PERCENT);
+    fee * U256::from(100 + percent) / U256::from(100) + U256::one()
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingTx {
+    pub hash: H256,
+    pub tx: TypedTransaction,
+}
+
+impl PendingTx {
+    pub fn nonce(&self) -> U256 {
+        *self.tx.nonce().expect("Pending transaction without nonce")
+    }
+}
+
+/// Sends transactions from a single account, keeping the nonce locally so concurrent sends do
+/// not collide, and waits for receipts before callers observe the resulting state.
+pub struct TransactionManager {
+    client: Arc<SignerClient>,
+    nonce: Mutex<Option<U256>>,
+    pub fee_tracker: FeeTracker,
+    pub confirmations: u64,
+    pub poll_interval: Duration,
+    pub timeout: Duration,
+}
+
+impl TransactionManager {
+    pub fn new(client: Arc<SignerClient>) -> Self {
+        TransactionManager {
+            client,
+            nonce: Mutex::new(None),
+            fee_tracker: FeeTracker::default(),
+            confirmations: 1,
+            poll_interval: Duration::from_millis(500),
+            timeout: Duration::from_secs(60),
+        }
+    }
+
+    pub async fn from_provider(provider: &EthProvider, private_key: &str) -> Self {
+        Self::new(provider.get_signer_middleware(private_key).await)
+    }
+
+    pub fn address(&self) -> H160 {
+        self.client.address()
+    }
+
+    pub fn client(&self) -> Arc<SignerClient> {
+        self.client.clone()
+    }
+
+    async fn next_nonce(&self) -> Result<U256, TxError> {
+        let mut nonce = self.nonce.lock().await;
+        let next = match *nonce {
+            Some(n) => n,
+            None => self
+                .client
+                .get_transaction_count(self.address(), Some(BlockNumber::Pending.into()))
+                .await
+                .map_err(TxError::from_middleware)?,
+        };
+        *nonce = Some(next + 1);
+        Ok(next)
+    }
+
+    /// Drops the cached nonce so the next send reads it from the node again.
+    pub async fn reset_nonce(&self) {
+        *self.nonce.lock().await = None;
+    }
+
+    /// Hands `nonce` out again after a send that never reached the chain, unless a later nonce
+    /// was taken meanwhile.
+    async fn release_nonce(&self, nonce: U256) {
+        let mut next = self.nonce.lock().await;
+        if *next == Some(nonce + 1) {
+            *next = Some(nonce);
+        }
+    }
+
+    /// Reads the next nonce from the node while holding the lock, so no concurrent send picks
+    /// a stale one in between.
+    async fn resync_nonce(&self) -> Result<(), TxError> {
+        let mut nonce = self.nonce.lock().await;
+        let next = self
+            .client
+            .get_transaction_count(self.address(), Some(BlockNumber::Pending.into()))
+            .await
+            .map_err(TxError::from_middleware)?;
+        *nonce = Some(next);
+        Ok(())
+    }
+
+    async fn broadcast(&self, tx: TypedTransaction) -> Result<PendingTx, TxError> {
+        let signature = self
+            .client
+            .signer()
+            .sign_transaction(&tx)
+            .await
+            .map_err(|e| TxError::Provider(e.to_string()))?;
+        let pending = self
+            .client
+            .send_raw_transaction(tx.rlp_signed(&signature))
+            .await
+            .map_err(TxError::from_middleware)?;
+        Ok(PendingTx { hash: *pending, tx })
+    }
+
+    /// Assigns the next local nonce, fills fees and gas, signs and broadcasts `tx`.
+    pub async fn send<T: Into<TypedTransaction>>(&self, tx: T) -> Result<PendingTx, TxError> {
+        let mut tx = tx.into();
+        tx.set_from(self.address());
+        let nonce = self.next_nonce().await?;
+        tx.set_nonce(nonce);
+        let sent = async {
+            if let Some(eip1559) = tx.as_eip1559_mut() {
+                if eip1559.max_fee_per_gas.is_none() {
+                    let gas_price = self
+                        .fee_tracker
+                        .gas_price(self.client.clone())
+                        .await
+                        .map_err(|e| TxError::Provider(e.to_string()))?;
+                    eip1559.max_fee_per_gas = Some(gas_price.max_fee_per_gas());
+                    eip1559.max_priority_fee_per_gas = Some(gas_price.priority_fee);
+                }
+            }
+            self.client
+                .fill_transaction(&mut tx, None)
+                .await
+                .map_err(TxError::from_middleware)?;
+            self.broadcast(tx).await
+        }
+        .await;
+        if let Err(err) = &sent {
+            if is_nonce_error(err) {
+                // Our local nonce drifted from the node's, e.g. after a send from elsewhere.
+                self.resync_nonce().await?;
+            } else {
+                self.release_nonce(nonce).await;
+            }
+        }
+        sent
+    }
+
+    /// Waits for the receipt of `pending` and for `confirmations` blocks on top of it. Reverted
+    /// transactions are replayed with `eth_call` to recover the revert reason.
+    pub async fn wait_for_receipt(
+        &self,
+        pending: &PendingTx,
+    ) -> Result<TransactionReceipt, TxError> {
+        self.wait_for_any_receipt(std::slice::from_ref(pending))
+            .await
+    }
+
+    /// Like `wait_for_receipt`, for a transaction and its replacements sharing one nonce: any
+    /// of them may be mined. Dropped only once the nonce is used by none of them.
+    async fn wait_for_any_receipt(
+        &self,
+        replacements: &[PendingTx],
+    ) -> Result<TransactionReceipt, TxError> {
+        let last = replacements.last().expect("At least one transaction");
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if Instant::now() > deadline {
+                return Err(TxError::Timeout(last.hash));
+            }
+            let mut found = self.find_receipt(replacements).await?;
+            if found.is_none() && self.nonce_consumed(last).await? {
+                // One of them may have been mined between the lookups and the nonce check.
+                found = self.find_receipt(replacements).await?;
+                if found.is_none() {
+                    return Err(TxError::Dropped(last.hash));
+                }
+            }
+            if let Some((pending, receipt)) = found {
+                if let Some(block_number) = receipt.block_number {
+                    let head = self
+                        .client
+                        .get_block_number()
+                        .await
+                        .map_err(TxError::from_middleware)?;
+                    if head.as_u64() + 1 >= block_number.as_u64() + self.confirmations {
+                        if receipt.status == Some(0.into()) {
+                            return Err(TxError::Reverted {
+                                hash: Some(pending.hash),
+                                reason: self.revert_reason(pending, block_number.as_u64()).await,
+                            });
+                        }
+                        return Ok(receipt);
+                    }
+                }
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    /// The first of `replacements` with a receipt, and the receipt.
+    async fn find_receipt<'a>(
+        &self,
+        replacements: &'a [PendingTx],
+    ) -> Result<Option<(&'a PendingTx, TransactionReceipt)>, TxError> {
+        for pending in replacements {
+            let receipt = self
+                .client
+                .get_transaction_receipt(pending.hash)
+                .await
+                .map_err(TxError::from_middleware)?;
+            if let Some(receipt) = receipt {
+                return Ok(Some((pending, receipt)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// True once a transaction with the same nonce as `pending` is mined.
+    async fn nonce_consumed(&self, pending: &PendingTx) -> Result<bool, TxError> {
+        let mined = self
+            .client
+            .get_transaction_count(self.address(), Some(BlockNumber::Latest.into()))
+            .await
+            .map_err(TxError::from_middleware)?;
+        Ok(mined > pending.nonce())
+    }
+
+    /// Replays `pending` on the state before the block it was mined in.
+    async fn revert_reason(&self, pending: &PendingTx, block_number: u64) -> Option<String> {
+        let parent = block_number.saturating_sub(1);
+        match self
+            .client
+            .call(&pending.tx, Some(BlockNumber::Number(parent.into()).into()))
+            .await
+        {
+            Ok(_) => None,
+            Err(err) => match TxError::from_middleware(err) {
+                TxError::Reverted { reason, .. } => reason,
+                _ => None,
+            },
+        }
+    }
+
+    /// Re-broadcasts `pending` with the same nonce and fees raised by `bump_percent`.
+    pub async fn speed_up(
+        &self,
+        pending: &PendingTx,
+        bump_percent: u64,
+    ) -> Result<PendingTx, TxError> {
+        let mut tx = pending.tx.clone();
+        match tx {
+            TypedTransaction::Eip1559(ref mut inner) => {
+                inner.max_fee_per_gas = inner.max_fee_per_gas.map(|f| bump_fee(f, bump_percent));
+                inner.max_priority_fee_per_gas = inner
+                    .max_priority_fee_per_gas
+                    .map(|f| bump_fee(f, bump_percent));
+            }
+            _ => {
+                if let Some(gas_price) = tx.gas_price() {
+                    tx.set_gas_price(bump_fee(gas_price, bump_percent));
+                }
+            }
+        }
+        self.broadcast(tx).await
+    }
+
+    /// Replaces `pending` with an empty self transfer using the same nonce.
+    pub async fn cancel(&self, pending: &PendingTx) -> Result<PendingTx, TxError> {
+        let mut replacement = pending.tx.clone();
+        replacement.set_to(self.address());
+        replacement.set_value(U256::zero());
+        replacement.set_data(Bytes::default());
+        replacement.set_gas(CANCEL_GAS);
+        let replacement = PendingTx {
+            hash: pending.hash,
+            tx: replacement,
+        };
+        self.speed_up(&replacement, MIN_REPLACEMENT_BUMP_PERCENT)
+            .await
+    }
+
+    /// Sends `tx` and waits for its receipt, speeding it up each time the wait times out, at
+    /// most `max_replacements` times. The receipt of whichever of the transaction and its
+    /// replacements is mined is returned.
+    pub async fn send_and_confirm<T: Into<TypedTransaction>>(
+        &self,
+        tx: T,
+        max_replacements: usize,
+    ) -> Result<TransactionReceipt, TxError> {
+        let mut sent = vec![self.send(tx).await?];
+        loop {
+            match self.wait_for_any_receipt(&sent).await {
+                Err(TxError::Timeout(hash)) if sent.len() <= max_replacements => {
+                    let last = sent.last().expect("At least one transaction");
+                    match self.speed_up(last, 20).await {
+                        Ok(replacement) => {
+                            warn!(
+                                ?hash,
+                                replacement = ?replacement.hash,
+                                replacements = sent.len(),
+                                "replaced stuck transaction"
+                            );
+                            sent.push(replacement);
+                        }
+                        // Likely mined meanwhile, the next wait finds out.
+                        Err(err) => warn!(?hash, %err, "could not replace stuck transaction"),
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Plain value transfer, mostly useful to unstick an account or fund a test wallet.
+    pub async fn transfer(&self, to: H160, value: U256) -> Result<TransactionReceipt, TxError> {
+        let tx = TransactionRequest::new().to(to).value(value);
+        self.send_and_confirm(tx, 0).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::{encode, Token};
+
+    #[test]
+    fn test_decode_revert_reason() {
+        let mut error_string = ERROR_STRING_SELECTOR.to_vec();
+        error_string.extend(encode(&[Token::String(
+            "UniswapV2Router: EXPIRED".to_string(),
+        )]));
+        assert_eq!(
+            decode_revert_reason(&error_string),
+            Some("UniswapV2Router: EXPIRED".to_string())
+        );
+
+        let mut panic = PANIC_SELECTOR.to_vec();
+        panic.extend(encode(&[Token::Uint(U256::from(0x11))]));
+        assert_eq!(
+            decode_revert_reason(&panic),
+            Some("panic code 0x11".to_string())
+        );
+
+        assert_eq!(
+            decode_revert_reason(&[0xde, 0xad, 0xbe, 0xef]),
+            Some("custom error 0xdeadbeef".to_string())
+        );
+        assert_eq!(decode_revert_reason(&[0x01]), None);
+    }
+
+    #[test]
+    fn test_is_nonce_error() {
+        assert!(is_nonce_error(&TxError::Provider(
+            "(code: -32000, message: nonce too low, data: None)".to_string()
+        )));
+        assert!(is_nonce_error(&TxError::Provider(
+            "Replacement transaction underpriced".to_string()
+        )));
+        assert!(!is_nonce_error(&TxError::Provider(
+            "insufficient funds for gas * price + value".to_string()
+        )));
+        assert!(!is_nonce_error(&TxError::Timeout(H256::zero())));
+    }
+
+    #[test]
+    fn test_bump_fee() {
+        assert_eq!(bump_fee(U256::from(100), 0), U256::from(111));
+        assert_eq!(bump_fee(U256::from(100), 25), U256::from(126));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_on_local_node() {
+        let fixture = crate::tests::fixtures::Fixtures::new().await;
+        let manager = TransactionManager::from_provider(
+            &fixture.local_provider,
+            &fixture.local_node_account.private_key,
+        )
+        .await;
+        let recipient = H160::from_low_u64_be(0xbeef);
+        let balance = fixture.local_provider.get_balance(recipient).await;
+        let receipts =
+            futures::future::join_all((0..3).map(|_| manager.transfer(recipient, U256::exp10(15))))
+                .await;
+        assert!(receipts.iter().all(|r| r.is_ok()), "{:?}", receipts);
+        assert_eq!(
+            fixture.local_provider.get_balance(recipient).await,
+            balance + U256::exp10(15) * 3
+        );
+    }
+}